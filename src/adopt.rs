@@ -0,0 +1,208 @@
+//! Snapshots made by other tools.
+//!
+//! A filesystem that was looked after by zfs-auto-snapshot or sanoid before
+//! rack, or still is, has snapshots whose names rack doesn't read, so they
+//! are left out of choosing what to keep, and backed up without their time.
+//! The `adopt` rules of the snap section take such names as those of a
+//! convention:
+//!
+//! ```yaml
+//! snap:
+//!   adopt:
+//!     - scheme: sanoid
+//!       label: daily
+//!       convention: daily
+//!     - pattern: '^backup-(?P<time>\d{8})$'
+//!       date_format: "%Y%m%d"
+//!       convention: weekly
+//! ```
+//!
+//! An adopted snapshot is then read everywhere a snapshot of the convention
+//! is: by the `prune` algorithms and keep rules, by the schedules, and for
+//! the time given to the backups of it, so it is pruned as theirs are.
+//! The names keep the time they were written in, which is local time
+//! unless `utc` is set, and are read back in UTC, as rack writes its own.
+//! `rack adopt` lists the snapshots of the snap volumes that aren't rack's
+//! own, and what each is adopted as.
+//!
+//! Snapper isn't one of the schemes, as it numbers its snapshots, of btrfs
+//! rather than zfs, and keeps their times apart from the names.
+
+use crate::{
+    config::{skipped, AdoptRule, AdoptScheme, Config},
+    naming::{naming, SnapName},
+    zfs::Zfs,
+    Result,
+};
+use chrono::{Local, NaiveDate, NaiveDateTime, TimeZone};
+use regex::Regex;
+use std::sync::Mutex;
+
+/// A rule, ready to match names with.
+struct Adopted {
+    convention: String,
+    re: Regex,
+    date_format: String,
+    label: Option<String>,
+    utc: bool,
+}
+
+/// The adopt rules of the config.  Set once the config is loaded.
+static ADOPTED: Mutex<Vec<Adopted>> = Mutex::new(Vec::new());
+
+impl Adopted {
+    fn new(rule: &AdoptRule) -> std::result::Result<Adopted, String> {
+        let (pattern, date_format) = match (rule.scheme, &rule.pattern) {
+            (Some(AdoptScheme::ZfsAutoSnapshot), None) => (
+                r"^zfs-auto-snap_(?P<label>[^-]+)-(?P<time>\d{4}-\d\d-\d\d-\d{4})$",
+                "%Y-%m-%d-%H%M",
+            ),
+            (Some(AdoptScheme::Sanoid), None) => (
+                r"^autosnap_(?P<time>\d{4}-\d\d-\d\d_\d\d:\d\d:\d\d)_(?P<label>.+)$",
+                "%Y-%m-%d_%H:%M:%S",
+            ),
+            (None, Some(pattern)) => (
+                pattern.as_str(),
+                rule.date_format.as_deref().unwrap_or("%Y%m%d%H%M"),
+            ),
+            _ => return Err("needs one of scheme and pattern".into()),
+        };
+        if rule.scheme.is_some() && rule.date_format.is_some() {
+            return Err("date_format is only used with a pattern".into());
+        }
+        let re = Regex::new(pattern).map_err(|e| format!("invalid pattern: {}", e))?;
+        let groups: Vec<_> = re.capture_names().flatten().collect();
+        if !groups.contains(&"time") {
+            return Err("the pattern has no \"time\" group".into());
+        }
+        if rule.label.is_some() && !groups.contains(&"label") {
+            return Err("the pattern has no \"label\" group to match the label with".into());
+        }
+        Ok(Adopted {
+            convention: rule.convention.clone(),
+            re,
+            date_format: date_format.to_string(),
+            label: rule.label.clone(),
+            utc: rule.utc == Some(true),
+        })
+    }
+
+    /// The time, in UTC, a snapshot this rule adopts was taken.
+    fn parse(&self, name: &str) -> Option<NaiveDateTime> {
+        let cap = self.re.captures(name)?;
+        if let Some(ref label) = self.label {
+            if cap.name("label")?.as_str() != label {
+                return None;
+            }
+        }
+        let text = cap.name("time")?.as_str();
+        let time = NaiveDateTime::parse_from_str(text, &self.date_format).ok().or_else(|| {
+            NaiveDate::parse_from_str(text, &self.date_format).ok()?.and_hms_opt(0, 0, 0)
+        })?;
+        if self.utc {
+            Some(time)
+        } else {
+            Some(Local.from_local_datetime(&time).earliest()?.naive_utc())
+        }
+    }
+}
+
+/// Check an adopt rule, returning a description of the problem if it
+/// can't be used.
+pub fn check(rule: &AdoptRule) -> std::result::Result<(), String> {
+    Adopted::new(rule).map(|_| ())
+}
+
+/// Set the adopt rules.  Those that can't be used are left out, as
+/// checking the config reports them.
+pub fn set_rules(rules: &[AdoptRule]) {
+    *ADOPTED.lock().unwrap() = rules.iter().filter_map(|r| Adopted::new(r).ok()).collect();
+}
+
+/// Take apart the name of a snapshot made by another tool that is adopted
+/// as one of the convention `prefix`.
+pub fn parse(prefix: &str, name: &str) -> Option<SnapName> {
+    ADOPTED
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|a| a.convention == prefix)
+        .find_map(|a| a.parse(name))
+        .map(|time| SnapName { index: None, time })
+}
+
+/// The convention a snapshot made by another tool is adopted as, and the
+/// time it was taken.
+pub fn adopted(name: &str) -> Option<(String, NaiveDateTime)> {
+    ADOPTED
+        .lock()
+        .unwrap()
+        .iter()
+        .find_map(|a| a.parse(name).map(|time| (a.convention.clone(), time)))
+}
+
+impl Config {
+    /// List the snapshots of the snap volumes that rack didn't name, and
+    /// the convention each is adopted as.
+    pub fn adopt(&self) -> Result<()> {
+        let zfs = Zfs::from_inventory("none", &self.inventory)?;
+        for vol in &self.snap.volumes {
+            if skipped("snap", &vol.name, vol.skip) {
+                continue;
+            }
+            let fs = zfs.find(&vol.zfs)?;
+            let (mut taken, mut left) = (0, 0);
+            for snap in &fs.snaps {
+                let ours = self.snap.conventions.iter().any(|c| {
+                    naming(&c.name).parse(&c.name, snap).is_some()
+                });
+                if ours {
+                    continue;
+                }
+                match adopted(snap) {
+                    Some((convention, time)) => {
+                        output!("{}@{}: {} at {} UTC", fs.name, snap, convention, time);
+                        taken += 1;
+                    }
+                    None => {
+                        output!("{}@{}: not adopted", fs.name, snap);
+                        left += 1;
+                    }
+                }
+            }
+            output!("{}: {} snapshots adopted, {} not", fs.name, taken, left);
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_adopt() {
+    let rule = |scheme, pattern: Option<&str>, label: Option<&str>| AdoptRule {
+        convention: "daily".into(),
+        scheme,
+        pattern: pattern.map(|p| p.to_string()),
+        label: label.map(|l| l.to_string()),
+        date_format: None,
+        utc: Some(true),
+    };
+    let at = |d, h, m, s| NaiveDate::from_ymd_opt(2024, 6, d).unwrap().and_hms_opt(h, m, s);
+
+    let auto = Adopted::new(&rule(Some(AdoptScheme::ZfsAutoSnapshot), None, None)).unwrap();
+    assert_eq!(auto.parse("zfs-auto-snap_daily-2024-06-01-0300"), at(1, 3, 0, 0));
+    assert_eq!(auto.parse("zfs-auto-snap_daily-2024-06-31-0300"), None);
+
+    let sanoid = Adopted::new(&rule(Some(AdoptScheme::Sanoid), None, Some("daily"))).unwrap();
+    assert_eq!(sanoid.parse("autosnap_2024-06-02_03:00:01_daily"), at(2, 3, 0, 1));
+    assert_eq!(sanoid.parse("autosnap_2024-06-02_03:00:01_hourly"), None);
+    assert_eq!(sanoid.parse("daily-202406020300"), None);
+
+    let custom = Adopted::new(&rule(None, Some(r"^backup-(?P<time>\d{12})$"), None)).unwrap();
+    assert_eq!(custom.parse("backup-202406030415"), at(3, 4, 15, 0));
+
+    assert!(check(&rule(None, None, None)).is_err());
+    assert!(check(&rule(Some(AdoptScheme::Sanoid), Some("^x$"), None)).is_err());
+    assert!(check(&rule(None, Some(r"^backup-\d+$"), None)).is_err());
+    assert!(check(&rule(None, Some(r"^backup-(?P<time>\d+)$"), Some("daily"))).is_err());
+    assert!(check(&rule(None, Some(r"^backup-(?P<time>\d+$"), None)).is_err());
+}
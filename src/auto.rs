@@ -0,0 +1,237 @@
+//! Running everything, in order.
+//!
+//! `rack auto` runs each configured operation in turn, in the order they
+//! depend on each other: container volumes are copied, snapshots are taken,
+//! and synced filesystems brought up to date, before anything is copied
+//! from them, and snapshots are only pruned once each backup has had its
+//! chance to take them.  With
+//! `auto.parallel`, the sure, restic and borg steps are run together, as a
+//! single step, since they only read the snapshots.  Clone, restic and
+//! borg steps reached outside their windows are deferred to a later run.
+
+use crate::{
+    borg,
+    config::{configured, Config, OnError},
+    jobs::Jobs,
+    restic::{self, Limiter},
+    window::Window,
+    zfs::Zfs,
+    Context, Error, Result,
+};
+use chrono::Utc;
+
+/// A step of `rack auto`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Step {
+    Containers,
+    Snapshot,
+    Sync,
+    Clone,
+    Sure,
+    Restic,
+    Borg,
+    Prune,
+}
+
+impl Step {
+    /// Every step, in the order they are run.
+    pub const ALL: &'static [Step] = &[
+        Step::Containers,
+        Step::Snapshot,
+        Step::Sync,
+        Step::Clone,
+        Step::Sure,
+        Step::Restic,
+        Step::Borg,
+        Step::Prune,
+    ];
+
+    /// The steps run together with `auto.parallel`.
+    const TOGETHER: &'static [Step] = &[Step::Sure, Step::Restic, Step::Borg];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Step::Containers => "containers",
+            Step::Snapshot => "snapshot",
+            Step::Sync => "sync",
+            Step::Clone => "clone",
+            Step::Sure => "sure",
+            Step::Restic => "restic",
+            Step::Borg => "borg",
+            Step::Prune => "prune",
+        }
+    }
+}
+
+impl Config {
+    /// Run every enabled step, stopping at the first failure, or carrying
+    /// on past them, as `auto.on_error` says.
+    pub fn run_auto(&self, pretend: bool) -> Result<()> {
+        let parallel = self.auto.parallel.map_or(false, |n| n > 1);
+        // The steps run together, once the first of them is reached.
+        let mut together: Vec<Step> = vec![];
+
+        let mut failed = vec![];
+        for &step in Step::ALL {
+            if !self.auto_enabled(step) {
+                decision!("auto: skip {}", step.name());
+                continue;
+            }
+            if together.contains(&step) {
+                continue;
+            }
+            if let Some(window) = self.closed_window(step) {
+                decision!("auto: defer {}, outside its window of {}", step.name(), window);
+                continue;
+            }
+            let steps = if parallel && Step::TOGETHER.contains(&step) {
+                together = Step::TOGETHER
+                    .iter()
+                    .copied()
+                    .filter(|&s| self.auto_enabled(s) && self.closed_window(s).is_none())
+                    .collect();
+                &together[..]
+            } else {
+                std::slice::from_ref(&step)
+            };
+            let names: Vec<_> = steps.iter().map(|s| s.name()).collect();
+            let name = names.join("+");
+            progress!("auto: {}", name);
+            if !pretend {
+                names.iter().for_each(|n| self.ping_start(n));
+            }
+            let result = match steps {
+                [step] => self.run_step(*step, pretend),
+                _ => self.run_together(steps, pretend),
+            };
+            if !pretend {
+                names.iter().for_each(|n| self.ping_finish(n, result.as_ref().err()));
+            }
+            if let Err(e) = result.context(format!("auto: {}", name)) {
+                if self.auto.on_error == OnError::Stop {
+                    return Err(e);
+                }
+                warning!("{}", e);
+                failed.push(name);
+            }
+        }
+        self.check_pools();
+        if !failed.is_empty() {
+            return Err(Error::msg(format!("auto: failed steps: {}", failed.join(", "))));
+        }
+        Ok(())
+    }
+
+    /// The window of a step, if it has one, and it is closed now.
+    fn closed_window(&self, step: Step) -> Option<Window> {
+        let window = match step {
+            Step::Clone => &self.clone.window,
+            Step::Restic => &self.restic.window,
+            Step::Borg => &self.borg.window,
+            _ => return None,
+        };
+        window.as_deref().and_then(Window::parse).filter(|w| !w.is_open())
+    }
+
+    /// Is the step run: as set in the `auto` section, otherwise if there is
+    /// anything for it to do.
+    pub fn auto_enabled(&self, step: Step) -> bool {
+        let auto = &self.auto;
+        let (flag, configured) = match step {
+            Step::Containers => (auto.containers, !self.containers.volumes.is_empty()),
+            Step::Snapshot => (auto.snapshot, !self.snap.volumes.is_empty()),
+            Step::Sync => (auto.sync, !self.sync.volumes.is_empty()),
+            Step::Clone => (auto.clone, !self.clone.volumes.is_empty()),
+            Step::Sure => (auto.sure, !self.sure.volumes.is_empty()),
+            Step::Restic => (auto.restic, !self.restic.volumes.is_empty()),
+            Step::Borg => (auto.borg, !self.borg.volumes.is_empty()),
+            Step::Prune => (
+                auto.prune,
+                !self.snap.volumes.is_empty()
+                    && !(self.restic.volumes.is_empty() && self.borg.volumes.is_empty()),
+            ),
+        };
+        flag.unwrap_or(configured)
+    }
+
+    fn run_step(&self, step: Step, pretend: bool) -> Result<()> {
+        match step {
+            Step::Containers => self.run_containers(pretend),
+            Step::Snapshot => {
+                let full_pools = self.full_pools()?;
+                self.snap.snapshot(&self.inventory, &full_pools, Utc::now(), true, pretend)
+            }
+            Step::Sync => self.sync_all(None, self.auto.jobs.unwrap_or(1), pretend),
+            // Receives kept by a clone that failed are resumed by the next one.
+            Step::Clone => self.run_clone(true, false, pretend),
+            Step::Sure => self.sure.run(&self.inventory, None, pretend),
+            Step::Restic => self.run_restic(None, None, pretend),
+            Step::Borg => self.run_borg(None, None, pretend),
+            Step::Prune => self.restic_prune(!pretend),
+        }
+    }
+
+    /// Run the sure, restic and borg steps given, together, as one queue of
+    /// jobs.  Captures are queued first, so that a restic volume pushing a
+    /// sure store waits for the store to be brought up to date.
+    fn run_together(&self, steps: &[Step], pretend: bool) -> Result<()> {
+        for step in steps {
+            match step {
+                Step::Sure => {
+                    configured("sure", &self.sure.volumes)?;
+                    self.sure.validate()?;
+                }
+                Step::Restic => {
+                    configured("restic", &self.restic.volumes)?;
+                    self.restic.validate()?;
+                }
+                _ => configured("borg", &self.borg.volumes)?,
+            }
+        }
+        let _lock = if pretend { None } else { self.preflight()? };
+        let zfs = Zfs::from_inventory("none", &self.inventory)?;
+        let limit = Limiter::new(None);
+
+        let mut jobs = Jobs::new();
+        for step in steps {
+            match step {
+                Step::Sure => self.sure.add_jobs(&mut jobs, &self.inventory, &limit, pretend),
+                Step::Restic => {
+                    restic::add_jobs(&mut jobs, self.restic_work(&zfs, None)?, &limit, pretend)
+                }
+                Step::Borg => {
+                    borg::add_jobs(&mut jobs, self.borg_work(&zfs, None)?, &limit, pretend)
+                }
+                _ => unreachable!("step {} run with others", step.name()),
+            }
+        }
+        jobs.run(self.auto.parallel.unwrap_or(1))
+    }
+}
+
+#[test]
+fn test_auto_enabled() {
+    let text = "\
+snap:
+  conventions: [{name: daily, daily: 7}]
+  volumes: [{name: home, convention: daily, zfs: a/home}]
+sure: {volumes: []}
+restic: {volumes: []}
+clone: {volumes: []}
+borg:
+  volumes: [{name: home, zfs: a/home, repo: /backup/borg, bind: /mnt/home, prefix: home-}]
+auto: {sure: true, borg: false, parallel: 4, on_error: continue}
+";
+    let conf = Config::parse(text, "lint").unwrap();
+    let enabled: Vec<_> = Step::ALL
+        .iter()
+        .filter(|s| conf.auto_enabled(**s))
+        .map(|s| s.name())
+        .collect();
+    assert_eq!(enabled, vec!["snapshot", "sure", "prune"]);
+    assert_eq!(conf.auto.on_error, OnError::Continue);
+    assert_eq!(conf.auto.parallel, Some(4));
+
+    let e = Config::parse(&text.replace("on_error: continue", "on_error: retry"), "lint");
+    assert!(e.unwrap_err().to_string().starts_with("auto.on_error"));
+}
@@ -1,93 +1,510 @@
 //! Borg backups
 
-use crate::sync::MountedDir;
-use crate::Result;
-use crate::zfs::{find_mount, Filesystem};
+use crate::checked::{heavy_command, CheckedExt, Watch};
+use crate::config::{configured, skipped, BorgVolume, Config, RetryOn, SnapConvention};
+use crate::digest;
+use crate::excludes;
+use crate::hooks::Hooks;
+use crate::jobs::Jobs;
+use crate::journal;
+use crate::meter::{Meter, Unit};
+use crate::naming::snap_time;
+use crate::restic::Limiter;
+use crate::retry::{network_error, Retries};
+use crate::runlock::{self, RunLock};
+use crate::secret::SecretSource;
+use crate::{Error, Result};
+use crate::zfs::{humanize_size, Filesystem, Zfs};
 
-use failure::format_err;
+use chrono::NaiveDateTime;
+use serde_derive::{Deserialize, Serialize};
 use std::{
-    collections::HashSet,
-    fs,
-    io::{BufRead, BufReader},
+    collections::{HashMap, HashSet},
     path::Path,
-    process::{Command, Stdio},
+    process::{Command, ExitStatus, Stdio},
 };
+use thiserror::Error;
 
-pub fn run(fs: &Filesystem, borg_repo: &str, name: &str, pretend: bool) -> Result<()> {
-    let out = Command::new("borg")
-        .args(&["list", "--short", borg_repo])
-        .stderr(Stdio::inherit())
-        .output()?;
-    if !out.status.success() {
-        return Err(format_err!("Unable to run borg: {:?}", out.status));
-    }
-    let buf = out.stdout;
+/// Errors from borg.
+#[derive(Debug, Error)]
+pub enum BorgError {
+    #[error("Borg repo {repo:?} is locked (set break_lock to recover)")]
+    Locked { repo: String },
+    #[error("Borg repo {repo:?} is locked, and another rack is running")]
+    LockedByOther { repo: String },
+    #[error("Error running borg: {status:?}")]
+    Failed { status: ExitStatus },
+    #[error("Convention {convention:?} has no keep rules for borg {volume:?}")]
+    NoKeepRules { convention: String, volume: String },
+}
+
+// The parts of the output of `borg create --json` that we care about.
+#[derive(Debug, Deserialize)]
+struct CreateOutput {
+    archive: CreateArchive,
+}
+
+#[derive(Debug, Deserialize)]
+struct CreateArchive {
+    name: String,
+    duration: f64,
+    stats: ArchiveStats,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ArchiveStats {
+    original_size: u64,
+    compressed_size: u64,
+    deduplicated_size: u64,
+    nfiles: u64,
+}
+
+/// The lines `borg create --log-json` writes to stderr, that we care about.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum LogLine {
+    ArchiveProgress {
+        #[serde(default)]
+        nfiles: u64,
+    },
+    LogMessage {
+        levelname: String,
+        message: String,
+    },
+    #[serde(other)]
+    Other,
+}
 
-    let mut present = HashSet::new();
-    for line in BufReader::new(&buf[..]).lines() {
-        let line = line?;
-        present.insert(line);
+/// Journal record of a single borg archive being written.
+#[derive(Debug, Serialize)]
+struct CreateRecord<'a> {
+    repo: &'a str,
+    archive: &'a str,
+    duration: f64,
+    stats: &'a ArchiveStats,
+}
+
+/// Back up the snapshots of `fs` that aren't in the repo yet, between the
+/// volume's hooks.
+pub fn run(fs: &Filesystem, vol: &BorgVolume, limit: &Limiter, pretend: bool) -> Result<()> {
+    if pretend {
+        return backup(fs, vol, limit, pretend);
     }
+    let hooks = Hooks::new(&vol.pre_hook, &vol.post_hook, vol.hook_failure);
+    hooks.around(&vol.name, || backup(fs, vol, limit, pretend))
+}
 
-    println!(
+fn backup(fs: &Filesystem, vol: &BorgVolume, limit: &Limiter, pretend: bool) -> Result<()> {
+    let borg_repo = &vol.repo;
+    let name = &vol.prefix;
+    let present = list_archives(vol)?;
+
+    progress!(
         "Borg: {} snapshots to backup",
         fs.snaps
             .iter()
-            .filter(|x| {
-                let snapname = format!("{}{}", name, x);
-                !present.contains(&snapname[..])
-            }).count()
+            .filter(|x| !present.contains(name, x))
+            .count()
     );
 
     // Go through all of the snapshots, in order, and back up ones that are missing.
     for snap in &fs.snaps {
-        let snapname = format!("{}{}", name, snap);
-        if present.contains(&snapname) {
+        if present.contains(name, snap) {
             continue;
         }
 
+        if limit.exhausted() {
+            break;
+        }
+
         if pretend {
-            println!("borg create -p --exclude-caches {:?} {:?} {:?}",
-                     borg_repo, snap, name);
+            decision!("borg create -p --exclude-caches {:?} {:?} {:?}",
+                      borg_repo, snap, name);
         } else {
-            fs.borg_backup(borg_repo, snap, name)?;
+            fs.borg_backup(vol, snap)?;
         }
     }
 
     Ok(())
 }
 
+// The output of `borg list --json`.
+#[derive(Debug, Deserialize)]
+struct ListOutput {
+    archives: Vec<Archive>,
+}
+
+/// A single archive in a borg repo.
+#[derive(Debug, Deserialize)]
+pub struct Archive {
+    pub id: String,
+    pub name: String,
+    /// The archive timestamp, in borg's ISO-8601 format, without a timezone.
+    pub time: String,
+}
+
+impl Archive {
+    fn timestamp(&self) -> Option<NaiveDateTime> {
+        let text = self.time.get(..19)?;
+        NaiveDateTime::parse_from_str(text, "%Y-%m-%dT%H:%M:%S").ok()
+    }
+}
+
+/// The archives present in a borg repo.
+pub struct Archives {
+    archives: Vec<Archive>,
+    names: HashSet<String>,
+}
+
+impl Archives {
+    /// Is the given zfs snapshot present in these archives, under the given
+    /// archive name prefix?  This matches by name, or failing that, by an
+    /// archive with the same prefix whose timestamp matches the snapshot.
+    pub fn contains(&self, prefix: &str, snap: &str) -> bool {
+        if self.names.contains(&format!("{}{}", prefix, snap)) {
+            return true;
+        }
+        match snap_time(snap) {
+            Some(time) => self
+                .archives
+                .iter()
+                .any(|a| a.name.starts_with(prefix) && a.timestamp() == Some(time)),
+            None => false,
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Archive> {
+        self.archives.iter()
+    }
+
+    /// The time of the newest archive with the given prefix, in local time.
+    pub fn newest(&self, prefix: &str) -> Option<NaiveDateTime> {
+        self.archives
+            .iter()
+            .filter(|a| a.name.starts_with(prefix))
+            .filter_map(|a| a.timestamp())
+            .max()
+    }
+}
+
+/// Return all of the archives in the borg repo of the given volume.
+pub fn list_archives(vol: &BorgVolume) -> Result<Archives> {
+    let mut cmd = Command::new("borg");
+    vol.add_auth(&mut cmd)?;
+    let out = cmd
+        .args(&["list", "--json", &vol.repo])
+        .stderr(Stdio::inherit())
+        .output()?;
+    if !out.status.success() {
+        return Err(BorgError::Failed { status: out.status }.into());
+    }
+
+    let list: ListOutput = serde_json::from_slice(&out.stdout)?;
+    let names = list.archives.iter().map(|a| a.name.clone()).collect();
+    Ok(Archives {
+        archives: list.archives,
+        names: names,
+    })
+}
+
+impl Config {
+    /// Run `borg prune` on every borg volume that has a convention, keeping
+    /// archives according to that convention's rules.  Unless `really` is
+    /// set, borg is run with `--dry-run`.
+    pub fn borg_prune(&self, really: bool) -> Result<()> {
+        configured("borg", &self.borg.volumes)?;
+        let convs: HashMap<&str, &SnapConvention> = self
+            .snap
+            .conventions
+            .iter()
+            .map(|c| (c.name.as_str(), c))
+            .collect();
+
+        // Look up all of the conventions first, so that a typo doesn't leave
+        // us having pruned only some of the repos.
+        let mut work = vec![];
+        for v in &self.borg.volumes {
+            if let Some(ref conv) = v.convention {
+                let c = convs.get(conv.as_str()).ok_or_else(|| {
+                    Error::msg(format!("Invalid convention {:?} in borg {:?}", conv, v.name))
+                })?;
+                work.push((v, *c));
+            }
+        }
+
+        for (v, c) in work {
+            v.prune(c, really)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Does this stderr output from borg indicate the repo lock couldn't be
+/// acquired?
+fn is_lock_error(stderr: &[u8]) -> bool {
+    let text = String::from_utf8_lossy(stderr);
+    text.contains("Failed to create/acquire the lock")
+}
+
+impl Config {
+    /// Run borg backups for the volumes in the config file.  If `name` is
+    /// given, only back up that volume.  At most `limit` archives are made,
+    /// across all volumes.
+    pub fn run_borg(&self, name: Option<&str>, limit: Option<usize>, pretend: bool) -> Result<()> {
+        configured("borg", &self.borg.volumes)?;
+        let limit = Limiter::new(limit);
+        let lock = RunLock::try_acquire()?;
+        if !pretend && lock.is_some() {
+            self.preflight()?;
+        }
+        let zfs = Zfs::from_inventory("none", &self.inventory)?;
+        for (vol, fs) in self.borg_work(&zfs, name)? {
+            run(fs, vol, &limit, pretend)?;
+        }
+        Ok(())
+    }
+
+    /// The borg volumes to back up, with their filesystems.
+    pub(crate) fn borg_work<'a>(
+        &'a self,
+        zfs: &'a Zfs,
+        name: Option<&str>,
+    ) -> Result<Vec<(&'a BorgVolume, &'a Filesystem)>> {
+        let mut work = vec![];
+        for vol in &self.borg.volumes {
+            match name {
+                None => (),
+                Some(given) if given == vol.name => (),
+                _ => continue,
+            }
+            if skipped("borg", &vol.name, vol.skip) {
+                continue;
+            }
+
+            let fs = zfs.find_filesystem(&vol.zfs)?;
+            if !fs.readable(vol.unmounted.unwrap_or_default())? {
+                continue;
+            }
+            work.push((vol, fs));
+        }
+        Ok(work)
+    }
+}
+
+/// Add a job backing up each of the volumes.  Volumes sharing a repo or a
+/// bind directory are run one after another.
+pub fn add_jobs<'a>(
+    jobs: &mut Jobs<'a>,
+    work: Vec<(&'a BorgVolume, &'a Filesystem)>,
+    limit: &'a Limiter,
+    pretend: bool,
+) {
+    for (vol, fs) in work {
+        let uses = vec![format!("dir:{}", vol.bind), format!("repo:{}", vol.repo)];
+        jobs.add(format!("borg {:?}", vol.name), uses, move || run(fs, vol, limit, pretend));
+    }
+}
+
+impl BorgVolume {
+    /// Is the repo on another host, as "ssh://host/path" or "host:path".
+    fn remote(&self) -> bool {
+        let repo = &self.repo;
+        repo.starts_with("ssh://") || repo.find(':').map_or(false, |c| !repo[..c].contains('/'))
+    }
+
+    /// Give borg the passphrase for this repo, if one is configured.  A
+    /// command is passed to borg to run itself, so the passphrase never
+    /// passes through rack.
+    fn add_auth(&self, cmd: &mut Command) -> Result<()> {
+        match self.passphrase {
+            Some(SecretSource::Command(ref command)) => {
+                cmd.env("BORG_PASSCOMMAND", command);
+            }
+            Some(ref source) => {
+                cmd.env("BORG_PASSPHRASE", source.get()?);
+            }
+            None => (),
+        }
+        Ok(())
+    }
+
+    /// Prune the archives of this volume, using the keep rules of the given
+    /// convention.
+    fn prune(&self, conv: &SnapConvention, really: bool) -> Result<()> {
+        let mut cmd = Command::new("borg");
+        self.add_auth(&mut cmd)?;
+        cmd.args(&["prune", "--list"]);
+        if !really {
+            cmd.arg("--dry-run");
+        }
+        cmd.arg("--glob-archives");
+        cmd.arg(&format!("{}*", self.prefix));
+
+        let rules = [
+            ("--keep-last", conv.last),
+            ("--keep-hourly", conv.hourly),
+            ("--keep-daily", conv.daily),
+            ("--keep-weekly", conv.weekly),
+            ("--keep-monthly", conv.monthly),
+            ("--keep-yearly", conv.yearly),
+        ];
+        let mut any = false;
+        for &(flag, count) in &rules {
+            if let Some(count) = count {
+                cmd.arg(flag);
+                cmd.arg(&count.to_string());
+                any = true;
+            }
+        }
+        // Borg refuses to prune without any rules, but make the error
+        // clearer.
+        if !any {
+            return Err(BorgError::NoKeepRules {
+                convention: conv.name.clone(),
+                volume: self.name.clone(),
+            }
+            .into());
+        }
+
+        cmd.arg(&self.repo);
+        cmd.stderr(Stdio::inherit());
+        progress!("Borg prune {:?}: {:?}", self.name, cmd);
+        cmd.checked_run()?;
+        Ok(())
+    }
+
+    /// Delete the given archives from the repo.
+    pub(crate) fn delete(&self, archives: &[String]) -> Result<()> {
+        let mut cmd = Command::new("borg");
+        self.add_auth(&mut cmd)?;
+        cmd.args(&["delete", &self.repo]);
+        cmd.args(archives);
+        cmd.stderr(Stdio::inherit());
+        cmd.checked_run()
+    }
+}
+
 impl Filesystem {
-    fn borg_backup(&self, borg_repo: &str, snap: &str, name: &str) -> Result<()> {
-        let mount = find_mount(&self.name)?;
-        let dest = format!("{}/.zfs/snapshot/{}", mount, snap);
-
-        // Stat "." in this directory to request ZFS automount the snapshot.
-        let meta = fs::metadata(format!("{}/.", dest))?;
-        if !meta.is_dir() {
-            return Err(format_err!("Snapshot is not a directory: {:?}", dest));
-        }
-
-        // Bind mount to have consistent path for borg.  This needs to be specific to the given
-        // filesystem.
-        let srcdir = match name {
-            "gentoo-" => "/mnt/root",
-            "home-" => "/mnt/home",
-            name => return Err(format_err!("Unsupported borg backup name: {:?}", name)),
-        };
-        let _root = MountedDir::new(&dest, Path::new(&srcdir))?;
+    fn borg_backup(&self, vol: &BorgVolume, snap: &str) -> Result<()> {
+        let borg_repo = &vol.repo;
+        let name = vol.prefix.as_str();
+        // Mount on the bind directory to have consistent path for borg.  This needs to be specific
+        // to the given filesystem.
+        let srcdir = vol.bind.as_str();
+        let _root = self.mount_snapshot(snap, Path::new(srcdir))?;
 
         let archive = format!("{}::{}{}", borg_repo, name, snap);
 
         // Run the backup itself.
-        println!("Backing up {:?} to {:?}", dest, archive);
-
-        let status = Command::new("borg")
-            .args(&["create", "-p", "--exclude-caches", &archive, &srcdir])
-            .stderr(Stdio::inherit())
-            .status()?;
-        if !status.success() {
-            return Err(format_err!("Error running borg: {:?}", status));
+        progress!("Backing up {}@{} to {:?}", self.name, snap, archive);
+
+        let build = || -> Result<Command> {
+            let mut cmd = heavy_command("borg");
+            vol.add_auth(&mut cmd)?;
+            cmd.args(&["create", "--progress", "--log-json", "--stats", "--json"]);
+            cmd.arg("--exclude-caches");
+            // Give the archive the time of the snapshot, rather than when the
+            // backup happened to run.
+            if let Some(time) = snap_time(snap) {
+                cmd.args(&["--timestamp", &time.format("%Y-%m-%dT%H:%M:%S").to_string()]);
+            }
+            if let Some(ref compression) = vol.compression {
+                cmd.args(&["--compression", compression]);
+            }
+            if let Some(ref chunker) = vol.chunker_params {
+                cmd.args(&["--chunker-params", chunker]);
+            }
+            cmd.args(excludes::borg_args(&vol.excludes, srcdir));
+            cmd.args(&[&archive, srcdir]);
+            Ok(cmd)
+        };
+
+        // Progress, and borg's messages, come as JSON on stderr.
+        let mut meter = Meter::new(&format!("Borg {}@{}", self.name, snap), Unit::Files, None);
+        let mut watch = |line: &str| match serde_json::from_str::<LogLine>(line) {
+            Ok(LogLine::ArchiveProgress { nfiles }) => meter.set(nfiles),
+            Ok(LogLine::LogMessage { levelname, message }) => match levelname.as_str() {
+                "WARNING" | "ERROR" | "CRITICAL" => warning!("borg: {}", message),
+                _ => progress!("borg: {}", message),
+            },
+            Ok(LogLine::Other) => (),
+            Err(_) => warning!("borg: {}", line),
+        };
+
+        let mut retries = Retries::new();
+        let mut out = loop {
+            let out = build()?.watch_output(Watch::Stderr, &mut watch)?;
+            if out.status.success() || !vol.remote() {
+                break out;
+            }
+            // A lock that will be broken isn't worth waiting for.
+            let failure = if network_error(&out.stderr) {
+                Some(RetryOn::Network)
+            } else if is_lock_error(&out.stderr) && vol.break_lock != Some(true) {
+                Some(RetryOn::Locked)
+            } else {
+                None
+            };
+            if !retries.again(&format!("Borg to {:?}", borg_repo), failure) {
+                break out;
+            }
+        };
+        if !out.status.success() && is_lock_error(&out.stderr) {
+            if vol.break_lock != Some(true) {
+                return Err(BorgError::Locked {
+                    repo: borg_repo.clone(),
+                }
+                .into());
+            }
+            // Only break the lock if we hold the run lock, which means that
+            // no other rack is using the repo.
+            if !runlock::held_by_us() {
+                return Err(BorgError::LockedByOther {
+                    repo: borg_repo.clone(),
+                }
+                .into());
+            }
+            warning!("Borg repo {:?} is locked, breaking lock", borg_repo);
+            let mut cmd = Command::new("borg");
+            vol.add_auth(&mut cmd)?;
+            cmd.args(&["break-lock", borg_repo]);
+            cmd.stderr(Stdio::inherit());
+            cmd.checked_run()?;
+
+            out = build()?.watch_output(Watch::Stderr, &mut watch)?;
+        }
+        drop(meter);
+        if !out.status.success() {
+            return Err(BorgError::Failed { status: out.status }.into());
+        }
+
+        // The stats are informational, so don't fail the backup if they
+        // can't be understood.
+        match serde_json::from_slice::<CreateOutput>(&out.stdout) {
+            Ok(created) => {
+                let stats = &created.archive.stats;
+                progress!(
+                    "Archive {}: {} files, {} original, {} deduplicated, {:.1}s",
+                    created.archive.name,
+                    stats.nfiles,
+                    humanize_size(stats.original_size as usize),
+                    humanize_size(stats.deduplicated_size as usize),
+                    created.archive.duration
+                );
+                digest::bytes(stats.deduplicated_size as u64);
+                journal::record(
+                    "borg-create",
+                    &vol.name,
+                    &CreateRecord {
+                        repo: borg_repo,
+                        archive: &created.archive.name,
+                        duration: created.archive.duration,
+                        stats: stats,
+                    },
+                )?;
+            }
+            Err(e) => warning!("Unable to parse borg stats: {}", e),
         }
 
         Ok(())
@@ -0,0 +1,158 @@
+//! Mounting snapshots to browse.
+//!
+//! `rack mount home 2024-06-01` finds the snapshot wanted of a volume, and
+//! mounts it on a directory of its own, where it stays until `rack umount`,
+//! so that an old file can be copied out without finding the way through
+//! `.zfs/snapshot`.  The snapshot is picked by its whole name, or by a date,
+//! with an optional time, for the newest snapshot taken by then, or else by
+//! part of its name, as long as only one snapshot has it.  The mount is
+//! made where rack was started, even with `private_mounts`, so that it is
+//! still there once rack exits.
+
+use crate::{
+    config::Config,
+    journal::state_dir,
+    mount,
+    naming::snap_time,
+    zfs::{Inventory, Zfs},
+    Error, Result,
+};
+use chrono::{Local, NaiveDate, NaiveDateTime, TimeZone};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+impl Config {
+    /// Mount the snapshot of `volume` picked by `selector` on `dir`, or on
+    /// a directory named for the volume, and print where it is.  The volume
+    /// is a snap volume, or a zfs filesystem.
+    pub fn mount(&self, volume: &str, selector: &str, dir: Option<&str>) -> Result<()> {
+        let name = self.snap_zfs(volume);
+        let zfs = Zfs::from_inventory("none", &Inventory::new().within(name))?;
+        let fs = zfs.find_filesystem(name)?;
+        let snap = select(&fs.snaps, selector)?;
+
+        let dir = match dir {
+            Some(dir) => PathBuf::from(dir),
+            None => mount_base()?.join(volume.replace('/', "_")),
+        };
+        fs::create_dir_all(&dir)?;
+        let dir = dir.canonicalize()?;
+        if is_mounted(&dir)? {
+            let msg = format!("{:?} is already mounted on, use rack umount first", dir);
+            return Err(Error::msg(msg));
+        }
+        fs.mount_snapshot(snap, &dir)?.keep();
+        output!("{}", dir.display());
+        Ok(())
+    }
+
+    /// The zfs filesystem of a snap volume, or, if there isn't one by that
+    /// name, `volume` itself.
+    pub(crate) fn snap_zfs<'a>(&'a self, volume: &'a str) -> &'a str {
+        match self.snap.volumes.iter().find(|v| v.name == volume) {
+            Some(vol) => vol.zfs.as_str(),
+            None => volume,
+        }
+    }
+}
+
+/// Unmount what `rack mount` mounted: `dir`, or the directory of `volume`,
+/// or with neither, everything mounted under rack's own directory.
+pub fn umount(volume: Option<&str>, dir: Option<&str>) -> Result<()> {
+    let base = mount_base()?;
+    let dirs = match (dir, volume) {
+        (Some(dir), _) => vec![Path::new(dir).canonicalize()?],
+        (None, Some(volume)) => vec![base.join(volume.replace('/', "_"))],
+        (None, None) => {
+            let mut dirs: Vec<_> = mount::mounts()?
+                .into_iter()
+                .map(|m| PathBuf::from(m.mountpoint))
+                .filter(|m| m.starts_with(&base) && *m != base)
+                .collect();
+            dirs.dedup();
+            if dirs.is_empty() {
+                progress!("Nothing mounted under {:?}", base);
+            }
+            dirs
+        }
+    };
+    for dir in dirs {
+        if !is_mounted(&dir)? {
+            return Err(Error::msg(format!("Nothing is mounted on {:?}", dir)));
+        }
+        progress!("Unmount {:?}", dir);
+        mount::unmount(&dir, false)?;
+        // Only the directories made for a volume are removed.
+        if dir.parent() == Some(base.as_path()) {
+            fs::remove_dir(&dir)?;
+        }
+    }
+    Ok(())
+}
+
+/// The directory the snapshots of each volume are mounted under.
+fn mount_base() -> Result<PathBuf> {
+    Ok(state_dir()?.join("mnt"))
+}
+
+fn is_mounted(dir: &Path) -> Result<bool> {
+    Ok(mount::mounts()?.iter().any(|m| Path::new(&m.mountpoint) == dir))
+}
+
+/// The snapshot, of `snaps`, that `selector` picks: one with that name, the
+/// newest taken by the date, and time, it gives, or the only one whose name
+/// contains it.
+pub(crate) fn select<'a>(snaps: &'a [String], selector: &str) -> Result<&'a str> {
+    if let Some(snap) = snaps.iter().find(|s| *s == selector) {
+        return Ok(snap);
+    }
+    if let Some(by) = parse_when(selector) {
+        return snaps
+            .iter()
+            .filter_map(|s| snap_time(s).map(|time| (s, time)))
+            .filter(|&(_, time)| time <= by)
+            .max_by_key(|&(_, time)| time)
+            .map(|(s, _)| s.as_str())
+            .ok_or_else(|| Error::msg(format!("No snapshot taken by {}", selector)));
+    }
+    let found: Vec<_> = snaps.iter().filter(|s| s.contains(selector)).collect();
+    match found[..] {
+        [snap] => Ok(snap),
+        [] => Err(Error::msg(format!("No snapshot matches {:?}", selector))),
+        _ => {
+            let names: Vec<_> = found.iter().map(|s| s.as_str()).collect();
+            let msg = format!("{:?} matches several snapshots: {}", selector, names.join(" "));
+            Err(Error::msg(msg))
+        }
+    }
+}
+
+/// The time, in UTC as snapshots are named, given by a selector such as
+/// "2024-06-01" or "2024-06-01 12:00", in local time.  A date alone means
+/// the end of that day.
+fn parse_when(text: &str) -> Option<NaiveDateTime> {
+    let formats = ["%Y-%m-%d %H:%M", "%Y-%m-%dT%H:%M", "%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S"];
+    let time = formats
+        .iter()
+        .find_map(|f| NaiveDateTime::parse_from_str(text, f).ok())
+        .or_else(|| NaiveDate::parse_from_str(text, "%Y-%m-%d").ok()?.and_hms_opt(23, 59, 59))?;
+    Some(Local.from_local_datetime(&time).earliest()?.naive_utc())
+}
+
+#[test]
+fn test_select() {
+    let snaps: Vec<String> = ["daily-202406010300", "daily-202406020300", "caz0042-202406021500"]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+    assert_eq!(select(&snaps, "daily-202406020300").unwrap(), "daily-202406020300");
+    assert_eq!(select(&snaps, "0042").unwrap(), "caz0042-202406021500");
+    assert!(select(&snaps, "daily").is_err());
+    assert!(select(&snaps, "weekly").is_err());
+
+    // The newest taken by the end of the day, whatever the local time zone.
+    assert_eq!(select(&snaps, "2024-06-03").unwrap(), "caz0042-202406021500");
+    assert!(select(&snaps, "2024-05-30").is_err());
+}
@@ -0,0 +1,45 @@
+//! Manage btrfs snapshots.
+
+use chrono::Local;
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use crate::checked::CheckedExt;
+use crate::Result;
+
+/// A read-only snapshot of a btrfs subvolume.  The snapshot is deleted when
+/// this is dropped.
+pub struct BtrfsSnap(PathBuf);
+
+impl BtrfsSnap {
+    /// Create a read-only snapshot of the given subvolume.  The snapshot is
+    /// placed inside the subvolume itself, which keeps it on the same
+    /// filesystem, and out of any later snapshots of it.
+    pub fn create(subvolume: &str) -> Result<BtrfsSnap> {
+        let name = format!(".rack-snap-{}", Local::now().format("%Y%m%d%H%M%S"));
+        let path = Path::new(subvolume).join(name);
+        Command::new("btrfs")
+            .args(&["subvolume", "snapshot", "-r", subvolume])
+            .arg(&path)
+            .checked_run()?;
+        Ok(BtrfsSnap(path))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for BtrfsSnap {
+    fn drop(&mut self) {
+        let st = Command::new("btrfs")
+            .args(&["subvolume", "delete"])
+            .arg(&self.0)
+            .checked_run();
+        if let Err(e) = st {
+            warning!("Error deleting btrfs snapshot {:?}: {:?}", self.0, e);
+        }
+    }
+}
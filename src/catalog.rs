@@ -0,0 +1,127 @@
+//! The catalog of exported streams.
+//!
+//! Each `zfs send` stream rack writes outside of zfs, such as to cloud
+//! storage, is recorded in the catalog, a JSON file in the state directory.
+//! Streams are either full, or an increment from an earlier snapshot, so
+//! restoring a snapshot means replaying a chain of them, starting with a
+//! full one.
+
+use crate::{config::Compression, journal, Context, Result};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Catalog {
+    #[serde(default)]
+    pub streams: Vec<Stream>,
+}
+
+/// A single stream that has been written.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Stream {
+    /// Where the stream was written, such as the rclone remote.
+    pub target: String,
+    /// The zfs filesystem the stream was sent from.
+    pub zfs: String,
+    /// The name of the stream within the target.
+    pub object: String,
+    /// The snapshot an incremental stream starts from, or None for a full
+    /// stream.
+    pub from: Option<String>,
+    /// The snapshot the stream brings the filesystem up to.
+    pub to: String,
+    pub compression: Compression,
+    pub encrypted: bool,
+    /// The SHA-256 of the stream as stored, where the target keeps it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    /// When the stream was written, in RFC3339.
+    pub time: String,
+}
+
+impl Catalog {
+    /// The catalog file in the state directory.
+    pub fn default_path() -> Result<PathBuf> {
+        Ok(journal::state_dir()?.join("catalog.json"))
+    }
+
+    /// Read a catalog, which is empty if the file doesn't exist yet.
+    pub fn load(path: &Path) -> Result<Catalog> {
+        if !path.exists() {
+            return Ok(Catalog::default());
+        }
+        let text = fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&text).context(format!("{:?}", path))?)
+    }
+
+    /// Write the catalog, replacing the file only once it is complete.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut text = serde_json::to_string_pretty(self)?;
+        text.push('\n');
+        let tmp = path.with_extension("json.tmp");
+        fs::write(&tmp, text)?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    /// The streams written of a filesystem to a target, oldest first.
+    pub fn streams(&self, target: &str, zfs: &str) -> Vec<&Stream> {
+        self.streams
+            .iter()
+            .filter(|s| s.target == target && s.zfs == zfs)
+            .collect()
+    }
+}
+
+/// The streams to replay, full first, to restore the snapshot `to`, or the
+/// latest one if not given.  Returns None if there is no stream of that
+/// snapshot, or the chain to it is broken.
+pub fn chain<'a>(streams: &[&'a Stream], to: Option<&str>) -> Option<Vec<&'a Stream>> {
+    let mut pos = match to {
+        Some(to) => streams.iter().rposition(|s| s.to == to)?,
+        None => streams.len().checked_sub(1)?,
+    };
+    let mut result = vec![streams[pos]];
+    while let Some(ref from) = streams[pos].from {
+        pos = streams[..pos].iter().rposition(|s| &s.to == from)?;
+        result.push(streams[pos]);
+    }
+    result.reverse();
+    Some(result)
+}
+
+#[test]
+fn test_chain() {
+    let stream = |from: Option<&str>, to: &str| Stream {
+        target: "b2:bucket".into(),
+        zfs: "pool/home".into(),
+        object: to.into(),
+        from: from.map(|f| f.to_string()),
+        to: to.into(),
+        compression: Compression::Zstd,
+        encrypted: false,
+        sha256: None,
+        time: String::new(),
+    };
+    let all = vec![
+        stream(None, "a"),
+        stream(Some("a"), "b"),
+        stream(Some("b"), "c"),
+        stream(None, "d"),
+        stream(Some("d"), "e"),
+        stream(Some("x"), "f"),
+    ];
+    let streams: Vec<_> = all.iter().collect();
+    let tos = |to| chain(&streams, to).map(|c| c.iter().map(|s| s.to.as_str()).collect::<Vec<_>>());
+
+    assert_eq!(tos(Some("c")), Some(vec!["a", "b", "c"]));
+    assert_eq!(tos(Some("e")), Some(vec!["d", "e"]));
+    assert_eq!(tos(Some("d")), Some(vec!["d"]));
+    assert_eq!(tos(Some("f")), None);
+    assert_eq!(tos(None), None);
+    assert_eq!(tos(Some("z")), None);
+    assert_eq!(chain(&[], None), None);
+}
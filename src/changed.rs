@@ -0,0 +1,214 @@
+//! What has changed since the last backup.
+//!
+//! `rack changed home` lists the files of a volume that have changed since
+//! the snapshot of its newest restic backup, largest first, to show what
+//! the next backup will have to read, and with `--last`, those that changed
+//! between the two newest backups, to show what made the last one as big
+//! as it was.  `--since` and `--until` pick other snapshots, as for `rack
+//! mount`.  Between two snapshots that both have sure data, the changes
+//! come from comparing it, and otherwise from `zfs diff`.  Sizes are those
+//! of the files now, or in the later snapshot, and for a removed file, in
+//! the earlier one.  Directories aren't listed.
+
+use crate::{
+    browse::select,
+    checked::CheckedExt,
+    config::Config,
+    mount,
+    restic::ResticError,
+    surecmp::{compare, Change},
+    zfs::{humanize_size, zfs_command, Filesystem, Zfs, ZfsError},
+    Error, Result,
+};
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+    process::Stdio,
+};
+
+impl Config {
+    /// Show the files of `volume` changed since the newest snapshot backed
+    /// up by restic, or between the two newest with `last`.  `since` and
+    /// `until` pick the snapshots to compare instead.
+    pub fn changed(
+        &self,
+        volume: &str,
+        last: bool,
+        since: Option<&str>,
+        until: Option<&str>,
+    ) -> Result<()> {
+        let name = self.snap_zfs(volume);
+        let zfs = Zfs::from_inventory("none", &self.inventory)?;
+        let fs = zfs.find_filesystem(name)?;
+        let mountpoint = mount::zfs_mountpoint(name)?
+            .ok_or_else(|| ZfsError::NotMounted { fs: name.to_string() })?;
+
+        let backed = if since.is_none() || (last && until.is_none()) {
+            self.backed_up(fs)?
+        } else {
+            vec![]
+        };
+        let newest = |back: usize| {
+            backed.iter().rev().nth(back).copied().ok_or_else(|| {
+                Error::msg(format!("{:?} doesn't have {} restic backups", name, back + 1))
+            })
+        };
+        let since = match since {
+            Some(selector) => select(&fs.snaps, selector)?,
+            None => newest(if last { 1 } else { 0 })?,
+        };
+        let until = match until {
+            Some(selector) => Some(select(&fs.snaps, selector)?),
+            None if last => Some(newest(0)?),
+            None => None,
+        };
+
+        let changes = match self.sure_changes(name, since, until)? {
+            Some(changes) => changes,
+            None => zfs_diff(fs, since, until, &mountpoint)?,
+        };
+
+        let snapshot = |snap: &str| Path::new(&mountpoint).join(".zfs/snapshot").join(snap);
+        let (old, new) = match until {
+            Some(until) => (snapshot(since), snapshot(until)),
+            None => (snapshot(since), PathBuf::from(&mountpoint)),
+        };
+        let mut sized = vec![];
+        for (change, path) in changes {
+            let dir = if change == '-' { &old } else { &new };
+            let meta = fs::symlink_metadata(dir.join(&path)).ok();
+            if meta.as_ref().map_or(false, |m| m.is_dir()) {
+                continue;
+            }
+            sized.push((meta.map(|m| m.len()), change, path));
+        }
+        // The largest first, and those whose size is unknown last.
+        sized.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.2.cmp(&b.2)));
+
+        let size = |bytes: u64| humanize_size(bytes as usize).trim().to_string();
+        for (bytes, change, path) in &sized {
+            let bytes = bytes.map_or_else(|| "?".to_string(), size);
+            output!("{:>10} {} {}", bytes, change, path);
+        }
+        let total = sized.iter().filter_map(|s| s.0).sum();
+        output!(
+            "{} files changed in {} from {} to {}, {} in all",
+            sized.len(),
+            fs.name,
+            since,
+            until.unwrap_or("now"),
+            size(total)
+        );
+        Ok(())
+    }
+
+    /// The snapshots of `fs` that are in a restic backup, oldest first.
+    fn backed_up<'a>(&self, fs: &'a Filesystem) -> Result<Vec<&'a str>> {
+        let mut tags = HashSet::new();
+        let mut any = false;
+        for r in self.restic.volumes.iter().filter(|r| r.zfs == fs.name) {
+            any = true;
+            for snap in r.get_snapshots()? {
+                if snap.paths.contains(&r.bind) {
+                    tags.extend(snap.tags.unwrap_or_default());
+                }
+            }
+        }
+        if !any {
+            return Err(ResticError::NoBackups(fs.name.clone()).into());
+        }
+        Ok(fs.snaps.iter().filter(|s| tags.contains(*s)).map(|s| s.as_str()).collect())
+    }
+
+    /// The changes between two snapshots of the filesystem `name`, from its
+    /// sure data, if both have been captured.
+    fn sure_changes(
+        &self,
+        name: &str,
+        since: &str,
+        until: Option<&str>,
+    ) -> Result<Option<Vec<(char, String)>>> {
+        let until = match until {
+            Some(until) => until,
+            None => return Ok(None),
+        };
+        for sv in self.sure.volumes.iter().filter(|sv| sv.zfs == name) {
+            let store = sv.open_store()?;
+            let versions = store.get_versions()?;
+            let find = |snap: &str| versions.iter().find(|v| v.name == snap);
+            let (old, new) = match (find(since), find(until)) {
+                (Some(old), Some(new)) => (old.version.clone(), new.version.clone()),
+                _ => continue,
+            };
+            progress!("Comparing the sure data of {} and {}", since, until);
+            let changes = compare(store.load_iter(old)?, store.load_iter(new)?)?;
+            let changes = changes.into_iter().map(|change| match change {
+                Change::Added { path } => ('+', path),
+                Change::Removed { path } => ('-', path),
+                Change::Changed { path, .. } => ('M', path),
+            });
+            return Ok(Some(changes.collect()));
+        }
+        Ok(None)
+    }
+}
+
+/// The changes to `fs` from the snapshot `since` to `until`, or to the
+/// filesystem as it is now, from `zfs diff`.
+fn zfs_diff(
+    fs: &Filesystem,
+    since: &str,
+    until: Option<&str>,
+    mountpoint: &str,
+) -> Result<Vec<(char, String)>> {
+    let mut cmd = zfs_command(None, false);
+    cmd.args(&["diff", "-FHh"]);
+    cmd.arg(format!("{}@{}", fs.name, since));
+    match until {
+        Some(until) => cmd.arg(format!("{}@{}", fs.name, until)),
+        None => cmd.arg(&fs.name),
+    };
+    cmd.stderr(Stdio::inherit());
+    let out = cmd.checked_output()?;
+    Ok(parse_diff(&String::from_utf8_lossy(&out.stdout), mountpoint))
+}
+
+/// The changes listed by `zfs diff -FHh`, other than to directories, by
+/// their paths within the filesystem mounted on `mountpoint`.  A renamed
+/// file is given by its new name.
+fn parse_diff(text: &str, mountpoint: &str) -> Vec<(char, String)> {
+    let prefix = format!("{}/", mountpoint.trim_end_matches('/'));
+    let mut changes = vec![];
+    for line in text.lines() {
+        let fields: Vec<_> = line.split('\t').collect();
+        let (change, path) = match fields[..] {
+            [change, kind, path] | [change, kind, _, path] if kind != "/" => (change, path),
+            _ => continue,
+        };
+        let change = change.chars().next().unwrap_or('?');
+        let path = path.strip_prefix(prefix.as_str()).unwrap_or(path);
+        changes.push((change, path.to_string()));
+    }
+    changes
+}
+
+#[test]
+fn test_parse_diff() {
+    let text = "\
+M\t/\t/home/davidb
++\tF\t/home/davidb/big.iso
+-\tF\t/home/davidb/old notes
+R\tF\t/home/davidb/a.txt\t/home/davidb/b.txt
+M\t@\t/home/davidb/link
+";
+    assert_eq!(
+        parse_diff(text, "/home"),
+        vec![
+            ('+', "davidb/big.iso".to_string()),
+            ('-', "davidb/old notes".to_string()),
+            ('R', "davidb/b.txt".to_string()),
+            ('M', "davidb/link".to_string()),
+        ]
+    );
+}
@@ -1,7 +1,168 @@
 //! An extension to Command to allow checked runs.
+//!
+//! The commands run through `CheckedExt` are handed to the current
+//! `Executor`, which normally just runs them.  Tests can substitute a
+//! `RecordingExecutor`, which runs nothing, so that the decisions made from
+//! the output of zfs, lvs, and the like can be checked without touching the
+//! system.  The executor is shared by every thread, so the commands of the
+//! workers of a `Jobs` are covered, and pipelines run with `run_pipeline`
+//! or `watch_pipeline`, such as the zfs send pipeline of clone and the
+//! rsync of a sync, go through it as well.  Only commands that are talked
+//! with as they run, the database client that holds a database quiesced
+//! and the gzip an rsync log is written through, are spawned directly.
 
-use crate::{RackError, Result};
-use std::process::{Command, Output};
+use crate::{config::PriorityConfig, events, Error, Result};
+use nix::{
+    sys::signal::{kill, Signal},
+    unistd::Pid,
+};
+use std::{
+    ffi::OsStr,
+    io::{self, BufRead, BufReader, Read, Write},
+    os::unix::process::ExitStatusExt,
+    process::{Child, ChildStdout, Command, ExitStatus, Output, Stdio},
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// The priority heavy commands are run at.  Set once the config is loaded.
+static PRIORITY: Mutex<Option<PriorityConfig>> = Mutex::new(None);
+
+/// Set the priority to run heavy commands at.
+pub fn set_priority(priority: &PriorityConfig) {
+    *PRIORITY.lock().unwrap() = Some(priority.clone());
+}
+
+/// Build a command for a program that does a lot of I/O or computation, such
+/// as rsync, zfs send, restic or borg.  The command is run under `nice` and
+/// `ionice` as given by the priority section of the config.
+pub fn heavy_command<S: AsRef<OsStr>>(program: S) -> Command {
+    let mut wrap: Vec<String> = vec![];
+    if let Some(ref prio) = *PRIORITY.lock().unwrap() {
+        if let Some(nice) = prio.nice {
+            wrap.extend(vec!["nice".into(), "-n".into(), nice.to_string()]);
+        }
+        if let Some(class) = prio.ionice_class {
+            wrap.extend(vec!["ionice".into(), "-c".into(), class.to_string()]);
+            if let Some(level) = prio.ionice_level {
+                wrap.extend(vec!["-n".into(), level.to_string()]);
+            }
+        }
+    }
+
+    if wrap.is_empty() {
+        return Command::new(program);
+    }
+    let mut cmd = Command::new(&wrap[0]);
+    cmd.args(&wrap[1..]);
+    cmd.arg(program);
+    cmd
+}
+
+/// Something that can run commands, from any thread.
+pub trait Executor: Send + Sync {
+    /// Run the command, returning its exit status.
+    fn status(&self, cmd: &mut Command) -> Result<ExitStatus>;
+
+    /// Run the command, collecting all of its output.
+    fn output(&self, cmd: &mut Command) -> Result<Output>;
+
+    /// Run the command, collecting its stderr, and its stdout if that is
+    /// piped, while also copying stderr to our own stderr.
+    fn tee_output(&self, cmd: &mut Command) -> Result<Output>;
+
+    /// Run the commands together, each reading the output of the one before,
+    /// returning the exit status of each.
+    fn pipeline(&self, cmds: &mut [Command]) -> Result<Vec<ExitStatus>>;
+
+    /// As `pipeline`, but with each line of the output `watched.1` of the
+    /// command `watched.0` given to `watch` as it is written.  Only the last
+    /// command's stdout can be watched, as the others' is piped on.
+    /// `started` is given the ids of the processes once they are running,
+    /// such as to kill them.  If `watch` fails, the commands are killed.
+    fn watch_pipeline(
+        &self,
+        cmds: &mut [Command],
+        watched: (usize, Watch),
+        started: &mut dyn FnMut(&[u32]),
+        watch: &mut dyn FnMut(&str) -> Result<()>,
+    ) -> Result<Vec<ExitStatus>>;
+
+    /// Run the command with `input` as its stdin, returning its exit status.
+    fn feed(&self, cmd: &mut Command, input: &[u8]) -> Result<ExitStatus>;
+
+    /// Run the command, collecting its output, with each line of the output
+    /// `which` given to `watch` as it is written.  When stdout is watched,
+    /// stderr is also copied to our own stderr, as with `tee_output`.
+    fn watch(&self, cmd: &mut Command, which: Watch, watch: &mut dyn FnMut(&str))
+        -> Result<Output>;
+
+    /// Run the command, giving each line of its output to `each` as it is
+    /// read, without keeping it.  If `each` fails, the command is killed.
+    fn stream(&self, cmd: &mut Command, each: &mut dyn FnMut(&str) -> Result<()>)
+        -> Result<ExitStatus>;
+
+    /// Run the command, returning its exit status, but killing it if it
+    /// writes nothing to stdout or stderr for `idle`.
+    fn status_timeout(&self, cmd: &mut Command, idle: Duration) -> Result<ExitStatus>;
+}
+
+/// The output of a command to watch, such as for progress.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Watch {
+    Stdout,
+    Stderr,
+}
+
+/// The executor that actually runs commands.
+pub struct SystemExecutor;
+
+/// The executor set in place of the `SystemExecutor`, if there is one.
+static EXECUTOR: Mutex<Option<Arc<dyn Executor>>> = Mutex::new(None);
+
+/// Held while an executor is set, so that only one is set at a time.
+static SETTING: Mutex<()> = Mutex::new(());
+
+/// An executor set with `set_executor`, which is put back to the one
+/// before when this is dropped.
+pub struct ExecutorGuard {
+    old: Option<Arc<dyn Executor>>,
+    _setting: MutexGuard<'static, ()>,
+}
+
+impl Drop for ExecutorGuard {
+    fn drop(&mut self) {
+        *EXECUTOR.lock().unwrap() = self.old.take();
+    }
+}
+
+/// Use the given executor for the commands run by every thread, until the
+/// guard returned is dropped.  Anything else setting one, such as another
+/// test, waits until then.
+pub fn set_executor(exec: Arc<dyn Executor>) -> ExecutorGuard {
+    // A test that panicked while holding it has still put its executor back.
+    let setting = SETTING.lock().unwrap_or_else(|e| e.into_inner());
+    let old = EXECUTOR.lock().unwrap().replace(exec);
+    ExecutorGuard {
+        old: old,
+        _setting: setting,
+    }
+}
+
+fn executor() -> Arc<dyn Executor> {
+    match *EXECUTOR.lock().unwrap() {
+        Some(ref exec) => exec.clone(),
+        None => Arc::new(SystemExecutor),
+    }
+}
+
+/// The program and arguments of a command, as strings.
+pub fn command_line(cmd: &Command) -> Vec<String> {
+    let mut line = vec![cmd.get_program().to_string_lossy().into_owned()];
+    line.extend(cmd.get_args().map(|a| a.to_string_lossy().into_owned()));
+    line
+}
 
 pub trait CheckedExt {
     /// Run the given command, normalizing to the local Result type, and returning a local error if
@@ -11,28 +172,648 @@ pub trait CheckedExt {
     /// Run command, collecting all of its output.  Runs Command's `output` method, with an
     /// additional check of the status result.
     fn checked_output(&mut self) -> Result<Output>;
+
+    /// Run command, collecting its output.  Stderr is copied to our own stderr as it is produced
+    /// (so progress messages are still seen), but is also returned in the Output so that error
+    /// messages can be examined.  Stdout is only collected if the command pipes it, and otherwise
+    /// goes where the command sends it.  The status is not checked.
+    fn tee_output(&mut self) -> Result<Output>;
+
+    /// Run the command, returning its status without checking it.
+    fn run_status(&mut self) -> Result<ExitStatus>;
+
+    /// Run the command with `input` as its stdin, and check that it succeeds.
+    fn checked_feed(&mut self, input: &[u8]) -> Result<()>;
+
+    /// Run the command, collecting its output, with each line of the output
+    /// `which` given to `watch` as it is written.  The status is not checked.
+    fn watch_output(&mut self, which: Watch, watch: &mut dyn FnMut(&str)) -> Result<Output>;
+
+    /// Run the command, giving each line of its output to `each` as it is
+    /// read, and check that it succeeds.
+    fn checked_stream(&mut self, each: &mut dyn FnMut(&str) -> Result<()>) -> Result<()>;
+
+    /// As `checked_run`, but the command is killed, and fails, if it goes
+    /// `idle` without writing anything, which is taken as a sign that it has
+    /// hung.  Its output is still copied to our own.
+    fn checked_run_timeout(&mut self, idle: Duration) -> Result<()>;
+}
+
+/// Record that a command was run, with its status if it could be started.
+pub(crate) fn ran(cmd: &Command, status: Option<ExitStatus>) {
+    events::command(&command_line(cmd), status);
+}
+
+/// Run a pipeline of commands, each reading the output of the one before.
+/// The first input and last output are our own.  All of the commands must
+/// succeed.
+pub fn run_pipeline(cmds: &mut [Command]) -> Result<()> {
+    let statuses = ran_all(executor().pipeline(cmds), cmds)?;
+    for (cmd, status) in cmds.iter().zip(statuses) {
+        if !status.success() {
+            return Err(Error::Command {
+                command: format!("{:?}", cmd),
+                status: status,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Run a pipeline of commands, as `run_pipeline`, with each line of the
+/// output `watched.1` of the command `watched.0` given to `watch` as it is
+/// written, and the process ids given to `started` once they are running.
+/// The status of each command is returned, without being checked.
+pub fn watch_pipeline(
+    cmds: &mut [Command],
+    watched: (usize, Watch),
+    started: &mut dyn FnMut(&[u32]),
+    watch: &mut dyn FnMut(&str) -> Result<()>,
+) -> Result<Vec<ExitStatus>> {
+    ran_all(executor().watch_pipeline(cmds, watched, started, watch), cmds)
+}
+
+/// Record that the commands of a pipeline were run, with their statuses.
+fn ran_all(statuses: Result<Vec<ExitStatus>>, cmds: &[Command]) -> Result<Vec<ExitStatus>> {
+    match statuses {
+        Ok(statuses) => {
+            for (cmd, status) in cmds.iter().zip(&statuses) {
+                ran(cmd, Some(*status));
+            }
+            Ok(statuses)
+        }
+        Err(e) => {
+            cmds.iter().for_each(|cmd| ran(cmd, None));
+            Err(e)
+        }
+    }
 }
 
 impl CheckedExt for Command {
     fn checked_run(&mut self) -> Result<()> {
-        let status = self.status()?;
+        let status = self.run_status()?;
         if !status.success() {
-            return Err(RackError::Command {
+            return Err(Error::Command {
                 command: format!("{:?}", self),
                 status: status,
-            }.into());
+            });
         }
         Ok(())
     }
 
     fn checked_output(&mut self) -> Result<Output> {
-        let out = self.output()?;
+        let out = executor().output(self);
+        ran(self, out.as_ref().ok().map(|o| o.status));
+        let out = out?;
         if !out.status.success() {
-            return Err(RackError::Command {
+            return Err(Error::Command {
                 command: format!("{:?}", self),
                 status: out.status,
-            }.into());
+            });
+        }
+        Ok(out)
+    }
+
+    fn tee_output(&mut self) -> Result<Output> {
+        let out = executor().tee_output(self);
+        ran(self, out.as_ref().ok().map(|o| o.status));
+        out
+    }
+
+    fn run_status(&mut self) -> Result<ExitStatus> {
+        let status = executor().status(self);
+        ran(self, status.as_ref().ok().copied());
+        status
+    }
+
+    fn checked_feed(&mut self, input: &[u8]) -> Result<()> {
+        let status = executor().feed(self, input);
+        ran(self, status.as_ref().ok().copied());
+        let status = status?;
+        if !status.success() {
+            return Err(Error::Command {
+                command: format!("{:?}", self),
+                status: status,
+            });
+        }
+        Ok(())
+    }
+
+    fn watch_output(&mut self, which: Watch, watch: &mut dyn FnMut(&str)) -> Result<Output> {
+        let out = executor().watch(self, which, watch);
+        ran(self, out.as_ref().ok().map(|o| o.status));
+        out
+    }
+
+    fn checked_stream(&mut self, each: &mut dyn FnMut(&str) -> Result<()>) -> Result<()> {
+        let status = executor().stream(self, each);
+        ran(self, status.as_ref().ok().copied());
+        let status = status?;
+        if !status.success() {
+            return Err(Error::Command {
+                command: format!("{:?}", self),
+                status: status,
+            });
+        }
+        Ok(())
+    }
+
+    fn checked_run_timeout(&mut self, idle: Duration) -> Result<()> {
+        let status = executor().status_timeout(self, idle);
+        ran(self, status.as_ref().ok().copied());
+        let status = status?;
+        if !status.success() {
+            return Err(Error::Command {
+                command: format!("{:?}", self),
+                status: status,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Kills a group of processes, such as the commands of a pipeline, once
+/// they have gone too long without making progress.  The watchdog runs on
+/// its own thread, and is told of progress through `poke`.
+pub(crate) struct Watchdog {
+    shared: Arc<(Mutex<WatchState>, Condvar)>,
+    thread: thread::JoinHandle<()>,
+}
+
+struct WatchState {
+    last: Instant,
+    done: bool,
+    fired: bool,
+}
+
+/// A handle for telling a watchdog of progress, from any thread.
+#[derive(Clone)]
+pub(crate) struct Progress(Arc<(Mutex<WatchState>, Condvar)>);
+
+impl Progress {
+    pub fn poke(&self) {
+        (self.0).0.lock().unwrap().last = Instant::now();
+    }
+}
+
+impl Watchdog {
+    /// Start watching the processes `pids`, killing them all once `idle`
+    /// passes without a poke.
+    pub fn start(idle: Duration, pids: Vec<u32>) -> Watchdog {
+        let state = WatchState {
+            last: Instant::now(),
+            done: false,
+            fired: false,
+        };
+        let shared = Arc::new((Mutex::new(state), Condvar::new()));
+        let watched = shared.clone();
+        let thread = thread::spawn(move || {
+            let (ref lock, ref cvar) = *watched;
+            let mut state = lock.lock().unwrap();
+            while !state.done {
+                let waited = state.last.elapsed();
+                if waited >= idle {
+                    for &pid in &pids {
+                        let _ = kill(Pid::from_raw(pid as i32), Signal::SIGKILL);
+                    }
+                    state.fired = true;
+                    break;
+                }
+                state = cvar.wait_timeout(state, idle - waited).unwrap().0;
+            }
+        });
+        Watchdog {
+            shared: shared,
+            thread: thread,
         }
+    }
+
+    pub fn progress(&self) -> Progress {
+        Progress(self.shared.clone())
+    }
+
+    /// Stop watching, once the processes have exited.  Returns whether they
+    /// were killed.
+    pub fn finish(self) -> bool {
+        let (ref lock, ref cvar) = *self.shared;
+        lock.lock().unwrap().done = true;
+        cvar.notify_all();
+        self.thread.join().expect("Watchdog thread");
+        let fired = lock.lock().unwrap().fired;
+        fired
+    }
+}
+
+/// Copy everything read from `from` to `to`, poking `progress` as it comes.
+fn copy_progress<R: Read, W: Write>(mut from: R, mut to: W, progress: &Progress) -> io::Result<()> {
+    let mut buf = [0u8; 4096];
+    loop {
+        let count = from.read(&mut buf)?;
+        if count == 0 {
+            return Ok(());
+        }
+        progress.poke();
+        to.write_all(&buf[..count])?;
+        to.flush()?;
+    }
+}
+
+/// Copy everything read from `from` to our own stderr as it comes, returning
+/// a copy of it.
+fn tee_stderr<R: Read>(mut from: R) -> io::Result<Vec<u8>> {
+    let mut saved = vec![];
+    let mut buf = [0u8; 4096];
+    loop {
+        let count = from.read(&mut buf)?;
+        if count == 0 {
+            return Ok(saved);
+        }
+        let err = io::stderr();
+        let mut err = err.lock();
+        err.write_all(&buf[..count])?;
+        err.flush()?;
+        saved.extend_from_slice(&buf[..count]);
+    }
+}
+
+/// Start the commands of a pipeline, each reading the output of the one
+/// before, with the output `watched.1` of the command `watched.0` piped.
+/// If any fails to start, those already started are killed.
+fn spawn_pipeline(cmds: &mut [Command], watched: Option<(usize, Watch)>) -> Result<Vec<Child>> {
+    let mut children: Vec<Child> = vec![];
+    let mut input: Option<ChildStdout> = None;
+    let count = cmds.len();
+    for (i, cmd) in cmds.iter_mut().enumerate() {
+        if let Some(out) = input.take() {
+            cmd.stdin(Stdio::from(out));
+        }
+        if i + 1 < count || watched == Some((i, Watch::Stdout)) {
+            cmd.stdout(Stdio::piped());
+        }
+        if watched == Some((i, Watch::Stderr)) {
+            cmd.stderr(Stdio::piped());
+        }
+        match cmd.spawn() {
+            Ok(mut child) => {
+                if i + 1 < count {
+                    input = child.stdout.take();
+                }
+                children.push(child);
+            }
+            Err(e) => {
+                for mut child in children {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                }
+                return Err(e.into());
+            }
+        }
+    }
+    Ok(children)
+}
+
+/// Wait for each of the commands of a pipeline to exit.
+fn wait_all(children: Vec<Child>) -> Result<Vec<ExitStatus>> {
+    let mut statuses = vec![];
+    for mut child in children {
+        statuses.push(child.wait()?);
+    }
+    Ok(statuses)
+}
+
+impl Executor for SystemExecutor {
+    fn status(&self, cmd: &mut Command) -> Result<ExitStatus> {
+        Ok(cmd.status()?)
+    }
+
+    fn output(&self, cmd: &mut Command) -> Result<Output> {
+        Ok(cmd.output()?)
+    }
+
+    fn tee_output(&self, cmd: &mut Command) -> Result<Output> {
+        cmd.stderr(Stdio::piped());
+        let mut child = cmd.spawn()?;
+        let stderr = child.stderr.take().expect("Child stderr");
+        let copier = thread::spawn(move || tee_stderr(stderr));
+
+        let mut out = child.wait_with_output()?;
+        out.stderr = copier.join().expect("Stderr copy thread")?;
         Ok(out)
     }
+
+    fn pipeline(&self, cmds: &mut [Command]) -> Result<Vec<ExitStatus>> {
+        wait_all(spawn_pipeline(cmds, None)?)
+    }
+
+    fn watch_pipeline(
+        &self,
+        cmds: &mut [Command],
+        watched: (usize, Watch),
+        started: &mut dyn FnMut(&[u32]),
+        watch: &mut dyn FnMut(&str) -> Result<()>,
+    ) -> Result<Vec<ExitStatus>> {
+        let mut children = spawn_pipeline(cmds, Some(watched))?;
+        let (index, which) = watched;
+        let output: Box<dyn Read> = match which {
+            Watch::Stdout => Box::new(children[index].stdout.take().expect("Child stdout")),
+            Watch::Stderr => Box::new(children[index].stderr.take().expect("Child stderr")),
+        };
+        let pids: Vec<_> = children.iter().map(|c| c.id()).collect();
+        started(&pids);
+        for line in BufReader::new(output).lines() {
+            if let Err(e) = line.map_err(Error::from).and_then(|line| watch(&line)) {
+                for child in &mut children {
+                    let _ = child.kill();
+                }
+                let _ = wait_all(children);
+                return Err(e);
+            }
+        }
+        wait_all(children)
+    }
+
+    fn feed(&self, cmd: &mut Command, input: &[u8]) -> Result<ExitStatus> {
+        cmd.stdin(Stdio::piped());
+        let mut child = cmd.spawn()?;
+        let written = child.stdin.take().expect("Child stdin").write_all(input);
+        let status = child.wait()?;
+        written?;
+        Ok(status)
+    }
+
+    fn watch(
+        &self,
+        cmd: &mut Command,
+        which: Watch,
+        watch: &mut dyn FnMut(&str),
+    ) -> Result<Output> {
+        cmd.stdout(Stdio::piped());
+        cmd.stderr(Stdio::piped());
+        let mut child = cmd.spawn()?;
+        let stdout = child.stdout.take().expect("Child stdout");
+        let stderr = child.stderr.take().expect("Child stderr");
+        let (watched, mut other): (Box<dyn Read>, Box<dyn Read + Send>) = match which {
+            Watch::Stdout => (Box::new(stdout), Box::new(stderr)),
+            Watch::Stderr => (Box::new(stderr), Box::new(stdout)),
+        };
+
+        // The other output is read alongside, so that neither pipe fills, and stderr is shown as
+        // it comes.
+        let reader = thread::spawn(move || -> io::Result<Vec<u8>> {
+            if which == Watch::Stdout {
+                return tee_stderr(other);
+            }
+            let mut saved = vec![];
+            other.read_to_end(&mut saved)?;
+            Ok(saved)
+        });
+        let mut saved = vec![];
+        for line in BufReader::new(watched).lines() {
+            let line = line?;
+            watch(&line);
+            saved.extend_from_slice(line.as_bytes());
+            saved.push(b'\n');
+        }
+        let status = child.wait()?;
+        let other = reader.join().expect("Output read thread")?;
+        let (stdout, stderr) = match which {
+            Watch::Stdout => (saved, other),
+            Watch::Stderr => (other, saved),
+        };
+        Ok(Output {
+            status: status,
+            stdout: stdout,
+            stderr: stderr,
+        })
+    }
+
+    fn stream(
+        &self,
+        cmd: &mut Command,
+        each: &mut dyn FnMut(&str) -> Result<()>,
+    ) -> Result<ExitStatus> {
+        cmd.stdout(Stdio::piped());
+        let mut child = cmd.spawn()?;
+        let stdout = child.stdout.take().expect("Child stdout");
+        for line in BufReader::new(stdout).lines() {
+            if let Err(e) = line.map_err(Error::from).and_then(|line| each(&line)) {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(e);
+            }
+        }
+        Ok(child.wait()?)
+    }
+
+    fn status_timeout(&self, cmd: &mut Command, idle: Duration) -> Result<ExitStatus> {
+        cmd.stdout(Stdio::piped());
+        cmd.stderr(Stdio::piped());
+        let mut child = cmd.spawn()?;
+        let stdout = child.stdout.take().expect("Child stdout");
+        let stderr = child.stderr.take().expect("Child stderr");
+
+        let watchdog = Watchdog::start(idle, vec![child.id()]);
+        let progress = watchdog.progress();
+        let errs = thread::spawn(move || copy_progress(stderr, io::stderr(), &progress));
+        let progress = watchdog.progress();
+        let outs = thread::spawn(move || copy_progress(stdout, io::stdout(), &progress));
+        let status = child.wait()?;
+
+        // Anything the killed command started may still hold its output
+        // open, so the copies are left to finish on their own.
+        if watchdog.finish() {
+            return Err(Error::Timeout {
+                command: format!("{:?}", cmd),
+                secs: idle.as_secs(),
+            });
+        }
+        errs.join().expect("Stderr copy thread")?;
+        outs.join().expect("Stdout copy thread")?;
+        Ok(status)
+    }
+}
+
+/// An executor that runs nothing.  Each command is recorded, and "succeeds"
+/// with the output given for the longest matching prefix of its command line,
+/// or with no output if none match.
+#[derive(Default)]
+pub struct RecordingExecutor {
+    commands: Mutex<Vec<Vec<String>>>,
+    responses: Mutex<Vec<(Vec<String>, Vec<u8>)>>,
+    inputs: Mutex<Vec<Vec<u8>>>,
+}
+
+impl RecordingExecutor {
+    pub fn new() -> RecordingExecutor {
+        RecordingExecutor::default()
+    }
+
+    /// Give `stdout` as the output of commands starting with `prefix`.
+    pub fn respond(&self, prefix: &[&str], stdout: &str) {
+        let prefix = prefix.iter().map(|s| s.to_string()).collect();
+        self.responses.lock().unwrap().push((prefix, stdout.as_bytes().to_vec()));
+    }
+
+    /// The commands run so far, each joined into a single string.
+    pub fn commands(&self) -> Vec<String> {
+        self.commands.lock().unwrap().iter().map(|c| c.join(" ")).collect()
+    }
+
+    /// The input given to each of the commands that were fed one.
+    pub fn inputs(&self) -> Vec<String> {
+        let inputs = self.inputs.lock().unwrap();
+        inputs.iter().map(|i| String::from_utf8_lossy(i).into_owned()).collect()
+    }
+
+    fn run(&self, cmd: &Command) -> Output {
+        self.record(command_line(cmd))
+    }
+
+    /// Record a command line, returning the response to it.
+    fn record(&self, line: Vec<String>) -> Output {
+        let stdout = self
+            .responses
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(prefix, _)| line.starts_with(prefix))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, out)| out.clone())
+            .unwrap_or_default();
+        self.commands.lock().unwrap().push(line);
+        Output {
+            status: ExitStatus::from_raw(0),
+            stdout,
+            stderr: vec![],
+        }
+    }
+}
+
+impl Executor for RecordingExecutor {
+    fn status(&self, cmd: &mut Command) -> Result<ExitStatus> {
+        Ok(self.run(cmd).status)
+    }
+
+    fn output(&self, cmd: &mut Command) -> Result<Output> {
+        Ok(self.run(cmd))
+    }
+
+    fn tee_output(&self, cmd: &mut Command) -> Result<Output> {
+        Ok(self.run(cmd))
+    }
+
+    /// A pipeline is recorded as a single command, with "|" between each.
+    fn pipeline(&self, cmds: &mut [Command]) -> Result<Vec<ExitStatus>> {
+        let lines: Vec<_> = cmds.iter().map(|c| command_line(c)).collect();
+        self.record(lines.join(&"|".to_string()));
+        Ok(cmds.iter().map(|_| ExitStatus::from_raw(0)).collect())
+    }
+
+    /// Recorded as `pipeline`, with no processes, and the response given as
+    /// the watched output.
+    fn watch_pipeline(
+        &self,
+        cmds: &mut [Command],
+        _watched: (usize, Watch),
+        started: &mut dyn FnMut(&[u32]),
+        watch: &mut dyn FnMut(&str) -> Result<()>,
+    ) -> Result<Vec<ExitStatus>> {
+        let lines: Vec<_> = cmds.iter().map(|c| command_line(c)).collect();
+        let out = self.record(lines.join(&"|".to_string()));
+        started(&[]);
+        for line in String::from_utf8_lossy(&out.stdout).lines() {
+            watch(line)?;
+        }
+        Ok(cmds.iter().map(|_| ExitStatus::from_raw(0)).collect())
+    }
+
+    fn feed(&self, cmd: &mut Command, input: &[u8]) -> Result<ExitStatus> {
+        self.inputs.lock().unwrap().push(input.to_vec());
+        Ok(self.run(cmd).status)
+    }
+
+    /// The response is given as the watched output.
+    fn watch(
+        &self,
+        cmd: &mut Command,
+        which: Watch,
+        watch: &mut dyn FnMut(&str),
+    ) -> Result<Output> {
+        let mut out = self.run(cmd);
+        for line in String::from_utf8_lossy(&out.stdout).lines() {
+            watch(line);
+        }
+        if which == Watch::Stderr {
+            out.stderr = std::mem::take(&mut out.stdout);
+        }
+        Ok(out)
+    }
+
+    fn stream(
+        &self,
+        cmd: &mut Command,
+        each: &mut dyn FnMut(&str) -> Result<()>,
+    ) -> Result<ExitStatus> {
+        let out = self.run(cmd);
+        for line in String::from_utf8_lossy(&out.stdout).lines() {
+            each(line)?;
+        }
+        Ok(out.status)
+    }
+
+    fn status_timeout(&self, cmd: &mut Command, _idle: Duration) -> Result<ExitStatus> {
+        Ok(self.run(cmd).status)
+    }
+}
+
+#[test]
+fn test_shared() {
+    let exec = Arc::new(RecordingExecutor::new());
+    exec.respond(&["zfs", "|", "pv"], "1024\n2048\n");
+    let running = set_executor(exec.clone());
+    let worker = thread::spawn(|| Command::new("zfs").arg("list").checked_run());
+    worker.join().unwrap().unwrap();
+    let mut counts = vec![];
+    let mut cmds = [Command::new("zfs"), Command::new("pv")];
+    let mut watch = |line: &str| {
+        counts.push(line.to_string());
+        Ok(())
+    };
+    watch_pipeline(&mut cmds, (1, Watch::Stderr), &mut |_| (), &mut watch).unwrap();
+    drop(running);
+
+    // The worker's command went to the same executor.
+    assert_eq!(exec.commands(), ["zfs list", "zfs | pv"]);
+    assert_eq!(counts, ["1024", "2048"]);
+}
+
+#[test]
+fn test_watch_tee() {
+    let _running = set_executor(Arc::new(SystemExecutor));
+    let mut cmd = Command::new("sh");
+    cmd.args(&["-c", "echo out; echo err >&2"]);
+    let mut lines = vec![];
+    let out = cmd.watch_output(Watch::Stdout, &mut |line| lines.push(line.to_string())).unwrap();
+    assert_eq!(lines, ["out"]);
+    // Stderr has been copied to our own, and is still kept.
+    assert_eq!(out.stderr, b"err\n");
+}
+
+#[test]
+fn test_timeout() {
+    // Really run, even while other tests record.
+    let _running = set_executor(Arc::new(SystemExecutor));
+    let idle = Duration::from_millis(300);
+    let mut quiet = Command::new("sh");
+    quiet.args(&["-c", "echo started; sleep 10"]);
+    match quiet.checked_run_timeout(idle) {
+        Err(Error::Timeout { secs: 0, .. }) => (),
+        other => panic!("quiet command wasn't killed: {:?}", other),
+    }
+
+    // Output keeps a command alive past the idle time.
+    let mut busy = Command::new("sh");
+    busy.args(&["-c", "for i in 1 2 3 4 5; do echo $i; sleep 0.1; done"]);
+    busy.checked_run_timeout(idle).unwrap();
+    assert!(Command::new("false").checked_run_timeout(idle).is_err());
 }
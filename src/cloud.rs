@@ -0,0 +1,261 @@
+//! Export of zfs send streams to cloud storage.
+//!
+//! Each cloud volume sends the snapshots of a zfs filesystem, through
+//! compression and, optionally, encryption, to `rclone rcat`, which writes
+//! them to any storage rclone supports, such as S3, B2 or Drive.  The first
+//! stream is a full one, and each after that is an increment from the last
+//! snapshot uploaded.  The streams are recorded in the catalog.  A copy of
+//! the volume's part of the catalog is kept with the streams, so that
+//! `rack cloud-restore` can find them even if the machine, and its catalog,
+//! are gone.
+
+use crate::{
+    catalog::{Catalog, Stream},
+    checked::{run_pipeline, CheckedExt},
+    config::{configured, skipped, CloudVolume, Config, ConfigError},
+    journal,
+    send::{self, command, Encryption, StreamFormat},
+    zfs::{Filesystem, Zfs},
+    Context, Result,
+};
+use std::{path::Path, process::Command};
+
+/// The name of the copy of the catalog kept with the streams.
+const REMOTE_CATALOG: &str = "catalog.json";
+
+impl Config {
+    /// Upload the new snapshots of the cloud volumes.  If `name` is given,
+    /// only that volume is uploaded.
+    pub fn run_cloud(&self, name: Option<&str>, pretend: bool) -> Result<()> {
+        configured("cloud", &self.cloud.volumes)?;
+        let zfs = Zfs::from_inventory("none", &self.inventory)?;
+        let path = Catalog::default_path()?;
+        let mut catalog = Catalog::load(&path)?;
+
+        for vol in &self.cloud.volumes {
+            match name {
+                None => (),
+                Some(given) if given == vol.name => (),
+                _ => continue,
+            }
+            if skipped("cloud", &vol.name, vol.skip) {
+                continue;
+            }
+
+            let fs = zfs.find(&vol.zfs)?;
+            if let Some(stream) = vol.export(fs, &mut catalog, &path, pretend)? {
+                journal::record("cloud-upload", &vol.name, &stream)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Restore the cloud volume `name` into the zfs filesystem `dest`, which
+    /// must not exist yet, by replaying its streams up to `snap`, or to the
+    /// latest snapshot uploaded.
+    pub fn cloud_restore(
+        &self,
+        name: &str,
+        dest: &str,
+        snap: Option<&str>,
+        pretend: bool,
+    ) -> Result<()> {
+        let vol = self
+            .cloud
+            .volumes
+            .iter()
+            .find(|v| v.name == name)
+            .ok_or_else(|| ConfigError::NoVolume {
+                section: "cloud",
+                name: name.to_string(),
+            })?;
+        let catalog = Catalog::load(&Catalog::default_path()?)?;
+        vol.restore(&catalog, dest, snap, pretend)?;
+        self.inventory.invalidate();
+        Ok(())
+    }
+}
+
+impl CloudVolume {
+    /// Where an object is kept, as an rclone path.
+    fn url(&self, object: &str) -> String {
+        format!("{}/{}", self.remote.trim_end_matches('/'), object)
+    }
+
+    /// How this volume's streams are written.
+    fn format(&self) -> StreamFormat {
+        StreamFormat {
+            compression: self.compression.unwrap_or_default(),
+            encryption: Encryption::new(&self.encrypt, &self.decrypt, &self.age),
+            full_every: self.full_every,
+        }
+    }
+
+    /// Upload a stream bringing the cloud copy up to the latest snapshot,
+    /// recording it in the catalog, which is saved to `path`.  Returns the
+    /// stream uploaded, if there was anything to upload.
+    fn export(
+        &self,
+        fs: &Filesystem,
+        catalog: &mut Catalog,
+        path: &Path,
+        pretend: bool,
+    ) -> Result<Option<Stream>> {
+        let format = self.format();
+        let streams = catalog.streams(&self.remote, &self.zfs);
+        let next = match format.next(&self.name, &streams, fs) {
+            Some(next) => next,
+            None => return Ok(None),
+        };
+        let object = format.object(&next);
+
+        match next.from {
+            Some(from) => decision!(
+                "{}: upload {}@{} from @{} to {}",
+                self.name,
+                fs.name,
+                next.to,
+                from,
+                self.url(&object)
+            ),
+            None => decision!(
+                "{}: upload all of {}@{} to {}",
+                self.name,
+                fs.name,
+                next.to,
+                self.url(&object)
+            ),
+        }
+        if pretend {
+            return Ok(None);
+        }
+
+        let mut cmds = format.sender(&fs.name, &next);
+        cmds.push(command(&["rclone", "rcat", &self.url(&object)], false));
+        run_pipeline(&mut cmds)?;
+
+        let stream = format.record(&self.remote, &self.zfs, object, &next);
+        catalog.streams.push(stream.clone());
+        catalog.save(path)?;
+        self.upload_catalog(catalog, path)?;
+        Ok(Some(stream))
+    }
+
+    /// Keep a copy of this volume's part of the catalog with its streams.
+    /// The copy is written beside the catalog at `path` first.
+    fn upload_catalog(&self, catalog: &Catalog, path: &Path) -> Result<()> {
+        let streams = catalog.streams(&self.remote, &self.zfs);
+        let ours = Catalog {
+            streams: streams.into_iter().cloned().collect(),
+        };
+        let copy = path.with_file_name(format!("cloud-{}.json", self.name));
+        ours.save(&copy)?;
+        Command::new("rclone")
+            .arg("copyto")
+            .arg(&copy)
+            .arg(self.url(REMOTE_CATALOG))
+            .checked_run()
+    }
+
+    /// The streams of this volume, from the catalog, or, if it has none,
+    /// from the copy kept with the streams.
+    pub(crate) fn find_streams(&self, catalog: &Catalog) -> Result<Vec<Stream>> {
+        let local = catalog.streams(&self.remote, &self.zfs);
+        if !local.is_empty() {
+            return Ok(local.into_iter().cloned().collect());
+        }
+        let url = self.url(REMOTE_CATALOG);
+        let out = Command::new("rclone").arg("cat").arg(&url).checked_output()?;
+        let remote: Catalog = serde_json::from_slice(&out.stdout).context(&url)?;
+        Ok(remote.streams)
+    }
+
+    /// Replay the chain of streams up to `snap`, or the latest, into `dest`.
+    pub(crate) fn restore(
+        &self,
+        catalog: &Catalog,
+        dest: &str,
+        snap: Option<&str>,
+        pretend: bool,
+    ) -> Result<()> {
+        let streams = self.find_streams(catalog)?;
+        let chain = send::chain(&self.name, &streams, snap)?;
+        let fetch = |stream: &Stream| command(&["rclone", "cat", &self.url(&stream.object)], false);
+        self.format().replay(&self.name, &chain, dest, &fetch, pretend)
+    }
+}
+
+#[test]
+fn test_cloud() {
+    use crate::checked::{set_executor, RecordingExecutor};
+    use crate::verify::ScratchDir;
+    use crate::zfs::Inventory;
+    use std::sync::Arc;
+
+    let dir = ScratchDir::new("cloud").unwrap();
+    let path = dir.0.join("catalog.json");
+    let vol = CloudVolume {
+        name: "home".into(),
+        skip: None,
+        zfs: "pool/home".into(),
+        remote: "b2:bucket/home/".into(),
+        compression: None,
+        encrypt: Some(vec!["gpg".into(), "-e".into()]),
+        decrypt: Some(vec!["gpg".into(), "-d".into()]),
+        age: None,
+        full_every: Some(1),
+    };
+    let list = |snaps: &[&str]| {
+        let mut list = "pool/home\tfilesystem\tyes\t-\t-\t0\t0\t0\t/home\n".to_string();
+        for snap in snaps {
+            list.push_str(&format!("pool/home@{}\tsnapshot\t-\t0\t\t0\t0\t0\t-\n", snap));
+        }
+        list
+    };
+
+    let exec = Arc::new(RecordingExecutor::new());
+    let running = set_executor(exec.clone());
+    let mut catalog = Catalog::default();
+    let mut upload = |snaps: &[&str]| {
+        exec.respond(&["zfs", "list"], &list(snaps));
+        let zfs = Zfs::from_inventory("none", &Inventory::new()).unwrap();
+        let fs = zfs.find("pool/home").unwrap();
+        vol.export(fs, &mut catalog, &path, false).unwrap().map(|s| s.object)
+    };
+    assert_eq!(upload(&["a"]), Some("a.full.zfs.zst.enc".to_string()));
+    assert_eq!(upload(&["a"]), None);
+    assert_eq!(upload(&["a", "b", "c"]), Some("a_c.zfs.zst.enc".to_string()));
+    // After one increment, full_every asks for a new full stream.
+    assert_eq!(upload(&["a", "b", "c", "d"]), Some("d.full.zfs.zst.enc".to_string()));
+    vol.restore(&catalog, "pool/restored", Some("c"), false).unwrap();
+    drop(running);
+
+    let commands: Vec<_> =
+        exec.commands().into_iter().filter(|c| !c.starts_with("zfs list")).collect();
+    let copy = dir.0.join("cloud-home.json");
+    let copy = format!("rclone copyto {} b2:bucket/home/catalog.json", copy.display());
+    let send = |args: &str, object: &str| {
+        format!("zfs send {} | zstd -q | gpg -e | rclone rcat b2:bucket/home/{}", args, object)
+    };
+    let receive = |object: &str, args: &str| {
+        format!(
+            "rclone cat b2:bucket/home/{} | gpg -d | zstd -dq | zfs receive {} pool/restored",
+            object, args
+        )
+    };
+    assert_eq!(
+        commands,
+        vec![
+            send("pool/home@a", "a.full.zfs.zst.enc"),
+            copy.clone(),
+            send("-I @a pool/home@c", "a_c.zfs.zst.enc"),
+            copy.clone(),
+            send("pool/home@d", "d.full.zfs.zst.enc"),
+            copy,
+            receive("a.full.zfs.zst.enc", "-u"),
+            receive("a_c.zfs.zst.enc", "-u -F"),
+        ]
+    );
+    assert_eq!(Catalog::load(&path).unwrap().streams, catalog.streams);
+}
@@ -1,30 +1,132 @@
 //! Program configuration.
 //!
-//! This module defines the config file.
+//! This module defines the config file.  How the file is read, including
+//! its includes and per-host sections, is in `loader`.
 
-use crate::Result;
-use failure::err_msg;
+use crate::adopt;
+use crate::checked;
+use crate::loader::Document;
+use crate::mount;
+use crate::naming::{self, SnapNaming};
+use crate::retry;
+use crate::schedule::Schedule;
+use crate::secret::SecretSource;
+use crate::surestore;
+use crate::window::Window;
+use crate::zfs::Inventory;
+use crate::{Error, Result};
 use serde_derive::{Deserialize, Serialize};
 use std::{
-    fs::File,
+    collections::{BTreeMap, HashSet},
+    fmt,
     path::{Path, PathBuf},
 };
+use thiserror::Error;
 
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
+    #[serde(default)]
     pub snap: SnapConfig,
+    #[serde(default)]
     pub sure: SureConfig,
+    #[serde(default)]
     pub restic: ResticConfig,
+    #[serde(default)]
     pub clone: CloneConfig,
+    #[serde(default)]
+    pub borg: BorgConfig,
+    #[serde(default)]
+    pub sync: SyncConfig,
+    #[serde(default)]
+    pub containers: ContainerConfig,
+    #[serde(default)]
+    pub cloud: CloudConfig,
+    #[serde(default)]
+    pub export: ExportConfig,
+    #[serde(default)]
+    pub verify: VerifyConfig,
+    #[serde(default)]
+    pub notify: NotifyConfig,
+    #[serde(default)]
+    pub auto: AutoConfig,
+    #[serde(default)]
+    pub priority: PriorityConfig,
+    /// High-water marks for the zfs pools.
+    #[serde(default)]
+    pub pools: Vec<PoolConfig>,
+    /// URLs to ping as each operation starts and finishes, by the name of
+    /// the command, or of the step of `rack auto`.
+    #[serde(default)]
+    pub pings: BTreeMap<String, PingConfig>,
+    /// Lists of exclude patterns, by name, for sync, restic and borg
+    /// volumes to share.
+    #[serde(default)]
+    pub exclude_profiles: BTreeMap<String, Vec<String>>,
+    /// Copy the output of each run to a log file.
+    pub logging: Option<LoggingConfig>,
+    /// Write each action to a file of JSON lines, for other programs.
+    pub events: Option<EventsConfig>,
+    /// Retry commands that go over the network when they fail.  Without
+    /// this, they aren't retried.
+    pub retry: Option<RetryConfig>,
+    /// Mount snapshots in a mount namespace private to rack, so that they
+    /// are never left mounted after it exits.  Filesystems that zfs mounts
+    /// while rack runs, such as received clones, are then only mounted
+    /// within rack too, until the next `zfs mount -a`.
+    pub private_mounts: Option<bool>,
+    /// The zfs filesystems, shared by the operations run with this config.
+    #[serde(skip)]
+    pub inventory: Inventory,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct SnapConfig {
+    #[serde(default)]
     pub conventions: Vec<SnapConvention>,
+    /// How numbered snapshots, whose prefix isn't the name of a
+    /// convention, are named.
+    pub naming: Option<SnapNaming>,
+    /// Snapshots made by other tools, to be taken as those of a convention.
+    #[serde(default)]
+    pub adopt: Vec<AdoptRule>,
+    #[serde(default)]
     pub volumes: Vec<SnapVolume>,
 }
 
+/// Snapshots named by another tool, that are taken as made by a convention.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AdoptRule {
+    /// The convention the snapshots are taken as.
+    pub convention: String,
+    /// A tool whose names are known.
+    pub scheme: Option<AdoptScheme>,
+    /// Otherwise, a regex matching the whole name, whose `time` group is
+    /// when the snapshot was taken, and optional `label` group its label.
+    pub pattern: Option<String>,
+    /// Only the snapshots with this label, such as "daily".
+    pub label: Option<String>,
+    /// The format of the `time` group of a pattern, as for strftime.
+    /// Defaults to "%Y%m%d%H%M".
+    pub date_format: Option<String>,
+    /// Whether the times in the names are in UTC, rather than local time.
+    pub utc: Option<bool>,
+}
+
+/// The tools whose snapshot names can be adopted without a pattern.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AdoptScheme {
+    /// "zfs-auto-snap_daily-2024-06-01-0300"
+    ZfsAutoSnapshot,
+    /// "autosnap_2024-06-01_03:00:01_daily"
+    Sanoid,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct SnapConvention {
     pub name: String,
     pub last: Option<i32>,
@@ -33,59 +135,519 @@ pub struct SnapConvention {
     pub weekly: Option<i32>,
     pub monthly: Option<i32>,
     pub yearly: Option<i32>,
+    /// How the snapshots are named.
+    #[serde(default)]
+    pub naming: SnapNaming,
+    /// When `rack auto` snapshots the volumes using this convention, as a
+    /// cron entry such as "0 3 * * *".  Without one, every run does.
+    pub schedule: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct SnapVolume {
     pub name: String,
+    /// Leave this volume alone, unless picked out with `--only`.
+    pub skip: Option<bool>,
     pub convention: String,
     pub zfs: String,
+    /// Snapshots to keep when pruning, even if they aren't in any backup.
+    pub prune: Option<PruneAlgorithm>,
+    /// The backups that must have reached a snapshot, by having it or a
+    /// later one, before it is pruned.  Defaults to every kind of backup
+    /// made of the filesystem.
+    pub require: Option<Vec<BackupKind>>,
+    /// Don't take a snapshot when nothing has been written to the
+    /// filesystem since its newest one.
+    pub skip_unchanged: Option<bool>,
+    /// Commands, run with `sh -c`, before the snapshot is taken, such as to
+    /// flush an application's state to disk.
+    #[serde(default)]
+    pub pre_hook: Vec<String>,
+    /// Commands run after the snapshot is taken, or fails to be.
+    #[serde(default)]
+    pub post_hook: Vec<String>,
+    /// Whether a hook that fails is "fatal", the default, or only a "warn"ing.
+    pub hook_failure: Option<HookFailure>,
+    /// A database whose data is on the filesystem, to be dumped, or held
+    /// quiesced, as the snapshot is taken.
+    pub database: Option<DatabaseConfig>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// A database to make consistent in snapshots.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub kind: DatabaseKind,
+    /// How the database is made consistent.  Defaults to dump.
+    pub method: Option<QuiesceMethod>,
+    /// The directory dumps are written to, before the snapshot.  This must
+    /// be on the filesystem being snapshotted, for the dump to be in it.
+    pub dump_dir: Option<String>,
+    /// Arguments given to the database's client programs, such as to
+    /// connect as a given user.
+    #[serde(default)]
+    pub client_args: Vec<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DatabaseKind {
+    Postgres,
+    Mysql,
+}
+
+/// How a database is made consistent in a snapshot.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QuiesceMethod {
+    /// Dump all of its databases to `dump_dir`, with pg_dumpall or
+    /// mysqldump.
+    Dump,
+    /// Hold it in backup mode, for postgres, or with its tables flushed and
+    /// locked, for mysql, while the snapshot is taken.
+    Quiesce,
+}
+
+/// What a hook that fails does to the work on its volume.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HookFailure {
+    /// Fail the volume.
+    Fatal,
+    /// Warn, and carry on.
+    Warn,
+}
+
+/// A kind of backup that snapshots are captured by.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BackupKind {
+    Restic,
+    Borg,
+    Sure,
+}
+
+impl fmt::Display for BackupKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            BackupKind::Restic => "restic",
+            BackupKind::Borg => "borg",
+            BackupKind::Sure => "sure",
+        };
+        f.write_str(name)
+    }
+}
+
+/// How to choose the snapshots to keep.  See `prune` for the details of
+/// each.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PruneAlgorithm {
+    Hanoi,
+    Gfs,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct SureConfig {
+    /// The number of volumes to capture at the same time.  Volumes sharing
+    /// a sure file are always captured one at a time.
+    pub threads: Option<usize>,
+    /// The number of top directories of a snapshot to hash at the same
+    /// time, within each capture.
+    pub hash_threads: Option<usize>,
+    #[serde(default)]
     pub volumes: Vec<SureVolume>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct SureVolume {
     pub name: String,
+    /// Leave this volume alone, unless picked out with `--only`.
+    pub skip: Option<bool>,
     pub zfs: String,
     pub bind: String,
+    /// The sure store, either a plain path, whose kind rsure guesses from
+    /// the name, or "kind:path", where kind is "plain" or "weave".
     pub sure: String,
     pub convention: String,
+    /// Whether the store may be created if it does not already exist.
+    /// Defaults to true.
+    pub create: Option<bool>,
+    /// What to do if the zfs filesystem isn't mounted, such as one with a
+    /// legacy mountpoint: "fail" (the default), "skip" it, or "mount" each
+    /// snapshot directly while it is being read.
+    pub unmounted: Option<Unmounted>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct CloneConfig {
+    /// The rate limit of volumes that don't give their own.
+    pub rate_limit: Option<String>,
+    /// The hours, such as "01:00-06:00", that `rack auto` clones in.
+    pub window: Option<String>,
+    /// Pause a clone still running when its window closes, until it opens
+    /// again, rather than letting it finish.
+    pub pause: Option<bool>,
+    /// Kill a clone that has sent nothing for this many seconds, taking it
+    /// to have hung.
+    pub idle_timeout: Option<u64>,
+    /// What to do with what a clone that fails part way through has
+    /// received.  Defaults to abort.
+    pub partial: Option<Partial>,
+    /// The days `rack clone --delete` keeps a filesystem whose source is
+    /// gone, after its newest snapshot.  Defaults to 30.
+    pub orphan_days: Option<u64>,
+    /// Check what was received after cloning, as `rack clone --verify`.
+    pub verify: Option<bool>,
+    #[serde(default)]
     pub volumes: Vec<CloneVolume>,
 }
 
+/// What becomes of a receive that fails part way through.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Partial {
+    /// Throw it away, so that the next clone starts again.
+    Abort,
+    /// Keep it, for `rack clone --resume`, or the next `rack auto`, to
+    /// continue from where it stopped.
+    Keep,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct CloneVolume {
     pub name: String,
     pub source: String,
     pub dest: String,
+    /// Leave this volume alone, unless picked out with `--only`.
     pub skip: Option<bool>,
+    /// Pull `source` from this host, such as "root@laptop", running `zfs
+    /// send` there over ssh and receiving here.  The host being backed up
+    /// then needs no way to write to the backup pool.
+    pub host: Option<String>,
+    /// The most bytes per second to send, as understood by `pv -L`, such as
+    /// "500k" or "10M".
+    pub rate_limit: Option<String>,
+    /// Filesystems to receive under another name, by their names relative
+    /// to `source`, such as "home/david", mapped to those relative to
+    /// `dest`, such as "homes/david".  The filesystems under each are
+    /// renamed along with it.
+    #[serde(default)]
+    pub renames: BTreeMap<String, String>,
+    /// Filesystems under `dest`, by their names relative to it, that `rack
+    /// clone --delete` may destroy, with everything under them, once
+    /// nothing is cloned to them.  Others whose source is gone are only
+    /// listed.
+    #[serde(default)]
+    pub delete_orphans: Vec<String>,
+    /// Properties to override as the filesystems are received.
+    #[serde(default)]
+    pub recv_options: RecvOptions,
+    /// Properties to override as filesystems are created for a fresh
+    /// clone.
+    #[serde(default)]
+    pub create_options: CreateOptions,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Properties given to `zfs create` for the filesystems a clone makes,
+/// which otherwise get the local and received properties of their source.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CreateOptions {
+    /// Properties to set, in place of those of the source.
+    #[serde(default)]
+    pub set: BTreeMap<String, String>,
+    /// Properties of the source not to copy, such as `quota`, `sharenfs`
+    /// or `com.sun:auto-snapshot`.  The mountpoint is never copied.
+    #[serde(default)]
+    pub skip: Vec<String>,
+}
+
+/// Properties of a clone's destination that differ from its source, given
+/// to `zfs receive`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RecvOptions {
+    /// Properties to set, with `-o`, such as `compression: zstd`, or
+    /// `canmount: off`, so that the replicas never mount over live paths.
+    #[serde(default)]
+    pub set: BTreeMap<String, String>,
+    /// Properties of the source to leave behind, with `-x`, so that the
+    /// replicas inherit them.  The mountpoint is always left behind.
+    #[serde(default)]
+    pub exclude: Vec<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct ResticConfig {
+    /// The number of restic backups to run at the same time.  Volumes that
+    /// share a repo or a bind directory are never run concurrently.
+    pub parallel: Option<usize>,
+    /// The hours, such as "01:00-06:00", that `rack auto` runs restic in.
+    pub window: Option<String>,
+    #[serde(default)]
     pub volumes: Vec<ResticVolume>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct ResticVolume {
     pub name: String,
+    /// Leave this volume alone, unless picked out with `--only`.
+    pub skip: Option<bool>,
     pub zfs: String,
     pub bind: String,
-    pub repo: String,
+    /// A raw restic repository string.  Either this or `backend` must be
+    /// given.
+    pub repo: Option<String>,
+    /// A typed description of the repository backend.
+    pub backend: Option<ResticBackend>,
+    /// The password for the restic repository itself.
+    pub password: Option<SecretSource>,
+    /// Additional KEY=value environment settings passed to restic.
+    #[serde(default)]
     pub auth: Vec<String>,
+    /// Additional environment settings passed to restic, whose values are
+    /// secrets.  Unlike `auth`, this keeps the values out of the config.
+    #[serde(default)]
+    pub env: BTreeMap<String, SecretSource>,
+    /// If restic fails because of a stale lock left by a crashed run, run
+    /// `restic unlock` and retry once.
+    pub unlock_stale: Option<bool>,
+    /// After backing up each snapshot, also back up the sure store of the
+    /// sure volume for the same zfs filesystem, so that the integrity data
+    /// is kept offsite with the backup.
+    pub push_sure: Option<bool>,
+    /// What to do if the zfs filesystem isn't mounted, such as one with a
+    /// legacy mountpoint: "fail" (the default), "skip" it, or "mount" each
+    /// snapshot directly while it is being read.
+    pub unmounted: Option<Unmounted>,
+    /// Commands, run with `sh -c`, before the volume is backed up.
+    #[serde(default)]
+    pub pre_hook: Vec<String>,
+    /// Commands run after the backup, even if it fails.
+    #[serde(default)]
+    pub post_hook: Vec<String>,
+    /// Whether a hook that fails is "fatal", the default, or only a "warn"ing.
+    pub hook_failure: Option<HookFailure>,
+    /// Patterns of files to leave out of the backup: a name, matched at any
+    /// depth, or a path starting with "/", from the root of the volume.
+    #[serde(default)]
+    pub excludes: Vec<String>,
+    /// Exclude profiles, by name, whose patterns are added to `excludes`.
+    #[serde(default)]
+    pub exclude_profiles: Vec<String>,
+}
+
+/// The repository backends rack knows how to configure.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+pub enum ResticBackend {
+    S3 {
+        /// Defaults to "s3.amazonaws.com".
+        endpoint: Option<String>,
+        bucket: String,
+        path: Option<String>,
+        key_id: String,
+        secret: SecretSource,
+    },
+    B2 {
+        bucket: String,
+        path: Option<String>,
+        account_id: String,
+        key: SecretSource,
+    },
+    Rest {
+        url: String,
+        user: Option<String>,
+        password: Option<SecretSource>,
+    },
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BorgConfig {
+    /// The hours, such as "01:00-06:00", that `rack auto` runs borg in.
+    pub window: Option<String>,
+    #[serde(default)]
+    pub volumes: Vec<BorgVolume>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BorgVolume {
+    pub name: String,
+    /// Leave this volume alone, unless picked out with `--only`.
+    pub skip: Option<bool>,
+    pub zfs: String,
+    /// The directory snapshots are bind mounted on while being backed up,
+    /// so that the archive paths don't depend on the snapshot name.
+    pub bind: String,
+    pub repo: String,
+    /// Prefix for the archive names.  Each archive is named with this prefix
+    /// followed by the name of the zfs snapshot.
+    pub prefix: String,
+    /// The snap convention whose keep rules are used to prune this repo.
+    pub convention: Option<String>,
+    /// Compression passed to `borg create`, e.g. "zstd,9".
+    pub compression: Option<String>,
+    /// Chunker parameters passed to `borg create`.
+    pub chunker_params: Option<String>,
+    /// Where to find the passphrase of the repo.  Without this, borg will
+    /// prompt for it if the repo is encrypted.
+    pub passphrase: Option<SecretSource>,
+    /// If a backup fails because a crashed run left the repo locked, run
+    /// `borg break-lock` and retry.  This is only done when no other rack
+    /// is running.
+    pub break_lock: Option<bool>,
+    /// What to do if the zfs filesystem isn't mounted, such as one with a
+    /// legacy mountpoint: "fail" (the default), "skip" it, or "mount" each
+    /// snapshot directly while it is being read.
+    pub unmounted: Option<Unmounted>,
+    /// Commands, run with `sh -c`, before the volume is backed up.
+    #[serde(default)]
+    pub pre_hook: Vec<String>,
+    /// Commands run after the backup, even if it fails.
+    #[serde(default)]
+    pub post_hook: Vec<String>,
+    /// Whether a hook that fails is "fatal", the default, or only a "warn"ing.
+    pub hook_failure: Option<HookFailure>,
+    /// Patterns of files to leave out of the backup: a name, matched at any
+    /// depth, or a path starting with "/", from the root of the volume.
+    #[serde(default)]
+    pub excludes: Vec<String>,
+    /// Exclude profiles, by name, whose patterns are added to `excludes`.
+    #[serde(default)]
+    pub exclude_profiles: Vec<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CloudConfig {
+    #[serde(default)]
+    pub volumes: Vec<CloudVolume>,
+}
+
+/// A zfs filesystem whose snapshots are exported, as `zfs send` streams, to
+/// cloud storage with rclone.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CloudVolume {
+    pub name: String,
+    /// Leave this volume alone, unless picked out with `--only`.
+    pub skip: Option<bool>,
+    pub zfs: String,
+    /// The rclone remote and path the streams are stored under, such as
+    /// "b2:bucket/rack/home".
+    pub remote: String,
+    /// How streams are compressed.  Defaults to "zstd".
+    pub compression: Option<Compression>,
+    /// A command that encrypts its input, such as
+    /// `[gpg, --encrypt, -r, backup@example.com]`.
+    pub encrypt: Option<Vec<String>>,
+    /// The command that decrypts what `encrypt` wrote, used by restores.
+    pub decrypt: Option<Vec<String>>,
+    /// Encrypt with age, instead of `encrypt` and `decrypt`.
+    pub age: Option<AgeConfig>,
+    /// Send a new full stream after this many incremental ones, so that a
+    /// restore doesn't have to replay an ever longer chain.  By default, a
+    /// full stream is only sent when the last one uploaded can't be built
+    /// on.
+    pub full_every: Option<usize>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExportConfig {
+    /// The drives that are rotated, one attached while the others are kept
+    /// offsite.
+    #[serde(default)]
+    pub targets: Vec<ExportTarget>,
+    /// Warn when a drive hasn't been written for this many days.  Defaults
+    /// to 30.
+    pub stale_days: Option<u32>,
+    #[serde(default)]
+    pub volumes: Vec<ExportVolume>,
+}
+
+/// A drive that exports are written to.  It is found, wherever it is
+/// mounted, by exactly one of `guid` or `label`.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExportTarget {
+    pub name: String,
+    /// The GUID of the zfs pool on the drive, whose top filesystem the
+    /// streams are written to.
+    pub guid: Option<String>,
+    /// The label of the filesystem on the drive.
+    pub label: Option<String>,
+}
+
+/// A zfs filesystem whose snapshots are exported, as `zfs send` streams, to
+/// files on a removable drive.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExportVolume {
+    pub name: String,
+    /// Leave this volume alone, unless picked out with `--only`.
+    pub skip: Option<bool>,
+    pub zfs: String,
+    /// How streams are compressed.  Defaults to "zstd".
+    pub compression: Option<Compression>,
+    /// A command that encrypts its input.
+    pub encrypt: Option<Vec<String>>,
+    /// The command that decrypts what `encrypt` wrote, used by imports.
+    pub decrypt: Option<Vec<String>>,
+    /// Encrypt with age, instead of `encrypt` and `decrypt`.
+    pub age: Option<AgeConfig>,
+    /// Write a new full stream after this many incremental ones.
+    pub full_every: Option<usize>,
+}
+
+/// Encryption of streams with age.  Streams are encrypted to each of the
+/// recipients, and to the identity, if one is given.  Only the identity can
+/// decrypt them, so without it restores are done elsewhere, by whoever holds
+/// a matching key.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AgeConfig {
+    /// Public keys, such as "age1..." or an ssh public key.
+    pub recipients: Option<Vec<String>>,
+    /// An age identity file, such as written by `age-keygen`.
+    pub identity: Option<String>,
+}
+
+/// A compressor that streams are run through.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    None,
+    Gzip,
+    #[default]
+    Zstd,
+    Xz,
+}
+
+/// What to do with a filesystem that isn't mounted, when reading its
+/// snapshots.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Unmounted {
+    #[default]
+    Fail,
+    Skip,
+    Mount,
 }
 
 impl Config {
     pub fn get_default() -> Result<PathBuf> {
-        let home = dirs::home_dir().ok_or_else(|| err_msg("Unable to find home directory"))?;
+        let home = dirs::home_dir().ok_or_else(|| Error::msg("Unable to find home directory"))?;
         Ok(home.join(".gack.yaml"))
     }
 
@@ -94,12 +656,787 @@ impl Config {
     }
 
     pub fn load<P: AsRef<Path>>(path: P) -> Result<Config> {
-        let fd = File::open(path)?;
+        let host = surestore::hostname()?;
+        let item = Document::load(path.as_ref())?.config(&host)?;
 
-        let item = serde_yaml::from_reader(fd)?;
-
-        // TODO: Fixups?
+        checked::set_priority(&item.priority);
+        retry::set_policy(item.retry.as_ref());
+        naming::set_namings(&item.snap);
+        if item.private_mounts == Some(true) {
+            mount::private_namespace()?;
+        }
 
         Ok(item)
     }
+
+    /// Override the `skip` flags of the volumes of every section, from the
+    /// command line.  Given names in `only`, just those volumes are run,
+    /// even if their config skips them, and volumes named in `skip` aren't.
+    pub fn select(&mut self, only: &[String], skip: &[String]) -> Result<()> {
+        let mut flags = vec![];
+        flags.extend(self.snap.volumes.iter_mut().map(|v| (&v.name, &mut v.skip)));
+        flags.extend(self.sure.volumes.iter_mut().map(|v| (&v.name, &mut v.skip)));
+        flags.extend(self.restic.volumes.iter_mut().map(|v| (&v.name, &mut v.skip)));
+        flags.extend(self.clone.volumes.iter_mut().map(|v| (&v.name, &mut v.skip)));
+        flags.extend(self.borg.volumes.iter_mut().map(|v| (&v.name, &mut v.skip)));
+        flags.extend(self.sync.volumes.iter_mut().map(|v| (&v.name, &mut v.skip)));
+        flags.extend(self.cloud.volumes.iter_mut().map(|v| (&v.name, &mut v.skip)));
+        flags.extend(self.export.volumes.iter_mut().map(|v| (&v.name, &mut v.skip)));
+
+        // A mistyped name would otherwise quietly run, or skip, everything.
+        for name in only.iter().chain(skip) {
+            if !flags.iter().any(|(n, _)| *n == name) {
+                return Err(ConfigError::NoVolumeNamed { name: name.clone() }.into());
+            }
+        }
+        for (name, flag) in flags {
+            if !only.is_empty() {
+                *flag = Some(!only.contains(name));
+            }
+            if skip.contains(name) {
+                *flag = Some(true);
+            }
+        }
+        Ok(())
+    }
+
+    /// Decode the text of a config file, giving the effective config for
+    /// the given host.  Errors give the path within the file, such as
+    /// "snap.volumes[2].convention", of the problem.
+    pub fn parse(text: &str, host: &str) -> Result<Config> {
+        Document::parse(text, None, &mut vec![])?.config(host)
+    }
+
+    /// Add the patterns of the exclude profiles each volume uses to its own
+    /// excludes.
+    pub(crate) fn expand_excludes(&mut self) {
+        let profiles = &self.exclude_profiles;
+        let expand = |excludes: &mut Vec<String>, names: &[String]| {
+            for name in names {
+                excludes.extend(profiles.get(name).into_iter().flatten().cloned());
+            }
+        };
+        for v in &mut self.sync.volumes {
+            expand(&mut v.excludes, &v.exclude_profiles);
+        }
+        for v in &mut self.restic.volumes {
+            expand(&mut v.excludes, &v.exclude_profiles);
+        }
+        for v in &mut self.borg.volumes {
+            expand(&mut v.excludes, &v.exclude_profiles);
+        }
+    }
+
+    /// Check the fields that refer to other parts of the config, or
+    /// depend on each other.
+    pub(crate) fn check_fields(&self) -> Result<()> {
+        let err = |path: String, msg: String| Err(ConfigError::Field { path, msg }.into());
+
+        check_names("snap.volumes", self.snap.volumes.iter().map(|v| &v.name))?;
+        check_names("sure.volumes", self.sure.volumes.iter().map(|v| &v.name))?;
+        check_names("restic.volumes", self.restic.volumes.iter().map(|v| &v.name))?;
+        check_names("clone.volumes", self.clone.volumes.iter().map(|v| &v.name))?;
+        check_names("borg.volumes", self.borg.volumes.iter().map(|v| &v.name))?;
+        check_names("sync.volumes", self.sync.volumes.iter().map(|v| &v.name))?;
+        check_names("cloud.volumes", self.cloud.volumes.iter().map(|v| &v.name))?;
+        check_names("export.targets", self.export.targets.iter().map(|t| &t.name))?;
+        check_names("export.volumes", self.export.volumes.iter().map(|v| &v.name))?;
+
+        let profiles = &self.exclude_profiles;
+        let restic = self.restic.volumes.iter().map(|v| &v.exclude_profiles);
+        check_profiles("restic", profiles, restic)?;
+        check_profiles("borg", profiles, self.borg.volumes.iter().map(|v| &v.exclude_profiles))?;
+        check_profiles("sync", profiles, self.sync.volumes.iter().map(|v| &v.exclude_profiles))?;
+
+        let convs: HashSet<&str> = self.snap.conventions.iter().map(|c| c.name.as_str()).collect();
+        for (i, c) in self.snap.conventions.iter().enumerate() {
+            if let Err(msg) = c.naming.check() {
+                return err(format!("snap.conventions[{}].naming", i), msg);
+            }
+            if c.schedule.as_ref().map_or(false, |s| Schedule::parse(s).is_none()) {
+                let msg = "must be a cron entry, such as \"0 3 * * *\"".into();
+                return err(format!("snap.conventions[{}].schedule", i), msg);
+            }
+        }
+        if let Some(Err(msg)) = self.snap.naming.as_ref().map(|n| n.check()) {
+            return err("snap.naming".into(), msg);
+        }
+        for (i, rule) in self.snap.adopt.iter().enumerate() {
+            if !convs.contains(rule.convention.as_str()) {
+                let msg = format!("unknown convention {:?}", rule.convention);
+                return err(format!("snap.adopt[{}].convention", i), msg);
+            }
+            if let Err(msg) = adopt::check(rule) {
+                return err(format!("snap.adopt[{}]", i), msg);
+            }
+        }
+        for (i, v) in self.snap.volumes.iter().enumerate() {
+            if !convs.contains(v.convention.as_str()) {
+                let msg = format!("unknown convention {:?}", v.convention);
+                return err(format!("snap.volumes[{}].convention", i), msg);
+            }
+            let conv = self.snap.conventions.iter().find(|c| c.name == v.convention);
+            if v.prune == Some(PruneAlgorithm::Hanoi) && conv.and_then(|c| c.period()).is_none() {
+                let msg = format!(
+                    "hanoi numbers the snapshots by the finest keep rule, but {:?} has none",
+                    v.convention
+                );
+                return err(format!("snap.volumes[{}].prune", i), msg);
+            }
+        }
+        for (i, v) in self.snap.volumes.iter().enumerate() {
+            for kind in v.require.iter().flatten() {
+                let made = match kind {
+                    BackupKind::Restic => self.restic.volumes.iter().any(|r| r.zfs == v.zfs),
+                    BackupKind::Borg => self.borg.volumes.iter().any(|b| b.zfs == v.zfs),
+                    BackupKind::Sure => self.sure.volumes.iter().any(|s| s.zfs == v.zfs),
+                };
+                if !made {
+                    let msg = format!("no {} backup is made of {:?}", kind, v.zfs);
+                    return err(format!("snap.volumes[{}].require", i), msg);
+                }
+            }
+        }
+        for (i, v) in self.snap.volumes.iter().enumerate() {
+            if let Some(ref db) = v.database {
+                if db.method.unwrap_or(QuiesceMethod::Dump) == QuiesceMethod::Dump
+                    && db.dump_dir.is_none()
+                {
+                    let msg = "a dump_dir is needed to dump the database".into();
+                    return err(format!("snap.volumes[{}].database", i), msg);
+                }
+            }
+        }
+        for (i, v) in self.containers.volumes.iter().enumerate() {
+            let path = format!("containers.volumes[{}]", i);
+            if v.zfs_dest.is_some() == v.zfs.is_some() {
+                return err(path, "exactly one of zfs_dest and zfs must be given".into());
+            }
+            if v.volumes.is_empty() && v.project.is_none() {
+                return err(path, "volumes or a project must be given".into());
+            }
+            match (&v.zfs, &v.convention) {
+                (Some(_), None) => {
+                    return err(path, "a convention is needed to snapshot zfs".into());
+                }
+                (_, Some(conv)) if !convs.contains(conv.as_str()) => {
+                    let msg = format!("unknown convention {:?}", conv);
+                    return err(format!("{}.convention", path), msg);
+                }
+                _ => (),
+            }
+        }
+        for (i, v) in self.borg.volumes.iter().enumerate() {
+            if let Some(ref conv) = v.convention {
+                if !convs.contains(conv.as_str()) {
+                    let msg = format!("unknown convention {:?}", conv);
+                    return err(format!("borg.volumes[{}].convention", i), msg);
+                }
+            }
+        }
+
+        for (i, v) in self.restic.volumes.iter().enumerate() {
+            if v.repo.is_some() == v.backend.is_some() {
+                let msg = "exactly one of repo and backend must be given".into();
+                return err(format!("restic.volumes[{}]", i), msg);
+            }
+        }
+
+        for (i, v) in self.cloud.volumes.iter().enumerate() {
+            if let Err(msg) = check_crypt(&v.encrypt, &v.decrypt, &v.age) {
+                return err(format!("cloud.volumes[{}]", i), msg);
+            }
+        }
+        for (i, v) in self.export.volumes.iter().enumerate() {
+            if let Err(msg) = check_crypt(&v.encrypt, &v.decrypt, &v.age) {
+                return err(format!("export.volumes[{}]", i), msg);
+            }
+        }
+
+        if self.clone.rate_limit.as_ref().map_or(false, |r| !valid_rate(r)) {
+            return err("clone.rate_limit".into(), "must be a number, with k, m, g or t".into());
+        }
+        for (i, v) in self.clone.volumes.iter().enumerate() {
+            if v.rate_limit.as_ref().map_or(false, |r| !valid_rate(r)) {
+                let msg = "must be a number, with k, m, g or t".into();
+                return err(format!("clone.volumes[{}].rate_limit", i), msg);
+            }
+            let relative = |n: &String| !n.is_empty() && !n.starts_with('/') && !n.ends_with('/');
+            if !v.renames.iter().all(|(from, to)| relative(from) && relative(to)) {
+                let msg = "must map names relative to source to ones relative to dest".into();
+                return err(format!("clone.volumes[{}].renames", i), msg);
+            }
+            let recv = &v.recv_options;
+            if let Some(prop) = recv.exclude.iter().find(|p| recv.set.contains_key(*p)) {
+                let msg = format!("{:?} can't be both set and excluded", prop);
+                return err(format!("clone.volumes[{}].recv_options", i), msg);
+            }
+            let create = &v.create_options;
+            if let Some(prop) = create.skip.iter().find(|p| create.set.contains_key(*p)) {
+                let msg = format!("{:?} can't be both set and skipped", prop);
+                return err(format!("clone.volumes[{}].create_options", i), msg);
+            }
+        }
+
+        let windows = [
+            ("clone", &self.clone.window),
+            ("restic", &self.restic.window),
+            ("borg", &self.borg.window),
+        ];
+        for (section, window) in windows.iter() {
+            if window.as_ref().map_or(false, |w| Window::parse(w).is_none()) {
+                let msg = "must be a range of times, such as 01:00-06:00".into();
+                return err(format!("{}.window", section), msg);
+            }
+        }
+
+        let empty = |c: &Option<Vec<String>>| c.as_ref().map_or(false, |c| c.is_empty());
+        if empty(&self.notify.sendmail) {
+            return err("notify.sendmail".into(), "must name a command".into());
+        }
+        if empty(&self.notify.command) {
+            return err("notify.command".into(), "must name a command".into());
+        }
+
+        for (i, t) in self.export.targets.iter().enumerate() {
+            if t.guid.is_some() == t.label.is_some() {
+                let msg = "exactly one of guid and label must be given".into();
+                return err(format!("export.targets[{}]", i), msg);
+            }
+        }
+
+        for (i, v) in self.sync.volumes.iter().enumerate() {
+            let (needed, wrong) = match v.kind {
+                SyncKind::Lvm => (v.vg.is_some() && v.lv.is_some(), v.subvolume.is_some()),
+                SyncKind::Btrfs => (v.subvolume.is_some(), v.vg.is_some() || v.lv.is_some()),
+            };
+            if !needed || wrong {
+                let msg = match v.kind {
+                    SyncKind::Lvm => "lvm volumes take vg and lv, and not subvolume",
+                    SyncKind::Btrfs => "btrfs volumes take subvolume, and not vg or lv",
+                };
+                return err(format!("sync.volumes[{}]", i), msg.into());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    /// A problem with a particular part of the config, such as
+    /// "snap.volumes[2].convention".
+    #[error("{path}: {msg}")]
+    Field { path: String, msg: String },
+    #[error("{file:?}: includes itself")]
+    IncludeLoop { file: PathBuf },
+    /// A problem found in the section for one host.
+    #[error("{source} (on host {host:?})")]
+    Host { host: String, source: Box<Error> },
+    #[error("No {section} volumes are configured")]
+    NotConfigured { section: String },
+    #[error("No {section} volume named {name:?}")]
+    NoVolume { section: &'static str, name: String },
+    #[error("No {section} volume for zfs {zfs:?}")]
+    NoVolumeFor { section: &'static str, zfs: String },
+    #[error("No volume of any section is named {name:?}")]
+    NoVolumeNamed { name: String },
+}
+
+/// Every section of the config may be left out.  Commands that need one
+/// check for it first, so that the error says what is missing.
+pub fn configured<T>(section: &str, volumes: &[T]) -> Result<()> {
+    if volumes.is_empty() {
+        return Err(ConfigError::NotConfigured { section: section.to_string() }.into());
+    }
+    Ok(())
+}
+
+/// Is the volume to be left alone?  Says so, if it is.
+pub fn skipped(section: &str, name: &str, skip: Option<bool>) -> bool {
+    if skip == Some(true) {
+        decision!("{}: skip {:?}", section, name);
+        return true;
+    }
+    false
+}
+
+/// Is this a rate `pv -L` understands: a number of bytes, optionally with a
+/// suffix for a power of 1024?
+fn valid_rate(rate: &str) -> bool {
+    let digits = rate.trim_end_matches(|c| "kKmMgGtT".contains(c));
+    let number = !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit());
+    number && rate.len() - digits.len() <= 1
+}
+
+/// Check the encryption of a volume whose streams are written out.
+fn check_crypt(
+    encrypt: &Option<Vec<String>>,
+    decrypt: &Option<Vec<String>>,
+    age: &Option<AgeConfig>,
+) -> std::result::Result<(), String> {
+    let empty = |c: &Option<Vec<String>>| c.as_ref().map_or(false, |c| c.is_empty());
+    if empty(encrypt) || empty(decrypt) {
+        return Err("encrypt and decrypt must name a command".into());
+    }
+    if encrypt.is_some() != decrypt.is_some() {
+        return Err("encrypt and decrypt must be given together".into());
+    }
+    if let Some(age) = age {
+        if encrypt.is_some() {
+            return Err("age can't be given with encrypt and decrypt".into());
+        }
+        if empty(&age.recipients) || (age.recipients.is_none() && age.identity.is_none()) {
+            return Err("age needs recipients or an identity".into());
+        }
+    }
+    Ok(())
+}
+
+/// Make sure that the exclude profiles used by the volumes of a section are
+/// all defined.
+fn check_profiles<'a, I>(
+    section: &str,
+    profiles: &BTreeMap<String, Vec<String>>,
+    uses: I,
+) -> Result<()>
+where
+    I: Iterator<Item = &'a Vec<String>>,
+{
+    for (i, names) in uses.enumerate() {
+        if let Some(name) = names.iter().find(|n| !profiles.contains_key(*n)) {
+            return Err(ConfigError::Field {
+                path: format!("{}.volumes[{}].exclude_profiles", section, i),
+                msg: format!("unknown exclude profile {:?}", name),
+            }
+            .into());
+        }
+    }
+    Ok(())
+}
+
+/// Make sure that no two entries of a section have the same name.
+fn check_names<'a, I: Iterator<Item = &'a String>>(section: &str, names: I) -> Result<()> {
+    let mut seen = HashSet::new();
+    for (i, name) in names.enumerate() {
+        if !seen.insert(name) {
+            return Err(ConfigError::Field {
+                path: format!("{}[{}].name", section, i),
+                msg: format!("duplicate name {:?}", name),
+            }
+            .into());
+        }
+    }
+    Ok(())
+}
+
+/// The test restores done by `rack verify`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VerifyConfig {
+    /// The number of files to compare in each restore.  Defaults to 20.
+    pub count: Option<usize>,
+    /// A zfs filesystem, under which streams of the cloud and export
+    /// volumes are received to check them.  Without it, only restic
+    /// backups are verified.
+    pub scratch: Option<String>,
+}
+
+/// Where notifications, such as of failed verifications and the digest of
+/// a run, are sent.  Each is sent to every destination given.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NotifyConfig {
+    /// An address to mail them to.
+    pub email: Option<String>,
+    /// The command that mails a message given on its stdin, with the
+    /// recipient in its headers.  Defaults to `[sendmail, -t, -oi]`.
+    pub sendmail: Option<Vec<String>>,
+    /// A command to run, such as `[logger, -t, rack]`, with the subject as
+    /// its last argument, and the rest of the message on its stdin.
+    pub command: Option<Vec<String>>,
+}
+
+/// The steps run by `rack auto`.  Each runs when its section has volumes,
+/// unless turned off here; a step turned on without volumes fails.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AutoConfig {
+    pub snapshot: Option<bool>,
+    pub sync: Option<bool>,
+    pub containers: Option<bool>,
+    pub clone: Option<bool>,
+    pub sure: Option<bool>,
+    pub restic: Option<bool>,
+    pub borg: Option<bool>,
+    /// Pruning needs snap volumes, and restic or borg ones.
+    pub prune: Option<bool>,
+    /// The number of volumes to sync at once.  Defaults to 1.
+    pub jobs: Option<usize>,
+    /// The number of sure, restic and borg volumes to work on at once.  Above
+    /// 1, the three steps are run together, never using the same repo, sure
+    /// store or bind directory twice at a time.  Defaults to 1.
+    pub parallel: Option<usize>,
+    #[serde(default)]
+    pub on_error: OnError,
+}
+
+/// What `rack auto` does when a step fails.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OnError {
+    /// Skip the rest of the steps.
+    #[default]
+    Stop,
+    /// Go on to the rest, failing once they are done.
+    Continue,
+}
+
+/// The URLs pinged for an operation, for a dead man's switch such as
+/// healthchecks.io.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PingConfig {
+    pub start: Option<String>,
+    pub success: Option<String>,
+    /// Given the error, as the body of the request.
+    pub fail: Option<String>,
+}
+
+/// Where the logs of each run are written, and how many are kept.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// The directory the logs are written to.  Defaults to `logs` in the
+    /// state directory.
+    pub dir: Option<String>,
+    /// The number of logs to keep, removing the oldest.  Defaults to 14.
+    pub keep: Option<usize>,
+}
+
+/// Where the events of each run are written, and how many are kept.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EventsConfig {
+    /// The directory the events are written to.  Defaults to `events` in
+    /// the state directory.
+    pub dir: Option<String>,
+    /// The number of runs to keep the events of.  Defaults to 14.
+    pub keep: Option<usize>,
+}
+
+/// How restic and borg with remote repositories, and pulls over ssh, are
+/// retried after a failure.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RetryConfig {
+    /// The most times to retry a command.  Defaults to 3.
+    pub count: Option<u32>,
+    /// Seconds to wait before the first retry, doubling for each after it.
+    /// Defaults to 30.
+    pub backoff: Option<u64>,
+    /// The failures that are retried.  Defaults to network.
+    pub on: Option<Vec<RetryOn>>,
+}
+
+/// A kind of failure that can be retried.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RetryOn {
+    /// A connection that couldn't be made, or was lost.
+    Network,
+    /// A repository locked by another client.
+    Locked,
+    /// Any failure at all.
+    Any,
+}
+
+/// How full a zfs pool can get.  The marks are percentages of its size.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PoolConfig {
+    pub name: String,
+    /// Warn, and notify, once the pool is this full.
+    pub warn: Option<u8>,
+    /// Take no more snapshots on the pool once it is this full.
+    pub refuse_snapshots: Option<u8>,
+}
+
+/// The priority to run heavy commands (rsync, zfs send, restic and borg)
+/// at.  Unset values leave the priority alone.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PriorityConfig {
+    /// The `nice` adjustment.
+    pub nice: Option<i32>,
+    /// The `ionice` scheduling class: 1 realtime, 2 best-effort, 3 idle.
+    pub ionice_class: Option<u8>,
+    /// The `ionice` level within the class, 0 (highest) to 7.
+    pub ionice_level: Option<u8>,
+}
+
+/// The volumes of docker or podman containers, copied into ZFS, or
+/// snapshotted where they are, while their containers are stopped.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ContainerConfig {
+    /// "docker", the default, or "podman".
+    #[serde(default)]
+    pub engine: ContainerEngine,
+    #[serde(default)]
+    pub volumes: Vec<ContainerVolume>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ContainerEngine {
+    #[default]
+    Docker,
+    Podman,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ContainerVolume {
+    pub name: String,
+    /// Leave this volume alone, unless picked out with `--only`.
+    pub skip: Option<bool>,
+    /// The named volumes to back up.
+    #[serde(default)]
+    pub volumes: Vec<String>,
+    /// A compose project, whose volumes are all backed up, and whose running
+    /// containers are stopped while they are.
+    pub project: Option<String>,
+    /// Containers to stop while the volumes are backed up, if they are
+    /// running.  They are started again afterwards.
+    #[serde(default)]
+    pub containers: Vec<String>,
+    /// Pause the containers, rather than stopping them.
+    pub pause: Option<bool>,
+    /// The ZFS filesystem the volumes are copied into with rsync, each into
+    /// a directory of its name.
+    pub zfs_dest: Option<String>,
+    /// The ZFS filesystem the volumes are on, to be snapshotted instead,
+    /// with the snapshot named by `convention`.
+    pub zfs: Option<String>,
+    pub convention: Option<String>,
+}
+
+/// Filesystems that live on LVM, and are mirrored into ZFS with rsync, from
+/// a snapshot.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SyncConfig {
+    #[serde(default)]
+    pub volumes: Vec<SyncVolume>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SyncVolume {
+    pub name: String,
+    /// Leave this volume alone, unless picked out with `--only`.
+    pub skip: Option<bool>,
+    /// How the source is snapshotted.  Defaults to lvm.
+    #[serde(default)]
+    pub kind: SyncKind,
+    /// The LVM volume group and logical volume of the source, for lvm.
+    pub vg: Option<String>,
+    pub lv: Option<String>,
+    /// The path of the source subvolume, for btrfs.
+    pub subvolume: Option<String>,
+    /// An empty directory where the snapshot is mounted during the sync.
+    pub mountpoint: String,
+    /// The ZFS filesystem that is the destination of the sync.
+    pub zfs_dest: String,
+    /// Patterns passed to rsync as `--exclude`.
+    #[serde(default)]
+    pub excludes: Vec<String>,
+    /// Exclude profiles, by name, whose patterns are added to `excludes`.
+    #[serde(default)]
+    pub exclude_profiles: Vec<String>,
+    /// A file of exclude patterns, passed to rsync as `--exclude-from`.
+    pub exclude_from: Option<String>,
+    /// Limit rsync's I/O bandwidth, in any form accepted by rsync's
+    /// `--bwlimit`, such as "20M".
+    pub bwlimit: Option<String>,
+    /// After the sync, compare the snapshot against the destination,
+    /// checksumming this many randomly chosen files.
+    pub verify: Option<usize>,
+    /// Retention of old lvm snapshots: the number of most recent ones to
+    /// keep, and the number of days to keep any snapshot for.  Without
+    /// either, old snapshots are never removed.
+    pub keep_last: Option<usize>,
+    pub keep_days: Option<i64>,
+    /// Refuse to snapshot a thin volume when its pool's data or metadata
+    /// is more than this percent used.  Defaults to 90.
+    pub pool_threshold: Option<f64>,
+    /// Only warn, rather than refusing, when the pool is over the threshold.
+    pub pool_warn_only: Option<bool>,
+    /// For a volume that isn't thin, the size of the classic snapshot to
+    /// make, in any form accepted by `lvcreate -L`, such as "20G".
+    pub snapshot_size: Option<String>,
+    /// Commands, run with `sh -c`, before the source is snapshotted, such as
+    /// to stop a service writing to it.
+    #[serde(default)]
+    pub pre_hook: Vec<String>,
+    /// Commands run once the source is snapshotted, or fails to be, before
+    /// it is synced.
+    #[serde(default)]
+    pub post_hook: Vec<String>,
+    /// Whether a hook that fails is "fatal", the default, or only a "warn"ing.
+    pub hook_failure: Option<HookFailure>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SyncKind {
+    Lvm,
+    Btrfs,
+}
+
+impl Default for SyncKind {
+    fn default() -> SyncKind {
+        SyncKind::Lvm
+    }
+}
+
+#[test]
+fn test_parse_errors() {
+    let config = |vol: &str| {
+        format!(
+            "snap:\n  conventions: [{{name: daily, daily: 7}}]\n  volumes:\n    - {}\n\
+             sure: {{volumes: []}}\nrestic: {{volumes: []}}\nclone: {{volumes: []}}\n",
+            vol
+        )
+    };
+    let parse = |text: &str| Config::parse(text, "lint");
+    let conf = parse("priority: {nice: 5}").unwrap();
+    assert!(conf.snap.volumes.is_empty());
+    assert!(configured("snap", &conf.snap.volumes).is_err());
+
+    assert!(parse(&config("{name: home, convention: daily, zfs: a/home}")).is_ok());
+
+    let e = parse(&config("{name: home, convetion: daily, zfs: a/home}")).unwrap_err();
+    let e = e.to_string();
+    assert!(e.starts_with("snap.volumes[0]"), "{}", e);
+    assert!(e.contains("unknown field `convetion`"), "{}", e);
+
+    let e = parse(&config("{name: home, convention: weekly, zfs: a/home}")).unwrap_err();
+    assert_eq!(e.to_string(), "snap.volumes[0].convention: unknown convention \"weekly\"");
+
+    let e = parse(&config("{name: home, convention: daily, zfs: a/home, require: [borg]}"));
+    let msg = "snap.volumes[0].require: no borg backup is made of \"a/home\"";
+    assert_eq!(e.unwrap_err().to_string(), msg);
+
+    assert!(parse(&config("{name: home, convention: daily, zfs: a/home, prune: hanoi}")).is_ok());
+    let last = "snap:\n  conventions: [{name: last, last: 5}]\n  volumes:\n    \
+                - {name: home, convention: last, zfs: a/home, prune: hanoi}\n";
+    let e = parse(last).unwrap_err().to_string();
+    assert!(e.starts_with("snap.volumes[0].prune: hanoi numbers"), "{}", e);
+
+    let db = "{name: db, convention: daily, zfs: a/db, database: {kind: postgres}}";
+    let e = parse(&config(db)).unwrap_err();
+    let msg = "snap.volumes[0].database: a dump_dir is needed to dump the database";
+    assert_eq!(e.to_string(), msg);
+
+    let restic = "exclude_profiles: {dev-caches: [target/, node_modules/]}\n\
+                  restic:\n  volumes:\n    - {name: home, zfs: a/home, bind: /mnt/home, \
+                  repo: /srv/restic, excludes: [/tmp/], exclude_profiles: [dev-caches]}\n";
+    let conf = parse(restic).unwrap();
+    assert_eq!(conf.restic.volumes[0].excludes, vec!["/tmp/", "target/", "node_modules/"]);
+    let e = parse(&restic.replace("[dev-caches]", "[dev]")).unwrap_err();
+    let msg = "restic.volumes[0].exclude_profiles: unknown exclude profile \"dev\"";
+    assert_eq!(e.to_string(), msg);
+
+    let clone = "clone:\n  volumes:\n    - {name: home, source: tank/home, dest: backup/home, \
+                 recv_options: {set: {canmount: off, compression: zstd}, exclude: [quota]}}\n";
+    let conf = parse(clone).unwrap();
+    let args = conf.clone.volumes[0].recv_options.args();
+    assert_eq!(args, vec!["-o", "canmount=off", "-o", "compression=zstd", "-x", "quota"]);
+    let e = parse(&clone.replace("[quota]", "[canmount]")).unwrap_err();
+    let msg = "clone.volumes[0].recv_options: \"canmount\" can't be both set and excluded";
+    assert_eq!(e.to_string(), msg);
+
+    assert!(parse("clone: {rate_limit: 10M, volumes: []}").is_ok());
+    let e = parse("clone: {rate_limit: 10 MB/s, volumes: []}").unwrap_err();
+    assert_eq!(e.to_string(), "clone.rate_limit: must be a number, with k, m, g or t");
+
+    assert!(parse("borg: {window: 22:00-06:00, volumes: []}").is_ok());
+    let conf = parse("borg: {window: 01:00-06:00}\nsync: {}").unwrap();
+    let e = configured("borg", &conf.borg.volumes).unwrap_err();
+    assert_eq!(e.to_string(), "No borg volumes are configured");
+    assert!(configured("sync", &conf.sync.volumes).is_err());
+    let e = parse("restic: {window: 10pm-6am, volumes: []}").unwrap_err();
+    assert_eq!(e.to_string(), "restic.window: must be a range of times, such as 01:00-06:00");
+}
+
+#[test]
+fn test_hosts() {
+    let text = "\
+snap:
+  conventions: [{name: daily, daily: 7}]
+  volumes: [{name: home, convention: daily, zfs: a/home}]
+sure: {volumes: []}
+restic: {volumes: []}
+clone: {volumes: []}
+hosts:
+  lint:
+    snap:
+      volumes: [{name: root, convention: daily, zfs: a/root}]
+    priority: {nice: 10}
+  other:
+    snap:
+      volumes: [{name: data, convention: hourly, zfs: b/data}]
+";
+    let e = Config::parse(text, "lint").unwrap_err();
+    assert_eq!(
+        e.to_string(),
+        "snap.volumes[1].convention: unknown convention \"hourly\" (on host \"other\")"
+    );
+
+    let text = text.replace("hourly", "daily");
+    let lint = Config::parse(&text, "lint").unwrap();
+    let names: Vec<_> = lint.snap.volumes.iter().map(|v| v.name.as_str()).collect();
+    assert_eq!(names, vec!["home", "root"]);
+    assert_eq!(lint.priority.nice, Some(10));
+
+    let third = Config::parse(&text, "third").unwrap();
+    assert_eq!(third.snap.volumes.len(), 1);
+    assert_eq!(third.priority.nice, None);
+}
+
+#[test]
+fn test_select() {
+    let text = "\
+snap:
+  conventions: [{name: daily, daily: 7}]
+  volumes:
+    - {name: home, convention: daily, zfs: a/home}
+    - {name: root, convention: daily, zfs: a/root, skip: true}
+sure: {volumes: []}
+restic: {volumes: []}
+clone: {volumes: [{name: home, source: a/home, dest: b/home}]}
+";
+    let skips = |only: &[&str], skip: &[&str]| {
+        let mut conf = Config::parse(text, "lint").unwrap();
+        let only: Vec<_> = only.iter().map(|s| s.to_string()).collect();
+        let skip: Vec<_> = skip.iter().map(|s| s.to_string()).collect();
+        conf.select(&only, &skip).map(|()| {
+            let mut skips: Vec<_> = conf.snap.volumes.iter().map(|v| v.skip).collect();
+            skips.extend(conf.clone.volumes.iter().map(|v| v.skip));
+            skips
+        })
+    };
+
+    assert_eq!(skips(&[], &[]).unwrap(), vec![None, Some(true), None]);
+    assert_eq!(skips(&["root"], &[]).unwrap(), vec![Some(true), Some(false), Some(true)]);
+    assert_eq!(skips(&[], &["home"]).unwrap(), vec![Some(true), Some(true), Some(true)]);
+    assert_eq!(
+        skips(&["home", "root"], &["root"]).unwrap(),
+        vec![Some(false), Some(true), Some(false)]
+    );
+    let e = skips(&[], &["hmoe"]).unwrap_err();
+    assert_eq!(e.to_string(), "No volume of any section is named \"hmoe\"");
 }
@@ -0,0 +1,234 @@
+//! Backing up the volumes of docker or podman containers.
+//!
+//! Each volume of the `containers` section names the container volumes to
+//! back up, or a compose project, all of whose volumes are backed up.  The
+//! containers using them, the ones listed, and the running containers of
+//! the project, are stopped, or paused, while the volumes are either copied
+//! with rsync into a ZFS filesystem, `zfs_dest`, or, when they are already
+//! on ZFS, snapshotted where they are.  The containers are started again
+//! even if the copy fails.  The ZFS filesystems can then be given to snap,
+//! restic and borg volumes like any other, for history and offsite copies.
+
+use crate::{
+    checked::{heavy_command, CheckedExt},
+    config::{configured, skipped, Config, ContainerEngine, ContainerVolume},
+    digest,
+    plan::Plan,
+    zfs::{find_mount, Zfs},
+    Error, Result,
+};
+use chrono::Utc;
+use std::process::{Command, Stdio};
+
+impl ContainerEngine {
+    fn command(self) -> Command {
+        Command::new(self.program())
+    }
+
+    pub(crate) fn program(self) -> &'static str {
+        match self {
+            ContainerEngine::Docker => "docker",
+            ContainerEngine::Podman => "podman",
+        }
+    }
+
+    /// The label compose gives the volumes and containers of a project.
+    fn project_label(self, project: &str) -> String {
+        let label = match self {
+            ContainerEngine::Docker => "com.docker.compose.project",
+            ContainerEngine::Podman => "io.podman.compose.project",
+        };
+        format!("label={}={}", label, project)
+    }
+
+    /// Run a command, such as "stop", on the containers.
+    fn each(self, verb: &str, containers: &[String]) -> Result<()> {
+        if containers.is_empty() {
+            return Ok(());
+        }
+        progress!("{} {} {}", self.program(), verb, containers.join(" "));
+        let mut cmd = self.command();
+        cmd.arg(verb).args(containers);
+        cmd.stdout(Stdio::null());
+        cmd.checked_run()
+    }
+
+    /// The lines a command writes.
+    fn lines(self, args: &[&str]) -> Result<Vec<String>> {
+        let out = self.command().args(args).stderr(Stdio::inherit()).checked_output()?;
+        let text = String::from_utf8(out.stdout)?;
+        Ok(text.lines().filter(|l| !l.is_empty()).map(|l| l.to_string()).collect())
+    }
+}
+
+impl Config {
+    /// Back up the container volumes in the config.
+    pub fn run_containers(&self, pretend: bool) -> Result<()> {
+        configured("containers", &self.containers.volumes)?;
+        let _lock = if pretend { None } else { self.preflight()? };
+        for vol in &self.containers.volumes {
+            if skipped("containers", &vol.name, vol.skip) {
+                continue;
+            }
+            vol.back_up(self, pretend)?;
+        }
+        Ok(())
+    }
+}
+
+impl ContainerVolume {
+    fn back_up(&self, conf: &Config, pretend: bool) -> Result<()> {
+        let engine = conf.containers.engine;
+        let volumes = self.find_volumes(engine)?;
+        let running = self.running(engine)?;
+        let (stop, start) = if self.pause == Some(true) {
+            ("pause", "unpause")
+        } else {
+            ("stop", "start")
+        };
+
+        if pretend {
+            for (name, mountpoint) in &volumes {
+                decision!("Containers {:?}: back up {} at {}", self.name, name, mountpoint);
+            }
+            decision!("Containers {:?}: {} {}", self.name, stop, running.join(" "));
+            return Ok(());
+        }
+
+        progress!("Containers {:?}", self.name);
+        engine.each(stop, &running)?;
+        let result = self.copy(conf, &volumes);
+        let started = engine.each(start, &running);
+        result?;
+        started?;
+        digest::volume("containers", &self.name);
+        Ok(())
+    }
+
+    /// The volumes to back up, with where they are mounted.
+    fn find_volumes(&self, engine: ContainerEngine) -> Result<Vec<(String, String)>> {
+        let mut names = self.volumes.clone();
+        if let Some(ref project) = self.project {
+            let label = engine.project_label(project);
+            names.extend(engine.lines(&["volume", "ls", "-q", "--filter", &label])?);
+        }
+        if names.is_empty() {
+            return Err(Error::msg(format!("Containers {:?}: no volumes found", self.name)));
+        }
+
+        let mut args = vec!["volume", "inspect", "--format", "{{.Name}}\t{{.Mountpoint}}"];
+        args.extend(names.iter().map(|n| n.as_str()));
+        let mut volumes = vec![];
+        for line in engine.lines(&args)? {
+            match line.split_once('\t') {
+                Some((name, mountpoint)) => volumes.push((name.into(), mountpoint.into())),
+                None => {
+                    let msg = format!("Invalid line from volume inspect: {:?}", line);
+                    return Err(Error::msg(msg));
+                }
+            }
+        }
+        Ok(volumes)
+    }
+
+    /// The containers to stop: those listed, and those of the project, that
+    /// are running.  Ones already stopped are left alone, so that they
+    /// aren't started afterwards.
+    fn running(&self, engine: ContainerEngine) -> Result<Vec<String>> {
+        let format = ["ps", "--format", "{{.Names}}"];
+        let running = engine.lines(&format)?;
+        let mut containers: Vec<String> =
+            self.containers.iter().filter(|c| running.contains(c)).cloned().collect();
+        if let Some(ref project) = self.project {
+            let label = engine.project_label(project);
+            for name in engine.lines(&[&format[..], &["--filter", &label]].concat())? {
+                if !containers.contains(&name) {
+                    containers.push(name);
+                }
+            }
+        }
+        Ok(containers)
+    }
+
+    /// Copy, or snapshot, the volumes, while the containers are stopped.
+    fn copy(&self, conf: &Config, volumes: &[(String, String)]) -> Result<()> {
+        if let Some(ref dest) = self.zfs_dest {
+            let dest = find_mount(dest)?;
+            for (name, mountpoint) in volumes {
+                progress!("Containers {:?}: rsync {}", self.name, name);
+                let mut cmd = heavy_command("rsync");
+                cmd.args(&["-aHAX", "--delete", "--numeric-ids"]);
+                cmd.arg(format!("{}/", mountpoint));
+                cmd.arg(format!("{}/{}/", dest, name));
+                cmd.checked_run()?;
+            }
+            return Ok(());
+        }
+
+        let (fs, conv) = match (&self.zfs, &self.convention) {
+            (Some(fs), Some(conv)) => (fs, conv),
+            _ => return Err(Error::msg(format!("Containers {:?}: nowhere to copy to", self.name))),
+        };
+        let conv = conf
+            .snap
+            .conventions
+            .iter()
+            .find(|c| &c.name == conv)
+            .ok_or_else(|| Error::msg(format!("Invalid convention {:?}", conv)))?;
+        let now = Utc::now();
+        let name = conv.naming.name(&conv.name, None, now.naive_utc());
+        let zfs = Zfs::from_inventory("none", &conf.inventory)?;
+        let mut plan = Plan::new("containers");
+        let reason = format!("Snapshot of {:?}@{:?} at {}", fs, name, now);
+        zfs.plan_named_snapshot(fs, &name, reason, &mut plan);
+        plan.apply()?;
+        conf.inventory.invalidate();
+        Ok(())
+    }
+}
+
+#[test]
+fn test_containers() {
+    use crate::checked::{set_executor, RecordingExecutor};
+    use std::sync::Arc;
+
+    let text = "\
+snap:
+  conventions: [{name: daily, daily: 7}]
+containers:
+  volumes:
+    - name: wiki
+      project: wiki
+      containers: [backup-agent, proxy]
+      zfs: tank/docker
+      convention: daily
+";
+    let conf = Config::parse(text, "lint").unwrap();
+    let exec = Arc::new(RecordingExecutor::new());
+    exec.respond(&["docker", "volume", "ls"], "wiki_db\nwiki_uploads\n");
+    exec.respond(
+        &["docker", "volume", "inspect"],
+        "wiki_db\t/tank/docker/volumes/wiki_db/_data\n\
+         wiki_uploads\t/tank/docker/volumes/wiki_uploads/_data\n",
+    );
+    exec.respond(&["docker", "ps", "--format", "{{.Names}}", "--filter"], "wiki-web-1\n");
+    exec.respond(&["docker", "ps"], "wiki-web-1\nproxy\n");
+    let running = set_executor(exec.clone());
+    conf.containers.volumes[0].back_up(&conf, false).unwrap();
+    drop(running);
+
+    let commands = exec.commands();
+    let project = "label=com.docker.compose.project=wiki";
+    assert_eq!(
+        commands[..5],
+        [
+            format!("docker volume ls -q --filter {}", project),
+            "docker volume inspect --format {{.Name}}\t{{.Mountpoint}} wiki_db wiki_uploads".into(),
+            "docker ps --format {{.Names}}".into(),
+            format!("docker ps --format {{{{.Names}}}} --filter {}", project),
+            "docker stop proxy wiki-web-1".into(),
+        ]
+    );
+    assert!(commands[6].starts_with("zfs snapshot tank/docker@daily"), "{:?}", commands);
+    assert_eq!(commands[7], "docker start proxy wiki-web-1");
+}
@@ -0,0 +1,203 @@
+//! Databases made consistent in snapshots.
+//!
+//! The files of a running database, caught in a snapshot, may not be
+//! restorable.  A snap volume can name the database whose data is on it, to
+//! be dealt with one of two ways as the snapshot is taken.  With "dump", all
+//! of its databases are dumped, with `pg_dumpall` or `mysqldump`, into a
+//! directory on the filesystem just before the snapshot, so the snapshot
+//! holds a dump that can always be loaded.  With "quiesce", the snapshot is
+//! taken while a client holds postgres in backup mode, between
+//! `pg_backup_start` and `pg_backup_stop`, or holds mysql with its tables
+//! flushed and locked.  The backup label postgres gives back, which has to
+//! be put in the data directory when the snapshot is restored, is recorded
+//! in the journal.
+
+use crate::{
+    checked::ran,
+    config::{DatabaseConfig, DatabaseKind, QuiesceMethod},
+    hooks::Hooks,
+    journal,
+    plan::{Action, Plan},
+    Error, Result,
+};
+use serde_derive::Serialize;
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    path::Path,
+    process::{Command, Stdio},
+};
+
+#[derive(Serialize)]
+struct Label<'a> {
+    label: &'a str,
+}
+
+/// Add the snapshot planned by `op` to `plan`, between `hooks`, along with
+/// what makes `database` consistent in it.
+pub(crate) fn plan_snapshot<F>(
+    database: Option<&DatabaseConfig>,
+    hooks: &Hooks,
+    what: &str,
+    plan: &mut Plan,
+    op: F,
+) where
+    F: FnOnce(&mut Plan),
+{
+    let db = match database {
+        Some(db) => db,
+        None => return hooks.plan_around(what, plan, op),
+    };
+    hooks.plan_around(what, plan, |plan| match db.method.unwrap_or(QuiesceMethod::Dump) {
+        QuiesceMethod::Dump => {
+            let dir = db.dump_dir.as_deref().unwrap_or_default();
+            plan.run(format!("Dump the {:?} database of {:?}", db.kind, what), &dump(db, dir));
+            op(plan);
+        }
+        QuiesceMethod::Quiesce => {
+            let mut snapshot = Plan::new(&plan.operation);
+            op(&mut snapshot);
+            for action in snapshot.actions {
+                plan.actions.push(match action {
+                    Action::Run { command, reason } => Action::Quiesced {
+                        database: db.clone(),
+                        command: command,
+                        reason: reason,
+                    },
+                    action => action,
+                });
+            }
+        }
+    });
+}
+
+/// The command dumping all of the databases into `dir`.
+fn dump(db: &DatabaseConfig, dir: &str) -> Command {
+    let dir = Path::new(dir);
+    let mut cmd;
+    match db.kind {
+        DatabaseKind::Postgres => {
+            cmd = Command::new("pg_dumpall");
+            cmd.args(&db.client_args);
+            cmd.arg("-f").arg(dir.join("pg_dumpall.sql"));
+        }
+        DatabaseKind::Mysql => {
+            cmd = Command::new("mysqldump");
+            cmd.args(&db.client_args);
+            cmd.args(&["--single-transaction", "--all-databases"]);
+            cmd.arg(format!("--result-file={}", dir.join("mysqldump.sql").display()));
+        }
+    }
+    cmd
+}
+
+/// The client of the database, with the statements that quiesce it, which
+/// answer with a line once it is, and that release it.
+fn client(db: &DatabaseConfig) -> (Command, &'static str, &'static str) {
+    match db.kind {
+        DatabaseKind::Postgres => {
+            let mut cmd = Command::new("psql");
+            cmd.args(&db.client_args);
+            cmd.args(&["-X", "-A", "-t", "-q", "-v", "ON_ERROR_STOP=1"]);
+            let hold = "SELECT pg_backup_start('rack', true);\n";
+            // The WAL is in the snapshot, so there is no need to wait for it
+            // to be archived.
+            let release = "SELECT labelfile FROM pg_backup_stop(false);\n";
+            (cmd, hold, release)
+        }
+        DatabaseKind::Mysql => {
+            let mut cmd = Command::new("mysql");
+            cmd.args(&db.client_args);
+            cmd.args(&["--batch", "--skip-column-names"]);
+            (cmd, "FLUSH TABLES WITH READ LOCK;\nSELECT 'locked';\n", "UNLOCK TABLES;\n")
+        }
+    }
+}
+
+/// Run `op`, which takes the snapshot `target`, while the database is held
+/// quiesced by a client.  The database is released even if `op` fails.
+pub(crate) fn quiesced<F>(db: &DatabaseConfig, target: &str, op: F) -> Result<()>
+where
+    F: FnOnce() -> Result<()>,
+{
+    let (mut cmd, hold, release) = client(db);
+    let mut child = cmd
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()?;
+    let mut stdin = child.stdin.take().expect("Client stdin");
+    let mut stdout = BufReader::new(child.stdout.take().expect("Client stdout"));
+
+    stdin.write_all(hold.as_bytes())?;
+    stdin.flush()?;
+    let mut answer = String::new();
+    stdout.read_line(&mut answer)?;
+    if answer.is_empty() {
+        // The client gave up, such as on failing to connect.
+        drop(stdin);
+        let status = child.wait()?;
+        ran(&cmd, Some(status));
+        return Err(Error::Command {
+            command: format!("{:?}", cmd),
+            status: status,
+        });
+    }
+
+    let result = op();
+
+    let written = stdin.write_all(release.as_bytes());
+    drop(stdin);
+    let mut label = String::new();
+    let read = stdout.read_to_string(&mut label);
+    let status = child.wait()?;
+    ran(&cmd, Some(status));
+    result?;
+    written?;
+    read?;
+    if !status.success() {
+        return Err(Error::Command {
+            command: format!("{:?}", cmd),
+            status: status,
+        });
+    }
+    if db.kind == DatabaseKind::Postgres {
+        let label = Label {
+            label: label.trim_end(),
+        };
+        journal::record("backup-label", target, &label)?;
+    }
+    Ok(())
+}
+
+#[test]
+fn test_plan_snapshot() {
+    let mut db = DatabaseConfig {
+        kind: DatabaseKind::Postgres,
+        method: None,
+        dump_dir: Some("/srv/db/dumps".into()),
+        client_args: vec!["-U".into(), "postgres".into()],
+    };
+    let hooks = Hooks::new(&[], &[], None);
+    let snapshot = |plan: &mut Plan| {
+        plan.run("Snapshot".into(), Command::new("zfs").args(&["snapshot", "a/db@daily"]))
+    };
+
+    let mut plan = Plan::new("snap");
+    plan_snapshot(Some(&db), &hooks, "db", &mut plan, snapshot);
+    let dump = vec!["pg_dumpall", "-U", "postgres", "-f", "/srv/db/dumps/pg_dumpall.sql"];
+    match &plan.actions[..] {
+        [Action::Run { command, .. }, Action::Run { .. }] => assert_eq!(command, &dump),
+        actions => panic!("Unexpected plan: {:?}", actions),
+    }
+
+    db.method = Some(QuiesceMethod::Quiesce);
+    let mut plan = Plan::new("snap");
+    plan_snapshot(Some(&db), &hooks, "db", &mut plan, snapshot);
+    match &plan.actions[..] {
+        [Action::Quiesced { database, command, .. }] => {
+            assert_eq!(database, &db);
+            assert_eq!(command, &["zfs", "snapshot", "a/db@daily"]);
+        }
+        actions => panic!("Unexpected plan: {:?}", actions),
+    }
+}
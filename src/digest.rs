@@ -0,0 +1,178 @@
+//! A digest of what a run did.
+//!
+//! Notifying of every failure as it happens is noisy.  Instead, a run can
+//! collect a digest: the volumes it worked on, the bytes it sent, the
+//! snapshots it created and pruned, and what went wrong, to be sent as a
+//! single message when it finishes.  Operations note what they do, which
+//! is dropped unless a digest is being collected, and warnings are picked
+//! up from the reporter.
+
+use crate::{
+    config::NotifyConfig,
+    report::{Event, Reporter},
+    surestore,
+    zfs::humanize_size,
+    Error, Result,
+};
+use std::{
+    collections::BTreeSet,
+    fmt::Write,
+    sync::{Arc, Mutex},
+};
+
+#[derive(Debug, Default)]
+pub struct Digest {
+    /// The volumes worked on, as the kind of work and the volume's name.
+    pub volumes: BTreeSet<(String, String)>,
+    /// The bytes sent to backups and clones, where they are known.
+    pub bytes: u64,
+    pub created: Vec<String>,
+    pub pruned: Vec<String>,
+    pub warnings: Vec<String>,
+    pub failures: Vec<String>,
+}
+
+static DIGEST: Mutex<Option<Digest>> = Mutex::new(None);
+
+/// Start collecting a digest.
+pub fn start() {
+    *DIGEST.lock().unwrap() = Some(Digest::default());
+}
+
+/// Stop collecting, returning the digest collected, if one was started.
+pub fn finish() -> Option<Digest> {
+    DIGEST.lock().unwrap().take()
+}
+
+fn with<F: FnOnce(&mut Digest)>(f: F) {
+    if let Some(ref mut digest) = *DIGEST.lock().unwrap() {
+        f(digest);
+    }
+}
+
+/// Note work done on a volume.
+pub fn volume(kind: &str, name: &str) {
+    with(|d| {
+        d.volumes.insert((kind.to_string(), name.to_string()));
+    });
+}
+
+/// Note bytes sent.
+pub fn bytes(count: u64) {
+    with(|d| d.bytes += count);
+}
+
+/// Note a snapshot created.
+pub fn created(snap: &str) {
+    with(|d| d.created.push(snap.to_string()));
+}
+
+/// Note a snapshot pruned.
+pub fn pruned(snap: &str) {
+    with(|d| d.pruned.push(snap.to_string()));
+}
+
+/// Note a failure that stopped some of the work.
+pub fn failure(what: &str) {
+    with(|d| d.failures.push(what.to_string()));
+}
+
+/// Finish collecting, noting the error that ended the run, if there was
+/// one, and send the digest to the notify destinations.
+pub fn send(notify: &NotifyConfig, error: Option<&Error>) -> Result<()> {
+    if let Some(e) = error {
+        failure(&e.to_string());
+    }
+    let digest = match finish() {
+        Some(digest) => digest,
+        None => return Ok(()),
+    };
+    if !notify.configured() {
+        warning!("No notify destinations are configured, the digest is not sent");
+        return Ok(());
+    }
+    let host = surestore::hostname()?;
+    notify.send(&digest.subject(&host), &digest.body())
+}
+
+impl Digest {
+    /// A subject line, which gives the gist of it.
+    pub fn subject(&self, host: &str) -> String {
+        let status = match (self.failures.len(), self.warnings.len()) {
+            (0, 0) => "ok".to_string(),
+            (0, w) => format!("ok, {} warning{}", w, plural(w)),
+            (f, _) => format!("{} failure{}", f, plural(f)),
+        };
+        format!("rack on {}: {}", host, status)
+    }
+
+    /// The body of the message, as plain text, laid out for mail.
+    pub fn body(&self) -> String {
+        let mut body = String::new();
+        let mut section = |title: &str, lines: &[String]| {
+            if lines.is_empty() {
+                return;
+            }
+            let _ = writeln!(body, "{} ({}):", title, lines.len());
+            for line in lines {
+                let _ = writeln!(body, "  {}", line);
+            }
+            body.push('\n');
+        };
+        section("Failures", &self.failures);
+        section("Warnings", &self.warnings);
+        let volumes: Vec<_> = self.volumes.iter().map(|(k, n)| format!("{}: {}", k, n)).collect();
+        section("Volumes", &volumes);
+        section("Snapshots created", &self.created);
+        section("Snapshots pruned", &self.pruned);
+
+        let _ = writeln!(body, "Sent: {}", humanize_size(self.bytes as usize).trim());
+        body
+    }
+}
+
+fn plural(count: usize) -> &'static str {
+    if count == 1 {
+        ""
+    } else {
+        "s"
+    }
+}
+
+/// A reporter that passes events on, also collecting the warnings into the
+/// digest.
+pub struct DigestReporter(pub Arc<dyn Reporter>);
+
+impl Reporter for DigestReporter {
+    fn report(&self, event: Event) {
+        if let Event::Warning(ref msg) = event {
+            with(|d| d.warnings.push(msg.clone()));
+        }
+        self.0.report(event);
+    }
+}
+
+#[test]
+fn test_digest() {
+    let mut digest = Digest::default();
+    digest.volumes.insert(("restic".into(), "home".into()));
+    digest.volumes.insert(("borg".into(), "root".into()));
+    digest.bytes = 3 * 1024 * 1024;
+    digest.created.push("pool/home@daily-202610160300".into());
+    digest.pruned.push("pool/home@daily-202610010300".into());
+    assert_eq!(digest.subject("lint"), "rack on lint: ok");
+    digest.warnings.push("Skipping \"pool/old\", which isn't mounted".into());
+    assert_eq!(digest.subject("lint"), "rack on lint: ok, 1 warning");
+    digest.failures.push("borg: repository is locked".into());
+    assert_eq!(digest.subject("lint"), "rack on lint: 1 failure");
+
+    assert_eq!(
+        digest.body(),
+        "Failures (1):\n  borg: repository is locked\n\n\
+         Warnings (1):\n  Skipping \"pool/old\", which isn't mounted\n\n\
+         Volumes (2):\n  borg: root\n  restic: home\n\n\
+         Snapshots created (1):\n  pool/home@daily-202610160300\n\n\
+         Snapshots pruned (1):\n  pool/home@daily-202610010300\n\n\
+         Sent: 3.000MiB\n"
+    );
+}
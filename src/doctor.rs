@@ -0,0 +1,312 @@
+//! Checking that rack can do what it is configured to.
+//!
+//! `rack doctor` looks for the programs the config needs, checks that zfs
+//! can be used, and with what permissions, that the configured filesystems
+//! exist, that the backup repositories and clone hosts can be reached, and
+//! that the directories snapshots are mounted on exist.  Everything is
+//! checked, rather than stopping at the first problem, and each problem is
+//! printed with what to do about it.
+
+use crate::{
+    borg,
+    checked::CheckedExt,
+    config::{
+        Config,
+        DatabaseKind::{Mysql, Postgres},
+        QuiesceMethod::{Dump, Quiesce},
+        SyncKind,
+    },
+    restic::RESTIC_BIN,
+    zfs::zfs_command,
+    Error, Result,
+};
+use std::{
+    collections::BTreeSet, env, fmt::Display, fs, os::unix::fs::MetadataExt, path::Path,
+    process::Command,
+};
+
+/// A program the config needs, with the arguments that print its version.
+struct Program {
+    program: &'static str,
+    version: &'static [&'static str],
+    /// What it is needed for.
+    used_by: &'static str,
+}
+
+struct Doctor {
+    problems: usize,
+}
+
+impl Doctor {
+    fn ok(&self, what: &str, detail: &str) {
+        output!("  ok    {}: {}", what, detail);
+    }
+
+    fn problem(&mut self, what: &str, error: &dyn Display, fix: &str) {
+        self.problems += 1;
+        output!("  FAIL  {}: {}", what, error);
+        output!("        {}", fix);
+    }
+
+    fn check(&mut self, what: &str, result: Result<String>, fix: &str) {
+        match result {
+            Ok(detail) => self.ok(what, &detail),
+            Err(e) => self.problem(what, &e, fix),
+        }
+    }
+}
+
+impl Config {
+    /// Check the environment, printing what was found.  Fails if there
+    /// were any problems.
+    pub fn doctor(&self) -> Result<()> {
+        let mut doc = Doctor { problems: 0 };
+
+        output!("Programs:");
+        for p in self.programs() {
+            let fix = if p.program == RESTIC_BIN {
+                format!("install restic as {:?}, for {}", RESTIC_BIN, p.used_by)
+            } else {
+                format!("install {} on the PATH, for {}", p.program, p.used_by)
+            };
+            doc.check(p.program, version(&p), &fix);
+        }
+
+        output!("Permissions:");
+        self.check_permissions(&mut doc);
+
+        output!("Filesystems:");
+        self.check_filesystems(&mut doc);
+
+        output!("Repositories:");
+        self.check_repositories(&mut doc);
+
+        output!("Directories:");
+        for dir in self.bind_dirs() {
+            if Path::new(&dir).is_dir() {
+                doc.ok(&dir, "exists");
+            } else {
+                doc.problem(&dir, &"not a directory", &format!("mkdir -p {}", dir));
+            }
+        }
+
+        match doc.problems {
+            0 => {
+                output!("No problems found");
+                Ok(())
+            }
+            1 => Err(Error::msg("1 problem found")),
+            n => Err(Error::msg(format!("{} problems found", n))),
+        }
+    }
+
+    /// The programs needed by the configured volumes.
+    fn programs(&self) -> Vec<Program> {
+        let sync = |kind| self.sync.volumes.iter().any(|v| v.kind == kind);
+        let pulls = self.clone.volumes.iter().any(|v| v.host.is_some());
+        let age = self.cloud.volumes.iter().any(|v| v.age.is_some())
+            || self.export.volumes.iter().any(|v| v.age.is_some());
+        let db = |kind, method| {
+            let mut dbs = self.snap.volumes.iter().filter_map(|v| v.database.as_ref());
+            dbs.any(|d| d.kind == kind && d.method.unwrap_or(Dump) == method)
+        };
+        let containers = !self.containers.volumes.is_empty();
+        let engine = self.containers.engine;
+        let mirrors = self.containers.volumes.iter().any(|v| v.zfs_dest.is_some());
+        let needed = [
+            (true, "zfs", &["version"][..], "everything"),
+            (sync(SyncKind::Lvm), "lvs", &["--version"][..], "lvm sync volumes"),
+            (sync(SyncKind::Btrfs), "btrfs", &["--version"][..], "btrfs sync volumes"),
+            (!self.sync.volumes.is_empty(), "rsync", &["--version"][..], "sync volumes"),
+            (containers, engine.program(), &["--version"][..], "container volumes"),
+            (mirrors, "rsync", &["--version"][..], "container volumes copied to zfs_dest"),
+            (!self.restic.volumes.is_empty(), RESTIC_BIN, &["version"][..], "restic volumes"),
+            (!self.borg.volumes.is_empty(), "borg", &["--version"][..], "borg volumes"),
+            (!self.clone.volumes.is_empty(), "pv", &["--version"][..], "clone volumes"),
+            (pulls, "ssh", &["-V"][..], "clone volumes pulled from another host"),
+            (!self.cloud.volumes.is_empty(), "rclone", &["version"][..], "cloud volumes"),
+            (age, "age", &["--version"][..], "encrypting with age"),
+            (!self.pings.is_empty(), "curl", &["--version"][..], "pings"),
+            (db(Postgres, Dump), "pg_dumpall", &["--version"][..], "dumping postgres"),
+            (db(Postgres, Quiesce), "psql", &["--version"][..], "quiescing postgres"),
+            (db(Mysql, Dump), "mysqldump", &["--version"][..], "dumping mysql"),
+            (db(Mysql, Quiesce), "mysql", &["--version"][..], "quiescing mysql"),
+        ];
+        needed
+            .iter()
+            .filter(|n| n.0)
+            .map(|&(_, program, version, used_by)| Program {
+                program: program,
+                version: version,
+                used_by: used_by,
+            })
+            .collect()
+    }
+
+    fn check_permissions(&self, doc: &mut Doctor) {
+        let uid = match fs::metadata("/proc/self") {
+            Ok(meta) => meta.uid(),
+            Err(e) => {
+                doc.problem("user", &e, "make sure /proc is mounted");
+                return;
+            }
+        };
+        if uid == 0 {
+            doc.ok("user", "running as root");
+            return;
+        }
+
+        if !self.sync.volumes.is_empty() {
+            doc.problem(
+                "user",
+                &format!("running as uid {}", uid),
+                "run as root, sync needs it to make lvm snapshots and mount them",
+            );
+        }
+        // Without root, each filesystem snapshotted needs its permissions
+        // delegated.
+        let user = env::var("USER").unwrap_or_else(|_| uid.to_string());
+        for vol in self.snap.volumes.iter().filter(|v| v.skip != Some(true)) {
+            let allowed = Command::new("zfs")
+                .args(&["allow", &vol.zfs])
+                .checked_output()
+                .map(|out| String::from_utf8_lossy(&out.stdout).contains(&user));
+            let fix =
+                format!("run as root, or: zfs allow {} snapshot,destroy,mount {}", user, vol.zfs);
+            match allowed {
+                Ok(true) => doc.ok(&vol.zfs, &format!("delegated to {}", user)),
+                Ok(false) => {
+                    doc.problem(&vol.zfs, &format!("nothing delegated to {}", user), &fix)
+                }
+                Err(e) => doc.problem(&vol.zfs, &e, &fix),
+            }
+        }
+    }
+
+    fn check_filesystems(&self, doc: &mut Doctor) {
+        let list = match self.inventory.filesystems() {
+            Ok(list) => list,
+            Err(e) => {
+                let fix = "check that the zfs module is loaded, and the pools are imported";
+                doc.problem("zfs list", &e, fix);
+                return;
+            }
+        };
+        doc.ok("zfs list", &format!("{} filesystems", list.len()));
+
+        let mut wanted = vec![];
+        let mut add = |section: &str, name: &str, zfs: &str, skip: Option<bool>| {
+            if skip != Some(true) {
+                wanted.push((format!("{} volume {:?}", section, name), zfs.to_string()));
+            }
+        };
+        for v in &self.snap.volumes {
+            add("snap", &v.name, &v.zfs, v.skip);
+        }
+        for v in &self.sure.volumes {
+            add("sure", &v.name, &v.zfs, v.skip);
+        }
+        for v in &self.restic.volumes {
+            add("restic", &v.name, &v.zfs, v.skip);
+        }
+        for v in &self.borg.volumes {
+            add("borg", &v.name, &v.zfs, v.skip);
+        }
+        for v in &self.cloud.volumes {
+            add("cloud", &v.name, &v.zfs, v.skip);
+        }
+        for v in &self.export.volumes {
+            add("export", &v.name, &v.zfs, v.skip);
+        }
+        for v in self.clone.volumes.iter().filter(|v| v.host.is_none()) {
+            add("clone", &v.name, &v.source, v.skip);
+        }
+
+        for (what, zfs) in wanted {
+            if list.iter().any(|fs| fs.name == zfs) {
+                doc.ok(&what, &zfs);
+            } else {
+                let fix = format!("create {}, or correct the config", zfs);
+                doc.problem(&what, &format!("no filesystem {:?}", zfs), &fix);
+            }
+        }
+    }
+
+    fn check_repositories(&self, doc: &mut Doctor) {
+        let fix = "check that the repository exists, and its credentials";
+        for vol in self.restic.volumes.iter().filter(|v| v.skip != Some(true)) {
+            // The listing kept from an earlier run would hide a repository
+            // that can no longer be reached.
+            let list = vol.list_snapshots().map(|_| "reachable".to_string());
+            doc.check(&format!("restic volume {:?}", vol.name), list, fix);
+        }
+
+        let mut repos = BTreeSet::new();
+        for vol in self.borg.volumes.iter().filter(|v| v.skip != Some(true)) {
+            if repos.insert(vol.repo.as_str()) {
+                let list = borg::list_archives(vol).map(|_| "reachable".to_string());
+                doc.check(&format!("borg repo {:?}", vol.repo), list, fix);
+            }
+        }
+
+        for vol in self.cloud.volumes.iter().filter(|v| v.skip != Some(true)) {
+            let list = Command::new("rclone")
+                .args(&["lsf", "--max-depth", "1", &vol.remote])
+                .checked_output()
+                .map(|_| "reachable".to_string());
+            let fix = format!("check the rclone remote with: rclone config show {}", vol.remote);
+            doc.check(&format!("cloud volume {:?}", vol.name), list, &fix);
+        }
+
+        for vol in self.clone.volumes.iter().filter(|v| v.skip != Some(true)) {
+            let host = match vol.host {
+                Some(ref host) => host,
+                None => continue,
+            };
+            let list = zfs_command(Some(host), false)
+                .args(&["list", "-H", "-o", "name", &vol.source])
+                .checked_output()
+                .map(|_| format!("{} on {}", vol.source, host));
+            let fix = format!(
+                "check that `ssh {} zfs list {}` works without a password",
+                host, vol.source
+            );
+            doc.check(&format!("clone volume {:?}", vol.name), list, &fix);
+        }
+    }
+
+    /// The directories snapshots are mounted on, which must exist.
+    fn bind_dirs(&self) -> BTreeSet<String> {
+        let mut dirs = BTreeSet::new();
+        let active = |skip: Option<bool>| skip != Some(true);
+        dirs.extend(self.sync.volumes.iter().filter(|v| active(v.skip)).map(|v| &v.mountpoint));
+        dirs.extend(self.sure.volumes.iter().filter(|v| active(v.skip)).map(|v| &v.bind));
+        dirs.extend(self.restic.volumes.iter().filter(|v| active(v.skip)).map(|v| &v.bind));
+        dirs.extend(self.borg.volumes.iter().filter(|v| active(v.skip)).map(|v| &v.bind));
+        dirs.into_iter().cloned().collect()
+    }
+}
+
+/// The first line of the program's version, which some print on stderr.
+fn version(p: &Program) -> Result<String> {
+    let out = Command::new(p.program).args(p.version).checked_output()?;
+    let text = if out.stdout.is_empty() { out.stderr } else { out.stdout };
+    let text = String::from_utf8_lossy(&text);
+    Ok(text.lines().next().unwrap_or("").trim().to_string())
+}
+
+#[test]
+fn test_programs() {
+    let text = "\
+snap: {volumes: []}
+sure: {volumes: []}
+restic: {volumes: []}
+clone:
+  volumes: [{name: laptop, source: a/home, dest: b/laptop, host: root@laptop}]
+sync:
+  volumes: [{name: root, vg: vg, lv: root, mountpoint: /mnt/root, zfs_dest: a/root}]
+";
+    let conf = Config::parse(text, "lint").unwrap();
+    let programs: Vec<_> = conf.programs().iter().map(|p| p.program).collect();
+    assert_eq!(programs, vec!["zfs", "lvs", "rsync", "pv", "ssh"]);
+}
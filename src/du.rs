@@ -0,0 +1,93 @@
+//! The space taken by snapshots.
+//!
+//! `rack du` lists the snapshots of a volume with the space each one holds
+//! on its own, which is what destroying just that snapshot would free, to
+//! show which are worth pruning.  Data shared by several snapshots, and no
+//! longer in the filesystem, isn't counted against any of them, so
+//! destroying a run of neighbouring snapshots can free more than the sum of
+//! what they use.
+
+use crate::{
+    config::Config,
+    zfs::{humanize_size, Filesystem, Inventory, Zfs},
+    Result,
+};
+use chrono::{TimeZone, Utc};
+
+impl Config {
+    /// Show the space taken by the snapshots of a volume, which is either
+    /// the name of a snap volume, or of a zfs filesystem.  With `by_used`,
+    /// the snapshots using the most space come first, otherwise the oldest.
+    pub fn du(&self, volume: &str, by_used: bool) -> Result<()> {
+        let name = self.snap_zfs(volume);
+        let zfs = Zfs::from_inventory("none", &Inventory::new().within(name))?;
+        for line in report(zfs.find(name)?, by_used) {
+            output!("{}", line);
+        }
+        Ok(())
+    }
+}
+
+/// The lines of the report, for one filesystem.
+fn report(fs: &Filesystem, by_used: bool) -> Vec<String> {
+    let mut snaps: Vec<_> = fs
+        .snaps
+        .iter()
+        .map(|snap| (snap, fs.space.get(snap).copied().unwrap_or_default()))
+        .collect();
+    if by_used {
+        snaps.sort_by(|a, b| b.1.used.cmp(&a.1.used));
+    }
+
+    let size = |bytes: u64| humanize_size(bytes as usize).trim().to_string();
+    let header = format!("{:<40} {:<16} {:>10} {:>10}", "SNAPSHOT", "CREATED", "USED", "REFER");
+    let mut lines = vec![header];
+    for (snap, space) in &snaps {
+        let created = match Utc.timestamp_opt(space.creation, 0).single() {
+            Some(time) => time.format("%Y-%m-%d %H:%M").to_string(),
+            None => "-".to_string(),
+        };
+        lines.push(format!(
+            "{:<40} {:<16} {:>10} {:>10}",
+            format!("{}@{}", fs.name, snap),
+            created,
+            size(space.used),
+            size(space.referenced)
+        ));
+    }
+    let total: u64 = snaps.iter().map(|(_, space)| space.used).sum();
+    lines.push(format!("{} snapshots, using {} on their own", snaps.len(), size(total)));
+    lines
+}
+
+#[test]
+fn test_report() {
+    use crate::zfs::{DatasetKind, MountState, SnapSpace};
+    use std::collections::HashMap;
+
+    let mut fs = Filesystem {
+        name: "pool/home".into(),
+        kind: DatasetKind::Filesystem,
+        snaps: vec!["daily-a".into(), "daily-b".into()],
+        bookmarks: vec![],
+        mount_state: MountState::Mounted,
+        pins: HashMap::new(),
+        space: HashMap::new(),
+    };
+    let space = |used, creation| SnapSpace {
+        used: used,
+        referenced: 8 * 1024 * 1024,
+        creation: creation,
+    };
+    fs.space.insert("daily-a".into(), space(1024, 1551713400));
+    fs.space.insert("daily-b".into(), space(4 * 1024 * 1024, 1551799800));
+
+    let lines = report(&fs, true);
+    assert_eq!(lines.len(), 4);
+    assert!(lines[1].starts_with("pool/home@daily-b"));
+    assert!(lines[1].contains("2019-03-05 15:30"));
+    assert!(lines[1].contains("4.000MiB"));
+    assert!(lines[2].starts_with("pool/home@daily-a"));
+    assert_eq!(lines[3], "2 snapshots, using 4.001MiB on their own");
+    assert!(report(&fs, false)[1].starts_with("pool/home@daily-a"));
+}
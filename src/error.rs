@@ -0,0 +1,100 @@
+//! Errors.
+//!
+//! Each part of rack has its own error type, such as `ZfsError`, for the
+//! problems a caller may want to tell apart.  These are all gathered into
+//! `Error`, along with errors from the libraries rack uses.  Problems that
+//! only need to be reported, not acted on, are kept as messages.
+
+use crate::{
+    borg::BorgError, config::ConfigError, export::ExportError, lvm::LvmError, restic::ResticError,
+    secret::SecretError, send::StreamError, surestore::SureError, sync::SyncError, zfs::ZfsError,
+};
+use std::{fmt::Display, io, process::ExitStatus, result, string::FromUtf8Error};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("error running command: {status:?}: {command}")]
+    Command { command: String, status: ExitStatus },
+    #[error("no progress for {secs}s, killed: {command}")]
+    Timeout { command: String, secs: u64 },
+    #[error(transparent)]
+    Zfs(#[from] ZfsError),
+    #[error(transparent)]
+    Lvm(#[from] LvmError),
+    #[error(transparent)]
+    Restic(#[from] ResticError),
+    #[error(transparent)]
+    Borg(#[from] BorgError),
+    #[error(transparent)]
+    Sync(#[from] SyncError),
+    #[error(transparent)]
+    Sure(#[from] SureError),
+    #[error(transparent)]
+    Stream(#[from] StreamError),
+    #[error(transparent)]
+    Export(#[from] ExportError),
+    #[error(transparent)]
+    Config(#[from] ConfigError),
+    #[error(transparent)]
+    Secret(#[from] SecretError),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Yaml(#[from] serde_yaml::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    Utf8(#[from] FromUtf8Error),
+    #[error(transparent)]
+    Regex(#[from] regex::Error),
+    /// An error from rsure, kept as its message, as rsure still uses the
+    /// `failure` crate, which rack doesn't depend on.
+    #[error("{0}")]
+    Rsure(String),
+    #[error("{0}")]
+    Msg(String),
+    #[error("{context}: {source}")]
+    Context { context: String, source: Box<Error> },
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+/// The type of error rsure gives, named through its `Result`.
+type RsureError = <rsure::Result<()> as Failing>::Error;
+
+/// A result, giving the type of its error.
+pub trait Failing {
+    type Error;
+}
+
+impl<T, E> Failing for result::Result<T, E> {
+    type Error = E;
+}
+
+impl From<RsureError> for Error {
+    fn from(e: RsureError) -> Error {
+        Error::Rsure(e.to_string())
+    }
+}
+
+impl Error {
+    /// An error that is just a message.
+    pub fn msg<S: Into<String>>(msg: S) -> Error {
+        Error::Msg(msg.into())
+    }
+}
+
+/// Add context, such as the file being worked on, to errors.
+pub trait Context<T> {
+    fn context<C: Display>(self, context: C) -> Result<T>;
+}
+
+impl<T, E: Into<Error>> Context<T> for result::Result<T, E> {
+    fn context<C: Display>(self, context: C) -> Result<T> {
+        self.map_err(|e| Error::Context {
+            context: context.to_string(),
+            source: Box::new(e.into()),
+        })
+    }
+}
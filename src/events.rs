@@ -0,0 +1,138 @@
+//! A record of every action, for other programs to read.
+//!
+//! With an `events` section in the config, each run writes a file of JSON
+//! lines, one per event, under `events` in the state directory.  The events
+//! are the commands run, with their exit status, the actions of plans, such
+//! as snapshots created and pruned, with the reasons for them, the entries
+//! added to the journal, such as archives written, and the progress,
+//! decisions and warnings that are also shown.  The file starts with a
+//! `started` event, and ends with `finished`, carrying the error the run
+//! failed with, if it did.  Unlike the journal, which keeps a summary of
+//! everything ever done, each file is the full story of one run.
+
+use crate::{config::EventsConfig, logging::run_file, report::Event, Error, Result};
+use chrono::Utc;
+use serde::Serialize;
+use serde_derive::Serialize;
+use std::{fs::File, io::Write, process::ExitStatus, sync::Mutex};
+
+static EVENTS: Mutex<Option<File>> = Mutex::new(None);
+
+/// A line of the file: the event, with its details alongside.
+#[derive(Serialize)]
+struct Line<'a, T: Serialize> {
+    time: String,
+    event: &'a str,
+    #[serde(flatten)]
+    detail: &'a T,
+}
+
+#[derive(Serialize)]
+struct Started<'a> {
+    operation: &'a str,
+    args: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct Finished {
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Ran<'a> {
+    command: &'a [String],
+    /// The exit code, or none if the command was killed, or couldn't be
+    /// started.
+    code: Option<i32>,
+}
+
+#[derive(Serialize)]
+struct Message<'a> {
+    message: &'a str,
+}
+
+/// Start writing the events of this run, of `operation`.
+pub fn start_events(conf: &EventsConfig, operation: &str) -> Result<()> {
+    let file = run_file(conf.dir.as_deref(), "events", conf.keep, operation, ".jsonl")?;
+    *EVENTS.lock().unwrap() = Some(file);
+    let started = Started {
+        operation: operation,
+        args: std::env::args().collect(),
+    };
+    emit("started", &started);
+    Ok(())
+}
+
+/// Finish the events of this run, with how it ended.
+pub fn finish_events(error: Option<&Error>) {
+    let finished = Finished {
+        error: error.map(|e| e.to_string()),
+    };
+    emit("finished", &finished);
+    *EVENTS.lock().unwrap() = None;
+}
+
+/// Record an event, if events are being written.  The detail must
+/// serialize as a map, whose fields are added to the line.
+pub(crate) fn emit<T: Serialize>(event: &str, detail: &T) {
+    let mut events = EVENTS.lock().unwrap();
+    let file = match *events {
+        Some(ref mut file) => file,
+        None => return,
+    };
+    // Events that can't be written are dropped, rather than stopping the
+    // work being recorded.
+    if let Ok(line) = line(event, detail) {
+        let _ = file.write_all(line.as_bytes());
+    }
+}
+
+/// Record a command that was run.
+pub(crate) fn command(command: &[String], status: Option<ExitStatus>) {
+    let ran = Ran {
+        command: command,
+        code: status.and_then(|s| s.code()),
+    };
+    emit("command", &ran);
+}
+
+/// Record an event that was reported.  The output of queries isn't
+/// recorded, since it isn't something that was done.
+pub(crate) fn reported(event: &Event) {
+    let (name, message) = match event {
+        Event::Progress(msg) => ("progress", msg),
+        Event::Decision(msg) => ("decision", msg),
+        Event::Warning(msg) => ("warning", msg),
+        Event::Output(_) => return,
+    };
+    emit(name, &Message { message: message });
+}
+
+fn line<T: Serialize>(event: &str, detail: &T) -> Result<String> {
+    let line = Line {
+        time: Utc::now().to_rfc3339(),
+        event: event,
+        detail: detail,
+    };
+    let mut text = serde_json::to_string(&line)?;
+    text.push('\n');
+    Ok(text)
+}
+
+#[test]
+fn test_line() {
+    use crate::plan::Action;
+
+    let action = Action::Run {
+        command: vec!["zfs".into(), "snapshot".into(), "pool/home@daily-5".into()],
+        reason: "Snapshot pool/home".into(),
+    };
+    let text = line("action", &action).unwrap();
+    let value: serde_json::Value = serde_json::from_str(&text).unwrap();
+    assert!(text.ends_with("}\n"));
+    assert_eq!(value["event"], "action");
+    assert_eq!(value["action"], "run");
+    assert_eq!(value["command"][2], "pool/home@daily-5");
+    assert_eq!(value["reason"], "Snapshot pool/home");
+    assert!(value["time"].is_string());
+}
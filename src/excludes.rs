@@ -0,0 +1,62 @@
+//! Exclude patterns, shared between the backends.
+//!
+//! Sync, restic and borg volumes take the same simple patterns: a name,
+//! such as "node_modules" or "*.tmp", matches at any depth, a pattern
+//! starting with "/" matches from the root of the volume, and a trailing
+//! "/" matches only directories.  These are rsync's own patterns, so are
+//! given to rsync as they are.  Restic and borg see the volume at its bind
+//! directory, and can't tell directories apart in a pattern, so anchored
+//! patterns are put under the bind directory, and the trailing "/" dropped.
+
+/// The arguments to `restic backup` for `excludes`, of a volume backed up
+/// from `bind`.
+pub(crate) fn restic_args(excludes: &[String], bind: &str) -> Vec<String> {
+    let mut args = vec![];
+    for pattern in excludes {
+        // Restic matches a pattern that isn't absolute at any depth.
+        args.push(format!("--exclude={}", anchor(pattern, bind)));
+    }
+    args
+}
+
+/// The arguments to `borg create` for `excludes`, of a volume backed up
+/// from `bind`.
+pub(crate) fn borg_args(excludes: &[String], bind: &str) -> Vec<String> {
+    let mut args = vec![];
+    for pattern in excludes {
+        let pattern = if pattern.starts_with('/') {
+            anchor(pattern, bind)
+        } else {
+            format!("**/{}", pattern.trim_end_matches('/'))
+        };
+        args.push(format!("--exclude=sh:{}", pattern));
+    }
+    args
+}
+
+/// The pattern, without any trailing "/", under `bind` if it is anchored.
+fn anchor(pattern: &str, bind: &str) -> String {
+    let pattern = pattern.trim_end_matches('/');
+    if pattern.starts_with('/') {
+        format!("{}{}", bind.trim_end_matches('/'), pattern)
+    } else {
+        pattern.to_string()
+    }
+}
+
+#[test]
+fn test_excludes() {
+    let excludes = vec!["node_modules/".to_string(), "/var/cache/".to_string(), "*.o".into()];
+    assert_eq!(
+        restic_args(&excludes, "/mnt/home/"),
+        vec!["--exclude=node_modules", "--exclude=/mnt/home/var/cache", "--exclude=*.o"]
+    );
+    assert_eq!(
+        borg_args(&excludes, "/mnt/home"),
+        vec![
+            "--exclude=sh:**/node_modules",
+            "--exclude=sh:/mnt/home/var/cache",
+            "--exclude=sh:**/*.o",
+        ]
+    );
+}
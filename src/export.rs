@@ -0,0 +1,584 @@
+//! Export of zfs send streams to a removable drive.
+//!
+//! Each export volume writes the snapshots of a zfs filesystem as stream
+//! files under `rack/{volume}` on the drive, with a manifest recording the
+//! chain of streams and the checksum of each file.  The manifest is all
+//! `rack import` needs to receive them back, on this machine or another.
+//!
+//! Drives can be rotated, with one attached while the others are kept
+//! offsite.  The configured targets are found by their pool GUID or
+//! filesystem label, wherever they are mounted, and the local catalog
+//! records which snapshots are on each, so that `rack status` can tell
+//! when one that is away hasn't been brought up to date for too long.
+
+use crate::{
+    catalog::{Catalog, Stream},
+    checked::{run_pipeline, CheckedExt},
+    config::{configured, skipped, Config, ConfigError, ExportTarget, ExportVolume},
+    digest, journal,
+    send::{self, command, Encryption, StreamError, StreamFormat},
+    zfs::{Filesystem, Zfs},
+    Result,
+};
+use chrono::{DateTime, Duration, Utc};
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    process::Command,
+};
+use thiserror::Error;
+
+/// Errors from exports.
+#[derive(Debug, Error)]
+pub enum ExportError {
+    #[error("{target:?} is not a mounted drive")]
+    NotMounted { target: String },
+    #[error("Export drive {name:?} is not attached")]
+    NotAttached { name: String },
+    #[error("No export drive is attached")]
+    NoTarget,
+    #[error("Several export drives are attached ({names}), choose one with --target")]
+    Ambiguous { names: String },
+}
+
+/// The name of the manifest kept with each volume's streams.
+const MANIFEST: &str = "manifest.json";
+
+/// How long a drive can go without being written before it is stale.
+const STALE_DAYS: u32 = 30;
+
+impl Config {
+    /// Write the new snapshots of the export volumes to a drive.  If `name`
+    /// is given, only that volume is exported.  See `find_target` for how
+    /// the drive is chosen.
+    pub fn run_export(
+        &self,
+        target: Option<&str>,
+        name: Option<&str>,
+        pretend: bool,
+    ) -> Result<()> {
+        configured("export", &self.export.volumes)?;
+        let (disk, path) = self.find_target(target)?;
+        check_mounted(&path)?;
+        let zfs = Zfs::from_inventory("none", &self.inventory)?;
+        let catalog_path = Catalog::default_path()?;
+        let mut catalog = Catalog::load(&catalog_path)?;
+
+        for vol in &self.export.volumes {
+            match name {
+                None => (),
+                Some(given) if given == vol.name => (),
+                _ => continue,
+            }
+            if skipped("export", &vol.name, vol.skip) {
+                continue;
+            }
+
+            let fs = zfs.find(&vol.zfs)?;
+            let (manifest, stream) = vol.export(fs, &disk, &path, pretend)?;
+            if let Some(stream) = stream {
+                journal::record("export", &vol.name, &stream)?;
+            }
+
+            // The drive's manifest is what is really on it, even if it was
+            // written from elsewhere, or the catalog was lost.
+            catalog.streams.retain(|s| s.target != disk || s.zfs != vol.zfs);
+            catalog.streams.extend(manifest.streams.into_iter().map(|mut s| {
+                s.target = disk.clone();
+                s
+            }));
+        }
+
+        if !pretend {
+            catalog.save(&catalog_path)?;
+        }
+        Ok(())
+    }
+
+    /// Receive the export volume `name` from a drive into the zfs filesystem
+    /// `dest`, which must not exist yet, replaying its streams up to `snap`,
+    /// or to the latest snapshot exported.
+    pub fn import(
+        &self,
+        target: Option<&str>,
+        name: &str,
+        dest: &str,
+        snap: Option<&str>,
+        pretend: bool,
+    ) -> Result<()> {
+        let vol = self
+            .export
+            .volumes
+            .iter()
+            .find(|v| v.name == name)
+            .ok_or_else(|| ConfigError::NoVolume {
+                section: "export",
+                name: name.to_string(),
+            })?;
+        let (_, path) = self.find_target(target)?;
+        vol.import(&path, dest, snap, pretend)?;
+        self.inventory.invalidate();
+        Ok(())
+    }
+
+    /// Find the drive to use, returning its name and where it is mounted.
+    /// `target` is the name of a configured drive, or the path of one that
+    /// isn't configured, which is named by its path.  If not given, the one
+    /// configured drive that is attached is used.
+    pub(crate) fn find_target(&self, target: Option<&str>) -> Result<(String, PathBuf)> {
+        let attached = self.attached()?;
+        match target {
+            Some(given) => {
+                if let Some(t) = self.export.targets.iter().find(|t| t.name == given) {
+                    return attached
+                        .into_iter()
+                        .find(|(name, _)| name == &t.name)
+                        .ok_or_else(|| ExportError::NotAttached { name: t.name.clone() }.into());
+                }
+                let path = PathBuf::from(given);
+                let name = attached
+                    .into_iter()
+                    .find(|(_, p)| p == &path)
+                    .map_or_else(|| given.to_string(), |(name, _)| name);
+                Ok((name, path))
+            }
+            None => match attached.len() {
+                0 => Err(ExportError::NoTarget.into()),
+                1 => Ok(attached.into_iter().next().unwrap()),
+                _ => {
+                    let names: Vec<_> = attached.into_iter().map(|(name, _)| name).collect();
+                    Err(ExportError::Ambiguous { names: names.join(", ") }.into())
+                }
+            },
+        }
+    }
+
+    /// The configured drives that are attached, with where they are mounted.
+    fn attached(&self) -> Result<Vec<(String, PathBuf)>> {
+        if self.export.targets.is_empty() {
+            return Ok(vec![]);
+        }
+        let mounts = mounts()?;
+        let pools = if self.export.targets.iter().any(|t| t.guid.is_some()) {
+            pools()?
+        } else {
+            HashMap::new()
+        };
+        Ok(self
+            .export
+            .targets
+            .iter()
+            .filter_map(|t| t.mounted(&mounts, &pools).map(|p| (t.name.clone(), p.to_path_buf())))
+            .collect())
+    }
+
+    /// Report what is on each configured drive, and warn about any that
+    /// haven't been written for too long.
+    pub fn export_status(&self) -> Result<()> {
+        let catalog = Catalog::load(&Catalog::default_path()?)?;
+        let attached = self.attached()?;
+        let days = self.export.stale_days.unwrap_or(STALE_DAYS);
+        for t in &self.export.targets {
+            match attached.iter().find(|(name, _)| name == &t.name) {
+                Some((_, path)) => output!("{}: attached at {}", t.name, path.display()),
+                None => output!("{}: offsite", t.name),
+            }
+            for vol in &self.export.volumes {
+                match catalog.streams(&t.name, &vol.zfs).last() {
+                    Some(s) => output!("  {}: @{}, written {}", vol.name, s.to, s.time),
+                    None => output!("  {}: never written", vol.name),
+                }
+            }
+            if let Some(msg) = staleness(&catalog, t, &self.export.volumes, Utc::now(), days) {
+                warning!("{}", msg);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Why the drive is stale, if its oldest volume was last written more than
+/// `days` days before `now`.
+fn staleness(
+    catalog: &Catalog,
+    target: &ExportTarget,
+    volumes: &[ExportVolume],
+    now: DateTime<Utc>,
+    days: u32,
+) -> Option<String> {
+    let mut oldest = None;
+    for vol in volumes {
+        let time = catalog
+            .streams(&target.name, &vol.zfs)
+            .last()
+            .and_then(|s| DateTime::parse_from_rfc3339(&s.time).ok());
+        match time {
+            Some(time) => {
+                let time = time.with_timezone(&Utc);
+                if oldest.map_or(true, |o| time < o) {
+                    oldest = Some(time);
+                }
+            }
+            None => return Some(format!("{}: {} has never been written", target.name, vol.name)),
+        }
+    }
+    let age = now - oldest?;
+    if age > Duration::days(days.into()) {
+        return Some(format!(
+            "{}: last written {} days ago, it is time to rotate it",
+            target.name,
+            age.num_days()
+        ));
+    }
+    None
+}
+
+/// A mounted filesystem.
+struct Mount {
+    target: PathBuf,
+    source: String,
+    label: String,
+}
+
+/// The mounted filesystems, as listed by findmnt.
+fn mounts() -> Result<Vec<Mount>> {
+    let out = Command::new("findmnt").args(&["-rn", "-o", "TARGET,SOURCE,LABEL"]).checked_output()?;
+    let out = String::from_utf8(out.stdout)?;
+    Ok(out
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split(' ').map(unescape);
+            Some(Mount {
+                target: PathBuf::from(fields.next()?),
+                source: fields.next()?,
+                label: fields.next().unwrap_or_default(),
+            })
+        })
+        .collect())
+}
+
+/// Undo the `\xHH` escapes of findmnt's raw output.
+fn unescape(field: &str) -> String {
+    let mut result = vec![];
+    let bytes = field.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' && bytes.get(i + 1) == Some(&b'x') && i + 4 <= bytes.len() {
+            if let Ok(b) = u8::from_str_radix(&field[i + 2..i + 4], 16) {
+                result.push(b);
+                i += 4;
+                continue;
+            }
+        }
+        result.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&result).into_owned()
+}
+
+/// The names of the imported zfs pools, by GUID.
+fn pools() -> Result<HashMap<String, String>> {
+    let out = Command::new("zpool").args(&["list", "-H", "-o", "guid,name"]).checked_output()?;
+    let out = String::from_utf8(out.stdout)?;
+    Ok(out
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split('\t');
+            Some((fields.next()?.to_string(), fields.next()?.to_string()))
+        })
+        .collect())
+}
+
+impl ExportTarget {
+    /// Where the drive is mounted, if it is attached.
+    fn mounted<'a>(
+        &self,
+        mounts: &'a [Mount],
+        pools: &HashMap<String, String>,
+    ) -> Option<&'a Path> {
+        let mount = match (&self.guid, &self.label) {
+            (Some(guid), _) => {
+                let pool = pools.get(guid)?;
+                mounts.iter().find(|m| &m.source == pool)
+            }
+            (None, Some(label)) => mounts.iter().find(|m| &m.label == label),
+            (None, None) => None,
+        };
+        mount.map(|m| m.target.as_path())
+    }
+}
+
+/// Make sure that `target` is the root of a mounted filesystem, so that
+/// streams meant for a drive that isn't plugged in don't fill up the
+/// directory it would be mounted on.
+fn check_mounted(target: &Path) -> Result<()> {
+    let meta = fs::metadata(target)?;
+    let parent = fs::metadata(target.join(".."))?;
+    if meta.dev() == parent.dev() && meta.ino() != parent.ino() {
+        return Err(ExportError::NotMounted { target: target.display().to_string() }.into());
+    }
+    Ok(())
+}
+
+/// The SHA-256 of a file, as hex.
+fn sha256(path: &Path) -> Result<String> {
+    let out = Command::new("sha256sum").arg(path).checked_output()?;
+    let out = String::from_utf8(out.stdout)?;
+    Ok(out.split_whitespace().next().unwrap_or("").to_string())
+}
+
+impl ExportVolume {
+    /// How this volume's streams are written.
+    fn format(&self) -> StreamFormat {
+        StreamFormat {
+            compression: self.compression.unwrap_or_default(),
+            encryption: Encryption::new(&self.encrypt, &self.decrypt, &self.age),
+            full_every: self.full_every,
+        }
+    }
+
+    /// The directory on the drive holding this volume's streams.
+    fn dir(&self, target: &Path) -> PathBuf {
+        target.join("rack").join(&self.name)
+    }
+
+    /// The manifest of this volume on the drive mounted at `target`.
+    pub(crate) fn manifest(&self, target: &Path) -> Result<Catalog> {
+        Catalog::load(&self.dir(target).join(MANIFEST))
+    }
+
+    /// Write a stream bringing the drive `disk`, mounted at `target`, up to
+    /// the latest snapshot, and record it in the drive's manifest.  Returns
+    /// the manifest, and the stream written, if there was anything to write.
+    fn export(
+        &self,
+        fs: &Filesystem,
+        disk: &str,
+        target: &Path,
+        pretend: bool,
+    ) -> Result<(Catalog, Option<Stream>)> {
+        let dir = self.dir(target);
+        let manifest_path = dir.join(MANIFEST);
+        let mut manifest = Catalog::load(&manifest_path)?;
+        let format = self.format();
+        let streams: Vec<_> = manifest.streams.iter().collect();
+        let next = match format.next(&self.name, &streams, fs) {
+            Some(next) => next,
+            None => return Ok((manifest, None)),
+        };
+        let object = format.object(&next);
+        let path = dir.join(&object);
+
+        match next.from {
+            Some(from) => decision!(
+                "{}: write {}@{} from @{} to {}",
+                self.name,
+                fs.name,
+                next.to,
+                from,
+                path.display()
+            ),
+            None => decision!(
+                "{}: write all of {}@{} to {}",
+                self.name,
+                fs.name,
+                next.to,
+                path.display()
+            ),
+        }
+        if pretend {
+            return Ok((manifest, None));
+        }
+
+        // The stream is written under a temporary name, so that one cut
+        // short, such as by pulling the drive, is never mistaken for a
+        // complete one.
+        fs::create_dir_all(&dir)?;
+        let part = dir.join(format!("{}.part", object));
+        let mut cmds = format.sender(&fs.name, &next);
+        if let Some(last) = cmds.last_mut() {
+            last.stdout(File::create(&part)?);
+        }
+        run_pipeline(&mut cmds)?;
+        Command::new("sync").arg(&part).checked_run()?;
+
+        // The checksum is of the file as read back from the drive.
+        let mut stream = format.record(disk, &self.zfs, object, &next);
+        stream.sha256 = Some(sha256(&part)?);
+        digest::bytes(fs::metadata(&part)?.len());
+        fs::rename(&part, &path)?;
+
+        manifest.streams.push(stream.clone());
+        manifest.save(&manifest_path)?;
+        Command::new("sync").arg(&manifest_path).checked_run()?;
+        Ok((manifest, Some(stream)))
+    }
+
+    /// Replay the chain of streams up to `snap`, or the latest, into `dest`,
+    /// after checking that none of them are damaged.
+    pub(crate) fn import(
+        &self,
+        target: &Path,
+        dest: &str,
+        snap: Option<&str>,
+        pretend: bool,
+    ) -> Result<()> {
+        let dir = self.dir(target);
+        let manifest = self.manifest(target)?;
+        let chain = send::chain(&self.name, &manifest.streams, snap)?;
+
+        for stream in &chain {
+            let path = dir.join(&stream.object);
+            if let Some(ref expect) = stream.sha256 {
+                if &sha256(&path)? != expect {
+                    let file = path.display().to_string();
+                    return Err(StreamError::Checksum { file: file }.into());
+                }
+            }
+        }
+
+        let fetch = |stream: &Stream| {
+            let path = dir.join(&stream.object);
+            command(&["cat", &path.display().to_string()], false)
+        };
+        self.format().replay(&self.name, &chain, dest, &fetch, pretend)
+    }
+}
+
+#[test]
+fn test_export() {
+    use crate::checked::{set_executor, RecordingExecutor};
+    use crate::verify::ScratchDir;
+    use crate::zfs::Inventory;
+    use std::sync::Arc;
+
+    let target = ScratchDir::new("export").unwrap();
+    let vol = ExportVolume {
+        name: "home".into(),
+        skip: None,
+        zfs: "pool/home".into(),
+        compression: Some(crate::config::Compression::Gzip),
+        encrypt: None,
+        decrypt: None,
+        age: None,
+        full_every: None,
+    };
+    let list = |snaps: &[&str]| {
+        let mut list = "pool/home\tfilesystem\tyes\t-\t-\t0\t0\t0\t/home\n".to_string();
+        for snap in snaps {
+            list.push_str(&format!("pool/home@{}\tsnapshot\t-\t0\t\t0\t0\t0\t-\n", snap));
+        }
+        list
+    };
+
+    let exec = Arc::new(RecordingExecutor::new());
+    let running = set_executor(exec.clone());
+    exec.respond(&["sha256sum"], "0123abcd  file\n");
+    let export = |snaps: &[&str]| {
+        exec.respond(&["zfs", "list"], &list(snaps));
+        let zfs = Zfs::from_inventory("none", &Inventory::new()).unwrap();
+        let fs = zfs.find("pool/home").unwrap();
+        vol.export(fs, "blue", &target.0, false).unwrap().1.map(|s| s.object)
+    };
+    assert_eq!(export(&["a"]), Some("a.full.zfs.gz".to_string()));
+    assert_eq!(export(&["a", "b"]), Some("a_b.zfs.gz".to_string()));
+    assert_eq!(export(&["a", "b"]), None);
+    vol.import(&target.0, "pool/restored", None, false).unwrap();
+
+    // A damaged stream stops the import before anything is received.
+    exec.respond(&["sha256sum"], "ffff  file\n");
+    assert!(vol.import(&target.0, "pool/other", None, false).is_err());
+    drop(running);
+
+    let dir = target.0.join("rack/home");
+    let manifest = Catalog::load(&dir.join(MANIFEST)).unwrap();
+    let chain: Vec<_> =
+        manifest.streams.iter().map(|s| (s.from.as_deref(), s.to.as_str())).collect();
+    assert_eq!(chain, vec![(None, "a"), (Some("a"), "b")]);
+    assert!(manifest.streams.iter().all(|s| s.sha256.as_deref() == Some("0123abcd")));
+    assert!(manifest.streams.iter().all(|s| s.target == "blue"));
+    assert!(dir.join("a_b.zfs.gz").exists());
+
+    let commands: Vec<_> = exec
+        .commands()
+        .into_iter()
+        .filter(|c| !["zfs list", "sha256sum", "sync"].iter().any(|p| c.starts_with(p)))
+        .collect();
+    let file = |object: &str| dir.join(object).display().to_string();
+    assert_eq!(
+        commands,
+        vec![
+            "zfs send pool/home@a | gzip".to_string(),
+            "zfs send -I @a pool/home@b | gzip".to_string(),
+            format!("cat {} | gzip -d | zfs receive -u pool/restored", file("a.full.zfs.gz")),
+            format!("cat {} | gzip -d | zfs receive -u -F pool/restored", file("a_b.zfs.gz")),
+        ]
+    );
+}
+
+#[test]
+fn test_rotation() {
+    use chrono::TimeZone;
+
+    let mount = |target: &str, source: &str, label: &str| Mount {
+        target: PathBuf::from(target),
+        source: source.into(),
+        label: label.into(),
+    };
+    let mounts = vec![
+        mount("/", "/dev/sda2", "root"),
+        mount("/media/red disk", "/dev/sdc1", "rack-red"),
+        mount("/backup/blue", "blue", ""),
+    ];
+    let pools: HashMap<_, _> = vec![("1234".to_string(), "blue".to_string())].into_iter().collect();
+    let target = |name: &str, guid: Option<&str>, label: Option<&str>| ExportTarget {
+        name: name.into(),
+        guid: guid.map(|g| g.into()),
+        label: label.map(|l| l.into()),
+    };
+    let blue = target("blue", Some("1234"), None);
+    let red = target("red", None, Some("rack-red"));
+    let green = target("green", None, Some("rack-green"));
+    assert_eq!(blue.mounted(&mounts, &pools), Some(Path::new("/backup/blue")));
+    assert_eq!(red.mounted(&mounts, &pools), Some(Path::new("/media/red disk")));
+    assert_eq!(green.mounted(&mounts, &pools), None);
+    assert_eq!(unescape("/media/red\\x20disk"), "/media/red disk");
+
+    let vol = |name: &str| ExportVolume {
+        name: name.into(),
+        skip: None,
+        zfs: format!("pool/{}", name),
+        compression: None,
+        encrypt: None,
+        decrypt: None,
+        age: None,
+        full_every: None,
+    };
+    let volumes = vec![vol("home"), vol("root")];
+    let stream = |zfs: &str, time: &str| Stream {
+        target: "red".into(),
+        zfs: zfs.into(),
+        object: String::new(),
+        from: None,
+        to: "a".into(),
+        compression: Default::default(),
+        encrypted: false,
+        sha256: None,
+        time: time.into(),
+    };
+    let mut catalog = Catalog::default();
+    catalog.streams.push(stream("pool/home", "2026-09-01T00:00:00+00:00"));
+    let now = Utc.with_ymd_and_hms(2026, 10, 16, 0, 0, 0).unwrap();
+    assert_eq!(
+        staleness(&catalog, &red, &volumes, now, 30),
+        Some("red: root has never been written".to_string())
+    );
+    catalog.streams.push(stream("pool/root", "2026-10-01T00:00:00+00:00"));
+    assert_eq!(
+        staleness(&catalog, &red, &volumes, now, 30),
+        Some("red: last written 45 days ago, it is time to rotate it".to_string())
+    );
+    assert_eq!(staleness(&catalog, &red, &volumes, now, 60), None);
+}
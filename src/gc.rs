@@ -0,0 +1,224 @@
+//! Cleanup after crashed runs.
+//!
+//! A rack run that dies part way through can leave snapshots bind mounted,
+//! and LVM snapshots mounted and active.  The next run would then fail,
+//! since the mountpoints aren't empty.  This finds and tears these down.
+//! Only snapshots are unmounted: a zfs snapshot, a btrfs snapshot rack
+//! made, or an LVM snapshot rack named, so that a filesystem someone else
+//! mounted on one of rack's directories is left alone.
+
+use crate::{
+    checked::CheckedExt,
+    config::{Config, SyncKind},
+    lvm,
+    mount::{self, MountEntry},
+    runlock::{self, RunLock},
+    Result, HOME_BIND_DIR, ROOT_BIND_DIR,
+};
+use std::{
+    collections::BTreeSet,
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+impl Config {
+    /// Tear down anything left behind by a previous run.  Nothing is done
+    /// if another rack is running, since what it has mounted is still in
+    /// use.  With `pretend`, just show what would be done.
+    pub fn gc(&self, pretend: bool) -> Result<()> {
+        self.lock_and_clean(pretend)?;
+        Ok(())
+    }
+
+    /// Clean up before starting work.  The run lock is taken, and returned
+    /// to be held while the work is done, so that another rack doesn't
+    /// mistake what is mounted for something left behind.  Taking the lock,
+    /// the pools are checked for being too full.
+    pub fn preflight(&self) -> Result<Option<RunLock>> {
+        let lock = self.lock_and_clean(false)?;
+        if lock.is_some() {
+            self.check_pools();
+        }
+        Ok(lock)
+    }
+
+    fn lock_and_clean(&self, pretend: bool) -> Result<Option<RunLock>> {
+        if runlock::held_by_us() {
+            self.clean(pretend)?;
+            return Ok(None);
+        }
+        match RunLock::try_acquire()? {
+            Some(lock) => {
+                self.clean(pretend)?;
+                Ok(Some(lock))
+            }
+            None => {
+                progress!("Another rack is running, not cleaning up");
+                Ok(None)
+            }
+        }
+    }
+
+    fn clean(&self, pretend: bool) -> Result<()> {
+        // A snapshot mounted at a directory rack mounts on must be left over.
+        let ours = self.mountpoints();
+        let mut lvm_snaps = None;
+        for m in mount::mounts()?.into_iter().rev() {
+            if !ours.contains(&m.mountpoint) || !rack_snapshot(&m, &mut lvm_snaps)? {
+                continue;
+            }
+            if pretend {
+                decision!("would unmount {:?} from {:?}", m.mountpoint, m.source);
+            } else {
+                progress!("Unmounting stale {:?} from {:?}", m.mountpoint, m.source);
+                mount::unmount(Path::new(&m.mountpoint), false)?;
+            }
+        }
+
+        // Sync snapshots are only active while they are being synced from.
+        // Only thin snapshots named by rack are looked at: a classic
+        // snapshot is active whenever its origin is, and can't be
+        // deactivated on its own, and others belong to someone else.
+        for vol in &self.sync.volumes {
+            if vol.kind != SyncKind::Lvm {
+                continue;
+            }
+            let (vg, lv) = match (&vol.vg, &vol.lv) {
+                (Some(vg), Some(lv)) => (vg, lv),
+                _ => continue,
+            };
+            for rec in lvm::report()? {
+                let active = rec.lv_attr.as_bytes().get(4) == Some(&b'a');
+                if rec.vg_name != *vg || rec.origin != *lv || !active {
+                    continue;
+                }
+                if !rec.is_thin() || !lvm::made_by_rack(lv, &rec.lv_name) {
+                    continue;
+                }
+                let name = format!("{}/{}", vg, rec.lv_name);
+                if pretend {
+                    decision!("would deactivate {}", name);
+                    continue;
+                }
+
+                // It may still be mounted somewhere other than where rack
+                // put it.
+                let dev = fs::canonicalize(format!("/dev/{}", name))?;
+                for m in mount::mounts()? {
+                    if fs::canonicalize(&m.source).ok().as_ref() == Some(&dev) {
+                        progress!("Unmounting stale {:?} from {}", m.mountpoint, name);
+                        mount::unmount(Path::new(&m.mountpoint), false)?;
+                    }
+                }
+                progress!("Deactivating stale {}", name);
+                let deactivate = Command::new("lvchange").args(&["-an", "-K", &name]).checked_run();
+                if let Err(e) = deactivate {
+                    warning!("Unable to deactivate stale {}: {}", name, e);
+                }
+            }
+        }
+
+        // Btrfs snapshots are deleted once the sync is done.
+        for vol in &self.sync.volumes {
+            let subvolume = match (&vol.kind, &vol.subvolume) {
+                (SyncKind::Btrfs, Some(subvolume)) => subvolume,
+                _ => continue,
+            };
+            for ent in fs::read_dir(subvolume)? {
+                let path = ent?.path();
+                let stale = path
+                    .file_name()
+                    .map_or(false, |n| n.to_string_lossy().starts_with(".rack-snap-"));
+                if !stale {
+                    continue;
+                }
+                if pretend {
+                    decision!("would delete btrfs snapshot {:?}", path);
+                } else {
+                    progress!("Deleting stale btrfs snapshot {:?}", path);
+                    Command::new("btrfs")
+                        .args(&["subvolume", "delete"])
+                        .arg(&path)
+                        .checked_run()?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// All of the directories rack mounts things on.
+    fn mountpoints(&self) -> BTreeSet<String> {
+        let mut dirs = BTreeSet::new();
+        dirs.insert(ROOT_BIND_DIR.to_string());
+        dirs.insert(HOME_BIND_DIR.to_string());
+        dirs.extend(self.sync.volumes.iter().map(|v| v.mountpoint.clone()));
+        dirs.extend(self.sure.volumes.iter().map(|v| v.bind.clone()));
+        dirs.extend(self.restic.volumes.iter().map(|v| v.bind.clone()));
+        dirs.extend(self.borg.volumes.iter().map(|v| v.bind.clone()));
+        dirs.into_iter()
+            .map(|d| d.trim_end_matches('/').to_string())
+            .collect()
+    }
+}
+
+/// Whether `m` is a mount of a snapshot rack makes.  The devices of the LVM
+/// snapshots named by rack are only looked for, and kept in `lvm_snaps`, once
+/// a mount of a device is seen, so that hosts without LVM don't need it.
+fn rack_snapshot(m: &MountEntry, lvm_snaps: &mut Option<BTreeSet<PathBuf>>) -> Result<bool> {
+    match m.fstype.as_str() {
+        "zfs" => return Ok(m.source.contains('@')),
+        "btrfs" => {
+            let name = Path::new(&m.root).file_name();
+            return Ok(name.map_or(false, |n| n.to_string_lossy().starts_with(".rack-snap-")));
+        }
+        _ => (),
+    }
+    if !m.source.starts_with("/dev/") {
+        return Ok(false);
+    }
+    if lvm_snaps.is_none() {
+        let mut devs = BTreeSet::new();
+        for rec in lvm::report()? {
+            if rec.origin.is_empty() || !lvm::made_by_rack(&rec.origin, &rec.lv_name) {
+                continue;
+            }
+            if let Ok(dev) = fs::canonicalize(format!("/dev/{}/{}", rec.vg_name, rec.lv_name)) {
+                devs.insert(dev);
+            }
+        }
+        *lvm_snaps = Some(devs);
+    }
+    let dev = fs::canonicalize(&m.source).ok();
+    Ok(dev.map_or(false, |dev| lvm_snaps.iter().any(|snaps| snaps.contains(&dev))))
+}
+
+#[test]
+fn test_rack_snapshot() {
+    use crate::checked::{set_executor, RecordingExecutor};
+    use std::sync::Arc;
+
+    let exec = Arc::new(RecordingExecutor::new());
+    exec.respond(&["lvs"], r#"{"report": [{"lv": []}]}"#);
+    let running = set_executor(exec.clone());
+
+    let entry = |root: &str, fstype: &str, source: &str| MountEntry {
+        root: root.to_string(),
+        mountpoint: "/mnt/home".to_string(),
+        fstype: fstype.to_string(),
+        source: source.to_string(),
+    };
+    let mut lvm_snaps = None;
+    let mut check = |m: MountEntry| rack_snapshot(&m, &mut lvm_snaps).unwrap();
+    assert!(check(entry("/", "zfs", "tank/home@caz.2024-06-01")));
+    assert!(!check(entry("/", "zfs", "tank/home")));
+    assert!(check(entry("/home/.rack-snap-20240601030000", "btrfs", "/dev/sda2")));
+    assert!(!check(entry("/home", "btrfs", "/dev/sda2")));
+    assert!(!check(entry("/", "tmpfs", "tmpfs")));
+    assert!(!check(entry("/", "ext4", "/dev/sdb1")));
+    drop(running);
+
+    // The LVM snapshots are only listed once, and only for a device.
+    assert_eq!(exec.commands().len(), 1);
+}
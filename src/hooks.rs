@@ -0,0 +1,117 @@
+//! Commands run before and after the work on a volume.
+//!
+//! Snapshot, sync, restic and borg volumes can each be given a `pre_hook`
+//! and a `post_hook`, lists of commands run with `sh -c`.  For a snapshot,
+//! or the LVM or btrfs snapshot of a sync, they are run just before and
+//! after the snapshot is taken, so that an application can flush its state,
+//! or a service be stopped, and started again as soon as the snapshot
+//! exists.  For restic and borg, they are run around the backup of the
+//! volume, such as to wake a disk the repository is on.  The post hooks are
+//! run even when the work, or a pre hook, fails, since they are usually
+//! undoing what the pre hooks did.  A hook that fails stops the work on the
+//! volume, unless the volume's `hook_failure` is "warn".
+
+use crate::{checked::CheckedExt, config::HookFailure, plan::Plan, Context, Result};
+use std::process::Command;
+
+/// The hooks of a volume.
+pub(crate) struct Hooks<'a> {
+    pre: &'a [String],
+    post: &'a [String],
+    failure: HookFailure,
+}
+
+impl<'a> Hooks<'a> {
+    pub fn new(pre: &'a [String], post: &'a [String], failure: Option<HookFailure>) -> Hooks<'a> {
+        Hooks {
+            pre: pre,
+            post: post,
+            failure: failure.unwrap_or(HookFailure::Fatal),
+        }
+    }
+
+    /// Run `op`, the work on the volume `what`, between the hooks.
+    pub fn around<T, F>(&self, what: &str, op: F) -> Result<T>
+    where
+        F: FnOnce() -> Result<T>,
+    {
+        let result = self.run("Pre hook", what, self.pre).and_then(|()| op());
+        let post = self.run("Post hook", what, self.post);
+        let value = result?;
+        post?;
+        Ok(value)
+    }
+
+    /// Add the hooks to `plan`, around the actions added by `op`.  A fatal
+    /// hook that fails stops the rest of the plan, as any command does.
+    pub fn plan_around<F>(&self, what: &str, plan: &mut Plan, op: F)
+    where
+        F: FnOnce(&mut Plan),
+    {
+        self.plan("Pre hook", what, self.pre, plan);
+        op(plan);
+        self.plan("Post hook", what, self.post, plan);
+    }
+
+    fn run(&self, kind: &str, what: &str, commands: &[String]) -> Result<()> {
+        for command in commands {
+            progress!("{} of {:?}: {}", kind, what, command);
+            let result = shell(command)
+                .checked_run()
+                .context(format!("{} of {:?}", kind, what));
+            match (result, self.failure) {
+                (Ok(()), _) => (),
+                (Err(e), HookFailure::Warn) => warning!("{}", e),
+                (Err(e), HookFailure::Fatal) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    fn plan(&self, kind: &str, what: &str, commands: &[String], plan: &mut Plan) {
+        for command in commands {
+            let reason = format!("{} of {:?}", kind, what);
+            match self.failure {
+                HookFailure::Fatal => plan.run(reason, &shell(command)),
+                HookFailure::Warn => plan.try_run(reason, &shell(command)),
+            }
+        }
+    }
+}
+
+fn shell(command: &str) -> Command {
+    let mut cmd = Command::new("sh");
+    cmd.args(&["-c", command]);
+    cmd
+}
+
+#[test]
+fn test_hooks() {
+    use crate::{
+        checked::{set_executor, RecordingExecutor},
+        Error,
+    };
+    use std::sync::Arc;
+
+    let pre = vec!["systemctl stop db".to_string()];
+    let post = vec!["systemctl start db".to_string()];
+    let exec = Arc::new(RecordingExecutor::new());
+    let running = set_executor(exec.clone());
+    let hooks = Hooks::new(&pre, &post, None);
+    let failed: Result<()> = hooks.around("db", || Err(Error::msg("snapshot failed")));
+    drop(running);
+
+    // The service is started again, even though the work failed.
+    assert!(failed.is_err());
+    assert_eq!(exec.commands(), vec!["sh -c systemctl stop db", "sh -c systemctl start db"]);
+
+    let mut plan = Plan::new("snap");
+    let warn = Hooks::new(&pre, &post, Some(HookFailure::Warn));
+    warn.plan_around("db", &mut plan, |plan| plan.run("Snapshot".into(), &shell("true")));
+    let kinds: Vec<_> = plan
+        .actions
+        .iter()
+        .map(|a| serde_json::to_value(a).unwrap()["action"].as_str().unwrap().to_string())
+        .collect();
+    assert_eq!(kinds, vec!["try", "run", "try"]);
+}
@@ -0,0 +1,181 @@
+//! Running independent work at the same time.
+//!
+//! Each job names the resources it uses, such as a bind directory or a
+//! repository, which can only be used by one job at a time.  Jobs are run by
+//! a small pool of workers, taking the first job whose resources are free,
+//! so that two jobs sharing a resource are always run in the order they were
+//! added, while jobs that share nothing run alongside each other.  Once a job
+//! fails, later jobs sharing any of its resources are skipped, since a
+//! repository that is locked, or a directory left mounted, would just make
+//! them fail as well.
+
+use crate::{Error, Result};
+use std::{
+    sync::{Condvar, Mutex},
+    thread,
+};
+
+type Work<'a> = Box<dyn FnOnce() -> Result<()> + Send + 'a>;
+
+struct Job<'a> {
+    name: String,
+    uses: Vec<String>,
+    work: Work<'a>,
+}
+
+/// The jobs to run.
+#[derive(Default)]
+pub struct Jobs<'a> {
+    pending: Vec<Job<'a>>,
+}
+
+impl<'a> Jobs<'a> {
+    pub fn new() -> Jobs<'a> {
+        Jobs::default()
+    }
+
+    /// Add a job, using the given resources, such as `"repo:/backup/home"`.
+    pub fn add<F>(&mut self, name: String, mut uses: Vec<String>, work: F)
+    where
+        F: FnOnce() -> Result<()> + Send + 'a,
+    {
+        uses.sort();
+        uses.dedup();
+        self.pending.push(Job {
+            name: name,
+            uses: uses,
+            work: Box::new(work),
+        });
+    }
+
+    /// Run the jobs, at most `parallel` at a time.  Each failure is warned
+    /// about as it happens, and the first is returned once every job that
+    /// can be run has been.  With a single worker, the jobs are run in this
+    /// thread, in order.
+    pub fn run(self, parallel: usize) -> Result<()> {
+        let workers = parallel.max(1).min(self.pending.len());
+        if workers > 1 {
+            progress!("Running {} jobs, {} at a time", self.pending.len(), workers);
+        }
+        let queue = Mutex::new(Queue {
+            pending: self.pending,
+            busy: vec![],
+            failed: vec![],
+            errors: vec![],
+        });
+        let ready = Condvar::new();
+
+        if workers <= 1 {
+            work(&queue, &ready);
+        } else {
+            thread::scope(|s| {
+                for _ in 0..workers {
+                    s.spawn(|| work(&queue, &ready));
+                }
+            });
+        }
+
+        match queue.into_inner().unwrap().errors.into_iter().next() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+struct Queue<'a> {
+    pending: Vec<Job<'a>>,
+    /// The resources used by running jobs.
+    busy: Vec<String>,
+    /// The resources used by jobs that failed.
+    failed: Vec<String>,
+    errors: Vec<Error>,
+}
+
+impl<'a> Queue<'a> {
+    /// Take the first job that can be run now, marking its resources busy.
+    /// A job can't run while one of its resources is busy, nor ahead of an
+    /// earlier job waiting for the same resource.
+    fn take(&mut self) -> Option<Job<'a>> {
+        let failed = &self.failed;
+        self.pending.retain(|job| match job.uses.iter().find(|r| failed.contains(r)) {
+            Some(r) => {
+                warning!("Skipping {}, after a failure using {}", job.name, r);
+                false
+            }
+            None => true,
+        });
+
+        let mut waiting: Vec<&String> = vec![];
+        let mut found = None;
+        for (i, job) in self.pending.iter().enumerate() {
+            if job.uses.iter().all(|r| !self.busy.contains(r) && !waiting.contains(&r)) {
+                found = Some(i);
+                break;
+            }
+            waiting.extend(&job.uses);
+        }
+
+        let job = self.pending.remove(found?);
+        self.busy.extend(job.uses.iter().cloned());
+        Some(job)
+    }
+
+    fn finish(&mut self, name: &str, uses: Vec<String>, result: Result<()>) {
+        self.busy.retain(|r| !uses.contains(r));
+        if let Err(e) = result {
+            warning!("{}: {}", name, e);
+            self.failed.extend(uses);
+            self.errors.push(e);
+        }
+    }
+}
+
+/// A worker, running jobs until there are none left.
+fn work(queue: &Mutex<Queue>, ready: &Condvar) {
+    let mut q = queue.lock().unwrap();
+    loop {
+        match q.take() {
+            Some(job) => {
+                drop(q);
+                let result = (job.work)();
+                q = queue.lock().unwrap();
+                q.finish(&job.name, job.uses, result);
+                ready.notify_all();
+            }
+            None if q.pending.is_empty() => break,
+            None => q = ready.wait(q).unwrap(),
+        }
+    }
+}
+
+#[test]
+fn test_jobs() {
+    use std::{sync::atomic::AtomicUsize, sync::atomic::Ordering, time::Duration};
+
+    let log = Mutex::new(vec![]);
+    let on_x = AtomicUsize::new(0);
+    let mut jobs = Jobs::new();
+    for name in ["a", "b", "c", "d"] {
+        let (log, on_x) = (&log, &on_x);
+        jobs.add(name.into(), vec!["x".into(), format!("dir:{}", name)], move || {
+            assert_eq!(on_x.fetch_add(1, Ordering::SeqCst), 0);
+            thread::sleep(Duration::from_millis(5));
+            log.lock().unwrap().push(name);
+            on_x.fetch_sub(1, Ordering::SeqCst);
+            Ok(())
+        });
+    }
+    jobs.add("e".into(), vec!["y".into()], || Err(Error::msg("e failed")));
+    jobs.add("f".into(), vec!["y".into(), "z".into()], || panic!("f should be skipped"));
+    jobs.add("g".into(), vec!["z".into()], || {
+        log.lock().unwrap().push("g");
+        Ok(())
+    });
+
+    let e = jobs.run(3).unwrap_err();
+    assert_eq!(e.to_string(), "e failed");
+    let mut log = log.into_inner().unwrap();
+    let g = log.iter().position(|n| *n == "g").unwrap();
+    log.remove(g);
+    assert_eq!(log, vec!["a", "b", "c", "d"]);
+}
@@ -0,0 +1,67 @@
+//! The run journal.
+//!
+//! The journal is a record of what rack has done, kept as a file of JSON
+//! lines in the state directory.  Each line records a single event, such as
+//! the statistics from an archive being written.
+
+use crate::{digest, events, Error, Result};
+use chrono::Utc;
+use serde::Serialize;
+use serde_derive::Serialize;
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::PathBuf,
+};
+
+/// A single entry in the journal.
+#[derive(Debug, Serialize)]
+struct Entry<'a, T: Serialize> {
+    time: String,
+    kind: &'a str,
+    volume: &'a str,
+    detail: &'a T,
+}
+
+/// The directory rack keeps its state in, creating it if needed.
+pub fn state_dir() -> Result<PathBuf> {
+    let base = dirs::data_local_dir().ok_or_else(|| Error::msg("Unable to find data directory"))?;
+    let dir = base.join("rack");
+    fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+/// An entry, as an event of the run.
+#[derive(Serialize)]
+struct Record<'a, T: Serialize> {
+    kind: &'a str,
+    volume: &'a str,
+    detail: &'a T,
+}
+
+/// Append an entry to the journal.  The `kind` describes the type of event,
+/// and `detail` holds whatever information is specific to that kind.
+pub fn record<T: Serialize>(kind: &str, volume: &str, detail: &T) -> Result<()> {
+    let entry = Entry {
+        time: Utc::now().to_rfc3339(),
+        kind: kind,
+        volume: volume,
+        detail: detail,
+    };
+    let mut line = serde_json::to_string(&entry)?;
+    line.push('\n');
+
+    let mut fd = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(state_dir()?.join("journal.jsonl"))?;
+    fd.write_all(line.as_bytes())?;
+    digest::volume(kind, volume);
+    let record = Record {
+        kind: kind,
+        volume: volume,
+        detail: detail,
+    };
+    events::emit("record", &record);
+    Ok(())
+}
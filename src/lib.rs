@@ -9,44 +9,108 @@
 #![cfg_attr(feature = "clippy", plugin(clippy))]
 
 use chrono::{DateTime, Utc};
-use failure::{err_msg, format_err};
-use failure_derive::Fail;
-use regex::Regex;
 use std::{
     collections::{HashMap, HashSet},
     path::Path,
-    process::ExitStatus,
-    result,
+    time::{Duration, Instant},
 };
 
 // Reexports.
 pub use crate::config::{
-    CloneConfig, CloneVolume, Config, ResticConfig, ResticVolume, SnapConfig, SnapConvention,
-    SnapVolume, SureConfig, SureVolume,
+    AgeConfig, AutoConfig, BackupKind, BorgConfig, BorgVolume, CloneConfig, CloneVolume,
+    CloudConfig, CloudVolume, Compression, Config, ContainerConfig, ContainerEngine,
+    ContainerVolume, DatabaseConfig, DatabaseKind, EventsConfig,
+    ExportConfig, ExportTarget, ExportVolume, HookFailure, LoggingConfig, NotifyConfig, OnError,
+    Partial, PingConfig, PoolConfig, PruneAlgorithm, QuiesceMethod, RecvOptions, ResticBackend,
+    ResticConfig, ResticVolume, RetryConfig, RetryOn, SnapConfig, SnapConvention, SnapVolume,
+    SureConfig, SureVolume, SyncConfig, SyncKind, SyncVolume, Unmounted, VerifyConfig,
 };
-
+pub use crate::borg::BorgError;
+pub use crate::browse::umount;
+pub use crate::config::ConfigError;
+pub use crate::digest::{send as send_digest, start as start_digest, Digest, DigestReporter};
+pub use crate::error::{Context, Error, Result};
+pub use crate::events::{finish_events, start_events};
+pub use crate::listings::refresh as refresh_listings;
+pub use crate::export::ExportError;
+pub use crate::logging::{finish_log, start_log};
+pub use crate::lvm::LvmError;
+pub use crate::mount::stay_shared;
+pub use crate::naming::SnapNaming;
+pub use crate::plan::Plan;
+pub use crate::report::{set_reporter, ConsoleReporter, Event, Reporter};
+pub use crate::restic::ResticError;
+pub use crate::secret::{SecretError, SecretSource};
+pub use crate::send::StreamError;
+pub use crate::surestore::SureError;
+pub use crate::sync::SyncError;
+pub use crate::window::Window;
+pub use crate::zfs::{CloneOptions, Inventory, ZfsError};
+
+#[macro_use]
+mod report;
+
+mod adopt;
+mod auto;
 mod borg;
+mod browse;
+mod btrfs;
+mod catalog;
+mod changed;
 mod checked;
+mod cloud;
 mod config;
+mod containers;
+mod database;
+mod digest;
+mod doctor;
+mod du;
+mod error;
+mod events;
+mod excludes;
+mod export;
+mod gc;
+mod hooks;
+mod jobs;
+mod journal;
+mod listings;
+mod loader;
+mod logging;
+#[cfg(feature = "libzfs_core")]
+mod lzc;
+mod meter;
+mod mount;
 mod lvm;
+mod naming;
+mod notify;
+mod pings;
+mod plan;
+mod pools;
+mod prune;
 mod restic;
+mod restore;
+mod retention;
+mod retry;
+mod runlock;
+mod schedule;
+mod secret;
+mod send;
+mod stale;
+mod surecmp;
+mod surehash;
+mod surestore;
 mod sync;
+mod verify;
+mod window;
 mod zfs;
 
-use crate::restic::Limiter;
-use crate::zfs::Zfs;
-
-/// Local error type.
-#[derive(Fail, Debug)]
-enum RackError {
-    #[fail(display = "error running command: {:?}: {}", status, command)]
-    Command { command: String, status: ExitStatus },
-    #[fail(display = "not mounted: {:?}", fs)]
-    NotMounted { fs: String },
-}
-
-pub type Result<T> = result::Result<T, Error>;
-pub type Error = failure::Error;
+pub use crate::restic::Limiter;
+use crate::config::{configured, skipped};
+use crate::hooks::Hooks;
+use crate::jobs::Jobs;
+use crate::retry::retrying;
+use crate::schedule::Schedule;
+use crate::zfs::{Filesystem, Zfs};
 
 /// The path where root will be temporarily bind mounted.
 static ROOT_BIND_DIR: &'static str = "/mnt/root";
@@ -61,15 +125,59 @@ pub fn snapshot(prefix: &str, filesystem: &str) -> Result<()> {
     let snap = Zfs::new(prefix)?;
     // println!("snap: {:?}", snap);
     let next = snap.next_under(filesystem)?;
-    println!("next: {}: {}", next, snap.snap_name(next));
+    progress!("next: {}: {}", next, snap.snap_name(next));
     snap.take_snapshot(filesystem, next)?;
     Ok(())
 }
 
+/// Rename the numbered snapshots under a filesystem to the current padding of their prefix, and
+/// those of the clones of it made by the `clones` that are sent from this host.
+pub fn renumber(
+    inv: &Inventory,
+    clones: &[CloneVolume],
+    prefix: &str,
+    filesystem: &str,
+    pretend: bool,
+) -> Result<()> {
+    let snap = Zfs::from_inventory(prefix, inv)?;
+    let mut plan = Plan::new("renumber");
+    snap.plan_renumber(filesystem, &mut plan)?;
+    for vol in clones.iter().filter(|v| v.host.is_none()) {
+        snap.plan_renumber_clone(filesystem, vol, &mut plan)?;
+    }
+    plan.execute(pretend)?;
+    inv.invalidate();
+    Ok(())
+}
+
 impl SnapConfig {
     /// Create time-based snapshots for all volumes mentioned in the config
-    /// file.
-    pub fn snapshot(&self, now: DateTime<Utc>, pretend: bool) -> Result<()> {
+    /// file.  With `scheduled`, only those whose convention's schedule is
+    /// due.
+    pub fn snapshot(
+        &self,
+        inv: &Inventory,
+        full_pools: &[String],
+        now: DateTime<Utc>,
+        scheduled: bool,
+        pretend: bool,
+    ) -> Result<()> {
+        self.plan(inv, full_pools, now, scheduled)?.execute(pretend)?;
+        inv.invalidate();
+        Ok(())
+    }
+
+    /// Plan the snapshots for all volumes mentioned in the config file,
+    /// other than those on the pools given as too full, and with
+    /// `scheduled`, those whose convention's schedule isn't due.
+    pub fn plan(
+        &self,
+        inv: &Inventory,
+        full_pools: &[String],
+        now: DateTime<Utc>,
+        scheduled: bool,
+    ) -> Result<Plan> {
+        configured("snap", &self.volumes)?;
         let convs: HashMap<&str, &SnapConvention> = self
             .conventions
             .iter()
@@ -80,175 +188,491 @@ impl SnapConfig {
         // can report an error before creating any snapshots.
         let mut sn: Vec<(&SnapVolume, &SnapConvention)> = vec![];
         for v in &self.volumes {
+            if skipped("snap", &v.name, v.skip) {
+                continue;
+            }
+            let pool = v.zfs.split('/').next().unwrap_or_default();
+            if full_pools.iter().any(|p| p == pool) {
+                warning!("Not snapshotting {:?}, pool {} is too full", v.zfs, pool);
+                continue;
+            }
             let c = convs.get(v.convention.as_str()).ok_or_else(|| {
-                format_err!("Invalid convention {:?} in snap {:?}", v.convention, v.name)
+                Error::msg(format!("Invalid convention {:?} in snap {:?}", v.convention, v.name))
             })?;
             sn.push((v, *c));
         }
 
-        let zfs = Zfs::new("none")?;
+        let zfs = Zfs::from_inventory("none", inv)?;
+        let mut plan = Plan::new("snap");
 
         for &(v, c) in &sn {
-            v.snapshot(c, now, &zfs, pretend)?;
+            let schedule = c.schedule.as_deref().and_then(Schedule::parse);
+            if let Some(schedule) = schedule.filter(|_| scheduled) {
+                let newest = zfs
+                    .find(&v.zfs)?
+                    .snaps
+                    .iter()
+                    .filter_map(|s| naming::parse(&c.name, s).map(|n| n.time))
+                    .max();
+                if !schedule.is_due(newest, now) {
+                    let (fs, sched) = (&v.zfs, schedule.to_string());
+                    decision!("Not snapshotting {:?}, {:?} isn't due since its newest", fs, sched);
+                    continue;
+                }
+            }
+            if v.skip_unchanged == Some(true) {
+                if let Some(last) = zfs.unchanged(&v.zfs, &c.name)? {
+                    decision!("Not snapshotting {:?}, nothing written since {}", v.zfs, last);
+                    continue;
+                }
+            }
+            v.plan(c, now, &zfs, &mut plan);
         }
 
-        Ok(())
+        Ok(plan)
     }
 }
 
 impl SnapVolume {
-    // Create a time-based snapshot.
-    pub fn snapshot(
-        &self,
-        conv: &SnapConvention,
-        now: DateTime<Utc>,
-        zfs: &Zfs,
-        pretend: bool,
-    ) -> Result<()> {
-        let name = format!("{}-{}", conv.name, now.format("%Y%m%d%H%M"));
-        println!("Snapshot of {:?}@{:?} at {}", self.zfs, name, now);
-        if !pretend {
-            zfs.take_named_snapshot(&self.zfs, &name)?;
-        }
-        Ok(())
+    // Plan a time-based snapshot, between the volume's hooks, and with its
+    // database made consistent.
+    pub fn plan(&self, conv: &SnapConvention, now: DateTime<Utc>, zfs: &Zfs, plan: &mut Plan) {
+        let name = conv.naming.name(&conv.name, None, now.naive_utc());
+        let reason = format!("Snapshot of {:?}@{:?} at {}", self.zfs, name, now);
+        let hooks = Hooks::new(&self.pre_hook, &self.post_hook, self.hook_failure);
+        database::plan_snapshot(self.database.as_ref(), &hooks, &self.name, plan, |plan| {
+            zfs.plan_named_snapshot(&self.zfs, &name, reason, plan)
+        });
     }
 }
 
 impl SureConfig {
-    pub fn run(&self, pretend: bool) -> Result<()> {
-        for vol in &self.volumes {
-            println!("Sure update {:?}", vol);
+    /// Capture sure data for all of the volumes.  At most `limit` snapshots are captured, across
+    /// all volumes.
+    pub fn run(&self, inv: &Inventory, limit: Option<usize>, pretend: bool) -> Result<()> {
+        configured("sure", &self.volumes)?;
+        self.validate()?;
+        let limit = Limiter::new(limit);
+        let threads = self.threads.unwrap_or(1);
+        let hash_threads = self.hash_threads.unwrap_or(1);
+        if threads <= 1 || pretend {
+            for vol in &self.volumes {
+                if skipped("sure", &vol.name, vol.skip) {
+                    continue;
+                }
+                progress!("Sure update {:?}", vol);
+
+                if !pretend {
+                    vol.capture(inv, &limit, hash_threads)?;
+                }
+            }
+            return Ok(());
+        }
+
+        let mut jobs = Jobs::new();
+        self.add_jobs(&mut jobs, inv, &limit, pretend);
+        jobs.run(threads)
+    }
 
-            if !pretend {
-                sure(&vol.convention, &vol.zfs, &vol.sure)?;
+    /// Add a job capturing each volume.  Captures into the same store have to
+    /// be done in order, and a bind directory can only be used by one capture
+    /// at a time.
+    fn add_jobs<'a>(
+        &'a self,
+        jobs: &mut Jobs<'a>,
+        inv: &'a Inventory,
+        limit: &'a Limiter,
+        pretend: bool,
+    ) {
+        let hash_threads = self.hash_threads.unwrap_or(1);
+        for vol in &self.volumes {
+            if skipped("sure", &vol.name, vol.skip) {
+                continue;
+            }
+            let mut uses = vec![format!("dir:{}", vol.bind)];
+            if let Ok(store) = vol.store_path() {
+                uses.push(format!("sure:{}", store));
             }
+            jobs.add(format!("sure {:?}", vol.name), uses, move || {
+                progress!("Sure update {:?}", vol);
+                if pretend {
+                    return Ok(());
+                }
+                vol.capture(inv, limit, hash_threads)
+            });
         }
-        Ok(())
+    }
+}
+
+impl SureVolume {
+    /// Capture sure data for the snapshots of this volume that don't have it yet, hashing
+    /// `hash_threads` top directories of each at a time.
+    fn capture(&self, inv: &Inventory, limit: &Limiter, hash_threads: usize) -> Result<()> {
+        let store = self.store_path()?;
+        let unmounted = self.unmounted.unwrap_or_default();
+        let (conv, zfs, bind) = (&self.convention, &self.zfs, &self.bind);
+        sure(inv, conv, zfs, store, bind, unmounted, limit, hash_threads)
+    }
+}
+
+impl RecvOptions {
+    /// The arguments to `zfs receive` for the options.
+    pub fn args(&self) -> Vec<String> {
+        let mut args = vec![];
+        for (prop, value) in &self.set {
+            args.push("-o".to_string());
+            args.push(format!("{}={}", prop, value));
+        }
+        for prop in &self.exclude {
+            args.push("-x".to_string());
+            args.push(prop.clone());
+        }
+        args
     }
 }
 
 impl CloneConfig {
-    pub fn run(&self, pretend: bool) -> Result<()> {
+    /// Clone the volumes, first resuming the receives kept from earlier
+    /// clones, if `resume`.
+    pub fn run(&self, inv: &Inventory, resume: bool, pretend: bool) -> Result<()> {
+        configured("clone", &self.volumes)?;
         for vol in &self.volumes {
-            if vol.skip == Some(true) {
+            if skipped("clone", &vol.name, vol.skip) {
                 continue;
             }
-            println!("Clone: {:?}", vol);
-
-            clone(&vol.source, &vol.dest, !pretend, &[])?;
+            progress!("Clone: {:?}", vol);
+
+            let options = CloneOptions {
+                rate_limit: vol.rate_limit.clone().or_else(|| self.rate_limit.clone()),
+                pause: self.pause_window(),
+                idle_timeout: self.idle_timeout.map(Duration::from_secs),
+                keep_partial: self.partial == Some(Partial::Keep),
+                resume: resume,
+                renames: vol.renames.clone().into_iter().collect(),
+                recv_args: vol.recv_options.args(),
+                create_set: vol.create_options.set.clone().into_iter().collect(),
+                create_skip: vol.create_options.skip.clone(),
+            };
+            let (source, dest) = (&vol.source, &vol.dest);
+            match vol.host {
+                Some(ref host) => retrying(&format!("Pull of {}", vol.name), || {
+                    pull(inv, host, source, dest, !pretend, &[], &options)
+                })?,
+                None => clone(inv, source, dest, !pretend, &[], &options)?,
+            }
         }
 
         Ok(())
     }
 
+    /// Plan destroying the filesystems under the destinations that nothing
+    /// is cloned to any more, and that are listed in `delete_orphans`,
+    /// which are kept for `orphan_days` after their newest snapshot.
+    pub fn plan_delete(&self, inv: &Inventory) -> Result<Plan> {
+        configured("clone", &self.volumes)?;
+        let days = self.orphan_days.unwrap_or(30) as i64;
+        let before = (Utc::now() - chrono::Duration::days(days)).timestamp();
+        let mut plan = Plan::new("clone-delete");
+        for vol in &self.volumes {
+            if skipped("clone", &vol.name, vol.skip) {
+                continue;
+            }
+            let options = CloneOptions {
+                renames: vol.renames.clone().into_iter().collect(),
+                ..CloneOptions::default()
+            };
+            let from = match vol.host {
+                Some(ref host) => {
+                    Zfs::from_inventory("caz", &Inventory::remote(host).within(&vol.source))?
+                }
+                None => Zfs::from_inventory("caz", inv)?,
+            };
+            // The destinations of other volumes under this one are theirs,
+            // even those of volumes skipped this time.
+            let under = format!("{}/", vol.dest);
+            let nested: Vec<_> = self
+                .volumes
+                .iter()
+                .map(|v| v.dest.as_str())
+                .filter(|d| d.starts_with(&under))
+                .collect();
+            let dest = receiver(inv, &options)?;
+            dest.plan_orphans(&from, vol, &nested, before, &mut plan)?;
+        }
+        Ok(plan)
+    }
+
+    /// The window clones are paused outside of, if they are.
+    fn pause_window(&self) -> Option<Window> {
+        if self.pause != Some(true) {
+            return None;
+        }
+        self.window.as_deref().and_then(Window::parse)
+    }
 }
 
 impl Config {
+    /// Clone the volumes, as `CloneConfig::run`, then check what was
+    /// received, if `verify`, or the config asks to.
+    pub fn run_clone(&self, resume: bool, verify: bool, pretend: bool) -> Result<()> {
+        self.clone.run(&self.inventory, resume, pretend)?;
+        if pretend || !(verify || self.clone.verify == Some(true)) {
+            return Ok(());
+        }
+        self.inventory.invalidate();
+        self.verify_clones()
+    }
+
+    /// Check the config for problems that can be found without running
+    /// any backups.
+    pub fn check(&self) -> Result<()> {
+        self.restic.validate()?;
+        self.sure.validate()?;
+        Ok(())
+    }
+
     pub fn run_restic(&self, name: Option<&str>, limit: Option<usize>, pretend: bool) -> Result<()> {
-        let mut limit = Limiter(limit);
+        configured("restic", &self.restic.volumes)?;
+        self.restic.validate()?;
+        let _lock = if pretend { None } else { self.preflight()? };
+
+        let limit = Limiter::new(limit);
 
-        let snaps = Zfs::new("none")?;
+        let snaps = Zfs::from_inventory("none", &self.inventory)?;
+        let work = self.restic_work(&snaps, name)?;
+        restic::run_all(work, self.restic.parallel.unwrap_or(1), &limit, pretend)
+    }
 
+    /// The restic volumes to back up, with their filesystems, and the sure
+    /// stores to push with them.
+    fn restic_work<'a>(
+        &'a self,
+        snaps: &'a Zfs,
+        name: Option<&str>,
+    ) -> Result<Vec<(&'a ResticVolume, &'a Filesystem, Option<&'a str>)>> {
+        let mut work = vec![];
         for vol in &self.restic.volumes {
             match name {
                 None => (),
                 Some(given) if given == vol.name => (),
                 _ => continue,
             }
+            if skipped("restic", &vol.name, vol.skip) {
+                continue;
+            }
 
             // Find the filesystem in ZFS.
-            let fs = if let Some(fs) = snaps.filesystems.iter().find(|&fs| fs.name == vol.zfs) {
-                fs
+            let fs = snaps.find_filesystem(&vol.zfs)?;
+            if !fs.readable(vol.unmounted.unwrap_or_default())? {
+                continue;
+            }
+            // The sure store to push along with the backups.
+            let sure = if vol.push_sure == Some(true) {
+                let svol = self
+                    .sure
+                    .volumes
+                    .iter()
+                    .find(|s| s.zfs == vol.zfs)
+                    .ok_or_else(|| ConfigError::NoVolumeFor {
+                        section: "sure",
+                        zfs: vol.zfs.clone(),
+                    })?;
+                Some(svol.store_path()?)
             } else {
-                return Err(err_msg("No snapshots match"));
+                None
             };
-            vol.run(&fs, &mut limit, pretend)?;
+            work.push((vol, fs, sure));
         }
+        Ok(work)
+    }
+}
 
-        Ok(())
+/// Clone one volume to another, held back by `options`.
+pub fn clone(
+    inv: &Inventory,
+    source: &str,
+    dest: &str,
+    perform: bool,
+    excludes: &[&str],
+    options: &CloneOptions,
+) -> Result<()> {
+    progress!("Cloning {} to {}", source, dest);
+    if options.resumes() {
+        let snap = receiver(inv, options)?;
+        snap.resume(&snap, dest, perform)?;
     }
+    receiver(inv, options)?.clone(source, dest, perform, excludes)?;
+
+    Ok(())
 }
 
-/// Clone one volume to another.
-pub fn clone(source: &str, dest: &str, perform: bool, excludes: &[&str]) -> Result<()> {
-    println!("Cloning {} to {}", source, dest);
-    let snap = Zfs::new("caz")?;
-    snap.clone(source, dest, perform, excludes)?;
+/// Clone a volume on another host to a local one, running `zfs send` on
+/// `host` over ssh.
+pub fn pull(
+    inv: &Inventory,
+    host: &str,
+    source: &str,
+    dest: &str,
+    perform: bool,
+    excludes: &[&str],
+    options: &CloneOptions,
+) -> Result<()> {
+    progress!("Pulling {}:{} to {}", host, source, dest);
+    let from = Zfs::from_inventory("caz", &Inventory::remote(host).within(source))?;
+    if options.resumes() {
+        receiver(inv, options)?.resume(&from, dest, perform)?;
+    }
+    receiver(inv, options)?.clone_from(&from, source, dest, perform, excludes)?;
 
     Ok(())
 }
 
-/// Update sure data for existing snapshots.
-pub fn sure(prefix: &str, filesystem: &str, surefile: &str) -> Result<()> {
-    let snap = Zfs::new(prefix)?;
+/// The volumes to clone into, as they are now, which is after any
+/// receives that were resumed.
+fn receiver(inv: &Inventory, options: &CloneOptions) -> Result<Zfs> {
+    let mut snap = Zfs::from_inventory("caz", inv)?;
+    snap.options = options.clone();
+    Ok(snap)
+}
 
-    // A regex to filter snapshots matching the desired prefix.
-    let quoted = regex::escape(prefix);
-    // let pat = format!(r"^{}\d{{4}}-[-\d]+$", quoted);
-    let pat = format!(r"^{}-[-\d]+$", quoted);
-    let re = Regex::new(&pat)?;
+/// Update sure data for existing snapshots.  Each snapshot is bind mounted at `bind` while it is
+/// captured, so that the paths recorded are the same for every snapshot, and the directories at
+/// the top of it are hashed `hash_threads` at a time.
+pub fn sure(
+    inv: &Inventory,
+    prefix: &str,
+    filesystem: &str,
+    surefile: &str,
+    bind: &str,
+    unmounted: Unmounted,
+    limit: &Limiter,
+    hash_threads: usize,
+) -> Result<()> {
+    let snap = Zfs::from_inventory(prefix, inv)?;
+
+    // Filter snapshots of the convention of the desired prefix.
+    let ours = |name: &str| sure_captures(prefix, name);
 
     // Find the filesystem that matches
-    let fs = if let Some(fs) = snap.filesystems.iter().find(|&fs| fs.name == filesystem) {
-        fs
-    } else {
-        return Err(err_msg("No snapshots match"));
-    };
+    let fs = snap.find_filesystem(filesystem)?;
+    if !fs.readable(unmounted)? {
+        return Ok(());
+    }
 
-    let snaps: Vec<_> = fs.snaps.iter().filter(|x| re.is_match(x)).collect();
+    let snaps: Vec<_> = fs.snaps.iter().filter(|x| ours(x)).collect();
 
     // println!("Snaps: {:?}", snaps);
-    // println!("Mountpoint: {:?}", fs.mount);
+    // println!("Mount state: {:?}", fs.mount_state);
 
     let store = rsure::parse_store(surefile)?;
     let versions = store.get_versions()?;
+    let host = surestore::hostname()?;
+
+    // An update is always based off of the most recently written version in the store.
+    let mut latest: Option<String> = versions
+        .iter()
+        .max_by_key(|x| x.time)
+        .map(|x| x.name.clone());
 
-    let versions: Vec<_> = versions.iter().filter(|x| re.is_match(&x.name)).collect();
-    let verset: HashSet<&String> = versions.iter().map(|x| &x.name).collect();
+    let versions: Vec<_> = versions.iter().filter(|x| ours(&x.name)).collect();
+    let mut verset: HashSet<String> = versions.iter().map(|x| x.name.clone()).collect();
 
     // println!("Sure versions: {:?}", versions.iter().map(|x| &x.name).collect::<Vec<_>>());
 
-    // Go through the snapshots, in order, showing any that haven't been rsured.  If ones in the
-    // middle are not present, we should really base off of those, but in the normal case, this
-    // will always just add ones at the end.
+    // Go through the snapshots, in order, capturing any that haven't been rsured.  Each capture
+    // should be based off of the nearest earlier snapshot that has been captured.  Rsure can only
+    // update from the most recently written version, so when filling a gap in the middle of the
+    // history, where the predecessor is not the latest, do a full scan instead.  In the normal
+    // case, this will always just add ones at the end.
+    let todo = snaps.iter().filter(|v| !verset.contains(v.as_str())).count();
+    let mut done = 0;
+    let mut pred: Option<&String> = None;
     for vers in &snaps {
-        if verset.contains(vers) {
+        if verset.contains(vers.as_str()) {
+            pred = Some(vers);
             continue;
         }
 
-        println!("Capture: {:?}", vers);
-        // Although ZFS tells us where it thinks things should be mounted,
-        // it isn't always right, instead find out where Linux view the
-        // mounpoints.
-        let mount = snap.find_mount(&fs.name)?;
-
-        // Zfs snapshots seem to not mount until something inside is read.  It seems sufficient to
-        // stat "." in the root (but no the root directory itself).
-        let base = Path::new(&mount).join(".zfs").join("snapshot").join(vers);
-        let dotfile = base.join(".");
-        let _ = dotfile.metadata()?;
-        println!("Stat {:?} for {:?}", dotfile, base);
+        if limit.exhausted() {
+            break;
+        }
+
+        let is_update = match (pred, &latest) {
+            (Some(p), Some(l)) => p == l,
+            _ => false,
+        };
+        if !is_update {
+            progress!("Full scan for {:?}, based on {:?}, latest {:?}", vers, pred, latest);
+        }
+
+        done += 1;
+        progress!("Capture [{}/{}] {}: {:?}", done, todo, filesystem, vers);
+        let start = Instant::now();
+        let mounted = fs.mount_snapshot(vers, Path::new(bind))?;
         let mut tags = rsure::StoreTags::new();
         tags.insert("name".into(), vers.to_string());
-        rsure::update(base, &*store, true, &tags)?;
+        tags.insert("host".into(), host.clone());
+        tags.insert("convention".into(), prefix.to_string());
+        tags.insert("zfs".into(), filesystem.to_string());
+        tags.insert("captured".into(), Utc::now().to_rfc3339());
+        surehash::update(bind, &*store, is_update, &tags, hash_threads)?;
+        mounted.unmount()?;
+        verset.insert(vers.to_string());
+        latest = Some(vers.to_string());
+        pred = Some(vers);
+        progress!(
+            "Captured [{}/{}] {}: {:?} in {}s",
+            done,
+            todo,
+            filesystem,
+            vers,
+            start.elapsed().as_secs()
+        );
     }
 
     Ok(())
 }
 
-pub fn run_borg(filesystem: &str, borg_repo: &str, name: &str, pretend: bool) -> Result<()> {
+pub fn run_borg(
+    filesystem: &str,
+    bind: &str,
+    borg_repo: &str,
+    name: &str,
+    limit: Option<usize>,
+    pretend: bool,
+) -> Result<()> {
     let snap = Zfs::new(filesystem)?;
 
-    let fs = if let Some(fs) = snap.filesystems.iter().find(|&fs| fs.name == filesystem) {
-        fs
-    } else {
-        return Err(err_msg("No snapshots match"));
+    let fs = snap.find_filesystem(filesystem)?;
+
+    let vol = BorgVolume {
+        name: name.to_string(),
+        skip: None,
+        zfs: filesystem.to_string(),
+        bind: bind.to_string(),
+        repo: borg_repo.to_string(),
+        prefix: name.to_string(),
+        convention: None,
+        compression: None,
+        chunker_params: None,
+        passphrase: None,
+        break_lock: None,
+        unmounted: None,
+        pre_hook: vec![],
+        post_hook: vec![],
+        hook_failure: None,
+        excludes: vec![],
+        exclude_profiles: vec![],
     };
 
     // Just get the snapshots matching this single prefix.
-    borg::run(fs, borg_repo, name, pretend).unwrap();
+    borg::run(fs, &vol, &Limiter::new(limit), pretend)
+}
 
-    Ok(())
+/// Whether sure captures the snapshot `name` for the convention `prefix`: one it made, without
+/// a sequence number, or one adopted as its own.
+fn sure_captures(prefix: &str, name: &str) -> bool {
+    naming::parse(prefix, name).map_or(false, |n| n.index.is_none())
 }
 
 /// A filesystem volume, which can be local or on a given host.
@@ -297,3 +721,52 @@ fn test_parse_fsname() {
         }
     );
 }
+
+#[test]
+fn test_sure_captures_adopted() {
+    use crate::config::AdoptRule;
+
+    adopt::set_rules(&[AdoptRule {
+        convention: "sure-adopted".into(),
+        scheme: None,
+        pattern: Some(r"^nightly-(?P<time>\d{12})$".into()),
+        label: None,
+        date_format: None,
+        utc: Some(true),
+    }]);
+    let snaps = ["sure-adopted-202406020300", "nightly-202406030415", "nightly-x", "manual"];
+    let captured: Vec<_> = snaps.iter().filter(|s| sure_captures("sure-adopted", s)).collect();
+    adopt::set_rules(&[]);
+    assert_eq!(captured, [&"sure-adopted-202406020300", &"nightly-202406030415"]);
+}
+
+#[test]
+fn test_pull_resumes_kept() {
+    use crate::checked::{set_executor, RecordingExecutor};
+    use std::sync::Arc;
+
+    let exec = Arc::new(RecordingExecutor::new());
+    exec.respond(
+        &["ssh", "root@laptop", "zfs", "list"],
+        "tank/home\tfilesystem\tyes\t-\t-\t0\t0\t0\t/home\n\
+         tank/home@a\tsnapshot\t-\t0\t\t0\t0\t0\t-\n",
+    );
+    exec.respond(
+        &["zfs", "list"],
+        "backup/laptop\tfilesystem\tno\t-\t-\t0\t0\t0\tnone\n\
+         backup/laptop@a\tsnapshot\t-\t0\t\t0\t0\t0\t-\n",
+    );
+    exec.respond(&["zfs", "get"], "backup/laptop\t1-abc\n");
+    let running = set_executor(exec.clone());
+    let options = CloneOptions {
+        keep_partial: true,
+        ..CloneOptions::default()
+    };
+    let inv = Inventory::new();
+    pull(&inv, "root@laptop", "tank/home", "backup/laptop", false, &[], &options).unwrap();
+    drop(running);
+
+    // The receive kept by an earlier pull is looked for before pulling.
+    let get = "zfs get -H -r -o name,value receive_resume_token backup/laptop";
+    assert_eq!(exec.commands()[2], get);
+}
@@ -0,0 +1,123 @@
+//! Listings of restic snapshots, kept between runs.
+//!
+//! Listing the snapshots of a restic repository means reading the index of
+//! the whole repository, which against a cloud backend can take longer than
+//! the backup itself.  The listing of each repository is kept in a JSON file
+//! under `restic` in the state directory, and used in place of asking restic
+//! again.  Rack drops the file of a repository whenever it writes to it, so
+//! the listing is only out of date if something else has changed the
+//! repository, such as a `restic forget` run by hand, or another host
+//! backing up to it.  Giving `--refresh` asks every repository again.
+
+use crate::{journal::state_dir, Result};
+use chrono::Utc;
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+static REFRESH: AtomicBool = AtomicBool::new(false);
+
+/// The file kept of a repository.
+#[derive(Serialize, Deserialize)]
+struct Listing {
+    /// The repository, in case two map to the same file name.
+    repo: String,
+    /// When restic was asked, in RFC3339.
+    time: String,
+    /// The snapshots, as `restic snapshots --json` gave them.
+    snapshots: serde_json::Value,
+}
+
+/// Ignore the kept listings for the rest of this run, asking restic again,
+/// and keeping what it says.
+pub fn refresh() {
+    REFRESH.store(true, Ordering::SeqCst);
+}
+
+/// The snapshots of the repository `repo`, as json, from the kept listing,
+/// or from `query` if there isn't one.
+pub(crate) fn snapshots<F>(repo: &str, query: F) -> Result<Vec<u8>>
+where
+    F: FnOnce() -> Result<Vec<u8>>,
+{
+    let dir = state_dir()?.join("restic");
+    if !REFRESH.load(Ordering::SeqCst) {
+        if let Some(snapshots) = load(&dir, repo) {
+            return Ok(snapshots);
+        }
+    }
+    let snapshots = query()?;
+    // Failing to keep the listing only means asking again next time.
+    if let Err(e) = store(&dir, repo, &snapshots) {
+        warning!("Unable to keep the snapshots of restic repo {:?}: {}", repo, e);
+    }
+    Ok(snapshots)
+}
+
+/// Drop the kept listing of `repo`, after writing to it.
+pub(crate) fn invalidate(repo: &str) {
+    let path = match state_dir() {
+        Ok(dir) => path(&dir.join("restic"), repo),
+        Err(e) => {
+            warning!("Unable to drop the snapshots kept of {:?}: {}", repo, e);
+            return;
+        }
+    };
+    if let Err(e) = fs::remove_file(&path) {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            warning!("Unable to remove {:?}: {}", path, e);
+        }
+    }
+}
+
+/// The file in `dir` for `repo`, named by the repository with anything but
+/// letters, digits, "-" and "." replaced.
+fn path(dir: &Path, repo: &str) -> PathBuf {
+    let name: String = repo
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '.' { c } else { '_' })
+        .collect();
+    dir.join(format!("{}.json", name))
+}
+
+/// The kept listing of `repo`, if there is a usable one.
+fn load(dir: &Path, repo: &str) -> Option<Vec<u8>> {
+    let text = fs::read_to_string(path(dir, repo)).ok()?;
+    let listing: Listing = serde_json::from_str(&text).ok()?;
+    if listing.repo != repo {
+        return None;
+    }
+    serde_json::to_vec(&listing.snapshots).ok()
+}
+
+/// Keep the listing of `repo`, replacing the file only once it is complete.
+fn store(dir: &Path, repo: &str, snapshots: &[u8]) -> Result<()> {
+    let listing = Listing {
+        repo: repo.to_string(),
+        time: Utc::now().to_rfc3339(),
+        snapshots: serde_json::from_slice(snapshots)?,
+    };
+    fs::create_dir_all(dir)?;
+    let path = path(dir, repo);
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, serde_json::to_string(&listing)?)?;
+    fs::rename(&tmp, &path)?;
+    Ok(())
+}
+
+#[test]
+fn test_listings() {
+    let dir = std::env::temp_dir().join(format!("rack-listings-{}", std::process::id()));
+    let repo = "s3:https://s3.example.com/backups";
+    assert_eq!(path(&dir, repo), dir.join("s3_https___s3.example.com_backups.json"));
+    assert!(load(&dir, repo).is_none());
+
+    store(&dir, repo, br#"[{"short_id": "1a2b3c4d"}]"#).unwrap();
+    assert_eq!(load(&dir, repo).unwrap(), br#"[{"short_id":"1a2b3c4d"}]"#);
+    // Another repository with the same file name isn't given this listing.
+    assert!(load(&dir, "s3:https://s3.example.com:backups").is_none());
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,295 @@
+//! Reading the config file.
+//!
+//! The config is read as a YAML document before being decoded, so that it
+//! can be put together from more than one place.
+//!
+//! - An `include` list, at the top of any file, names further files, relative
+//!   to the including file, whose contents are merged in.  The including
+//!   file's own settings are merged last, so they win.
+//! - The `hosts` section maps host names to further config, which is merged
+//!   into the rest only on that host.  This lets one file be shared between
+//!   machines.
+//!
+//! When merging, lists, such as the volumes of a section, are appended to,
+//! and other values replace what was there.  Each part of the merged
+//! document remembers which file it came from, so that problems are reported
+//! against the right file.
+
+use crate::{
+    config::{Config, ConfigError},
+    Context, Error, Result,
+};
+use serde_yaml::{Mapping, Value};
+use std::{
+    fs, mem,
+    path::{Path, PathBuf},
+};
+
+#[derive(Clone, Debug)]
+pub struct Document {
+    value: Value,
+    /// Paths within the document, such as "snap.volumes[2]", with the file
+    /// that gave them.  The longest matching path gives the file.
+    origins: Vec<(String, Option<PathBuf>)>,
+}
+
+impl Document {
+    /// Read a config file, and everything it includes.
+    pub fn load(path: &Path) -> Result<Document> {
+        Document::read(path, &mut vec![])
+    }
+
+    /// `stack` holds the files being read, to catch files that include
+    /// themselves.
+    fn read(path: &Path, stack: &mut Vec<PathBuf>) -> Result<Document> {
+        let canon = fs::canonicalize(path).context(format!("{:?}", path))?;
+        if stack.contains(&canon) {
+            return Err(ConfigError::IncludeLoop { file: path.to_path_buf() }.into());
+        }
+        let text = fs::read_to_string(path).context(format!("{:?}", path))?;
+        stack.push(canon);
+        let doc = Document::parse(&text, Some(path), stack);
+        stack.pop();
+        doc
+    }
+
+    /// Parse the text of a config file.  Includes are found relative to
+    /// `file`, or to the current directory if the text isn't from a file.
+    pub fn parse(text: &str, file: Option<&Path>, stack: &mut Vec<PathBuf>) -> Result<Document> {
+        let in_file = |e: Error| match file {
+            Some(file) => Error::Context {
+                context: format!("{:?}", file),
+                source: Box::new(e),
+            },
+            None => e,
+        };
+        let mut value: Value = serde_yaml::from_str(text).map_err(|e| in_file(e.into()))?;
+        let includes = take_includes(&mut value).map_err(in_file)?;
+
+        let file = file.map(|f| f.to_path_buf());
+        let mut doc = Document {
+            value: Value::Mapping(Mapping::new()),
+            origins: vec![(String::new(), file.clone())],
+        };
+        let dir = file.as_ref().and_then(|f| f.parent()).unwrap_or_else(|| Path::new(""));
+        for name in includes {
+            let mut sub = Document::read(&dir.join(&name), stack)?;
+            let value = mem::replace(&mut sub.value, Value::Null);
+            merge(&mut doc.value, value, "", "", &|p| sub.origin(p), &mut doc.origins);
+        }
+        merge(&mut doc.value, value, "", "", &|_| file.clone(), &mut doc.origins);
+        Ok(doc)
+    }
+
+    /// The file the given part of the document came from.
+    fn origin(&self, path: &str) -> Option<PathBuf> {
+        self.origins
+            .iter()
+            .filter(|(prefix, _)| within(path, prefix))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .and_then(|(_, file)| file.clone())
+    }
+
+    /// Decode the effective config for the given host.  The sections for
+    /// every host are checked, so that a mistake is found on whichever
+    /// machine is run first.
+    pub fn config(&self, host: &str) -> Result<Config> {
+        let mut base = self.clone();
+        let hosts = match base.value.as_mapping_mut() {
+            Some(map) => map.remove(&Value::from("hosts")),
+            None => None,
+        };
+        let hosts = match hosts {
+            None => Mapping::new(),
+            Some(Value::Mapping(hosts)) => hosts,
+            Some(_) => return Err(base.error("hosts", "must be a mapping of host names")),
+        };
+
+        let mut result = None;
+        for (name, section) in hosts {
+            let name = match name.as_str() {
+                Some(name) => name.to_string(),
+                None => return Err(base.error("hosts", &format!("invalid host {:?}", name))),
+            };
+            let mut merged = base.clone();
+            let from = format!("hosts.{}", name);
+            let origin = |p: &str| self.origin(p);
+            merge(&mut merged.value, section, "", &from, &origin, &mut merged.origins);
+            let item = merged.decode().map_err(|e| ConfigError::Host {
+                host: name.clone(),
+                source: Box::new(e),
+            })?;
+            if name == host {
+                result = Some(item);
+            }
+        }
+
+        match result {
+            Some(item) => Ok(item),
+            None => base.decode(),
+        }
+    }
+
+    fn decode(&self) -> Result<Config> {
+        let mut item: Config = serde_path_to_error::deserialize(self.value.clone())
+            .map_err(|e| self.error(&e.path().to_string(), &e.inner().to_string()))?;
+        item.check_fields().map_err(|e| match e {
+            Error::Config(ConfigError::Field { path, msg }) => self.error(&path, &msg),
+            e => e,
+        })?;
+        item.expand_excludes();
+        Ok(item)
+    }
+
+    /// An error in the given part of the document, naming the file it came
+    /// from.
+    fn error(&self, path: &str, msg: &str) -> Error {
+        let err = field(path, msg.to_string());
+        match self.origin(path) {
+            Some(file) => Error::Context {
+                context: format!("{:?}", file),
+                source: Box::new(err),
+            },
+            None => err,
+        }
+    }
+}
+
+/// Remove the `include` entry from the top of a document, returning the
+/// files named.
+fn take_includes(value: &mut Value) -> Result<Vec<String>> {
+    let include = match value.as_mapping_mut() {
+        Some(map) => map.remove(&Value::from("include")),
+        None => None,
+    };
+    let names = match include {
+        None => vec![],
+        Some(Value::String(name)) => vec![Value::String(name)],
+        Some(Value::Sequence(names)) => names,
+        Some(_) => return Err(field("include", "must be a file name, or a list of them".into())),
+    };
+    names
+        .into_iter()
+        .map(|n| match n {
+            Value::String(name) => Ok(name),
+            other => Err(field("include", format!("invalid file name {:?}", other))),
+        })
+        .collect()
+}
+
+fn field(path: &str, msg: String) -> Error {
+    ConfigError::Field {
+        path: path.to_string(),
+        msg,
+    }
+    .into()
+}
+
+/// Is `path` the same as, or inside of, `prefix`?
+fn within(path: &str, prefix: &str) -> bool {
+    if prefix.is_empty() {
+        return true;
+    }
+    match path.strip_prefix(prefix) {
+        Some(rest) => rest.is_empty() || rest.starts_with('.') || rest.starts_with('['),
+        None => false,
+    }
+}
+
+/// Extend a path with a mapping key.
+fn key_path(path: &str, key: &Value) -> String {
+    let key = match key.as_str() {
+        Some(key) => key.to_string(),
+        None => format!("{:?}", key),
+    };
+    if path.is_empty() {
+        key
+    } else {
+        format!("{}.{}", path, key)
+    }
+}
+
+/// Merge `over` into `base`.  Mappings are merged key by key, sequences are
+/// appended, and anything else is replaced.  `at` is where `base` is in the
+/// merged document, and `from` is where `over` is in its own.  The origin of
+/// each part added is recorded, as given by `origin` for its place in `over`.
+fn merge(
+    base: &mut Value,
+    over: Value,
+    at: &str,
+    from: &str,
+    origin: &dyn Fn(&str) -> Option<PathBuf>,
+    origins: &mut Vec<(String, Option<PathBuf>)>,
+) {
+    match (base, over) {
+        (Value::Mapping(base), Value::Mapping(over)) => {
+            for (key, value) in over {
+                let (sub_at, sub_from) = (key_path(at, &key), key_path(from, &key));
+                match base.get_mut(&key) {
+                    Some(old) => merge(old, value, &sub_at, &sub_from, origin, origins),
+                    None => {
+                        origins.push((sub_at, origin(&sub_from)));
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (Value::Sequence(base), Value::Sequence(over)) => {
+            for (i, value) in over.into_iter().enumerate() {
+                let sub_at = format!("{}[{}]", at, base.len());
+                origins.push((sub_at, origin(&format!("{}[{}]", from, i))));
+                base.push(value);
+            }
+        }
+        (base, over) => {
+            origins.push((at.to_string(), origin(from)));
+            *base = over;
+        }
+    }
+}
+
+#[test]
+fn test_include() {
+    use crate::verify::ScratchDir;
+
+    let dir = ScratchDir::new("include").unwrap();
+    let write = |name: &str, text: &str| fs::write(dir.0.join(name), text).unwrap();
+    write(
+        "main.yaml",
+        "include: [snap.yaml, other.yaml]\n\
+         sure: {volumes: []}\nrestic: {volumes: []}\nclone: {volumes: []}\n\
+         priority: {nice: 5}\n",
+    );
+    write(
+        "snap.yaml",
+        "snap:\n  conventions: [{name: daily, daily: 7}]\n\
+         \x20 volumes: [{name: home, convention: daily, zfs: a/home}]\n",
+    );
+    write(
+        "other.yaml",
+        "snap: {volumes: [{name: root, convention: hourly, zfs: a/root}]}\n\
+         priority: {nice: 10, ionice_class: 3}\n",
+    );
+
+    let main = dir.0.join("main.yaml");
+    let e = Document::load(&main).unwrap().config("lint").unwrap_err();
+    let msg = format!(
+        "{:?}: snap.volumes[1].convention: unknown convention \"hourly\"",
+        dir.0.join("other.yaml")
+    );
+    assert_eq!(e.to_string(), msg);
+
+    write(
+        "other.yaml",
+        "snap: {volumes: [{name: root, convention: daily, zfs: a/root}]}\n\
+         priority: {nice: 10, ionice_class: 3}\n",
+    );
+    let conf = Document::load(&main).unwrap().config("lint").unwrap();
+    assert_eq!(conf.snap.volumes.len(), 2);
+    assert_eq!(conf.priority.nice, Some(5));
+    assert_eq!(conf.priority.ionice_class, Some(3));
+
+    write("other.yaml", "include: main.yaml\n");
+    let e = Document::load(&main).unwrap_err();
+    assert_eq!(e.to_string(), format!("{:?}: includes itself", main));
+}
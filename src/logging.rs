@@ -0,0 +1,157 @@
+//! Logging each run to a file.
+//!
+//! With a `logging` section in the config, everything written to stdout and
+//! stderr, by rack and by the commands it runs, is also written to a log
+//! file for the run, under `logs` in the state directory.  Only the newest
+//! logs are kept.  The output is copied by replacing our stdout and stderr
+//! with pipes, which commands inherit, and copying from them on a thread,
+//! so that nothing needs to know it is being logged.
+
+use crate::{config::LoggingConfig, journal::state_dir, Context, Result};
+use chrono::Local;
+use nix::unistd::{dup, dup2, pipe};
+use std::{
+    fs::{self, File},
+    io::{self, Read, Write},
+    os::unix::io::{AsRawFd, FromRawFd, OwnedFd},
+    path::PathBuf,
+    sync::{
+        mpsc::{self, Receiver},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+/// The logs, or event files, kept when the config doesn't say.
+const DEFAULT_KEEP: usize = 14;
+
+/// How long to wait, at the end, for the last output to be copied.
+const DRAIN_WAIT: Duration = Duration::from_secs(2);
+
+/// An output being copied to the log: the descriptor it is on, where it was
+/// going before, and word of when the copy is done.
+struct Tee {
+    fd: i32,
+    original: OwnedFd,
+    copied: Receiver<()>,
+}
+
+static TEES: Mutex<Vec<Tee>> = Mutex::new(Vec::new());
+
+/// Create the file for this run, of `operation`, in `dir`, or else in
+/// `default` in the state directory, named for the time, and ending in
+/// `ext`.  The oldest files, beyond the `keep` newest, are removed.
+pub(crate) fn run_file(
+    dir: Option<&str>,
+    default: &str,
+    keep: Option<usize>,
+    operation: &str,
+    ext: &str,
+) -> Result<File> {
+    let dir = match dir {
+        Some(dir) => PathBuf::from(dir),
+        None => state_dir()?.join(default),
+    };
+    fs::create_dir_all(&dir).context(format!("Unable to create {:?}", dir))?;
+    let name = format!("{}-{}{}", Local::now().format("%Y%m%d-%H%M%S"), operation, ext);
+    let path = dir.join(name);
+    let file = File::create(&path).context(format!("Unable to create {:?}", path))?;
+
+    let mut names = vec![];
+    for entry in fs::read_dir(&dir)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if name.ends_with(ext) {
+            names.push(name);
+        }
+    }
+    for name in expired(names, keep.unwrap_or(DEFAULT_KEEP)) {
+        if let Err(e) = fs::remove_file(dir.join(&name)) {
+            warning!("Unable to remove old {:?}: {}", name, e);
+        }
+    }
+    Ok(file)
+}
+
+/// Start logging this run, of `operation`, to a new file, and remove the
+/// logs beyond those to keep.
+pub fn start_log(conf: &LoggingConfig, operation: &str) -> Result<()> {
+    let mut file = run_file(conf.dir.as_deref(), "logs", conf.keep, operation, ".log")?;
+    let args: Vec<_> = std::env::args().collect();
+    writeln!(file, "{} started {}", args.join(" "), Local::now().to_rfc3339())?;
+
+    let file = Arc::new(Mutex::new(file));
+    let mut tees = TEES.lock().unwrap();
+    for out in [io::stdout().as_raw_fd(), io::stderr().as_raw_fd()].iter().copied() {
+        tees.push(tee(out, file.clone())?);
+    }
+    Ok(())
+}
+
+/// Copy what is written to `fd` to the log, as well as where it went.
+fn tee(fd: i32, log: Arc<Mutex<File>>) -> Result<Tee> {
+    let original = dup(fd).map_err(io::Error::from)?;
+    let original = unsafe { OwnedFd::from_raw_fd(original) };
+    let (reader, writer) = pipe().map_err(io::Error::from)?;
+    dup2(writer.as_raw_fd(), fd).map_err(io::Error::from)?;
+    drop(writer);
+
+    let mut out = File::from(original.try_clone()?);
+    let mut reader = File::from(reader);
+    let (done, copied) = mpsc::channel();
+    thread::spawn(move || {
+        let mut buf = [0u8; 4096];
+        // There is nowhere left to report a failure to write, so the copy
+        // just carries on.
+        while let Ok(count) = reader.read(&mut buf) {
+            if count == 0 {
+                break;
+            }
+            let _ = out.write_all(&buf[..count]);
+            let _ = log.lock().unwrap().write_all(&buf[..count]);
+        }
+        let _ = done.send(());
+    });
+    Ok(Tee {
+        fd: fd,
+        original: original,
+        copied: copied,
+    })
+}
+
+/// Stop logging, once everything has been written, putting stdout and
+/// stderr back as they were.
+pub fn finish_log() {
+    let _ = io::stdout().flush();
+    let _ = io::stderr().flush();
+    for tee in TEES.lock().unwrap().drain(..) {
+        // Closing our end of the pipe ends the copy, unless a command that
+        // was killed left something behind still holding it.
+        if dup2(tee.original.as_raw_fd(), tee.fd).is_ok() {
+            let _ = tee.copied.recv_timeout(DRAIN_WAIT);
+        }
+    }
+}
+
+/// The logs to remove, given the names of all of them, to keep the newest
+/// `keep`.  The names start with the time, so sort in the order they were
+/// written.
+fn expired(mut names: Vec<String>, keep: usize) -> Vec<String> {
+    names.sort();
+    let count = names.len().saturating_sub(keep);
+    names.truncate(count);
+    names
+}
+
+#[test]
+fn test_expired() {
+    let names = vec![
+        "20240302-010000-auto.log".to_string(),
+        "20240301-010000-auto.log".to_string(),
+        "20240303-010000-auto.log".to_string(),
+        "20240302-120000-clone.log".to_string(),
+    ];
+    let oldest = vec!["20240301-010000-auto.log", "20240302-010000-auto.log"];
+    assert_eq!(expired(names.clone(), 2), oldest);
+    assert!(expired(names, 4).is_empty());
+}
@@ -1,72 +1,102 @@
 //! Manage lvm snapshots.
 
-use chrono::{Datelike, Local};
+use chrono::{Datelike, Duration, Local, NaiveDate};
+use serde::{de, Deserialize, Deserializer};
+use serde_derive::Deserialize;
 use std::{
     collections::{HashMap, HashSet},
-    io::{BufRead, BufReader},
+    path::Path,
     process::{Command, Stdio},
+    result,
+    str::FromStr,
 };
 
 use crate::checked::CheckedExt;
+use crate::mount;
+use crate::plan::Plan;
 use crate::Result;
+use thiserror::Error;
+
+/// Errors from lvm operations.
+#[derive(Debug, Error)]
+pub enum LvmError {
+    #[error("Logical volume {vg}/{lv} not present")]
+    NotFound { vg: String, lv: String },
+    #[error(
+        "Thin pool {vg}/{pool} is too full: data {data:.1}%, metadata {metadata:.1}% \
+         (limit {threshold:.1}%)"
+    )]
+    PoolFull {
+        vg: String,
+        pool: String,
+        data: f64,
+        metadata: f64,
+        threshold: f64,
+    },
+    #[error("unexpected output from lvm: {0}")]
+    BadOutput(String),
+}
 
 #[derive(Debug)]
 pub struct Lvm {
     vg: String,
     lv: String,
     snaps: Vec<String>,
+    /// The usage of the thin pool the volume is in, if it is thin.
+    pool: Option<PoolUsage>,
+}
+
+/// How full a thin pool is, in percent.
+#[derive(Debug)]
+pub struct PoolUsage {
+    pub name: String,
+    pub data: f64,
+    pub metadata: f64,
 }
 
 impl Lvm {
     /// Scan the system for LVM partitions releated to the specified one.
     pub fn scan(vg: &str, lv: &str) -> Result<Lvm> {
-        let out = Command::new("lvs")
-            .args(&[
-                "--nameprefixes",
-                "--noheadings",
-                "--all",
-                "--units",
-                "b",
-                "--nosuffix",
-            ]).stderr(Stdio::inherit())
-            .checked_output()?;
-        let buf = out.stdout;
+        let lvs = report()?;
 
         let mut main = None;
         let mut snaps = vec![];
-
-        for line in BufReader::new(&buf[..]).lines() {
-            let line = line?;
-            // println!("line: {:?}", line);
-            let fields = parse(&line);
-
-            // We care about either the named vg (which should have no origin), or ones that
-            // reference this as an origin.
-            if fields.get("LVM2_VG_NAME").map(|x| x.as_str()) != Some(vg) {
-                continue;
+        let mut pools = HashMap::new();
+
+        // We care about either the named vg (which should have no origin), or ones that
+        // reference this as an origin.
+        for rec in lvs.iter().filter(|r| r.vg_name == vg) {
+            // Remember every thin pool, since the volume may be in one.
+            if rec.lv_attr.starts_with('t') {
+                let usage = PoolUsage {
+                    name: rec.lv_name.clone(),
+                    data: rec.data_percent.unwrap_or(0.0),
+                    metadata: rec.metadata_percent.unwrap_or(0.0),
+                };
+                pools.insert(rec.lv_name.as_str(), usage);
             }
 
-            if fields.get("LVM2_LV_NAME").map(|x| x.as_str()) == Some(lv)
-                && fields.get("LVM2_ORIGIN").map(|x| x.as_str()) == Some("")
-            {
+            if rec.lv_name == lv && rec.origin.is_empty() {
                 if main.is_some() {
-                    panic!("Duplicate record for vg/lv");
+                    let msg = format!("Duplicate record for {}/{}", vg, lv);
+                    return Err(LvmError::BadOutput(msg).into());
                 }
-                main = Some(fields);
-            } else if fields.get("LVM2_ORIGIN").map(|x| x.as_str()) == Some(lv) {
-                snaps.push(fields);
+                main = Some(rec);
+            } else if rec.origin == lv {
+                snaps.push(rec.lv_name.clone());
             }
         }
 
-        let main = main.expect("VG not present");
+        let main = main.ok_or_else(|| LvmError::NotFound {
+            vg: vg.to_string(),
+            lv: lv.to_string(),
+        })?;
 
         Ok(Lvm {
-            vg: main.get("LVM2_VG_NAME").expect("vg_name field").clone(),
-            lv: main.get("LVM2_LV_NAME").expect("lv_name field").clone(),
-            snaps: snaps
-                .iter()
-                .map(|x| x.get("LVM2_LV_NAME").expect("lv_name in snapshot").clone())
-                .collect(),
+            vg: main.vg_name.clone(),
+            lv: main.lv_name.clone(),
+            snaps: snaps,
+            pool: pools.remove(main.pool_lv.as_str()),
         })
     }
 
@@ -99,24 +129,201 @@ impl Lvm {
         unreachable!();
     }
 
-    /// Create a new lvm snapshot of the given name.
-    pub fn create_snapshot(&mut self, name: &str) -> Result<()> {
+    /// Check that the thin pool holding this volume has room for another
+    /// snapshot: that neither its data nor its metadata are more than
+    /// `threshold` percent used.  Running a thin pool out of space corrupts
+    /// every volume in it.  With `warn_only`, just print a warning.
+    pub fn check_pool(&self, threshold: f64, warn_only: bool) -> Result<()> {
+        let pool = match self.pool {
+            Some(ref pool) => pool,
+            None => return Ok(()),
+        };
+        if pool.data <= threshold && pool.metadata <= threshold {
+            return Ok(());
+        }
+        let err = LvmError::PoolFull {
+            vg: self.vg.clone(),
+            pool: pool.name.clone(),
+            data: pool.data,
+            metadata: pool.metadata,
+            threshold: threshold,
+        };
+        if warn_only {
+            warning!("Warning: {}", err);
+            Ok(())
+        } else {
+            Err(err.into())
+        }
+    }
+
+    /// Create a new lvm snapshot of the given name.  Without a `size`, the
+    /// volume must be thin.  With a `size`, a classic copy-on-write snapshot
+    /// is made, with that much space for changes.
+    pub fn create_snapshot(&mut self, name: &str, size: Option<&str>) -> Result<()> {
         let origin = format!("{}/{}", self.vg, self.lv);
-        Command::new("lvcreate")
-            .args(&["-s", "-n", name, &origin])
-            .checked_run()?;
+        let mut cmd = Command::new("lvcreate");
+        cmd.args(&["-s", "-n", name]);
+        if let Some(size) = size {
+            cmd.args(&["-L", size]);
+        }
+        cmd.arg(&origin).checked_run()?;
 
         // Add this snapshot to our list.
         self.snaps.push(name.to_string());
         Ok(())
     }
 
+    /// How much of the space of a classic snapshot has been used, in
+    /// percent.  When this reaches 100, the snapshot becomes invalid.
+    pub fn snapshot_usage(&self, name: &str) -> Result<f64> {
+        let full = format!("{}/{}", self.vg, name);
+        report_on(Some(&full))?
+            .into_iter()
+            .find(|rec| rec.vg_name == self.vg && rec.lv_name == name)
+            .and_then(|rec| rec.data_percent)
+            .ok_or_else(|| LvmError::BadOutput(format!("No snapshot usage for {}", full)).into())
+    }
+
+    /// Plan the removal of old snapshots.  The newest `keep_last` snapshots
+    /// are kept, as are any taken within the last `keep_days` days.  If
+    /// neither is given, everything is kept.  Snapshots whose names don't
+    /// have the form given by `new_name` weren't made by rack, and are left
+    /// alone.
+    pub fn prune(&self, keep_last: Option<usize>, keep_days: Option<i64>, plan: &mut Plan) {
+        if keep_last.is_none() && keep_days.is_none() {
+            return;
+        }
+
+        let mut dated: Vec<_> = self
+            .snaps
+            .iter()
+            .filter_map(|s| snap_date(&self.lv, s).map(|d| (d, s.clone())))
+            .collect();
+        // Newest first.
+        dated.sort_by(|a, b| b.0.cmp(&a.0));
+
+        let cutoff = keep_days.map(|d| Local::now().date_naive() - Duration::days(d));
+        for (i, ((date, _), name)) in dated.iter().enumerate() {
+            let by_count = keep_last.map_or(false, |n| i < n);
+            let by_age = cutoff.map_or(false, |c| *date >= c);
+            if by_count || by_age {
+                decision!(" keep {}/{}", self.vg, name);
+                continue;
+            }
+
+            plan.run(
+                format!("Remove snapshot {}/{}", self.vg, name),
+                Command::new("lvremove").args(&["-y", &format!("{}/{}", self.vg, name)]),
+            );
+        }
+    }
+
     /// Mount the given LV snapshot, returning an object that will unmount it when dropped.
     pub fn mount_snapshot(&self, name: &str, mountpoint: &str) -> Result<SnapMount> {
         SnapMount::mount(self, name.to_owned(), mountpoint.to_owned())
     }
 }
 
+/// Whether `name` is that of a snapshot rack made of the volume `lv`.
+pub fn made_by_rack(lv: &str, name: &str) -> bool {
+    snap_date(lv, name).is_some()
+}
+
+/// Decode the date, and the disambiguating suffix, from the name of a
+/// snapshot made by `new_name`.  The suffix is returned with its length, so
+/// that the result sorts in the order the snapshots were made.
+fn snap_date(lv: &str, name: &str) -> Option<(NaiveDate, (usize, String))> {
+    let rest = name.strip_prefix(lv)?.strip_prefix('-')?;
+    if rest.len() < 10 || !rest.is_char_boundary(10) {
+        return None;
+    }
+    let date = NaiveDate::parse_from_str(&rest[..10], "%Y-%m-%d").ok()?;
+    let suffix = &rest[10..];
+    if !suffix.chars().all(|c| c.is_ascii_lowercase()) {
+        return None;
+    }
+    Some((date, (suffix.len(), suffix.to_string())))
+}
+
+/// A single logical volume, from the lvs json report.  Numeric fields are
+/// reported as strings, which are empty when the field doesn't apply.
+#[derive(Debug, Deserialize)]
+pub struct LvRecord {
+    pub lv_name: String,
+    pub vg_name: String,
+    pub lv_attr: String,
+    #[serde(deserialize_with = "number")]
+    pub lv_size: Option<u64>,
+    pub pool_lv: String,
+    pub origin: String,
+    #[serde(deserialize_with = "number")]
+    pub data_percent: Option<f64>,
+    #[serde(deserialize_with = "number")]
+    pub metadata_percent: Option<f64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Report {
+    report: Vec<ReportSection>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ReportSection {
+    lv: Vec<LvRecord>,
+}
+
+/// Decode a numeric field that lvs gives as a possibly empty string.
+fn number<'de, D, T>(d: D) -> result::Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+{
+    let text = String::deserialize(d)?;
+    if text.is_empty() {
+        return Ok(None);
+    }
+    text.parse()
+        .map(Some)
+        .map_err(|_| de::Error::custom(format!("invalid number {:?}", text)))
+}
+
+/// Get all of the logical volumes on the system.
+pub fn report() -> Result<Vec<LvRecord>> {
+    report_on(None)
+}
+
+/// Get the logical volume `lv`, given as "vg/lv", or all of them.  The
+/// numbers are asked for without the locale's decimal separator.
+fn report_on(lv: Option<&str>) -> Result<Vec<LvRecord>> {
+    let mut cmd = Command::new("lvs");
+    cmd.env("LC_ALL", "C");
+    cmd.args(&[
+            "--reportformat",
+            "json",
+            "--all",
+            "--units",
+            "b",
+            "--nosuffix",
+            "-o",
+            "lv_name,vg_name,lv_attr,lv_size,pool_lv,origin,data_percent,metadata_percent",
+        ]);
+    cmd.args(lv);
+    let out = cmd.stderr(Stdio::inherit()).checked_output()?;
+    parse_report(&out.stdout)
+}
+
+impl LvRecord {
+    /// Whether this is a thin volume, or a snapshot of one.
+    pub fn is_thin(&self) -> bool {
+        self.lv_attr.starts_with('V')
+    }
+}
+
+fn parse_report(text: &[u8]) -> Result<Vec<LvRecord>> {
+    let report: Report = serde_json::from_slice(text)?;
+    Ok(report.report.into_iter().flat_map(|s| s.lv).collect())
+}
+
 /// A suffix generator.  Generates strings of the form "a" - "z", then "aa" - "zz".
 struct SuffixGen {
     suffix: u32,
@@ -185,9 +392,7 @@ impl SnapMount {
         Command::new("fsck").args(&["-p", &devname]).checked_run()?;
 
         // Mount the filesystem.
-        Command::new("mount")
-            .args(&["-r", &devname, &me.mountpoint])
-            .checked_run()?;
+        mount::read_only(&devname, None, Path::new(&me.mountpoint))?;
         me.mounted = true;
 
         Ok(me)
@@ -197,11 +402,8 @@ impl SnapMount {
 impl Drop for SnapMount {
     fn drop(&mut self) {
         if self.mounted {
-            let st = Command::new("umount")
-                .args(&[&self.mountpoint])
-                .checked_run();
-            match st {
-                Err(e) => eprintln!("Error umounting: {:?}", e),
+            match mount::unmount(Path::new(&self.mountpoint), false) {
+                Err(e) => warning!("Error umounting: {:?}", e),
                 Ok(()) => (),
             }
         }
@@ -211,63 +413,73 @@ impl Drop for SnapMount {
             .args(&["-an", "-K", &self.lvm_name])
             .checked_run();
         match st {
-            Err(e) => eprintln!("Error running lvchange: {:?}", e),
+            Err(e) => warning!("Error running lvchange: {:?}", e),
             Ok(()) => (),
         }
     }
 }
 
-enum States {
-    Sep,
-    Name,
-    BQuote,
-    Value,
+#[test]
+fn test_snap_date() {
+    let date = NaiveDate::from_ymd_opt(2024, 3, 5).unwrap();
+    assert_eq!(snap_date("home", "home-2024-03-05"), Some((date, (0, "".into()))));
+    assert_eq!(snap_date("home", "home-2024-03-05ab"), Some((date, (2, "ab".into()))));
+    assert!(snap_date("home", "home-2024-03-05ab") > snap_date("home", "home-2024-03-05z"));
+    assert_eq!(snap_date("home", "homer-2024-03-05"), None);
+    assert_eq!(snap_date("home", "home-backup"), None);
+    assert!(made_by_rack("home", "home-2024-03-05ab"));
+    assert!(!made_by_rack("home", "home-backup"));
 }
 
-type Map = HashMap<String, String>;
-
-fn parse(line: &str) -> Map {
-    use self::States::*;
-
-    let mut state = Sep;
-    let mut key = String::new();
-    let mut value = String::new();
-    let mut result = Map::new();
-
-    for ch in line.chars() {
-        match state {
-            Sep => {
-                if ch != ' ' {
-                    key.clear();
-                    key.push(ch);
-                    state = Name;
-                }
-            }
-            Name => {
-                if ch == '=' {
-                    state = BQuote;
-                } else {
-                    key.push(ch);
-                }
-            }
-            BQuote => {
-                if ch == '\'' {
-                    state = Value;
-                    value.clear();
-                } else {
-                    panic!("Unexpected character");
-                }
-            }
-            Value => {
-                if ch == '\'' {
-                    state = Sep;
-                    result.insert(key.to_string(), value.to_string());
-                } else {
-                    value.push(ch);
-                }
+#[test]
+fn test_parse_report() {
+    let text = br#"{
+        "report": [
+            {
+                "lv": [
+                    {"lv_name":"pool", "vg_name":"vg", "lv_attr":"twi-aotz--",
+                     "lv_size":"1000", "pool_lv":"", "origin":"",
+                     "data_percent":"42.50", "metadata_percent":"7.00"},
+                    {"lv_name":"home", "vg_name":"vg", "lv_attr":"Vwi-aotz--",
+                     "lv_size":"500", "pool_lv":"pool", "origin":"",
+                     "data_percent":"10.00", "metadata_percent":""}
+                ]
             }
-        }
-    }
+        ]
+    }"#;
+    let lvs = parse_report(text).unwrap();
+    assert_eq!(lvs.len(), 2);
+    assert_eq!(lvs[0].data_percent, Some(42.5));
+    assert_eq!(lvs[1].lv_size, Some(500));
+    assert_eq!(lvs[1].metadata_percent, None);
+    assert_eq!(lvs[1].pool_lv, "pool");
+    assert!(!lvs[0].is_thin());
+    assert!(lvs[1].is_thin());
+}
 
-    result
+#[test]
+fn test_snapshot_usage() {
+    use crate::checked::{set_executor, RecordingExecutor};
+    use std::sync::Arc;
+
+    let exec = Arc::new(RecordingExecutor::new());
+    exec.respond(
+        &["lvs"],
+        r#"{"report": [{"lv": [
+            {"lv_name":"home-2024-03-05", "vg_name":"vg", "lv_attr":"swi-aos---",
+             "lv_size":"1000", "pool_lv":"", "origin":"home",
+             "data_percent":"12.34", "metadata_percent":""}
+        ]}]}"#,
+    );
+    let running = set_executor(exec.clone());
+    let lvm = Lvm {
+        vg: "vg".into(),
+        lv: "home".into(),
+        snaps: vec![],
+        pool: None,
+    };
+    assert_eq!(lvm.snapshot_usage("home-2024-03-05").unwrap(), 12.34);
+    assert!(lvm.snapshot_usage("home-2024-03-06").is_err());
+    drop(running);
+    assert!(exec.commands()[0].ends_with(" vg/home-2024-03-05"));
 }
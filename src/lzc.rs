@@ -0,0 +1,121 @@
+//! Snapshots through libzfs_core.
+//!
+//! Built with the `libzfs_core` feature, plans create and destroy snapshots
+//! by calling libzfs_core, rather than running a `zfs` command for each,
+//! which is most of the time taken pruning a system with many thousands of
+//! snapshots.  Everything else still runs the `zfs` command, including
+//! listing, which is a single command per run, and which libzfs_core has no
+//! call for.
+
+use crate::{Context, Result};
+use std::{
+    ffi::CString,
+    io,
+    os::raw::{c_char, c_int, c_uint},
+    ptr,
+    sync::Once,
+};
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+struct nvlist_t {
+    _private: [u8; 0],
+}
+
+const NV_UNIQUE_NAME: c_uint = 1;
+
+#[link(name = "nvpair")]
+extern "C" {
+    fn nvlist_alloc(nvlp: *mut *mut nvlist_t, nvflag: c_uint, kmflag: c_int) -> c_int;
+    fn nvlist_free(nvl: *mut nvlist_t);
+    fn nvlist_add_boolean(nvl: *mut nvlist_t, name: *const c_char) -> c_int;
+}
+
+#[link(name = "zfs_core")]
+extern "C" {
+    fn libzfs_core_init() -> c_int;
+    fn lzc_snapshot(
+        snaps: *mut nvlist_t,
+        props: *mut nvlist_t,
+        errlist: *mut *mut nvlist_t,
+    ) -> c_int;
+    fn lzc_destroy_snaps(snaps: *mut nvlist_t, defer: c_int, errlist: *mut *mut nvlist_t) -> c_int;
+}
+
+/// A list of names, as libzfs_core takes snapshots to create or destroy.
+struct Names(*mut nvlist_t);
+
+impl Names {
+    fn new(items: &[&str]) -> Result<Names> {
+        let mut list = ptr::null_mut();
+        check(unsafe { nvlist_alloc(&mut list, NV_UNIQUE_NAME, 0) })?;
+        let names = Names(list);
+        for item in items {
+            let item = CString::new(*item).map_err(io::Error::from)?;
+            check(unsafe { nvlist_add_boolean(names.0, item.as_ptr()) })?;
+        }
+        Ok(names)
+    }
+}
+
+impl Drop for Names {
+    fn drop(&mut self) {
+        unsafe { nvlist_free(self.0) };
+    }
+}
+
+fn check(err: c_int) -> Result<()> {
+    if err == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::from_raw_os_error(err).into())
+    }
+}
+
+fn init() -> Result<()> {
+    static INIT: Once = Once::new();
+    let mut err = 0;
+    INIT.call_once(|| err = unsafe { libzfs_core_init() });
+    check(err).context("Unable to open /dev/zfs")
+}
+
+/// Run a command from a plan through libzfs_core, if it is one that can
+/// be: a plain `zfs snapshot` or `zfs destroy` of a single snapshot.
+pub fn run(command: &[String]) -> Option<Result<()>> {
+    let (op, snap) = match command {
+        [zfs, op, snap] if zfs == "zfs" && snap.contains('@') => (op.as_str(), snap),
+        _ => return None,
+    };
+    let result = match op {
+        "snapshot" => snapshot(snap),
+        "destroy" => destroy(snap),
+        _ => return None,
+    };
+    Some(result.context(format!("zfs {} {}", op, snap)))
+}
+
+fn snapshot(snap: &str) -> Result<()> {
+    init()?;
+    let snaps = Names::new(&[snap])?;
+    let mut errors = ptr::null_mut();
+    let err = unsafe { lzc_snapshot(snaps.0, ptr::null_mut(), &mut errors) };
+    free_errors(errors);
+    check(err)
+}
+
+fn destroy(snap: &str) -> Result<()> {
+    init()?;
+    let snaps = Names::new(&[snap])?;
+    let mut errors = ptr::null_mut();
+    let err = unsafe { lzc_destroy_snaps(snaps.0, 0, &mut errors) };
+    free_errors(errors);
+    check(err)
+}
+
+/// The per-snapshot errors aren't needed, since only one snapshot is given
+/// at a time.
+fn free_errors(errors: *mut nvlist_t) {
+    if !errors.is_null() {
+        unsafe { nvlist_free(errors) };
+    }
+}
@@ -4,7 +4,12 @@
 use rack;
 
 use chrono::Utc;
-use std::path::Path;
+use std::{
+    path::{Path, PathBuf},
+    process,
+    sync::Arc,
+    time::Duration,
+};
 use structopt::StructOpt;
 
 #[derive(StructOpt)]
@@ -13,6 +18,18 @@ struct Opt {
     /// Override default config file.  Default ~/.gack.yaml.
     #[structopt(long = "config")]
     config: Option<String>,
+    /// Send a digest of what was done through the notify section when done
+    #[structopt(long = "digest")]
+    digest: bool,
+    /// Run only these volumes, of any section, even if the config skips them
+    #[structopt(long = "only", use_delimiter = true)]
+    only: Vec<String>,
+    /// Skip these volumes, of any section
+    #[structopt(long = "skip", use_delimiter = true)]
+    skip: Vec<String>,
+    /// Ask restic for its snapshots again, instead of using the kept listings
+    #[structopt(long = "refresh")]
+    refresh: bool,
     #[structopt(subcommand)]
     command: Command,
 }
@@ -25,6 +42,26 @@ enum Command {
         #[structopt(long = "fs", default_value = "lint/ext4gentoo")]
         /// ZFS filesystem name
         fs: String,
+
+        #[structopt(long = "name")]
+        /// Sync volume from .gack.yaml to sync, instead of the root volume
+        name: Option<String>,
+
+        #[structopt(long = "all")]
+        /// Sync every volume in .gack.yaml
+        all: bool,
+
+        #[structopt(short = "j", long = "jobs", default_value = "1")]
+        /// With --all, the number of volumes to sync at once
+        jobs: usize,
+
+        #[structopt(long = "bwlimit")]
+        /// Limit rsync bandwidth (passed to rsync --bwlimit)
+        bwlimit: Option<String>,
+
+        #[structopt(short = "n", long = "pretend")]
+        /// Show what rsync would change, without making a snapshot
+        pretend: bool,
     },
 
     #[structopt(name = "hsync")]
@@ -33,6 +70,30 @@ enum Command {
         #[structopt(long = "fs", default_value = "lint/ext4home")]
         /// ZFS filesystem name
         fs: String,
+
+        #[structopt(long = "bwlimit")]
+        /// Limit rsync bandwidth (passed to rsync --bwlimit)
+        bwlimit: Option<String>,
+
+        #[structopt(short = "n", long = "pretend")]
+        /// Show what rsync would change, without making a snapshot
+        pretend: bool,
+    },
+
+    #[structopt(name = "sync-prune")]
+    /// Remove old lvm snapshots made by syncs
+    SyncPrune {
+        #[structopt(long = "really")]
+        /// Actually remove the snapshots
+        really: bool,
+    },
+
+    #[structopt(name = "containers")]
+    /// Copy or snapshot container volumes, with their containers stopped
+    Containers {
+        #[structopt(short = "n", long = "pretend")]
+        /// Show the volumes and containers, without stopping anything
+        pretend: bool,
     },
 
     #[structopt(name = "snap")]
@@ -43,6 +104,21 @@ enum Command {
         pretend: bool,
     },
 
+    #[structopt(name = "renumber")]
+    /// Rename numbered snapshots to the padding configured for their prefix.
+    Renumber {
+        #[structopt(long = "prefix", default_value = "caz")]
+        /// Snapshot prefix
+        prefix: String,
+
+        #[structopt(long = "really")]
+        /// Actually rename the snapshots
+        really: bool,
+
+        /// Filesystem, whose snapshots, and those of its children, are renamed
+        filesystem: String,
+    },
+
     #[structopt(name = "cloneone")]
     /// Clone one volume tree to another.  With explicit arguments
     CloneOneCmd {
@@ -54,6 +130,26 @@ enum Command {
         /// Don't actually do the clone, but show what would be done
         pretend: bool,
 
+        #[structopt(long = "host")]
+        /// Pull the source from this host, running zfs send there over ssh
+        host: Option<String>,
+
+        #[structopt(long = "rate-limit")]
+        /// Most bytes per second to send, such as 10M
+        rate_limit: Option<String>,
+
+        #[structopt(long = "idle-timeout")]
+        /// Kill the clone once it has sent nothing for this many seconds
+        idle_timeout: Option<u64>,
+
+        #[structopt(long = "keep-partial")]
+        /// Keep what a failed receive got, to resume, rather than aborting it
+        keep_partial: bool,
+
+        #[structopt(long = "resume")]
+        /// Resume the receives kept by earlier clones first
+        resume: bool,
+
         /// Source zfs filesystem
         source: String,
 
@@ -67,6 +163,22 @@ enum Command {
         #[structopt(short = "n", long = "pretend")]
         /// Don't actually do the work, just show what would be done
         pretend: bool,
+
+        #[structopt(long = "resume")]
+        /// Resume the receives kept by earlier clones first
+        resume: bool,
+
+        #[structopt(long = "verify")]
+        /// Check the snapshots received, and a sample of files, afterwards
+        verify: bool,
+
+        #[structopt(long = "delete")]
+        /// Instead of cloning, show the destinations whose source is gone
+        delete: bool,
+
+        #[structopt(long = "really")]
+        /// With --delete, destroy them
+        really: bool,
     },
 
     #[structopt(name = "prune")]
@@ -77,12 +189,54 @@ enum Command {
         really: bool,
     },
 
+    #[structopt(name = "prune-all")]
+    /// Prune the snapshots each convention doesn't keep from zfs, restic, borg and sure
+    PruneAll {
+        #[structopt(long = "really")]
+        /// Actually do the prune
+        really: bool,
+    },
+
     #[structopt(name = "sure")]
     /// Update rsure data
     Sure {
         /// Don't actually do the operation, but show what would be done.
         #[structopt(short = "n", long = "pretend")]
         pretend: bool,
+
+        #[structopt(long = "limit")]
+        /// Limit how many snapshots are captured.
+        limit: Option<usize>,
+    },
+
+    #[structopt(name = "sure-verify")]
+    /// Compare the latest rsure data against the live filesystem
+    SureVerify {
+        #[structopt(long = "volume")]
+        /// Sure volume from .gack.yaml to check, defaults to all.
+        volume: Option<String>,
+
+        #[structopt(long = "snapshot")]
+        /// Compare against this zfs snapshot instead of the live filesystem.
+        snapshot: Option<String>,
+    },
+
+    #[structopt(name = "sure-diff")]
+    /// Show the differences between two captured rsure versions
+    SureDiff {
+        #[structopt(long = "volume")]
+        /// Sure volume from .gack.yaml.
+        volume: String,
+
+        #[structopt(long = "json")]
+        /// Write the report as JSON.
+        json: bool,
+
+        /// The older version (snapshot name).
+        old: String,
+
+        /// The newer version (snapshot name).
+        new: String,
     },
 
     #[structopt(name = "borg")]
@@ -92,17 +246,37 @@ enum Command {
         /// Don't actually do the backups, but show what would be done.
         pretend: bool,
 
-        #[structopt(long = "fs", default_value = "lint/ext4gentoo")]
-        /// ZFS filesystem name
-        fs: String,
+        #[structopt(long = "volume")]
+        /// Borg volume from .gack.yaml to back up.
+        volume: Option<String>,
+
+        #[structopt(long = "fs")]
+        /// ZFS filesystem name, to back up a volume not in the config
+        fs: Option<String>,
 
-        #[structopt(long = "repo", default_value = "/lint/borgs/linaro")]
+        #[structopt(long = "bind")]
+        /// Directory to bind mount snapshots on while backing up
+        bind: Option<String>,
+
+        #[structopt(long = "repo")]
         /// Borg repo path
-        repo: String,
+        repo: Option<String>,
 
-        #[structopt(long = "name", default_value = "gentoo-")]
+        #[structopt(long = "name")]
         /// Borg backup name prefix
-        name: String,
+        name: Option<String>,
+
+        #[structopt(long = "limit")]
+        /// Limit how many archives are made.
+        limit: Option<usize>,
+    },
+
+    #[structopt(name = "borg-prune")]
+    /// Prune borg archives according to each volume's convention
+    BorgPrune {
+        #[structopt(long = "really")]
+        /// Actually do the prune, instead of a dry run
+        really: bool,
     },
 
     #[structopt(name = "restic")]
@@ -121,60 +295,649 @@ enum Command {
         limit: Option<usize>,
     },
 
+    #[structopt(name = "verify")]
+    /// Restore a sample of files from backups, and check them against rsure.
+    Verify {
+        #[structopt(long = "volume")]
+        /// Restic volume from .gack.yaml to verify, instead of a random sample of backups.
+        volume: Option<String>,
+
+        #[structopt(long = "tag")]
+        /// Snapshot tag to verify, defaults to the most recent.
+        tag: Option<String>,
+
+        #[structopt(long = "count")]
+        /// Number of files to restore and check, defaults to 20.
+        count: Option<usize>,
+    },
+
+    #[structopt(name = "sure-versions")]
+    /// List the captured versions in the sure stores.
+    SureVersions {
+        #[structopt(long = "volume")]
+        /// Sure volume from .gack.yaml to list, defaults to all.
+        volume: Option<String>,
+
+        #[structopt(long = "tag")]
+        /// Only show versions with this tag, given as key=value, or just
+        /// key.  May be given more than once.
+        tags: Vec<String>,
+    },
+
+    #[structopt(name = "gc")]
+    /// Clean up mounts and snapshots left behind by a crashed run.
+    Gc {
+        #[structopt(short = "n", long = "pretend")]
+        /// Show what would be cleaned up, without doing it.
+        pretend: bool,
+    },
+
+    #[structopt(name = "check")]
+    /// Check the config file for problems.
+    Check,
+
+    #[structopt(name = "plan")]
+    /// Work out what an operation would do, and save it to be applied later.
+    Plan {
+        #[structopt(short = "o", long = "output")]
+        /// File to write the plan to.
+        output: String,
+
+        #[structopt(subcommand)]
+        operation: PlanOp,
+    },
+
+    #[structopt(name = "apply")]
+    /// Carry out a plan saved by "rack plan".
+    Apply {
+        /// The plan file.
+        plan: String,
+    },
+
+    #[structopt(name = "cloud")]
+    /// Upload new snapshots to cloud storage with rclone
+    Cloud {
+        #[structopt(long = "name")]
+        /// Cloud volume to upload, instead of all of them
+        name: Option<String>,
+
+        #[structopt(short = "n", long = "pretend")]
+        /// Show what would be uploaded, without uploading it
+        pretend: bool,
+    },
+
+    #[structopt(name = "cloud-restore")]
+    /// Restore a cloud volume into a new zfs filesystem
+    CloudRestore {
+        #[structopt(long = "snapshot")]
+        /// Snapshot to restore up to, instead of the latest uploaded
+        snapshot: Option<String>,
+
+        #[structopt(short = "n", long = "pretend")]
+        /// Show the streams that would be received, without receiving them
+        pretend: bool,
+
+        /// Cloud volume to restore
+        volume: String,
+
+        /// Zfs filesystem to receive into
+        dest: String,
+    },
+
+    #[structopt(name = "export")]
+    /// Write new snapshots to files on a removable drive
+    Export {
+        #[structopt(long = "target")]
+        /// Configured drive, or where a drive is mounted, instead of the attached one
+        target: Option<String>,
+
+        #[structopt(long = "name")]
+        /// Export volume to write, instead of all of them
+        name: Option<String>,
+
+        #[structopt(short = "n", long = "pretend")]
+        /// Show what would be written, without writing it
+        pretend: bool,
+    },
+
+    #[structopt(name = "import")]
+    /// Receive an export volume from a removable drive into a new zfs filesystem
+    Import {
+        #[structopt(long = "target")]
+        /// Configured drive, or where a drive is mounted, instead of the attached one
+        target: Option<String>,
+
+        #[structopt(long = "snapshot")]
+        /// Snapshot to receive up to, instead of the latest exported
+        snapshot: Option<String>,
+
+        #[structopt(short = "n", long = "pretend")]
+        /// Show the streams that would be received, without receiving them
+        pretend: bool,
+
+        /// Export volume to import
+        volume: String,
+
+        /// Zfs filesystem to receive into
+        dest: String,
+    },
+
+    #[structopt(name = "status")]
+    /// Show what is on each export drive, and warn of any getting stale
+    Status,
+
+    #[structopt(name = "auto")]
+    /// Run each configured step: snapshot, sync, clone, sure, restic, borg, prune
+    Auto {
+        /// Don't actually do the operation, but show what would be done.
+        #[structopt(short = "n", long = "pretend")]
+        pretend: bool,
+    },
+
+    #[structopt(name = "doctor")]
+    /// Check that the programs, repositories and directories the config needs are in place
+    Doctor,
+
+    #[structopt(name = "stale")]
+    /// List the volumes whose newest snapshot or backup is older than their convention allows
+    Stale,
+
+    #[structopt(name = "du")]
+    /// Show the space each snapshot of a volume holds on its own
+    Du {
+        #[structopt(long = "volume")]
+        /// Snap volume from .gack.yaml, or zfs filesystem, to show
+        volume: String,
+
+        #[structopt(long = "by-used")]
+        /// List the snapshots using the most space first, rather than the oldest
+        by_used: bool,
+    },
+
+    #[structopt(name = "adopt")]
+    /// List the snapshots made by other tools, and the convention each is adopted as
+    Adopt,
+
+    #[structopt(name = "changed")]
+    /// List the files changed since the last restic backup of a volume
+    Changed {
+        /// Snap volume from .gack.yaml, or zfs filesystem
+        volume: String,
+
+        #[structopt(long = "last")]
+        /// List what changed between the last two backups instead
+        last: bool,
+
+        #[structopt(long = "since")]
+        /// Snapshot to list the changes since, instead of the last backed up
+        since: Option<String>,
+
+        #[structopt(long = "until")]
+        /// Snapshot to list the changes until, instead of the filesystem now
+        until: Option<String>,
+    },
+
+    #[structopt(name = "mount")]
+    /// Mount a snapshot of a volume to browse, until "rack umount"
+    Mount {
+        /// Snap volume from .gack.yaml, or zfs filesystem
+        volume: String,
+
+        /// The snapshot: its name, a date such as 2024-06-01, or part of its name
+        snapshot: String,
+
+        #[structopt(long = "dir")]
+        /// Directory to mount it on, instead of one named for the volume
+        dir: Option<String>,
+    },
+
+    #[structopt(name = "restore")]
+    /// Copy files back from a snapshot of a volume
+    Restore {
+        /// Snap volume from .gack.yaml, or zfs filesystem
+        volume: String,
+
+        /// The snapshot: its name, a date such as 2024-06-01, or part of its name
+        snapshot: String,
+
+        #[structopt(required = true)]
+        /// Files or directories to restore, relative to the root of the volume
+        paths: Vec<String>,
+
+        #[structopt(long = "from-clone")]
+        /// Read the snapshot from the clone of the volume on this host
+        from_clone: bool,
+
+        #[structopt(long = "to")]
+        /// Directory to restore into, instead of the volume itself
+        to: Option<String>,
+
+        #[structopt(short = "n", long = "pretend")]
+        /// Show what would be written, without writing anything
+        pretend: bool,
+    },
+
+    #[structopt(name = "umount")]
+    /// Unmount snapshots mounted by "rack mount"
+    Umount {
+        /// Volume to unmount, instead of every one
+        volume: Option<String>,
+
+        #[structopt(long = "dir")]
+        /// Directory given to "rack mount" to unmount
+        dir: Option<String>,
+    },
+
     #[structopt(name = "hack")]
     /// Hacking work for new api.
     Hack,
 }
 
-fn main() -> rack::Result<()> {
+#[derive(StructOpt)]
+enum PlanOp {
+    #[structopt(name = "snap")]
+    /// Take a current snapshot of concerned volumes.
+    Snap,
+
+    #[structopt(name = "prune")]
+    /// Prune older snapshots
+    Prune,
+
+    #[structopt(name = "prune-all")]
+    /// Prune what each convention doesn't keep from every backend
+    PruneAll,
+
+    #[structopt(name = "sync-prune")]
+    /// Remove old lvm snapshots made by syncs
+    SyncPrune,
+
+    #[structopt(name = "clone-delete")]
+    /// Destroy clone destinations whose source is gone
+    CloneDelete,
+}
+
+fn main() {
+    let result = run();
+    if let Err(ref e) = result {
+        eprintln!("Error: {}", e);
+    }
+    // The log, if there is one, ends with the error.
+    rack::finish_log();
+    if result.is_err() {
+        process::exit(1);
+    }
+}
+
+fn run() -> rack::Result<()> {
     rsure::log_init();
+    rack::set_reporter(Arc::new(rack::ConsoleReporter));
+
+    let matches = Opt::clap().get_matches();
+    let opt = Opt::from_clap(&matches);
+    let (operation, sub) = matches.subcommand();
+    let operation = operation.to_string();
+    // A pretend run hasn't done the work the pings are watching for.
+    let pings = !sub.map_or(false, |m| m.is_present("pretend"));
 
-    let opt = Opt::from_args();
+    // Mounts made to browse are left for the user, so can't be made in a
+    // private namespace.
+    if operation == "mount" || operation == "umount" {
+        rack::stay_shared();
+    }
 
     let config_file = opt.config.as_ref().map_or_else(
         || rack::Config::get_default(),
         |c| Ok(Path::new(c).to_path_buf()),
     )?;
+    let loader = Loader {
+        file: config_file,
+        only: opt.only,
+        skip: opt.skip,
+    };
+
+    // Without a config, there is nothing to ping, and the command will say
+    // what is wrong with it.
+    let conf = rack::Config::load(&loader.file).ok();
+    if let Some(logging) = conf.as_ref().and_then(|c| c.logging.as_ref()) {
+        rack::start_log(logging, &operation)?;
+    }
+    let events = conf.as_ref().and_then(|c| c.events.as_ref());
+    if let Some(events) = events {
+        rack::start_events(events, &operation)?;
+    }
+    if let (Some(conf), true) = (&conf, pings) {
+        conf.ping_start(&operation);
+    }
+
+    if opt.refresh {
+        rack::refresh_listings();
+    }
+    if opt.digest {
+        rack::start_digest();
+        rack::set_reporter(Arc::new(rack::DigestReporter(Arc::new(rack::ConsoleReporter))));
+    }
+    let result = run_command(opt.command, &loader);
 
-    match opt.command {
-        Command::SyncCmd { fs } => {
-            rack::sync_root(&fs)?;
+    if let Some(ref conf) = conf {
+        if opt.digest {
+            // A digest that couldn't be sent has been warned about; the
+            // command's own result matters more.
+            let _ = rack::send_digest(&conf.notify, result.as_ref().err());
         }
-        Command::HSync { fs } => {
-            rack::sync_home(&fs)?;
+        if pings {
+            conf.ping_finish(&operation, result.as_ref().err());
+        }
+    }
+    if events.is_some() {
+        rack::finish_events(result.as_ref().err());
+    }
+    result
+}
+
+/// Loads the config, with the volumes picked out on the command line.
+struct Loader {
+    file: PathBuf,
+    only: Vec<String>,
+    skip: Vec<String>,
+}
+
+impl Loader {
+    fn load(&self) -> rack::Result<rack::Config> {
+        let mut conf = rack::Config::load(&self.file)?;
+        conf.select(&self.only, &self.skip)?;
+        Ok(conf)
+    }
+}
+
+fn run_command(command: Command, loader: &Loader) -> rack::Result<()> {
+    match command {
+        Command::SyncCmd {
+            fs,
+            name,
+            all,
+            jobs,
+            bwlimit,
+            pretend,
+        } => {
+            let bwlimit = bwlimit.as_ref().map(|s| s.as_str());
+            match (name, all) {
+                (Some(_), true) => {
+                    eprintln!("--name and --all can't be given together");
+                    process::exit(1);
+                }
+                (Some(name), false) => {
+                    let conf = loader.load()?;
+                    conf.sync(&name, bwlimit, pretend)?;
+                }
+                (None, true) => {
+                    let conf = loader.load()?;
+                    conf.sync_all(bwlimit, jobs, pretend)?;
+                }
+                (None, false) => rack::sync_root(&fs, bwlimit, pretend)?,
+            }
+        }
+        Command::HSync {
+            fs,
+            bwlimit,
+            pretend,
+        } => {
+            rack::sync_home(&fs, bwlimit.as_ref().map(|s| s.as_str()), pretend)?;
+        }
+        Command::SyncPrune { really } => {
+            let conf = loader.load()?;
+            conf.sync_prune(really)?;
+        }
+        Command::Containers { pretend } => {
+            let conf = loader.load()?;
+            conf.run_containers(pretend)?;
         }
         Command::Snap { pretend } => {
-            let conf = rack::Config::load(&config_file)?;
-            conf.snap.snapshot(Utc::now(), pretend)?;
+            let conf = loader.load()?;
+            let full_pools = conf.full_pools()?;
+            conf.snap.snapshot(&conf.inventory, &full_pools, Utc::now(), false, pretend)?;
+        }
+        Command::Renumber {
+            prefix,
+            really,
+            filesystem,
+        } => {
+            let conf = loader.load()?;
+            let clones = &conf.clone.volumes;
+            rack::renumber(&conf.inventory, clones, &prefix, &filesystem, !really)?;
         }
         Command::CloneOneCmd {
             excludes,
             pretend,
+            host,
+            rate_limit,
+            idle_timeout,
+            keep_partial,
+            resume,
             source,
             dest,
         } => {
             let excl: Vec<_> = excludes.iter().map(|x| x.as_str()).collect();
-            rack::clone(&source, &dest, !pretend, &excl)?;
+            let inv = rack::Inventory::new();
+            let options = rack::CloneOptions {
+                rate_limit: rate_limit,
+                pause: None,
+                idle_timeout: idle_timeout.map(Duration::from_secs),
+                keep_partial: keep_partial,
+                resume: resume,
+                renames: vec![],
+                recv_args: vec![],
+                create_set: vec![],
+                create_skip: vec![],
+            };
+            match host {
+                Some(host) => rack::pull(&inv, &host, &source, &dest, !pretend, &excl, &options)?,
+                None => rack::clone(&inv, &source, &dest, !pretend, &excl, &options)?,
+            }
         }
-        Command::CloneCmd { pretend } => {
-            let conf = rack::Config::load(&config_file)?;
-            conf.clone.run(pretend)?;
+        Command::CloneCmd {
+            pretend,
+            resume,
+            verify,
+            delete,
+            really,
+        } => {
+            let conf = loader.load()?;
+            if delete {
+                conf.clone.plan_delete(&conf.inventory)?.execute(!really || pretend)?;
+            } else {
+                conf.run_clone(resume, verify, pretend)?;
+            }
         }
         Command::Prune { really } => {
-            let conf = rack::Config::load(&config_file)?;
+            let conf = loader.load()?;
             conf.restic_prune(really)?;
         }
-        Command::Sure { pretend } => {
-            let conf = rack::Config::load(&config_file)?;
-            conf.sure.run(pretend)?;
+        Command::PruneAll { really } => {
+            let conf = loader.load()?;
+            conf.prune_all(really)?;
+        }
+        Command::Sure { pretend, limit } => {
+            let conf = loader.load()?;
+            conf.sure.run(&conf.inventory, limit, pretend)?;
         }
-        Command::Borg { fs, repo, name, pretend } => {
-            rack::run_borg(&fs, &repo, &name, pretend)?;
+        Command::SureVerify { volume, snapshot } => {
+            let conf = loader.load()?;
+            conf.sure_verify(
+                volume.as_ref().map(|s| s.as_str()),
+                snapshot.as_ref().map(|s| s.as_str()),
+            )?;
+        }
+        Command::SureVersions { volume, tags } => {
+            let conf = loader.load()?;
+            conf.sure_versions(volume.as_ref().map(|s| s.as_str()), &tags)?;
+        }
+        Command::SureDiff {
+            volume,
+            json,
+            old,
+            new,
+        } => {
+            let conf = loader.load()?;
+            conf.sure_diff(&volume, &old, &new, json)?;
+        }
+        Command::Borg {
+            volume,
+            fs,
+            bind,
+            repo,
+            name,
+            limit,
+            pretend,
+        } => match (fs, bind, repo, name) {
+            (Some(fs), Some(bind), Some(repo), Some(name)) => {
+                rack::run_borg(&fs, &bind, &repo, &name, limit, pretend)?;
+            }
+            (None, None, None, None) => {
+                let conf = loader.load()?;
+                conf.run_borg(volume.as_ref().map(|s| s.as_str()), limit, pretend)?;
+            }
+            _ => {
+                eprintln!("--fs, --bind, --repo, and --name must be given together");
+                process::exit(1);
+            }
+        },
+        Command::BorgPrune { really } => {
+            let conf = loader.load()?;
+            conf.borg_prune(really)?;
         }
         Command::Restic { name, pretend, limit } => {
-            let conf = rack::Config::load(&config_file)?;
+            let conf = loader.load()?;
             conf.run_restic(name.as_ref().map(|s| s.as_str()), limit, pretend)?;
         }
+        Command::Verify { volume, tag, count } => {
+            let conf = loader.load()?;
+            match volume {
+                Some(volume) => {
+                    let count = count.or(conf.verify.count).unwrap_or(20);
+                    conf.verify_restic(&volume, tag.as_ref().map(|s| s.as_str()), count)?;
+                }
+                None => conf.run_verify(count)?,
+            }
+        }
+        Command::Gc { pretend } => {
+            let conf = loader.load()?;
+            conf.gc(pretend)?;
+        }
+        Command::Check => {
+            let conf = loader.load()?;
+            conf.check()?;
+            println!("Config {:?} is ok", loader.file);
+        }
+        Command::Plan { output, operation } => {
+            let conf = loader.load()?;
+            let mut plan = match operation {
+                PlanOp::Snap => {
+                    conf.snap.plan(&conf.inventory, &conf.full_pools()?, Utc::now(), false)?
+                }
+                PlanOp::Prune => conf.plan_prune()?,
+                PlanOp::PruneAll => conf.plan_prune_all()?,
+                PlanOp::SyncPrune => conf.plan_sync_prune()?,
+                PlanOp::CloneDelete => conf.clone.plan_delete(&conf.inventory)?,
+            };
+            plan.print();
+            plan.record(&conf.inventory)?;
+            plan.save(Path::new(&output))?;
+        }
+        Command::Apply { plan } => {
+            let plan = rack::Plan::load(Path::new(&plan))?;
+            plan.apply_saved(&rack::Inventory::new())?;
+        }
+        Command::Cloud { name, pretend } => {
+            let conf = loader.load()?;
+            conf.run_cloud(name.as_ref().map(|s| s.as_str()), pretend)?;
+        }
+        Command::CloudRestore {
+            snapshot,
+            pretend,
+            volume,
+            dest,
+        } => {
+            let conf = loader.load()?;
+            conf.cloud_restore(&volume, &dest, snapshot.as_ref().map(|s| s.as_str()), pretend)?;
+        }
+        Command::Export {
+            target,
+            name,
+            pretend,
+        } => {
+            let conf = loader.load()?;
+            let target = target.as_ref().map(|s| s.as_str());
+            conf.run_export(target, name.as_ref().map(|s| s.as_str()), pretend)?;
+        }
+        Command::Import {
+            target,
+            snapshot,
+            pretend,
+            volume,
+            dest,
+        } => {
+            let conf = loader.load()?;
+            let snapshot = snapshot.as_ref().map(|s| s.as_str());
+            let target = target.as_ref().map(|s| s.as_str());
+            conf.import(target, &volume, &dest, snapshot, pretend)?;
+        }
+        Command::Status => {
+            let conf = loader.load()?;
+            conf.export_status()?;
+        }
+        Command::Auto { pretend } => {
+            let conf = loader.load()?;
+            conf.run_auto(pretend)?;
+        }
+        Command::Doctor => {
+            let conf = loader.load()?;
+            conf.doctor()?;
+        }
+        Command::Stale => {
+            let conf = loader.load()?;
+            conf.stale()?;
+        }
+        Command::Du { volume, by_used } => {
+            let conf = loader.load()?;
+            conf.du(&volume, by_used)?;
+        }
+        Command::Adopt => {
+            let conf = loader.load()?;
+            conf.adopt()?;
+        }
+        Command::Changed {
+            volume,
+            last,
+            since,
+            until,
+        } => {
+            let conf = loader.load()?;
+            let since = since.as_ref().map(|s| s.as_str());
+            conf.changed(&volume, last, since, until.as_ref().map(|s| s.as_str()))?;
+        }
+        Command::Mount {
+            volume,
+            snapshot,
+            dir,
+        } => {
+            let conf = loader.load()?;
+            conf.mount(&volume, &snapshot, dir.as_ref().map(|s| s.as_str()))?;
+        }
+        Command::Restore {
+            volume,
+            snapshot,
+            paths,
+            from_clone,
+            to,
+            pretend,
+        } => {
+            let conf = loader.load()?;
+            let to = to.as_ref().map(|s| s.as_str());
+            conf.restore(&volume, &snapshot, &paths, from_clone, to, pretend)?;
+        }
+        Command::Umount { volume, dir } => {
+            rack::umount(volume.as_ref().map(|s| s.as_str()), dir.as_ref().map(|s| s.as_str()))?;
+        }
         Command::Hack => {
             let conf = rack::Config::load_default()?;
             println!("Config file: {:?}", conf);
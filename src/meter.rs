@@ -0,0 +1,148 @@
+//! Meters showing the progress of long operations.
+//!
+//! On a terminal, each meter is an indicatif progress bar, or a spinner
+//! until the total is known.  Otherwise, such as when run from cron, a line
+//! is reported every so often instead, so that a log shows how far an
+//! operation got without being flooded.  A sure capture hashing with
+//! several threads counts the files hashed, as each directory is done, but
+//! with one thread, rsure shows its own progress while hashing.
+
+use crate::zfs::humanize_size;
+use indicatif::{ProgressBar, ProgressStyle};
+use std::{
+    io::{self, IsTerminal},
+    time::{Duration, Instant},
+};
+
+/// How often progress is reported when not on a terminal.
+const REPORT_EVERY: Duration = Duration::from_secs(60);
+
+/// What a meter counts.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Unit {
+    Bytes,
+    Files,
+}
+
+impl Unit {
+    fn format(self, count: u64) -> String {
+        match self {
+            Unit::Bytes => humanize_size(count as usize).trim().to_string(),
+            Unit::Files => format!("{} files", count),
+        }
+    }
+
+    fn style(self, total: bool) -> ProgressStyle {
+        let template = match (self, total) {
+            (Unit::Bytes, true) => {
+                "{msg} [{bar:40}] {bytes}/{total_bytes} {bytes_per_sec}, eta {eta}"
+            }
+            (Unit::Bytes, false) => "{spinner} {msg} {bytes} {bytes_per_sec}",
+            (Unit::Files, true) => "{msg} [{bar:40}] {pos}/{len} files, eta {eta}",
+            (Unit::Files, false) => "{spinner} {msg} {pos} files",
+        };
+        ProgressStyle::with_template(template)
+            .expect("Progress template")
+            .progress_chars("=> ")
+    }
+}
+
+/// The progress of one operation.  The bar is removed when the meter is
+/// dropped.
+pub struct Meter {
+    label: String,
+    unit: Unit,
+    total: Option<u64>,
+    bar: Option<ProgressBar>,
+    reported: Instant,
+}
+
+impl Meter {
+    /// A meter of an operation, which may know how much it has to do.
+    pub fn new(label: &str, unit: Unit, total: Option<u64>) -> Meter {
+        let bar = if io::stderr().is_terminal() {
+            let bar = match total {
+                Some(total) => ProgressBar::new(total),
+                None => ProgressBar::new_spinner(),
+            };
+            bar.set_style(unit.style(total.is_some()));
+            bar.set_message(label.to_string());
+            bar.enable_steady_tick(Duration::from_millis(250));
+            Some(bar)
+        } else {
+            None
+        };
+        Meter {
+            label: label.to_string(),
+            unit: unit,
+            total: total,
+            bar: bar,
+            reported: Instant::now(),
+        }
+    }
+
+    /// Set the total, such as once a scan has found more to do.
+    pub fn set_total(&mut self, total: u64) {
+        if self.total == Some(total) {
+            return;
+        }
+        if let Some(ref bar) = self.bar {
+            if self.total.is_none() {
+                bar.set_style(self.unit.style(true));
+            }
+            bar.set_length(total);
+        }
+        self.total = Some(total);
+    }
+
+    /// Set how much has been done.
+    pub fn set(&mut self, done: u64) {
+        match self.bar {
+            Some(ref bar) => bar.set_position(done),
+            None => {
+                if self.reported.elapsed() >= REPORT_EVERY {
+                    self.reported = Instant::now();
+                    progress!("{}: {}", self.label, self.describe(done));
+                }
+            }
+        }
+    }
+
+    fn describe(&self, done: u64) -> String {
+        match self.total {
+            Some(total) if total > 0 => format!(
+                "{} of {} ({}%)",
+                self.unit.format(done),
+                self.unit.format(total),
+                done * 100 / total
+            ),
+            _ => self.unit.format(done),
+        }
+    }
+}
+
+impl Drop for Meter {
+    fn drop(&mut self) {
+        if let Some(ref bar) = self.bar {
+            bar.finish_and_clear();
+        }
+    }
+}
+
+#[test]
+fn test_meter() {
+    let mut meter = Meter {
+        label: "Clone pool/home".into(),
+        unit: Unit::Files,
+        total: None,
+        bar: None,
+        reported: Instant::now(),
+    };
+    assert_eq!(meter.describe(12), "12 files");
+    meter.set_total(48);
+    assert_eq!(meter.describe(12), "12 files of 48 files (25%)");
+
+    meter.unit = Unit::Bytes;
+    meter.set_total(8 * 1024 * 1024);
+    assert_eq!(meter.describe(2 * 1024 * 1024), "2.000MiB of 8.000MiB (25%)");
+}
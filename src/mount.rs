@@ -0,0 +1,255 @@
+//! Mounting and unmounting.
+//!
+//! Snapshots are mounted with the mount system calls directly, rather than
+//! by running `mount` and `umount`, so that what happens doesn't depend on
+//! which mount binary is installed, or on `/etc/fstab`.  With
+//! `private_mounts` set in the config, rack first moves into a mount
+//! namespace of its own, so that anything it leaves mounted goes away with
+//! it, even if it is killed.
+//!
+//! Where things are mounted is found from `/proc/self/mountinfo`, which,
+//! unlike `/proc/mounts`, says which directory of a filesystem each mount
+//! shows, so that a bind mount of part of a filesystem isn't taken for the
+//! filesystem itself.  It lists the mounts of rack's own namespace, so
+//! mounts made outside a private namespace after it was made aren't seen.
+//! The table is read once, and read again after rack mounts or unmounts
+//! anything, or when something looked for isn't in it.
+
+use crate::{Context, Result};
+use nix::{
+    errno::Errno,
+    mount::{self, MntFlags, MsFlags},
+    sched::{self, CloneFlags},
+};
+use std::{
+    fs, io,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+/// A mount, from `/proc/self/mountinfo`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MountEntry {
+    /// The directory of the filesystem shown at the mountpoint, which is
+    /// "/" unless this is a bind mount of part of it.
+    pub root: String,
+    pub mountpoint: String,
+    pub fstype: String,
+    /// The device, or for zfs, the dataset.
+    pub source: String,
+}
+
+static TABLE: Mutex<Option<Vec<MountEntry>>> = Mutex::new(None);
+
+/// The mounts, in the order they were made.
+pub fn mounts() -> Result<Vec<MountEntry>> {
+    let mut table = TABLE.lock().unwrap();
+    if let Some(ref entries) = *table {
+        return Ok(entries.clone());
+    }
+    let text = fs::read_to_string("/proc/self/mountinfo")?;
+    let entries = parse_mountinfo(&text);
+    *table = Some(entries.clone());
+    Ok(entries)
+}
+
+/// Read the table again the next time it is needed.
+fn forget() {
+    *TABLE.lock().unwrap() = None;
+}
+
+/// Where the zfs filesystem `name` is mounted, if it is.
+pub fn zfs_mountpoint(name: &str) -> Result<Option<String>> {
+    if let Some(dir) = find_zfs(&mounts()?, name) {
+        return Ok(Some(dir));
+    }
+    // It may have been mounted, such as by a receive, since the table was
+    // read.
+    forget();
+    Ok(find_zfs(&mounts()?, name))
+}
+
+/// Where `name` is mounted in its entirety.  A filesystem mounted more than
+/// once, such as bind mounted elsewhere, is taken to be where it was first
+/// mounted, unless something since mounted on top of it hides it.
+fn find_zfs(entries: &[MountEntry], name: &str) -> Option<String> {
+    entries
+        .iter()
+        .enumerate()
+        .filter(|(_, e)| e.fstype == "zfs" && e.source == name && e.root == "/")
+        .find(|(i, e)| !entries[i + 1..].iter().any(|l| l.mountpoint == e.mountpoint))
+        .map(|(_, e)| e.mountpoint.clone())
+}
+
+/// Parse the lines of mountinfo, skipping any that can't be understood.
+/// Each line is the mount and parent ids, the device number, the root,
+/// the mountpoint and its options, any number of optional fields ended by
+/// "-", then the type, the source, and the filesystem's options.
+fn parse_mountinfo(text: &str) -> Vec<MountEntry> {
+    text.lines()
+        .filter_map(|line| {
+            let fields: Vec<_> = line.split(' ').collect();
+            let sep = fields.iter().skip(6).position(|f| *f == "-")? + 6;
+            if fields.len() < sep + 3 {
+                return None;
+            }
+            Some(MountEntry {
+                root: unescape(fields[3]),
+                mountpoint: unescape(fields[4]),
+                fstype: unescape(fields[sep + 1]),
+                source: unescape(fields[sep + 2]),
+            })
+        })
+        .collect()
+}
+
+/// Undo the octal escapes (such as "\040" for space) used in mountinfo.
+fn unescape(field: &str) -> String {
+    let mut result = String::new();
+    let mut rest = field;
+    while let Some(pos) = rest.find('\\') {
+        result.push_str(&rest[..pos]);
+        let code = rest.get(pos + 1..pos + 4).and_then(|c| u8::from_str_radix(c, 8).ok());
+        match code {
+            Some(ch) => {
+                result.push(ch as char);
+                rest = &rest[pos + 4..];
+            }
+            None => {
+                result.push('\\');
+                rest = &rest[pos + 1..];
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+/// Bind mount a directory onto another.
+pub fn bind(from: &Path, to: &Path) -> Result<()> {
+    forget();
+    mount::mount(Some(from), to, None::<&str>, MsFlags::MS_BIND, None::<&str>)
+        .map_err(io::Error::from)
+        .context(format!("Unable to bind mount {:?} on {:?}", from, to))
+}
+
+/// Mount a filesystem read-only.  Without a type, each of the kernel's
+/// block device filesystems is tried in turn, as `mount` does.
+pub fn read_only(source: &str, fstype: Option<&str>, to: &Path) -> Result<()> {
+    forget();
+    let context = format!("Unable to mount {} on {:?}", source, to);
+    let types = match fstype {
+        Some(fstype) => vec![fstype.to_string()],
+        None => block_filesystems().context(context.clone())?,
+    };
+    let mut result = Err(Errno::ENODEV);
+    for fstype in &types {
+        result = mount::mount(
+            Some(source),
+            to,
+            Some(fstype.as_str()),
+            MsFlags::MS_RDONLY,
+            None::<&str>,
+        );
+        // The wrong type gives EINVAL, as can a type that doesn't match.
+        match result {
+            Err(Errno::EINVAL) | Err(Errno::ENODEV) => continue,
+            _ => break,
+        }
+    }
+    result.map_err(io::Error::from).context(context)
+}
+
+/// The filesystems, listed in /proc/filesystems, that are mounted from a
+/// device.
+fn block_filesystems() -> Result<Vec<String>> {
+    Ok(parse_filesystems(&fs::read_to_string("/proc/filesystems")?))
+}
+
+fn parse_filesystems(text: &str) -> Vec<String> {
+    text.lines()
+        .filter(|line| !line.starts_with("nodev"))
+        .map(|line| line.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+
+/// Unmount a directory.  A lazy unmount detaches the filesystem now, even
+/// if it is busy, leaving it to be cleaned up once it isn't.
+pub fn unmount(dir: &Path, lazy: bool) -> Result<()> {
+    forget();
+    let flags = if lazy { MntFlags::MNT_DETACH } else { MntFlags::empty() };
+    mount::umount2(dir, flags)
+        .map_err(io::Error::from)
+        .context(format!("Unable to unmount {:?}", dir))
+}
+
+static PRIVATE: AtomicBool = AtomicBool::new(false);
+static SHARED: AtomicBool = AtomicBool::new(false);
+
+/// Keep rack in the mount namespace it was started in, even if the config
+/// asks for a private one, for mounts made to outlast it.  This has to be
+/// done before the config is loaded.
+pub fn stay_shared() {
+    SHARED.store(true, Ordering::SeqCst);
+}
+
+/// Move rack, and the commands it runs, into a mount namespace of its own.
+/// Mounts from outside are still seen, but those made by rack aren't seen
+/// outside, and are gone once it exits.  This has to be done before any
+/// threads are started.
+pub fn private_namespace() -> Result<()> {
+    if SHARED.load(Ordering::SeqCst) || PRIVATE.swap(true, Ordering::SeqCst) {
+        return Ok(());
+    }
+    forget();
+    sched::unshare(CloneFlags::CLONE_NEWNS)
+        .map_err(io::Error::from)
+        .context("Unable to make a private mount namespace")?;
+    mount::mount(
+        None::<&str>,
+        "/",
+        None::<&str>,
+        MsFlags::MS_REC | MsFlags::MS_SLAVE,
+        None::<&str>,
+    )
+    .map_err(io::Error::from)
+    .context("Unable to make mounts private")
+}
+
+#[test]
+fn test_mountinfo() {
+    let text = "\
+22 1 0:21 / / rw,relatime shared:1 - zfs lint/root rw,xattr
+40 22 0:35 / /home rw,relatime shared:20 - zfs lint/home rw,xattr
+41 22 0:36 / /mnt/old rw shared:21 - zfs lint/old rw
+42 41 0:37 / /mnt/old rw shared:22 - ext4 /dev/sdb1 rw
+43 22 0:35 /davidb /srv/david rw shared:20 - zfs lint/home rw
+44 22 0:35 / /mnt/home rw shared:20 - zfs lint/home rw
+45 22 0:38 / /media/my\\040disk rw master:3 shared:30 - zfs lint/my\\040disk rw
+bad line
+";
+    let entries = parse_mountinfo(text);
+    assert_eq!(entries.len(), 7);
+    assert_eq!(entries[3].source, "/dev/sdb1");
+    assert_eq!(entries[4].root, "/davidb");
+    assert_eq!(entries[6].mountpoint, "/media/my disk");
+
+    assert_eq!(find_zfs(&entries, "lint/home"), Some("/home".into()));
+    assert_eq!(find_zfs(&entries, "lint/my disk"), Some("/media/my disk".into()));
+    // Mounted over by something else.
+    assert_eq!(find_zfs(&entries, "lint/old"), None);
+    assert_eq!(find_zfs(&entries, "lint/gone"), None);
+
+    assert_eq!(unescape("/plain"), "/plain");
+    assert_eq!(unescape("/odd\\x"), "/odd\\x");
+}
+
+#[test]
+fn test_parse_filesystems() {
+    let text = "nodev\tsysfs\nnodev\ttmpfs\n\text4\n\tvfat\nnodev\tzfs\n\tbtrfs\n";
+    assert_eq!(parse_filesystems(text), vec!["ext4", "vfat", "btrfs"]);
+}
@@ -0,0 +1,236 @@
+//! Snapshot names.
+//!
+//! A snapshot name is made of a prefix, such as the name of a snap
+//! convention, an optional sequence number, a separator, and the time the
+//! snapshot was taken, for example "caz0042-201903041530" or
+//! "daily-201903041530".  How these are written can be set for each
+//! convention, so everything that makes or reads snapshot names goes
+//! through a `SnapNaming`.
+
+use crate::adopt;
+use crate::config::SnapConfig;
+use chrono::{NaiveDate, NaiveDateTime};
+use regex::Regex;
+use serde_derive::{Deserialize, Serialize};
+use std::sync::Mutex;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SnapNaming {
+    /// Written before the time.  Defaults to "-".
+    pub separator: String,
+    /// The number of digits sequence numbers are zero padded to.  Defaults
+    /// to 4.
+    pub padding: usize,
+    /// The format of the time, as for strftime.  Defaults to "%Y%m%d%H%M".
+    pub date_format: String,
+}
+
+impl Default for SnapNaming {
+    fn default() -> SnapNaming {
+        SnapNaming {
+            separator: "-".to_string(),
+            padding: 4,
+            date_format: "%Y%m%d%H%M".to_string(),
+        }
+    }
+}
+
+/// A snapshot name, taken apart.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SnapName {
+    pub index: Option<usize>,
+    pub time: NaiveDateTime,
+}
+
+impl SnapNaming {
+    /// Generate the name of a snapshot.
+    pub fn name(&self, prefix: &str, index: Option<usize>, time: NaiveDateTime) -> String {
+        let index = match index {
+            Some(index) => format!("{:0width$}", index, width = self.padding),
+            None => String::new(),
+        };
+        format!("{}{}{}{}", prefix, index, self.separator, time.format(&self.date_format))
+    }
+
+    /// Take apart a snapshot name with the given prefix, returning None if
+    /// the name isn't one of ours.  Sequence numbers of any length are
+    /// accepted, whatever the padding, so that numbering carries on past
+    /// the padded width.
+    pub fn parse(&self, prefix: &str, name: &str) -> Option<SnapName> {
+        let (digits, rest) = self.split(prefix, name)?;
+        let index = match digits {
+            Some(digits) => Some(digits.parse().ok()?),
+            None => None,
+        };
+        let time = self.parse_time(rest)?;
+        Some(SnapName { index, time })
+    }
+
+    /// If the sequence number of a name isn't written with this padding,
+    /// such as after the padding has been changed, return the name it
+    /// should have.  The rest of the name is kept as it is.
+    pub fn renumber(&self, prefix: &str, name: &str) -> Option<String> {
+        let (digits, rest) = self.split(prefix, name)?;
+        self.parse_time(rest)?;
+        let digits = digits?;
+        let padded = format!("{:0width$}", digits.parse::<usize>().ok()?, width = self.padding);
+        if padded == digits {
+            return None;
+        }
+        Some(format!("{}{}{}{}", prefix, padded, self.separator, rest))
+    }
+
+    /// Split a name into the digits of its sequence number, if it has one,
+    /// and the text of the time.
+    fn split<'a>(&self, prefix: &str, name: &'a str) -> Option<(Option<&'a str>, &'a str)> {
+        let rest = name.strip_prefix(prefix)?;
+        let digits = rest.len() - rest.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+        match rest[digits..].strip_prefix(self.separator.as_str()) {
+            Some(after) if digits > 0 && !self.separator.is_empty() => {
+                Some((Some(&rest[..digits]), after))
+            }
+            _ => Some((None, rest.strip_prefix(self.separator.as_str())?)),
+        }
+    }
+
+    /// Read a time written with the date format, which may only give a
+    /// date, in which case the time is midnight.
+    fn parse_time(&self, text: &str) -> Option<NaiveDateTime> {
+        NaiveDateTime::parse_from_str(text, &self.date_format)
+            .ok()
+            .or_else(|| {
+                NaiveDate::parse_from_str(text, &self.date_format)
+                    .ok()
+                    .and_then(|d| d.and_hms_opt(0, 0, 0))
+            })
+    }
+
+    /// Check that names can be read back, returning a description of the
+    /// problem if not.
+    pub fn check(&self) -> Result<(), String> {
+        let time = NaiveDate::from_ymd_opt(2019, 3, 4)
+            .and_then(|d| d.and_hms_opt(15, 30, 0))
+            .unwrap();
+        let name = self.name("p", Some(42), time);
+        match self.parse("p", &name) {
+            Some(SnapName { index: Some(42), .. }) => Ok(()),
+            _ => Err(format!("names such as {:?} can't be read back", name)),
+        }
+    }
+}
+
+/// The naming of each snap convention, by name.  Set once the config is
+/// loaded.
+static NAMINGS: Mutex<Vec<(String, SnapNaming)>> = Mutex::new(Vec::new());
+
+/// The naming of snapshots whose prefix isn't a convention, if the config
+/// gives one.
+static NUMBERED: Mutex<Option<SnapNaming>> = Mutex::new(None);
+
+/// Set the naming of snapshots made by each convention, and of other
+/// snapshots.
+pub fn set_namings(snap: &SnapConfig) {
+    *NAMINGS.lock().unwrap() = snap
+        .conventions
+        .iter()
+        .map(|c| (c.name.clone(), c.naming.clone()))
+        .collect();
+    *NUMBERED.lock().unwrap() = snap.naming.clone();
+    adopt::set_rules(&snap.adopt);
+}
+
+/// The naming used for snapshots with the given prefix.  This is the
+/// naming of the convention of that name, if there is one, otherwise that
+/// given for other snapshots, or the default.
+pub fn naming(prefix: &str) -> SnapNaming {
+    let by_convention = NAMINGS
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|(name, _)| name == prefix)
+        .map(|(_, naming)| naming.clone());
+    by_convention
+        .or_else(|| NUMBERED.lock().unwrap().clone())
+        .unwrap_or_default()
+}
+
+/// Take apart the name of a snapshot of the convention `prefix`, whether
+/// made by rack, or by another tool and adopted.
+pub fn parse(prefix: &str, name: &str) -> Option<SnapName> {
+    naming(prefix).parse(prefix, name).or_else(|| adopt::parse(prefix, name))
+}
+
+/// Decode the time a snapshot was taken from its name.  Names made by a
+/// convention are read with its naming, adopted names by their rule, and
+/// any other name is expected to end with YYYYMMDDHHMM.
+pub fn snap_time(snap: &str) -> Option<NaiveDateTime> {
+    let by_convention = NAMINGS
+        .lock()
+        .unwrap()
+        .iter()
+        .find_map(|(name, naming)| naming.parse(name, snap));
+    if let Some(name) = by_convention {
+        return Some(name.time);
+    }
+    if let Some((_, time)) = adopt::adopted(snap) {
+        return Some(time);
+    }
+
+    let re = Regex::new(r".*(\d{4})(\d\d)(\d\d)(\d\d)(\d\d)$").unwrap();
+
+    let cap = re.captures(snap)?;
+    let field = |n| cap.get(n).unwrap().as_str().parse::<u32>().unwrap();
+    NaiveDate::from_ymd_opt(field(1) as i32, field(2), field(3))
+        .and_then(|d| d.and_hms_opt(field(4), field(5), 0))
+}
+
+#[test]
+fn test_snap_time() {
+    assert_eq!(
+        snap_time("caz0042-201903041530"),
+        NaiveDate::from_ymd_opt(2019, 3, 4).and_then(|d| d.and_hms_opt(15, 30, 0))
+    );
+    assert_eq!(snap_time("daily-201902301530"), None);
+    assert_eq!(snap_time("manual"), None);
+}
+
+#[test]
+fn test_snap_naming() {
+    let time = NaiveDate::from_ymd_opt(2019, 3, 4)
+        .and_then(|d| d.and_hms_opt(15, 30, 0))
+        .unwrap();
+    let plain = SnapNaming::default();
+    assert_eq!(plain.name("caz", Some(42), time), "caz0042-201903041530");
+    assert_eq!(plain.name("daily", None, time), "daily-201903041530");
+    let parsed = plain.parse("caz", "caz123456-201903041530").unwrap();
+    assert_eq!(parsed, SnapName { index: Some(123456), time });
+    assert_eq!(plain.parse("daily", "daily-201903041530").unwrap().index, None);
+    assert_eq!(plain.parse("daily", "daily2-201903041530").unwrap().index, Some(2));
+    assert_eq!(plain.parse("caz", "daily-201903041530"), None);
+    assert_eq!(plain.parse("caz", "caz0042-manual"), None);
+    assert_eq!(plain.name("caz", Some(10000), time), "caz10000-201903041530");
+    assert_eq!(plain.parse("caz", "caz10000-201903041530").unwrap().index, Some(10000));
+
+    let dated = SnapNaming {
+        separator: "_".to_string(),
+        padding: 6,
+        date_format: "%Y-%m-%d".to_string(),
+    };
+    assert_eq!(dated.name("weekly", Some(7), time), "weekly000007_2019-03-04");
+    let parsed = dated.parse("weekly", "weekly000007_2019-03-04").unwrap();
+    assert_eq!(parsed.time, time.date().and_hms_opt(0, 0, 0).unwrap());
+    assert!(dated.check().is_ok());
+    assert_eq!(
+        dated.renumber("weekly", "weekly0042_2019-03-04"),
+        Some("weekly000042_2019-03-04".to_string())
+    );
+    assert_eq!(dated.renumber("weekly", "weekly000042_2019-03-04"), None);
+    assert_eq!(dated.renumber("weekly", "weekly_2019-03-04"), None);
+
+    let unreadable = SnapNaming {
+        date_format: "%H%M".to_string(),
+        ..SnapNaming::default()
+    };
+    assert!(unreadable.check().is_err());
+}
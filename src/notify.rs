@@ -0,0 +1,79 @@
+//! Notifications.
+//!
+//! Messages that someone should read, even when nobody is watching rack
+//! run, are sent to the destinations in the `notify` section of the config:
+//! mailed, through sendmail, or given to a command.
+
+use crate::{checked::CheckedExt, config::NotifyConfig, Result};
+use std::process::Command;
+
+impl NotifyConfig {
+    /// Is there anywhere for notifications to go?
+    pub fn configured(&self) -> bool {
+        self.email.is_some() || self.command.is_some()
+    }
+
+    /// Send a message to each configured destination.  Failing to send to
+    /// one doesn't stop it being sent to the others, but the first failure
+    /// is returned.
+    pub fn send(&self, subject: &str, body: &str) -> Result<()> {
+        let mut result = Ok(());
+
+        if let Some(ref to) = self.email {
+            let sendmail = match self.sendmail {
+                Some(ref sendmail) => sendmail.clone(),
+                None => vec!["sendmail".into(), "-t".into(), "-oi".into()],
+            };
+            let message = format!(
+                "To: {}\nSubject: {}\nContent-Type: text/plain; charset=utf-8\n\n{}",
+                to, subject, body
+            );
+            let sent = Command::new(&sendmail[0])
+                .args(&sendmail[1..])
+                .checked_feed(message.as_bytes());
+            if let Err(e) = sent {
+                warning!("Unable to mail notification to {}: {}", to, e);
+                result = result.and(Err(e));
+            }
+        }
+
+        if let Some(ref command) = self.command {
+            let sent = Command::new(&command[0])
+                .args(&command[1..])
+                .arg(subject)
+                .checked_feed(body.as_bytes());
+            if let Err(e) = sent {
+                warning!("Unable to run notify command: {}", e);
+                result = result.and(Err(e));
+            }
+        }
+
+        result
+    }
+}
+
+#[test]
+fn test_notify() {
+    use crate::checked::{set_executor, RecordingExecutor};
+    use std::sync::Arc;
+
+    let conf = NotifyConfig {
+        email: Some("root@example.com".into()),
+        sendmail: None,
+        command: Some(vec!["logger".into(), "-t".into(), "rack".into()]),
+    };
+    let exec = Arc::new(RecordingExecutor::new());
+    let running = set_executor(exec.clone());
+    conf.send("rack: all is well", "Nothing failed.\n").unwrap();
+    drop(running);
+
+    assert_eq!(exec.commands(), vec!["sendmail -t -oi", "logger -t rack rack: all is well"]);
+    assert_eq!(
+        exec.inputs(),
+        vec![
+            "To: root@example.com\nSubject: rack: all is well\n\
+             Content-Type: text/plain; charset=utf-8\n\nNothing failed.\n",
+            "Nothing failed.\n",
+        ]
+    );
+}
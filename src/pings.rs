@@ -0,0 +1,84 @@
+//! Pings to a dead man's switch.
+//!
+//! Notifications can't say that rack has stopped running altogether.  For
+//! that, a service such as healthchecks.io is pinged as each operation
+//! starts and finishes, and raises the alarm when the pings stop coming.
+//! The URLs are given in the `pings` section of the config, by the name of
+//! the command, or of the step of `rack auto`.  A ping that fails is only
+//! warned about, since the service will notice the missing ping anyway.
+
+use crate::{checked::CheckedExt, config::Config, Error};
+use std::process::Command;
+
+impl Config {
+    /// Ping that an operation is starting.
+    pub fn ping_start(&self, operation: &str) {
+        if let Some(url) = self.pings.get(operation).and_then(|p| p.start.as_ref()) {
+            ping(url, None);
+        }
+    }
+
+    /// Ping that an operation has finished, successfully or not.
+    pub fn ping_finish(&self, operation: &str, error: Option<&Error>) {
+        let pings = match self.pings.get(operation) {
+            Some(pings) => pings,
+            None => return,
+        };
+        match error {
+            None => {
+                if let Some(ref url) = pings.success {
+                    ping(url, None);
+                }
+            }
+            Some(e) => {
+                if let Some(ref url) = pings.fail {
+                    ping(url, Some(&e.to_string()));
+                }
+            }
+        }
+    }
+}
+
+fn ping(url: &str, body: Option<&str>) {
+    let mut cmd = Command::new("curl");
+    cmd.args(&["-fsS", "-m", "10", "--retry", "3", "-o", "/dev/null"]);
+    if let Some(body) = body {
+        cmd.args(&["--data-raw", body]);
+    }
+    cmd.arg(url);
+    if let Err(e) = cmd.checked_run() {
+        warning!("Unable to ping {}: {}", url, e);
+    }
+}
+
+#[test]
+fn test_pings() {
+    use crate::checked::{set_executor, RecordingExecutor};
+    use std::sync::Arc;
+
+    let text = "\
+pings:
+  auto: {start: 'https://hc/a/start', success: 'https://hc/a', fail: 'https://hc/a/fail'}
+  restic: {fail: 'https://hc/r/fail'}
+";
+    let conf = Config::parse(text, "lint").unwrap();
+    let exec = Arc::new(RecordingExecutor::new());
+    let running = set_executor(exec.clone());
+    conf.ping_start("auto");
+    conf.ping_start("restic");
+    conf.ping_finish("restic", None);
+    conf.ping_finish("restic", Some(&Error::msg("repo is locked")));
+    conf.ping_finish("auto", None);
+    conf.ping_finish("borg", None);
+    drop(running);
+
+    let curl = "curl -fsS -m 10 --retry 3 -o /dev/null";
+    assert_eq!(
+        exec.commands(),
+        vec![
+            format!("{} https://hc/a/start", curl),
+            format!("{} --data-raw repo is locked https://hc/r/fail", curl),
+            format!("{} https://hc/a", curl),
+        ]
+    );
+}
@@ -0,0 +1,389 @@
+//! Plans of the changes an operation will make.
+//!
+//! Operations that change things are done in two steps.  Planning works out
+//! what needs to be done, by looking at the system, and produces a `Plan`.
+//! Applying carries out the actions in the plan.  Pretending is just
+//! printing the plan, and a plan can also be saved with `rack plan`, looked
+//! over, and carried out later with `rack apply`.
+//!
+//! A saved plan records the snapshots of the datasets its zfs commands work
+//! on.  `rack apply` takes the run lock, so that it doesn't run alongside
+//! another rack, and refuses a plan whose snapshots have changed since it
+//! was made, as its decisions were based on what was there then.
+
+use crate::{
+    checked::{command_line, CheckedExt},
+    config::{BorgVolume, DatabaseConfig, ResticVolume},
+    database, digest, events,
+    runlock::RunLock,
+    surestore,
+    zfs::Inventory,
+    Context, Error, Result,
+};
+use chrono::Utc;
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::{self, File},
+    path::Path,
+    process::{Command, Stdio},
+};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Plan {
+    /// The operation the plan was made for.
+    pub operation: String,
+    /// When the plan was made, in RFC3339.
+    pub created: String,
+    /// The snapshots of each dataset the zfs commands of the plan work on,
+    /// as recorded when it was saved.
+    #[serde(default)]
+    pub snapshots: BTreeMap<String, Vec<String>>,
+    pub actions: Vec<Action>,
+}
+
+/// A single step of a plan.  Each records why it is being done.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "action", rename_all = "kebab-case")]
+pub enum Action {
+    /// Run a command, stopping if it fails.
+    Run { command: Vec<String>, reason: String },
+    /// Run a command, only warning if it fails.
+    Try { command: Vec<String>, reason: String },
+    /// Run a command while a database is held quiesced, stopping if it
+    /// fails.
+    Quiesced {
+        database: DatabaseConfig,
+        command: Vec<String>,
+        reason: String,
+    },
+    /// Drop the given versions from a sure store.
+    SurePrune {
+        store: String,
+        drop: Vec<String>,
+        reason: String,
+    },
+    /// Forget the given snapshots from a restic repository, pruning the
+    /// data only they used.
+    ResticForget {
+        volume: ResticVolume,
+        ids: Vec<String>,
+        reason: String,
+    },
+    /// Delete the given archives from a borg repository.
+    BorgDelete {
+        volume: BorgVolume,
+        archives: Vec<String>,
+        reason: String,
+    },
+}
+
+impl Plan {
+    pub fn new(operation: &str) -> Plan {
+        Plan {
+            operation: operation.to_string(),
+            created: Utc::now().to_rfc3339(),
+            snapshots: BTreeMap::new(),
+            actions: vec![],
+        }
+    }
+
+    /// Add a command to be run.
+    pub fn run(&mut self, reason: String, cmd: &Command) {
+        self.actions.push(Action::Run {
+            command: command_line(cmd),
+            reason: reason,
+        });
+    }
+
+    /// Add a command to be run, whose failure isn't fatal.
+    pub fn try_run(&mut self, reason: String, cmd: &Command) {
+        self.actions.push(Action::Try {
+            command: command_line(cmd),
+            reason: reason,
+        });
+    }
+
+    /// Add the dropping of versions from a sure store.
+    pub fn sure_prune(&mut self, reason: String, store: &str, drop: Vec<String>) {
+        self.actions.push(Action::SurePrune {
+            store: store.to_string(),
+            drop: drop,
+            reason: reason,
+        });
+    }
+
+    /// Add the forgetting of snapshots from the repository of a restic
+    /// volume.
+    pub fn restic_forget(&mut self, reason: String, volume: &ResticVolume, ids: Vec<String>) {
+        self.actions.push(Action::ResticForget {
+            volume: volume.clone(),
+            ids: ids,
+            reason: reason,
+        });
+    }
+
+    /// Add the deleting of archives from the repository of a borg volume.
+    pub fn borg_delete(&mut self, reason: String, volume: &BorgVolume, archives: Vec<String>) {
+        self.actions.push(Action::BorgDelete {
+            volume: volume.clone(),
+            archives: archives,
+            reason: reason,
+        });
+    }
+
+    /// Record the snapshots, as `inv` has them, of the datasets the plan
+    /// works on, so that applying it later can tell if they have changed.
+    pub fn record(&mut self, inv: &Inventory) -> Result<()> {
+        self.snapshots = snapshots(inv, &self.datasets())?;
+        Ok(())
+    }
+
+    /// The local datasets named by the zfs commands of the plan.  Commands
+    /// run on another host aren't looked at.
+    fn datasets(&self) -> BTreeSet<String> {
+        let mut datasets = BTreeSet::new();
+        for action in &self.actions {
+            let command = match action {
+                Action::Run { command, .. } => command,
+                Action::Try { command, .. } => command,
+                Action::Quiesced { command, .. } => command,
+                _ => continue,
+            };
+            if command.first().map_or(true, |program| program != "zfs") {
+                continue;
+            }
+            for arg in &command[1..] {
+                if let Some((dataset, _)) = arg.split_once(|c| c == '@' || c == '#') {
+                    datasets.insert(dataset.to_string());
+                }
+            }
+        }
+        datasets
+    }
+
+    pub fn load(path: &Path) -> Result<Plan> {
+        let plan = serde_json::from_reader(File::open(path)?)
+            .context(format!("Invalid plan {:?}", path))?;
+        Ok(plan)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut text = serde_json::to_string_pretty(self)?;
+        text.push('\n');
+        fs::write(path, text)?;
+        Ok(())
+    }
+
+    /// Show what the plan would do.
+    pub fn print(&self) {
+        if self.actions.is_empty() {
+            decision!("Plan for {}: nothing to do", self.operation);
+            return;
+        }
+        decision!("Plan for {}:", self.operation);
+        for action in &self.actions {
+            match action {
+                Action::Run { command, reason } => {
+                    decision!("  {}\n      {}", reason, command.join(" "));
+                }
+                Action::Try { command, reason } => {
+                    decision!("  {}\n      {} (may fail)", reason, command.join(" "));
+                }
+                Action::Quiesced {
+                    database,
+                    command,
+                    reason,
+                } => {
+                    let (db, command) = (database.kind, command.join(" "));
+                    decision!("  {}\n      {} (with {:?} quiesced)", reason, command, db);
+                }
+                Action::SurePrune { store, drop, reason } => {
+                    decision!("  {}\n      drop from {}: {}", reason, store, drop.join(" "));
+                }
+                Action::ResticForget { volume, ids, reason } => {
+                    let ids = ids.join(" ");
+                    decision!("  {}\n      forget from restic {:?}: {}", reason, volume.name, ids);
+                }
+                Action::BorgDelete {
+                    volume,
+                    archives,
+                    reason,
+                } => {
+                    let archives = archives.join(" ");
+                    decision!("  {}\n      delete from {}: {}", reason, volume.repo, archives);
+                }
+            }
+        }
+    }
+
+    /// Carry out the actions of the plan, in order.
+    pub fn apply(&self) -> Result<()> {
+        for action in &self.actions {
+            let result = apply(action);
+            let applied = Applied {
+                action: action,
+                error: result.as_ref().err().map(|e| e.to_string()),
+            };
+            events::emit("action", &applied);
+            result?;
+        }
+        Ok(())
+    }
+
+    /// Carry out a plan saved by `rack plan`.  The run lock is held while it
+    /// is applied, and the plan is refused if the snapshots it recorded have
+    /// changed.
+    pub fn apply_saved(&self, inv: &Inventory) -> Result<()> {
+        let _lock = RunLock::try_acquire()?
+            .ok_or_else(|| Error::msg("Another rack is running, not applying the plan"))?;
+        let current = snapshots(inv, &self.snapshots.keys().cloned().collect())?;
+        let changed = |name: &&String| current.get(*name) != self.snapshots.get(*name);
+        if let Some(name) = self.snapshots.keys().find(changed) {
+            let msg = format!(
+                "The snapshots of {} have changed since the {} plan was made, at {}",
+                name, self.operation, self.created
+            );
+            return Err(Error::msg(msg));
+        }
+        self.apply()
+    }
+
+    /// Apply the plan, or if pretending, just print it.
+    pub fn execute(&self, pretend: bool) -> Result<()> {
+        if pretend {
+            self.print();
+            Ok(())
+        } else {
+            self.apply()
+        }
+    }
+}
+
+/// An action of a plan that has been carried out, as an event.
+#[derive(Serialize)]
+struct Applied<'a> {
+    #[serde(flatten)]
+    action: &'a Action,
+    error: Option<String>,
+}
+
+fn apply(action: &Action) -> Result<()> {
+    match action {
+        Action::Run { command, reason } => {
+            progress!("{}", reason);
+            run(command)?;
+            note(command);
+        }
+        Action::Try { command, reason } => {
+            progress!("{}", reason);
+            match run(command) {
+                Ok(()) => note(command),
+                Err(e) => warning!("  {:?} failed: {}", command.join(" "), e),
+            }
+        }
+        Action::Quiesced {
+            database,
+            command,
+            reason,
+        } => {
+            progress!("{}", reason);
+            let target = command.last().map_or("", |c| c.as_str());
+            database::quiesced(database, target, || run(command))?;
+            note(command);
+        }
+        Action::SurePrune { store, drop, reason } => {
+            progress!("{}", reason);
+            surestore::prune(store, &|name| !drop.iter().any(|d| d == name))?;
+        }
+        Action::ResticForget { volume, ids, reason } => {
+            progress!("{}", reason);
+            volume.forget(ids)?;
+        }
+        Action::BorgDelete {
+            volume,
+            archives,
+            reason,
+        } => {
+            progress!("{}", reason);
+            volume.delete(archives)?;
+        }
+    }
+    Ok(())
+}
+
+/// The snapshots of each of `datasets` that `inv` has.
+fn snapshots(
+    inv: &Inventory,
+    datasets: &BTreeSet<String>,
+) -> Result<BTreeMap<String, Vec<String>>> {
+    let fss = inv.filesystems()?;
+    let ours = fss.iter().filter(|fs| datasets.contains(&fs.name));
+    Ok(ours.map(|fs| (fs.name.clone(), fs.snaps.clone())).collect())
+}
+
+/// Note snapshots created and pruned in the digest.
+fn note(command: &[String]) {
+    match command {
+        [zfs, op, snap] if zfs == "zfs" && op == "snapshot" => digest::created(snap),
+        [zfs, op, snap] if zfs == "zfs" && op == "destroy" => digest::pruned(snap),
+        _ => (),
+    }
+}
+
+/// Run a command of a plan.  Built with libzfs_core, snapshots are created
+/// and destroyed through it, instead of by running zfs.
+fn run(command: &[String]) -> Result<()> {
+    #[cfg(feature = "libzfs_core")]
+    {
+        use std::{os::unix::process::ExitStatusExt, process::ExitStatus};
+
+        if let Some(result) = crate::lzc::run(command) {
+            let status = result.as_ref().ok().map(|_| ExitStatus::from_raw(0));
+            events::command(command, status);
+            return result;
+        }
+    }
+    to_command(command)?.checked_run()
+}
+
+fn to_command(line: &[String]) -> Result<Command> {
+    let (program, args) = line
+        .split_first()
+        .ok_or_else(|| Error::msg("Empty command in plan"))?;
+    let mut cmd = Command::new(program);
+    cmd.args(args);
+    cmd.stderr(Stdio::inherit());
+    Ok(cmd)
+}
+
+#[test]
+fn test_plan_json() {
+    let mut plan = Plan::new("prune");
+    plan.try_run("bookmark".into(), Command::new("zfs").args(&["bookmark", "a@b", "a#b"]));
+    plan.sure_prune("drop".into(), "/a.weave.gz", vec!["b".into()]);
+    let text = serde_json::to_string(&plan).unwrap();
+    assert!(text.contains(r#""action":"try","command":["zfs","bookmark","a@b","a#b"]"#));
+    let back: Plan = serde_json::from_str(&text).unwrap();
+    assert_eq!(back.operation, "prune");
+    assert_eq!(back.actions, plan.actions);
+
+    // Plans saved before the snapshots were recorded still load.
+    let old: Plan = serde_json::from_str(r#"{"operation":"snap","created":"","actions":[]}"#)
+        .unwrap();
+    assert!(old.snapshots.is_empty());
+}
+
+#[test]
+fn test_plan_datasets() {
+    let mut plan = Plan::new("prune");
+    plan.run("destroy".into(), Command::new("zfs").args(&["destroy", "pool/a@x%y"]));
+    let bookmark = ["bookmark", "pool/b@x", "pool/b#x"];
+    plan.try_run("bookmark".into(), Command::new("zfs").args(&bookmark));
+    plan.run("snap".into(), Command::new("zfs").args(&["snapshot", "-r", "pool/c@z"]));
+    let remote = ["root@host", "zfs", "destroy", "pool/d@x"];
+    plan.run("remote".into(), Command::new("ssh").args(&remote));
+    plan.sure_prune("drop".into(), "/a@b.weave.gz", vec!["x".into()]);
+    let datasets: Vec<_> = plan.datasets().into_iter().collect();
+    assert_eq!(datasets, ["pool/a", "pool/b", "pool/c"]);
+}
@@ -0,0 +1,126 @@
+//! How full the zfs pools are.
+//!
+//! Pools in the `pools` section of the config can be given high-water
+//! marks.  The pools are checked before work starts, and again at the end
+//! of `rack auto`.  Each pool over its mark is warned about every time, but
+//! a notification is only sent when it first goes over, so that a pool
+//! that stays full isn't reported on every run.  A pool can also be too
+//! full to take more snapshots on, since they would only fill it further.
+
+use crate::{checked::CheckedExt, config::Config, journal::state_dir, Error, Result};
+use std::{collections::BTreeSet, fs, process::Command};
+
+/// The file holding the names of the pools last found over their marks.
+const OVER_FILE: &str = "pools-over";
+
+/// How full each pool is, as a percentage.
+fn capacities() -> Result<Vec<(String, u8)>> {
+    let out = Command::new("zpool")
+        .args(&["list", "-Hp", "-o", "name,capacity"])
+        .checked_output()?;
+    parse_capacities(&String::from_utf8_lossy(&out.stdout))
+}
+
+fn parse_capacities(text: &str) -> Result<Vec<(String, u8)>> {
+    let mut result = vec![];
+    for line in text.lines() {
+        let bad = || Error::msg(format!("unexpected output from zpool: {:?}", line));
+        let (name, capacity) = line.split_once('\t').ok_or_else(bad)?;
+        let capacity = capacity.trim().trim_end_matches('%').parse().map_err(|_| bad())?;
+        result.push((name.to_string(), capacity));
+    }
+    Ok(result)
+}
+
+impl Config {
+    /// Warn about each pool over its mark, notifying of those that weren't
+    /// before.  Problems checking are only warned about, since they
+    /// shouldn't stop a backup.
+    pub fn check_pools(&self) {
+        if self.pools.iter().all(|p| p.warn.is_none()) {
+            return;
+        }
+        let capacities = match capacities() {
+            Ok(capacities) => capacities,
+            Err(e) => {
+                warning!("Unable to check how full the pools are: {}", e);
+                return;
+            }
+        };
+
+        let mut over = BTreeSet::new();
+        for pool in &self.pools {
+            let (mark, capacity) = match (pool.warn, find(&capacities, &pool.name)) {
+                (Some(mark), Some(capacity)) if capacity >= mark => (mark, capacity),
+                _ => continue,
+            };
+            warning!("Pool {} is {}% full, over its mark of {}%", pool.name, capacity, mark);
+            over.insert((pool.name.clone(), capacity));
+        }
+
+        let path = match state_dir() {
+            Ok(dir) => dir.join(OVER_FILE),
+            Err(e) => {
+                warning!("Unable to record full pools: {}", e);
+                return;
+            }
+        };
+        let before = fs::read_to_string(&path).unwrap_or_default();
+        let before: BTreeSet<_> = before.lines().collect();
+        for (name, capacity) in &over {
+            if !before.contains(name.as_str()) && self.notify.configured() {
+                let subject = format!("rack: pool {} is {}% full", name, capacity);
+                let body = format!("The zfs pool {} has gone over its high-water mark.\n", name);
+                // Failures to send are warned about by send.
+                let _ = self.notify.send(&subject, &body);
+            }
+        }
+        let names: Vec<_> = over.iter().map(|(name, _)| format!("{}\n", name)).collect();
+        if let Err(e) = fs::write(&path, names.concat()) {
+            warning!("Unable to record full pools in {:?}: {}", path, e);
+        }
+    }
+
+    /// The pools too full to take new snapshots on.
+    pub fn full_pools(&self) -> Result<Vec<String>> {
+        if self.pools.iter().all(|p| p.refuse_snapshots.is_none()) {
+            return Ok(vec![]);
+        }
+        let capacities = capacities()?;
+        let mut full = vec![];
+        for pool in &self.pools {
+            match (pool.refuse_snapshots, find(&capacities, &pool.name)) {
+                (Some(mark), Some(capacity)) if capacity >= mark => full.push(pool.name.clone()),
+                _ => (),
+            }
+        }
+        Ok(full)
+    }
+}
+
+fn find(capacities: &[(String, u8)], name: &str) -> Option<u8> {
+    capacities.iter().find(|(n, _)| n == name).map(|&(_, c)| c)
+}
+
+#[test]
+fn test_full_pools() {
+    use crate::checked::{set_executor, RecordingExecutor};
+    use std::sync::Arc;
+
+    let text = "\
+pools:
+  - {name: backup, warn: 80, refuse_snapshots: 95}
+  - {name: tank, refuse_snapshots: 90}
+  - {name: scratch, refuse_snapshots: 90}
+";
+    let conf = Config::parse(text, "lint").unwrap();
+    let exec = Arc::new(RecordingExecutor::new());
+    exec.respond(&["zpool", "list"], "backup\t96\ntank\t89\n");
+    let running = set_executor(exec.clone());
+    let full = conf.full_pools().unwrap();
+    drop(running);
+    assert_eq!(full, vec!["backup"]);
+
+    assert!(parse_capacities("backup 96\n").is_err());
+    assert_eq!(parse_capacities("tank\t12%\n").unwrap(), vec![("tank".to_string(), 12)]);
+}
@@ -0,0 +1,255 @@
+//! Choosing which snapshots to keep.
+//!
+//! A snap volume can give a `prune` algorithm, whose choices are kept when
+//! pruning, even if they aren't in any backup.
+//!
+//! - `hanoi` keeps the newest `PRUNE_KEEP` snapshots, and of the rest, the
+//!   newest whose sequence number has each count of bits set.  This keeps
+//!   snapshots at roughly doubling intervals, but which ones is hard to
+//!   predict.  Snapshots made by a convention, which aren't numbered, are
+//!   numbered by how many of the periods of its finest keep rule, such as
+//!   days for `daily`, have passed since 1970, so a convention needs one
+//!   of `hourly` to `yearly` to use it.
+//! - `gfs` (grandfather-father-son) keeps every snapshot from the last week,
+//!   the newest of each week for a month, and the newest of each month for a
+//!   year.
+//!
+//! `rack prune-all` also keeps what the keep rules of the volume's
+//! convention, `last`, `hourly`, and so on, choose, just as `borg prune`
+//! would.
+
+use crate::config::{PruneAlgorithm, SnapConvention};
+use crate::naming;
+use chrono::{Datelike, Duration, NaiveDateTime};
+use std::{cmp::Reverse, collections::HashSet};
+
+/// The number of recent snapshots the Hanoi scheme always keeps.
+pub const PRUNE_KEEP: usize = 10;
+
+impl PruneAlgorithm {
+    /// Choose which of a volume's snapshots, given oldest first, to keep.
+    /// Only snapshots named by the given convention are considered.
+    pub fn keep<'a>(
+        &self,
+        conv: &SnapConvention,
+        snaps: &'a [String],
+        now: NaiveDateTime,
+    ) -> HashSet<&'a str> {
+        let ours: Vec<_> = snaps
+            .iter()
+            .filter_map(|s| naming::parse(&conv.name, s).map(|n| (s.as_str(), n)))
+            .collect();
+        match self {
+            // Snapshots made by a convention aren't numbered, so are numbered
+            // by the periods of its finest keep rule since the epoch, which
+            // stay the same as others are taken and pruned.
+            PruneAlgorithm::Hanoi => {
+                let period = conv.period().map_or(1, |p| p.num_seconds());
+                let nums: Vec<_> = ours
+                    .iter()
+                    .map(|(s, n)| {
+                        let num = n.index.unwrap_or_else(|| {
+                            (n.time.and_utc().timestamp() / period).max(0) as usize
+                        });
+                        (*s, num)
+                    })
+                    .collect();
+                hanoi(&nums)
+            }
+            PruneAlgorithm::Gfs => {
+                let times: Vec<_> = ours.iter().map(|(s, n)| (*s, n.time)).collect();
+                gfs(&times, now)
+            }
+        }
+    }
+}
+
+impl SnapConvention {
+    /// The length of the shortest period counted by the keep rules, past
+    /// `last`, if there is one.
+    pub fn period(&self) -> Option<Duration> {
+        let rules = [
+            (self.hourly, 1),
+            (self.daily, 24),
+            (self.weekly, 7 * 24),
+            (self.monthly, 30 * 24),
+            (self.yearly, 365 * 24),
+        ];
+        rules
+            .iter()
+            .find(|(count, _)| count.map_or(false, |c| c != 0))
+            .map(|&(_, hours)| Duration::hours(hours))
+    }
+}
+
+/// The Hanoi scheme, given snapshots and their sequence numbers, oldest
+/// first.
+pub fn hanoi<'a>(snaps: &[(&'a str, usize)]) -> HashSet<&'a str> {
+    let mut pops = HashSet::new();
+    let mut keep = HashSet::new();
+    for (pos, &(name, num)) in snaps.iter().rev().enumerate() {
+        if pos < PRUNE_KEEP || pops.insert(num.count_ones()) {
+            keep.insert(name);
+        }
+    }
+    keep
+}
+
+/// The grandfather-father-son scheme, given snapshots and the times they
+/// were taken.
+pub fn gfs<'a>(snaps: &[(&'a str, NaiveDateTime)], now: NaiveDateTime) -> HashSet<&'a str> {
+    let mut snaps = snaps.to_vec();
+    snaps.sort_by_key(|&(_, time)| Reverse(time));
+
+    let mut weeks = HashSet::new();
+    let mut months = HashSet::new();
+    let mut keep = HashSet::new();
+    for (name, time) in snaps {
+        let age = now - time;
+        let week = (time.iso_week().year(), time.iso_week().week());
+        let month = (time.year(), time.month());
+        let wanted = if age <= Duration::days(7) {
+            true
+        } else if age <= Duration::days(31) {
+            !weeks.contains(&week)
+        } else if age <= Duration::days(365) {
+            !months.contains(&month)
+        } else {
+            false
+        };
+        if wanted {
+            keep.insert(name);
+            weeks.insert(week);
+            months.insert(month);
+        }
+    }
+    keep
+}
+
+/// The snapshots the keep rules of a convention keep, given the times they
+/// were taken.  These are the rules of `borg prune`: each rule keeps the
+/// newest snapshot of each of its most recent periods, not counting those
+/// an earlier rule already kept, and a count of -1 keeps every period.
+pub fn rules<'a>(conv: &SnapConvention, snaps: &[(&'a str, NaiveDateTime)]) -> HashSet<&'a str> {
+    let mut snaps = snaps.to_vec();
+    snaps.sort_by_key(|&(_, time)| Reverse(time));
+
+    // Each snapshot is its own period for `last`.
+    let rules = [
+        (conv.last, "%Y-%m-%d %H:%M:%S"),
+        (conv.hourly, "%Y-%m-%d %H"),
+        (conv.daily, "%Y-%m-%d"),
+        (conv.weekly, "%G-%V"),
+        (conv.monthly, "%Y-%m"),
+        (conv.yearly, "%Y"),
+    ];
+    let mut keep = HashSet::new();
+    for &(count, format) in &rules {
+        let count = match count {
+            Some(count) if count < 0 => usize::MAX,
+            Some(count) if count > 0 => count as usize,
+            _ => continue,
+        };
+        let mut last = None;
+        let mut kept = 0;
+        for &(name, time) in &snaps {
+            let period = time.format(format).to_string();
+            if last.as_ref() == Some(&period) {
+                continue;
+            }
+            last = Some(period);
+            if keep.insert(name) {
+                kept += 1;
+                if kept == count {
+                    break;
+                }
+            }
+        }
+    }
+    keep
+}
+
+#[test]
+fn test_gfs() {
+    use chrono::NaiveDate;
+
+    // Daily snapshots for two years, up to Sunday, March 3rd, 2019.
+    let now = NaiveDate::from_ymd_opt(2019, 3, 3)
+        .and_then(|d| d.and_hms_opt(12, 0, 0))
+        .unwrap();
+    let times: Vec<_> = (0..730).rev().map(|days| now - Duration::days(days)).collect();
+    let names: Vec<_> = times.iter().map(|t| t.format("daily-%Y%m%d%H%M").to_string()).collect();
+    let snaps: Vec<_> = names.iter().map(|n| n.as_str()).zip(times).collect();
+    let keep = gfs(&snaps, now);
+
+    let kept = |date: &str| keep.contains(format!("daily-{}1200", date).as_str());
+    // The last week.
+    assert!((0..=7).all(|d| kept(&(now - Duration::days(d)).format("%Y%m%d").to_string())));
+    // Then the Sundays, which end each week.
+    assert!(kept("20190217") && kept("20190210") && kept("20190203"));
+    assert!(!kept("20190216") && !kept("20190211"));
+    // Then the newest of each month, for the rest of the year.  January
+    // 31st is in a week that is already kept.
+    assert!(kept("20190130") && kept("20181231") && kept("20180331"));
+    assert!(!kept("20190131") && !kept("20180301"));
+    // And nothing older.
+    assert!(!kept("20180228"));
+    assert_eq!(keep.len(), 8 + 3 + 11);
+}
+
+#[test]
+fn test_hanoi_convention() {
+    use chrono::NaiveDate;
+
+    let conv: SnapConvention = serde_yaml::from_str("{name: daily, daily: 7}").unwrap();
+    let start = NaiveDate::from_ymd_opt(2019, 3, 3)
+        .and_then(|d| d.and_hms_opt(3, 0, 0))
+        .unwrap();
+    let names: Vec<_> = (0..100)
+        .map(|n| (start + Duration::days(n)).format("daily-%Y%m%d%H%M").to_string())
+        .collect();
+    let now = start + Duration::days(100);
+    let keep = PruneAlgorithm::Hanoi.keep(&conv, &names, now);
+    assert!(keep.len() > PRUNE_KEEP && keep.len() < names.len());
+
+    // Pruning what isn't kept doesn't change the choice of what is.
+    let left: Vec<_> = names.iter().filter(|n| keep.contains(n.as_str())).cloned().collect();
+    let again = PruneAlgorithm::Hanoi.keep(&conv, &left, now);
+    assert_eq!(again, keep);
+}
+
+#[test]
+fn test_rules() {
+    use chrono::NaiveDate;
+
+    // Snapshots every six hours for 120 days, up to March 3rd, 2019.
+    let now = NaiveDate::from_ymd_opt(2019, 3, 3)
+        .and_then(|d| d.and_hms_opt(18, 0, 0))
+        .unwrap();
+    let times: Vec<_> = (0..480).rev().map(|n| now - Duration::hours(6 * n)).collect();
+    let names: Vec<_> = times.iter().map(|t| t.format("daily-%Y%m%d%H%M").to_string()).collect();
+    let snaps: Vec<_> = names.iter().map(|n| n.as_str()).zip(times).collect();
+    let conv = SnapConvention {
+        name: "daily".into(),
+        last: Some(2),
+        hourly: None,
+        daily: Some(3),
+        weekly: None,
+        monthly: Some(2),
+        yearly: None,
+        naming: Default::default(),
+        schedule: None,
+    };
+    let keep = rules(&conv, &snaps);
+
+    let kept = |name: &str| keep.contains(format!("daily-{}", name).as_str());
+    // The last two, then the newest of the three days before them, as
+    // today's is already kept.
+    assert!(kept("201903031800") && kept("201903031200"));
+    assert!(kept("201903021800") && kept("201903011800") && kept("201902281800"));
+    assert!(!kept("201903030600") && !kept("201903020600"));
+    // February is kept by the daily rule, so the monthly rule goes on to
+    // January, and December.
+    assert!(kept("201901311800") && kept("201812311800"));
+    assert_eq!(keep.len(), 2 + 3 + 2);
+}
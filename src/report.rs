@@ -0,0 +1,86 @@
+//! Reporting what rack is doing.
+//!
+//! Rather than printing, the library reports each thing it does, or decides
+//! to do, as an `Event`, to the `Reporter` that has been installed.  The
+//! `rack` program installs a `ConsoleReporter`, which prints them.  Without
+//! a reporter, events are dropped, so a program using the library decides
+//! for itself what, if anything, to show.
+
+use std::sync::{Arc, Mutex};
+
+/// Something that happened, as a message for a person to read.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    /// Progress of an operation, such as a backup being started.
+    Progress(String),
+    /// Something that was decided, such as a snapshot to keep.  When
+    /// pretending, this is what would have been done.
+    Decision(String),
+    /// A problem that didn't stop the operation.
+    Warning(String),
+    /// The result of a query, such as a listing of versions.
+    Output(String),
+}
+
+/// Something that receives events.  Events come from whichever thread the
+/// work is being done on.
+pub trait Reporter: Send + Sync {
+    fn report(&self, event: Event);
+}
+
+/// A reporter that prints events, with warnings going to stderr.
+pub struct ConsoleReporter;
+
+impl Reporter for ConsoleReporter {
+    fn report(&self, event: Event) {
+        match event {
+            Event::Progress(msg) | Event::Decision(msg) | Event::Output(msg) => println!("{}", msg),
+            Event::Warning(msg) => eprintln!("{}", msg),
+        }
+    }
+}
+
+static REPORTER: Mutex<Option<Arc<dyn Reporter>>> = Mutex::new(None);
+
+/// Send events to the given reporter from now on.  Returns the reporter
+/// previously in use.
+pub fn set_reporter(reporter: Arc<dyn Reporter>) -> Option<Arc<dyn Reporter>> {
+    REPORTER.lock().unwrap().replace(reporter)
+}
+
+/// Send an event to the current reporter, if there is one.
+pub fn report(event: Event) {
+    crate::events::reported(&event);
+    let reporter = REPORTER.lock().unwrap().clone();
+    if let Some(reporter) = reporter {
+        reporter.report(event);
+    }
+}
+
+/// Report progress, with arguments as for `format!`.
+macro_rules! progress {
+    ($($arg:tt)*) => {
+        $crate::report::report($crate::report::Event::Progress(format!($($arg)*)))
+    };
+}
+
+/// Report a decision, with arguments as for `format!`.
+macro_rules! decision {
+    ($($arg:tt)*) => {
+        $crate::report::report($crate::report::Event::Decision(format!($($arg)*)))
+    };
+}
+
+/// Report a warning, with arguments as for `format!`.
+macro_rules! warning {
+    ($($arg:tt)*) => {
+        $crate::report::report($crate::report::Event::Warning(format!($($arg)*)))
+    };
+}
+
+/// Report the result of a query, with arguments as for `format!`.
+macro_rules! output {
+    ($($arg:tt)*) => {
+        $crate::report::report($crate::report::Event::Output(format!($($arg)*)))
+    };
+}
@@ -1,40 +1,79 @@
 //! Backups using restic
 
 use crate::{
-    config::{Config, ResticConfig, ResticVolume},
-    Result,
-    sync::MountedDir,
-    zfs::{find_mount, Filesystem, Zfs},
+    borg,
+    checked::{heavy_command, CheckedExt, Watch},
+    config::{
+        configured, skipped, BackupKind, Config, ResticBackend, ResticConfig, ResticVolume,
+        RetryOn, SnapVolume,
+    },
+    digest, excludes,
+    hooks::Hooks,
+    jobs::Jobs,
+    listings,
+    meter::{Meter, Unit},
+    naming::snap_time,
+    plan::Plan,
+    retry::{network_error, Retries},
+    Context, Error, Result,
+    surestore,
+    zfs::{estimate_size, humanize_size, Filesystem, Zfs},
 };
-use failure::{err_msg, format_err};
-use regex::Regex;
+use chrono::Utc;
 use serde_derive::{Deserialize};
 use std::{
-    collections::HashSet,
-    fs,
+    collections::{HashMap, HashSet},
     path::Path,
-    process::{Command, Stdio},
+    process::{Command, ExitStatus, Output, Stdio},
+    sync::Mutex,
 };
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum ResticError {
+    /// The repository is locked by another restic, or by one that died.
+    #[error("Restic repo for {volume:?} is locked: {status:?}")]
+    Locked { volume: String, status: ExitStatus },
+    #[error("Unable to run restic: {0:?}")]
+    Failed(ExitStatus),
+    #[error("No restic backups found for zfs {0:?}")]
+    NoBackups(String),
+    #[error("Restic backups for zfs {0:?} have different bind")]
+    MixedBinds(String),
+    #[error("No restic snapshot has matching sure data")]
+    NoMatchingSure,
+    #[error("{0} restored files did not match sure data")]
+    VerifyFailed(usize),
+}
 
 // Mirrors the json that comes from the `restic snapshot --json` command.
 #[derive(Debug, Deserialize)]
-struct Snapshot {
-    tree: String,
-    short_id: String,
-    paths: Vec<String>,
-    time: String,
-    parent: Option<String>,
-    id: String,
-    hostname: String,
-    username: String,
-    tags: Option<Vec<String>>,
+pub struct Snapshot {
+    pub tree: String,
+    pub short_id: String,
+    pub paths: Vec<String>,
+    pub time: String,
+    pub parent: Option<String>,
+    pub id: String,
+    pub hostname: String,
+    pub username: String,
+    pub tags: Option<Vec<String>>,
 }
 
-pub struct Limiter(pub Option<usize>);
+/// Limits the number of backups made in a single run.  The count is shared
+/// between all of the worker threads.  Also used to limit borg backups.
+pub struct Limiter(Mutex<Option<usize>>);
 
 impl Limiter {
-    fn exhausted(&mut self) -> bool {
-        match self.0 {
+    pub fn new(limit: Option<usize>) -> Limiter {
+        Limiter(Mutex::new(limit))
+    }
+
+    /// Consume one backup from the limit, returning true if there are none
+    /// left.
+    pub fn exhausted(&self) -> bool {
+        let mut limit = self.0.lock().unwrap();
+        match *limit {
             None => false,
             Some(0) => true,
             Some(ref mut n) => {
@@ -45,11 +84,81 @@ impl Limiter {
     }
 }
 
-static RESTIC_BIN: &'static str = "/home/davidb/bin/restic";
+/// Run the restic backups for the given volumes, running up to `parallel`
+/// of them at a time.  Volumes that share a repo or a bind directory are
+/// run one after another, since neither the repo lock nor the bind mount can
+/// be shared.
+pub fn run_all(
+    work: Vec<(&ResticVolume, &Filesystem, Option<&str>)>,
+    parallel: usize,
+    limit: &Limiter,
+    pretend: bool,
+) -> Result<()> {
+    if parallel <= 1 {
+        for (vol, fs, sure) in work {
+            vol.run(fs, sure, limit, pretend)?;
+        }
+        return Ok(());
+    }
+
+    let mut jobs = Jobs::new();
+    add_jobs(&mut jobs, work, limit, pretend);
+    jobs.run(parallel)
+}
+
+/// Add a job backing up each of the volumes.  A volume pushing a sure store
+/// uses the store, so that it waits for any capture into it.
+pub fn add_jobs<'a>(
+    jobs: &mut Jobs<'a>,
+    work: Vec<(&'a ResticVolume, &'a Filesystem, Option<&'a str>)>,
+    limit: &'a Limiter,
+    pretend: bool,
+) {
+    for (vol, fs, sure) in work {
+        let mut uses = vec![format!("dir:{}", vol.bind)];
+        if let Ok(url) = vol.repo_url() {
+            uses.push(format!("repo:{}", url));
+        }
+        if let Some(store) = sure {
+            uses.push(format!("sure:{}", store));
+        }
+        jobs.add(format!("restic {:?}", vol.name), uses, move || {
+            vol.run(fs, sure, limit, pretend)
+        });
+    }
+}
+
+pub static RESTIC_BIN: &'static str = "/home/davidb/bin/restic";
+
+/// The tag given to restic snapshots holding sure data.
+pub static SURE_TAG: &'static str = "rack-sure";
 
 impl ResticVolume {
-    pub fn run(&self, fs: &Filesystem, limit: &mut Limiter, pretend: bool) -> Result<()> {
-        println!("Restic: {:?} {}", self, pretend);
+    /// Back up any snapshots not yet in restic, between the volume's hooks.
+    /// If `sure` is given, it is the sure store to push into the repo after
+    /// each snapshot.
+    pub fn run(
+        &self,
+        fs: &Filesystem,
+        sure: Option<&str>,
+        limit: &Limiter,
+        pretend: bool,
+    ) -> Result<()> {
+        if pretend {
+            return self.backup(fs, sure, limit, pretend);
+        }
+        let hooks = Hooks::new(&self.pre_hook, &self.post_hook, self.hook_failure);
+        hooks.around(&self.name, || self.backup(fs, sure, limit, pretend))
+    }
+
+    fn backup(
+        &self,
+        fs: &Filesystem,
+        sure: Option<&str>,
+        limit: &Limiter,
+        pretend: bool,
+    ) -> Result<()> {
+        progress!("Restic: {:?} {}", self, pretend);
 
         let snaps = self.get_snapshots()?;
 
@@ -71,8 +180,12 @@ impl ResticVolume {
 
         // We'll need to back up every zfs snapshot that isn't present in
         // restic.
+        let mut count = 0;
+        let mut total = 0;
+        let mut backed_up = None;
         for zsnap in &fs.snaps {
             if seen_tags.contains(zsnap) {
+                backed_up = Some(zsnap.as_str());
                 continue;
             }
 
@@ -80,139 +193,628 @@ impl ResticVolume {
                 break;
             }
 
-            println!("Restic dump {:?} snapshot {:?}", self.zfs, zsnap);
-
             if pretend {
+                // The new data restic will have to read is roughly the zfs
+                // delta from the newest snapshot it already has.  That delta
+                // covers every snapshot in between, so the estimate for the
+                // last one backed up is the estimate for the whole run.
+                let size = estimate_size(&fs.name, backed_up, zsnap)?;
+                decision!(
+                    "Restic dump {:?} snapshot {:?}: estimate {} since {:?}",
+                    self.zfs,
+                    zsnap,
+                    humanize_size(size),
+                    backed_up.unwrap_or("nothing")
+                );
+                count += 1;
+                total = size;
                 continue;
             }
 
-            fs.restic_backup(self, zsnap)?;
+            progress!("Restic dump {:?} snapshot {:?}", self.zfs, zsnap);
+            // Even a backup that failed may have left a snapshot behind.
+            let result = fs.restic_backup(self, zsnap);
+            listings::invalidate(&self.repo_url()?);
+            result?;
+            digest::volume("restic", &self.name);
+            if let Some(surefile) = sure {
+                self.push_sure(surefile, zsnap)?;
+            }
+        }
+
+        if pretend {
+            decision!(
+                "Restic {:?}: would back up {} snapshots, estimate {}",
+                self.name,
+                count,
+                humanize_size(total)
+            );
         }
 
         Ok(())
     }
 
-    fn add_auth(&self, cmd: &mut Command) -> Result<()> {
+    /// Back up the sure store into the repo, tagged with the snapshot it
+    /// was just pushed alongside.  This is skipped if the snapshot hasn't
+    /// been captured yet, since the store wouldn't cover it.
+    fn push_sure(&self, surefile: &str, snap: &str) -> Result<()> {
+        let store = rsure::parse_store(surefile)?;
+        if !store.get_versions()?.iter().any(|v| v.name == snap) {
+            progress!("Restic: no sure data for {:?} yet, not pushing", snap);
+            return Ok(());
+        }
+
+        progress!("Restic push sure {:?} for {:?}", surefile, snap);
+        let result = self.run_restic(|| {
+            let mut cmd = heavy_command(RESTIC_BIN);
+            self.add_auth(&mut cmd)?;
+            cmd.args(&["backup", "--tag", snap, "--tag", SURE_TAG, "--time", &fix_time(snap)]);
+            cmd.arg(surefile);
+            Ok(cmd)
+        });
+        listings::invalidate(&self.repo_url()?);
+        result?;
+        Ok(())
+    }
+
+    /// The repository string to pass to restic.
+    fn repo_url(&self) -> Result<String> {
+        match (&self.repo, &self.backend) {
+            (Some(repo), None) => Ok(repo.clone()),
+            (None, Some(backend)) => Ok(backend.repo_url()),
+            (Some(_), Some(_)) => Err(Error::msg(format!(
+                "Restic volume {:?} has both repo and backend",
+                self.name
+            ))),
+            (None, None) => Err(Error::msg(format!(
+                "Restic volume {:?} has neither repo nor backend",
+                self.name
+            ))),
+        }
+    }
+
+    /// Check that this volume is properly configured, before any backups
+    /// are run.
+    fn validate(&self) -> Result<()> {
+        self.repo_url()?;
+        if let Some(ref backend) = self.backend {
+            backend.validate().context(format!("Restic volume {:?}", self.name))?;
+        }
+        if let Some(ref password) = self.password {
+            password.validate()?;
+        }
+        for au in &self.auth {
+            if !au.contains('=') {
+                return Err(Error::msg("auth in config file is not KEY=value"));
+            }
+        }
+        for secret in self.env.values() {
+            secret.validate()?;
+        }
+        Ok(())
+    }
+
+    /// Add the repository and credential settings to the given restic
+    /// command.
+    pub fn add_auth(&self, cmd: &mut Command) -> Result<()> {
+        cmd.arg("-r");
+        cmd.arg(&self.repo_url()?);
+
+        if let Some(ref password) = self.password {
+            cmd.env("RESTIC_PASSWORD", password.get()?);
+        }
+        if let Some(ref backend) = self.backend {
+            for (key, value) in backend.environment()? {
+                cmd.env(key, value);
+            }
+        }
+
         for au in &self.auth {
             let fields: Vec<_> = au.splitn(2, "=").collect();
             if fields.len() != 2 {
-                return Err(format_err!("auth in config file is not KEY=value"));
+                return Err(Error::msg("auth in config file is not KEY=value"));
             }
             cmd.env(fields[0], fields[1]);
         }
+        for (key, secret) in &self.env {
+            cmd.env(key, secret.get()?);
+        }
 
         Ok(())
     }
 
     /// Collect all of the snapshots contained within a particular restic
-    /// backup.
-    fn get_snapshots(&self) -> Result<Vec<Snapshot>> {
-        let mut cmd = Command::new(RESTIC_BIN);
-        cmd.args(&["-r", &self.repo, "snapshots", "--json"]);
-        cmd.stderr(Stdio::inherit());
-        self.add_auth(&mut cmd)?;
-        let out = cmd.output()?;
-        if !out.status.success() {
-            return Err(format_err!("Unable to run restic: {:?}", out.status));
-        }
-        let buf = out.stdout;
-
+    /// backup.  The listing kept from an earlier run is used, if there is
+    /// one.
+    pub fn get_snapshots(&self) -> Result<Vec<Snapshot>> {
+        let buf = listings::snapshots(&self.repo_url()?, || self.list_snapshots())?;
         Ok(serde_json::from_slice(&buf)?)
     }
-}
 
-impl Filesystem {
-    fn restic_backup(&self, rvol: &ResticVolume, snap: &str) -> Result<()> {
-        let mount = find_mount(&self.name)?;
-        let dest = format!("{}/.zfs/snapshot/{}", mount, snap);
+    /// Ask restic for the snapshots in the repository, as json.
+    pub(crate) fn list_snapshots(&self) -> Result<Vec<u8>> {
+        let out = self.run_restic(|| {
+            let mut cmd = Command::new(RESTIC_BIN);
+            self.add_auth(&mut cmd)?;
+            cmd.args(&["snapshots", "--json"]);
+            cmd.stdout(Stdio::piped());
+            Ok(cmd)
+        })?;
+        Ok(out.stdout)
+    }
 
-        // Stat "." in this directory to request ZFS automount the
-        // snapshot.
-        let meta = fs::metadata(format!("{}/.", dest))?;
-        if !meta.is_dir() {
-            return Err(format_err!("Snapshot is not a directory: {:?}", dest));
-        }
+    /// Forget the snapshots with the given ids, and prune the data no
+    /// longer used by any other.
+    pub(crate) fn forget(&self, ids: &[String]) -> Result<()> {
+        let result = self.run_restic(|| {
+            let mut cmd = heavy_command(RESTIC_BIN);
+            self.add_auth(&mut cmd)?;
+            cmd.args(&["forget", "--prune"]);
+            cmd.args(ids);
+            Ok(cmd)
+        });
+        listings::invalidate(&self.repo_url()?);
+        result?;
+        Ok(())
+    }
 
-        // Bind mount to have a consistent path for restic.  This needs to
-        // be specific to the given filesystem.
-        println!("Bind mount: {:?} from {:?}", dest, &rvol.bind);
-        let _root = MountedDir::new(&dest, Path::new(&rvol.bind))?;
+    /// Run a restic command constructed by `build`.  The command's stderr
+    /// is echoed as it is written, and also captured, so that a failure due
+    /// to a stale repository lock can be recognized.  If this volume has
+    /// `unlock_stale` set, run `restic unlock` and retry the command once.
+    /// Failures of remote repositories are retried as the config says.
+    fn run_restic<F>(&self, build: F) -> Result<Output>
+    where
+        F: Fn() -> Result<Command>,
+    {
+        self.run_restic_watched(build, None)
+    }
 
-        // Run the actual restic command.
-        let mut cmd = Command::new(RESTIC_BIN);
-        cmd.args(&["-r", &rvol.repo,
-                 "backup", "--exclude-caches",
-                 "--tag", snap,
-                 "--time", &fix_time(snap),
-                 &rvol.bind]);
-        rvol.add_auth(&mut cmd)?;
-        let status = cmd.status()?;
+    /// As `run_restic`, but with each line restic writes to stdout given to
+    /// `watch` as it is written.
+    fn run_restic_watched<F>(
+        &self,
+        build: F,
+        mut watch: Option<&mut dyn FnMut(&str)>,
+    ) -> Result<Output>
+    where
+        F: Fn() -> Result<Command>,
+    {
+        let mut retried = false;
+        let mut retries = Retries::new();
+        loop {
+            let mut cmd = build()?;
+            cmd.stderr(Stdio::piped());
+            // Stderr is copied to our own as restic writes it, so its
+            // progress is seen, and kept to look for a lock error in.
+            let out = match watch {
+                Some(ref mut watch) => cmd.watch_output(Watch::Stdout, &mut **watch)?,
+                None => cmd.tee_output()?,
+            };
+
+            if out.status.success() {
+                return Ok(out);
+            }
 
-        if !status.success() {
-            return Err(format_err!("Unable to run restic: {:?}", status));
+            let locked = is_lock_error(&out.stderr);
+            if locked && !retried && self.unlock_stale == Some(true) {
+                warning!(
+                    "Restic repo for {:?} is locked, removing stale locks",
+                    self.name
+                );
+                self.unlock()?;
+                retried = true;
+                continue;
+            }
+
+            let failure = if locked {
+                Some(RetryOn::Locked)
+            } else if network_error(&out.stderr) {
+                Some(RetryOn::Network)
+            } else {
+                None
+            };
+            if self.remote() && retries.again(&format!("Restic for {:?}", self.name), failure) {
+                continue;
+            }
+
+            if locked {
+                return Err(ResticError::Locked {
+                    volume: self.name.clone(),
+                    status: out.status,
+                }
+                .into());
+            }
+            return Err(ResticError::Failed(out.status).into());
         }
+    }
 
-        Ok(())
+    /// Is the repository on another host.
+    fn remote(&self) -> bool {
+        match self.repo {
+            Some(ref repo) => !(repo.starts_with('/') || repo.starts_with("local:")),
+            None => true,
+        }
+    }
+
+    /// Run `restic unlock`, which removes locks left by processes that are no
+    /// longer running.  Locks held by live processes are left alone.
+    fn unlock(&self) -> Result<()> {
+        let mut cmd = Command::new(RESTIC_BIN);
+        self.add_auth(&mut cmd)?;
+        cmd.arg("unlock");
+        cmd.stderr(Stdio::inherit());
+        cmd.checked_run()
     }
 }
 
-fn fix_time(snap: &str) -> String {
-    let re = Regex::new(r".*(\d{4})(\d\d)(\d\d)(\d\d)(\d\d)$").unwrap();
+/// Does this stderr output from restic indicate the repository is locked?
+fn is_lock_error(stderr: &[u8]) -> bool {
+    let text = String::from_utf8_lossy(stderr);
+    text.contains("repository is already locked")
+}
 
-    match re.captures(snap) {
-        Some(cap) => {
-            let year = cap.get(1).unwrap().as_str();
-            let month = cap.get(2).unwrap().as_str();
-            let day = cap.get(3).unwrap().as_str();
-            let hour = cap.get(4).unwrap().as_str();
-            let min = cap.get(5).unwrap().as_str();
+impl Filesystem {
+    fn restic_backup(&self, rvol: &ResticVolume, snap: &str) -> Result<()> {
+        // Mount on the bind directory to have a consistent path for
+        // restic.  This needs to be specific to the given filesystem.
+        let root = self.mount_snapshot(snap, Path::new(&rvol.bind))?;
+
+        // Run the actual restic command, following its progress.
+        let mut meter = Meter::new(&format!("Restic {}@{}", self.name, snap), Unit::Files, None);
+        let mut watch = |line: &str| match serde_json::from_str::<BackupMessage>(line) {
+            Ok(BackupMessage::Status { total_files, files_done }) => {
+                meter.set_total(total_files);
+                meter.set(files_done);
+            }
+            Ok(BackupMessage::Summary {
+                files_new,
+                files_changed,
+                data_added,
+            }) => progress!(
+                "Restic {}@{}: {} new files, {} changed, {} added",
+                self.name,
+                snap,
+                files_new,
+                files_changed,
+                humanize_size(data_added as usize).trim()
+            ),
+            Ok(BackupMessage::Other) => (),
+            Err(_) => output!("{}", line),
+        };
+        rvol.run_restic_watched(
+            || {
+                let mut cmd = heavy_command(RESTIC_BIN);
+                rvol.add_auth(&mut cmd)?;
+                cmd.args(&["backup", "--json", "--exclude-caches"]);
+                cmd.args(excludes::restic_args(&rvol.excludes, &rvol.bind));
+                cmd.args(&["--tag", snap,
+                         "--time", &fix_time(snap),
+                         &rvol.bind]);
+                Ok(cmd)
+            },
+            Some(&mut watch),
+        )?;
+        drop(meter);
+
+        root.unmount()
+    }
+}
 
-            format!("{}-{}-{} {}:{}:00", year, month, day, hour, min)
-        }
+/// The messages written by `restic backup --json`, one per line.
+#[derive(Deserialize)]
+#[serde(tag = "message_type", rename_all = "snake_case")]
+enum BackupMessage {
+    Status {
+        #[serde(default)]
+        total_files: u64,
+        #[serde(default)]
+        files_done: u64,
+    },
+    Summary {
+        files_new: u64,
+        files_changed: u64,
+        data_added: u64,
+    },
+    #[serde(other)]
+    Other,
+}
+
+fn fix_time(snap: &str) -> String {
+    match snap_time(snap) {
+        Some(time) => time.format("%Y-%m-%d %H:%M:%S").to_string(),
         None => "now".to_string()
     }
 }
 
 impl Config {
+    /// Prune zfs snapshots that are no longer present in any backup.  A
+    /// snapshot is kept as long as it is present in restic (under the bind
+    /// directory for its volume) or as an archive in a borg volume for the
+    /// same zfs filesystem, or if the `prune` algorithm of its snap volume
+    /// keeps it.  A snapshot is also kept until each of the backups its
+    /// snap volume requires has reached it.  Afterwards, sure versions are
+    /// dropped whose snapshot has been pruned and that are not in any
+    /// backup.
     pub fn restic_prune(&self, really: bool) -> Result<()> {
+        self.plan_prune()?.execute(!really)?;
+        self.inventory.invalidate();
+        Ok(())
+    }
+
+    /// Plan the pruning of zfs snapshots that aren't in any backup, and of
+    /// the sure versions captured from them.
+    pub fn plan_prune(&self) -> Result<Plan> {
+        configured("snap", &self.snap.volumes)?;
+        self.restic.validate()?;
+
         // Collect all of the restic snapshots.
         let rsnaps = self.restic.get_snaps()?;
 
-        let zfs = Zfs::new("none")?;
+        // Collect the borg archives, by repo.
+        let mut barchives = HashMap::new();
+        for b in &self.borg.volumes {
+            if !barchives.contains_key(b.repo.as_str()) {
+                barchives.insert(b.repo.as_str(), borg::list_archives(b)?);
+            }
+        }
+
+        // Collect the sure versions, by filesystem.
+        let mut sversions: HashMap<&str, HashSet<String>> = HashMap::new();
+        for sv in &self.sure.volumes {
+            let names = sv.open_store()?.get_versions()?.into_iter().map(|v| v.name);
+            sversions.entry(sv.zfs.as_str()).or_default().extend(names);
+        }
+
+        let zfs = Zfs::from_inventory("none", &self.inventory)?;
+        let mut plan = Plan::new("prune");
+        let mut pruned = HashSet::new();
 
         // Go through the snapshots themselves, pruning any that aren't
         // present in the restic snapshots.
         for vol in &self.snap.volumes {
+            if skipped("snap", &vol.name, vol.skip) {
+                continue;
+            }
             // Find the restic bind directory this was backed up under.
             let bind = self.restic.find_bind(&vol.zfs)?;
-            println!("{:?}: {:?}", bind, vol);
+            progress!("{:?}: {:?}", bind, vol);
+
+            let borgs: Vec<_> = self.borg.volumes.iter().filter(|b| b.zfs == vol.zfs).collect();
 
             // Find the filesystem in ZFS.
-            let fs = if let Some(fs) = zfs.filesystems.iter().find(|&fs| fs.name == vol.zfs) {
-                fs
-            } else {
-                return Err(err_msg("No snapshots match"));
+            let fs = zfs.find(&vol.zfs)?;
+
+            let conv = self.snap.conventions.iter().find(|c| c.name == vol.convention);
+            let kept = match (vol.prune, conv) {
+                (Some(alg), Some(conv)) => alg.keep(conv, &fs.snaps, Utc::now().naive_utc()),
+                _ => HashSet::new(),
             };
 
-            // Go through each snapshot in zfs, and if not present in a
-            // restic backup, prune it.
-            for snap in &fs.snaps {
-                if !rsnaps.contains(&ResticSnap {
+            let has = |kind: BackupKind, snap: &str| match kind {
+                BackupKind::Restic => rsnaps.contains(&ResticSnap {
                     path: bind.clone(),
-                    tag: snap.to_owned()
-                }) {
-                    zfs.prune(&vol.zfs, snap, really)?;
-                } else {
-                    println!(" keep {:?}@{:?}", vol.zfs, snap);
+                    tag: snap.to_owned(),
+                }),
+                BackupKind::Borg => borgs
+                    .iter()
+                    .any(|b| barchives[b.repo.as_str()].contains(&b.prefix, snap)),
+                BackupKind::Sure => {
+                    sversions.get(vol.zfs.as_str()).map_or(false, |v| v.contains(snap))
+                }
+            };
+
+            // Before pruning, each required backup must have reached the
+            // snapshot, by having it or a later one.  Otherwise, it may still
+            // be waiting to be captured.
+            let reached: Vec<_> = self
+                .required_backups(vol)
+                .into_iter()
+                .map(|kind| (kind, fs.snaps.iter().rposition(|s| has(kind, s))))
+                .collect();
+
+            // Go through each snapshot in zfs, and if not present in a
+            // restic or borg backup, prune it.
+            for (pos, snap) in fs.snaps.iter().enumerate() {
+                let in_restic = has(BackupKind::Restic, snap);
+                let in_borg = has(BackupKind::Borg, snap);
+                if in_restic || in_borg || kept.contains(snap.as_str()) {
+                    decision!(" keep {:?}@{:?}", vol.zfs, snap);
+                    continue;
+                }
+                let waiting: Vec<_> = reached
+                    .iter()
+                    .filter(|(_, newest)| newest.map_or(true, |newest| newest < pos))
+                    .map(|(kind, _)| kind.to_string())
+                    .collect();
+                if !waiting.is_empty() {
+                    decision!(" keep {:?}@{:?}: not yet in {}", vol.zfs, snap, waiting.join(", "));
+                    continue;
+                }
+                if zfs.prune(&vol.zfs, snap, "not in any backup", &mut plan) {
+                    pruned.insert((vol.zfs.as_str(), snap.as_str()));
                 }
             }
         }
 
+        // Drop sure versions that no longer correspond to a snapshot, or to
+        // anything that was backed up.
+        for sv in &self.sure.volumes {
+            let live: HashSet<&str> = match zfs.filesystems.iter().find(|fs| fs.name == sv.zfs) {
+                Some(fs) => fs
+                    .snaps
+                    .iter()
+                    .map(|s| s.as_str())
+                    .filter(|s| !pruned.contains(&(sv.zfs.as_str(), *s)))
+                    .collect(),
+                None => HashSet::new(),
+            };
+            let binds: Vec<_> = self.restic.volumes.iter().filter(|r| r.zfs == sv.zfs).collect();
+            let borgs: Vec<_> = self.borg.volumes.iter().filter(|b| b.zfs == sv.zfs).collect();
+
+            let keep = |name: &str| {
+                live.contains(name)
+                    || binds.iter().any(|r| {
+                        rsnaps.contains(&ResticSnap {
+                            path: r.bind.clone(),
+                            tag: name.to_owned(),
+                        })
+                    })
+                    || borgs
+                        .iter()
+                        .any(|b| barchives[b.repo.as_str()].contains(&b.prefix, name))
+            };
+            surestore::plan_prune(sv.store_path()?, &keep, &mut plan)?;
+        }
+
+        Ok(plan)
+    }
+}
+
+impl Config {
+    /// The kinds of backup that must have reached a snapshot of the given
+    /// volume before it is pruned.  Unless the volume says, this is every
+    /// kind of backup made of its filesystem.
+    fn required_backups(&self, vol: &SnapVolume) -> Vec<BackupKind> {
+        if let Some(ref require) = vol.require {
+            return require.clone();
+        }
+        let mut kinds = vec![];
+        if self.restic.volumes.iter().any(|v| v.zfs == vol.zfs) {
+            kinds.push(BackupKind::Restic);
+        }
+        if self.borg.volumes.iter().any(|v| v.zfs == vol.zfs) {
+            kinds.push(BackupKind::Borg);
+        }
+        if self.sure.volumes.iter().any(|v| v.zfs == vol.zfs) {
+            kinds.push(BackupKind::Sure);
+        }
+        kinds
+    }
+}
+
+impl ResticBackend {
+    /// The restic repository string for this backend.
+    fn repo_url(&self) -> String {
+        match self {
+            ResticBackend::S3 {
+                endpoint,
+                bucket,
+                path,
+                ..
+            } => {
+                let endpoint = endpoint
+                    .as_ref()
+                    .map(|e| e.as_str())
+                    .unwrap_or("s3.amazonaws.com");
+                match path {
+                    Some(path) => format!("s3:{}/{}/{}", endpoint, bucket, path),
+                    None => format!("s3:{}/{}", endpoint, bucket),
+                }
+            }
+            ResticBackend::B2 { bucket, path, .. } => {
+                format!("b2:{}:{}", bucket, path.as_ref().map(|p| p.as_str()).unwrap_or(""))
+            }
+            ResticBackend::Rest { url, .. } => format!("rest:{}", url),
+        }
+    }
+
+    /// The environment variables restic needs to access this backend.
+    fn environment(&self) -> Result<Vec<(&'static str, String)>> {
+        let mut env = vec![];
+        match self {
+            ResticBackend::S3 { key_id, secret, .. } => {
+                env.push(("AWS_ACCESS_KEY_ID", key_id.clone()));
+                env.push(("AWS_SECRET_ACCESS_KEY", secret.get()?));
+            }
+            ResticBackend::B2 {
+                account_id, key, ..
+            } => {
+                env.push(("B2_ACCOUNT_ID", account_id.clone()));
+                env.push(("B2_ACCOUNT_KEY", key.get()?));
+            }
+            ResticBackend::Rest { user, password, .. } => {
+                if let Some(user) = user {
+                    env.push(("RESTIC_REST_USERNAME", user.clone()));
+                }
+                if let Some(password) = password {
+                    env.push(("RESTIC_REST_PASSWORD", password.get()?));
+                }
+            }
+        }
+        Ok(env)
+    }
+
+    /// Check the fields required by each type of backend.
+    fn validate(&self) -> Result<()> {
+        match self {
+            ResticBackend::S3 {
+                endpoint,
+                bucket,
+                key_id,
+                secret,
+                ..
+            } => {
+                if let Some(endpoint) = endpoint {
+                    if endpoint.is_empty() {
+                        return Err(Error::msg("s3 endpoint is empty"));
+                    }
+                }
+                if bucket.is_empty() || bucket.contains('/') {
+                    return Err(Error::msg(format!("invalid s3 bucket {:?}", bucket)));
+                }
+                if key_id.is_empty() {
+                    return Err(Error::msg("s3 backend requires key_id"));
+                }
+                secret.validate()?;
+            }
+            ResticBackend::B2 {
+                bucket,
+                account_id,
+                key,
+                ..
+            } => {
+                if bucket.is_empty() || bucket.contains(':') {
+                    return Err(Error::msg(format!("invalid b2 bucket {:?}", bucket)));
+                }
+                if account_id.is_empty() {
+                    return Err(Error::msg("b2 backend requires account_id"));
+                }
+                key.validate()?;
+            }
+            ResticBackend::Rest {
+                url,
+                user,
+                password,
+            } => {
+                if !url.starts_with("http://") && !url.starts_with("https://") {
+                    let msg = format!("rest url {:?} must be http or https", url);
+                    return Err(Error::msg(msg));
+                }
+                if user.is_some() != password.is_some() {
+                    let msg = "rest backend needs both user and password, or neither";
+                    return Err(Error::msg(msg));
+                }
+                if let Some(password) = password {
+                    password.validate()?;
+                }
+            }
+        }
         Ok(())
     }
 }
 
 impl ResticConfig {
+    /// Validate all of the volumes.  This should be done before running any
+    /// backups so that a configuration problem doesn't stop a run partway
+    /// through.
+    pub fn validate(&self) -> Result<()> {
+        for v in &self.volumes {
+            v.validate()?;
+        }
+        Ok(())
+    }
+
     fn get_snaps(&self) -> Result<HashSet<ResticSnap>> {
         let mut rsnaps = HashSet::new();
 
@@ -244,14 +846,14 @@ impl ResticConfig {
         let binds: Vec<_> = self.volumes.iter().filter(|v| v.zfs == zfs).collect();
         match binds.len() {
             1 => Ok(binds[0].bind.clone()),
-            0 => Err(format_err!("No restic backups found for zfs {:?}", zfs)),
+            0 => Err(ResticError::NoBackups(zfs.to_string()).into()),
             _ => {
                 // Multiple backups are fine, as long as they all use the
                 // same binding.
                 let result = binds[0].bind.clone();
                 for b in &binds[1..] {
                     if result != b.bind {
-                        return Err(format_err!("Restic backups for zfs {:?} have different bind", zfs));
+                        return Err(ResticError::MixedBinds(zfs.to_string()).into());
                     }
                 }
                 Ok(result)
@@ -265,3 +867,30 @@ struct ResticSnap {
     path: String,
     tag: String,
 }
+
+#[test]
+fn test_backend_repo_url() {
+    let s3 = ResticBackend::S3 {
+        endpoint: None,
+        bucket: "backups".to_string(),
+        path: Some("home".to_string()),
+        key_id: "id".to_string(),
+        secret: crate::secret::SecretSource::Value("secret".to_string()),
+    };
+    assert_eq!(s3.repo_url(), "s3:s3.amazonaws.com/backups/home");
+
+    let b2 = ResticBackend::B2 {
+        bucket: "backups".to_string(),
+        path: None,
+        account_id: "id".to_string(),
+        key: crate::secret::SecretSource::Value("key".to_string()),
+    };
+    assert_eq!(b2.repo_url(), "b2:backups:");
+
+    let rest = ResticBackend::Rest {
+        url: "https://host:8000/home".to_string(),
+        user: None,
+        password: None,
+    };
+    assert_eq!(rest.repo_url(), "rest:https://host:8000/home");
+}
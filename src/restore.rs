@@ -0,0 +1,128 @@
+//! Copying files back from a snapshot.
+//!
+//! `rack restore home 2024-06-01 davidb/notes` copies files, or whole
+//! directories, from a snapshot of a volume back into the live filesystem,
+//! or with `--to`, into another directory.  The snapshot is picked as for
+//! `rack mount`.  Paths are relative to the root of the filesystem, or are
+//! absolute paths under where it is mounted.  With `--from-clone`, the
+//! snapshot is read from the clone of the filesystem on this host instead,
+//! such as once it has been pruned here, or the filesystem itself is lost.
+//! The files are copied with rsync, which never deletes anything, and
+//! `--pretend` has rsync list what it would write, and so overwrite,
+//! without writing it.
+
+use crate::{
+    browse::select,
+    checked::{heavy_command, CheckedExt},
+    config::Config,
+    journal::state_dir,
+    mount,
+    zfs::{Zfs, ZfsError},
+    Error, Result,
+};
+use std::{
+    fs,
+    path::{Component, Path, PathBuf},
+};
+
+impl Config {
+    /// Restore `paths` from the snapshot of `volume` picked by `selector`,
+    /// into the filesystem, or `to`.  With `from_clone`, the snapshot is
+    /// that of the clone of the filesystem.
+    pub fn restore(
+        &self,
+        volume: &str,
+        selector: &str,
+        paths: &[String],
+        from_clone: bool,
+        to: Option<&str>,
+        pretend: bool,
+    ) -> Result<()> {
+        let name = self.snap_zfs(volume);
+        let mountpoint = mount::zfs_mountpoint(name)?;
+        let target = match (to, &mountpoint) {
+            (Some(to), _) => PathBuf::from(to),
+            (None, Some(mountpoint)) => PathBuf::from(mountpoint),
+            (None, None) => return Err(ZfsError::NotMounted { fs: name.to_string() }.into()),
+        };
+        let mut relative = vec![];
+        for path in paths {
+            relative.push(under(path, mountpoint.as_deref())?);
+        }
+
+        let zfs = Zfs::from_inventory("none", &self.inventory)?;
+        let fs = if from_clone {
+            zfs.find_filesystem(&self.clone_name(name)?)?
+        } else {
+            zfs.find_filesystem(name)?
+        };
+        let snap = select(&fs.snaps, selector)?;
+
+        let dir = state_dir()?.join("restore");
+        fs::create_dir_all(&dir)?;
+        let root = fs.mount_snapshot(snap, &dir)?;
+        for path in &relative {
+            if fs::symlink_metadata(dir.join(path)).is_err() {
+                return Err(Error::msg(format!("{:?} isn't in {}@{}", path, fs.name, snap)));
+            }
+        }
+
+        progress!("Restore from {}@{} to {:?}", fs.name, snap, target);
+        let mut cmd = heavy_command("rsync");
+        cmd.args(&["-aHAX", "--numeric-ids", "--relative"]);
+        if pretend {
+            cmd.args(&["--dry-run", "--itemize-changes"]);
+        }
+        // The "." marks where the paths rsync recreates under the target
+        // start.
+        for path in &relative {
+            cmd.arg(dir.join(".").join(path));
+        }
+        cmd.arg(format!("{}/", target.display()));
+        let result = cmd.checked_run();
+        root.unmount()?;
+        result
+    }
+
+    /// The name of the clone, on this host, of the local filesystem `name`.
+    fn clone_name(&self, name: &str) -> Result<String> {
+        for vol in &self.clone.volumes {
+            let within = name == vol.source || name.starts_with(&format!("{}/", vol.source));
+            if vol.host.is_some() || !within {
+                continue;
+            }
+            let mut zfs = Zfs::from_inventory("none", &self.inventory)?;
+            zfs.options.renames = vol.renames.clone().into_iter().collect();
+            return Ok(zfs.cloned_name(&vol.source, &vol.dest, name));
+        }
+        Err(Error::msg(format!("No clone volume copies {:?} to this host", name)))
+    }
+}
+
+/// The path within the filesystem of `path`, which is either relative to
+/// its root, or absolute, under `mountpoint`.
+fn under(path: &str, mountpoint: Option<&str>) -> Result<PathBuf> {
+    let given = Path::new(path);
+    let relative = if given.is_absolute() {
+        mountpoint
+            .and_then(|m| given.strip_prefix(m).ok())
+            .ok_or_else(|| Error::msg(format!("{:?} isn't in the volume", path)))?
+    } else {
+        given
+    };
+    if !relative.components().all(|c| matches!(c, Component::Normal(_) | Component::CurDir)) {
+        return Err(Error::msg(format!("{:?} reaches outside the volume", path)));
+    }
+    Ok(relative.to_path_buf())
+}
+
+#[test]
+fn test_under() {
+    let mountpoint = Some("/home");
+    assert_eq!(under("davidb/notes", mountpoint).unwrap(), Path::new("davidb/notes"));
+    assert_eq!(under("/home/davidb/notes", mountpoint).unwrap(), Path::new("davidb/notes"));
+    assert_eq!(under("/home", mountpoint).unwrap(), Path::new(""));
+    assert!(under("/homework/notes", mountpoint).is_err());
+    assert!(under("/home/davidb", None).is_err());
+    assert!(under("davidb/../../etc", mountpoint).is_err());
+}
@@ -0,0 +1,210 @@
+//! Pruning every copy of a snapshot together.
+//!
+//! `rack prune` destroys zfs snapshots once no backup needs them, while
+//! `rack borg-prune` leaves borg to its own rules, so the copies of one
+//! snapshot can be kept for quite different times.  `rack prune-all` makes
+//! one decision for each snapshot of a snap volume, from the keep rules of
+//! its convention along with its `prune` algorithm, and removes each
+//! snapshot it doesn't keep from everywhere: zfs, the restic and borg
+//! volumes of the same filesystem, and its sure store.  Snapshots are
+//! matched by name, so one only left in some backup is decided on as well.
+//!
+//! The zfs snapshots are destroyed first, as a restic or borg run would
+//! back up again a snapshot still in zfs that was gone from the repository.
+//! The sure versions are dropped last, so that if the plan stops partway,
+//! the copies left can still be verified.  A zfs snapshot that can't be
+//! destroyed, being held or cloned, is kept in the backups too.
+
+use crate::{
+    borg,
+    config::{configured, skipped, Config},
+    naming,
+    plan::Plan,
+    prune,
+    restic::SURE_TAG,
+    surestore,
+    zfs::Zfs,
+    Error, Result,
+};
+use chrono::{NaiveDateTime, Utc};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+impl Config {
+    /// Prune the snapshots the snap volumes don't keep from zfs, restic,
+    /// borg, and sure.  Unless `really` is set, just print the plan.
+    pub fn prune_all(&self, really: bool) -> Result<()> {
+        self.plan_prune_all()?.execute(!really)?;
+        self.inventory.invalidate();
+        Ok(())
+    }
+
+    /// Plan the pruning of every copy of the snapshots the snap volumes
+    /// don't keep.
+    pub fn plan_prune_all(&self) -> Result<Plan> {
+        configured("snap", &self.snap.volumes)?;
+        self.restic.validate()?;
+
+        // Collect the borg archives, by repo.
+        let mut barchives = HashMap::new();
+        for b in &self.borg.volumes {
+            if !barchives.contains_key(b.repo.as_str()) {
+                barchives.insert(b.repo.as_str(), borg::list_archives(b)?);
+            }
+        }
+
+        let zfs = Zfs::from_inventory("none", &self.inventory)?;
+        let now = Utc::now().naive_utc();
+
+        // The actions are gathered by kind, and put in order at the end.
+        let mut plan = Plan::new("prune-all");
+        let mut backups = Plan::new("prune-all");
+        let mut sures = Plan::new("prune-all");
+
+        for vol in &self.snap.volumes {
+            if skipped("snap", &vol.name, vol.skip) {
+                continue;
+            }
+            let conv = self
+                .snap
+                .conventions
+                .iter()
+                .find(|c| c.name == vol.convention)
+                .ok_or_else(|| {
+                    let (conv, name) = (&vol.convention, &vol.name);
+                    Error::msg(format!("Invalid convention {:?} in snap {:?}", conv, name))
+                })?;
+            let counts = [
+                conv.last,
+                conv.hourly,
+                conv.daily,
+                conv.weekly,
+                conv.monthly,
+                conv.yearly,
+            ];
+            if vol.prune.is_none() && counts.iter().all(|c| c.is_none()) {
+                decision!("Snap {:?}: nothing decides what to keep, leaving it alone", vol.name);
+                continue;
+            }
+            let fs = zfs.find(&vol.zfs)?;
+
+            let rvols: Vec<_> = self
+                .restic
+                .volumes
+                .iter()
+                .filter(|r| r.zfs == vol.zfs && !skipped("restic", &r.name, r.skip))
+                .collect();
+            let bvols: Vec<_> = self
+                .borg
+                .volumes
+                .iter()
+                .filter(|b| b.zfs == vol.zfs && !skipped("borg", &b.name, b.skip))
+                .collect();
+            let svols: Vec<_> = self.sure.volumes.iter().filter(|s| s.zfs == vol.zfs).collect();
+            let mut rsnaps = vec![];
+            for r in &rvols {
+                rsnaps.push(r.get_snapshots()?);
+            }
+            let mut stores = vec![];
+            for sv in &svols {
+                let names: Vec<_> =
+                    sv.open_store()?.get_versions()?.into_iter().map(|v| v.name).collect();
+                stores.push((sv.store_path()?, names));
+            }
+
+            // Every snapshot of the convention with a copy anywhere, oldest
+            // first.
+            let mut times: BTreeMap<String, NaiveDateTime> = BTreeMap::new();
+            {
+                let mut add = |name: &str| {
+                    if let Some(parsed) = naming::parse(&vol.convention, name) {
+                        times.insert(name.to_string(), parsed.time);
+                    }
+                };
+                fs.snaps.iter().for_each(|s| add(s));
+                for (r, snaps) in rvols.iter().zip(&rsnaps) {
+                    for s in snaps.iter().filter(|s| s.paths.contains(&r.bind)) {
+                        s.tags.iter().flatten().for_each(|t| add(t));
+                    }
+                }
+                for b in &bvols {
+                    for a in barchives[b.repo.as_str()].iter() {
+                        if let Some(name) = a.name.strip_prefix(b.prefix.as_str()) {
+                            add(name);
+                        }
+                    }
+                }
+                for (_, names) in &stores {
+                    names.iter().for_each(|n| add(n));
+                }
+            }
+            let mut names: Vec<_> = times.keys().cloned().collect();
+            names.sort_by_key(|n| times[n]);
+
+            let dated: Vec<_> = names.iter().map(|n| (n.as_str(), times[n])).collect();
+            let mut keep = prune::rules(conv, &dated);
+            if let Some(alg) = vol.prune {
+                keep.extend(alg.keep(conv, &names, now));
+            }
+            let mut keep: HashSet<String> = keep.into_iter().map(|n| n.to_string()).collect();
+
+            for snap in &fs.snaps {
+                if !times.contains_key(snap) || keep.contains(snap) {
+                    continue;
+                }
+                // One that can't be destroyed is kept everywhere.
+                if !zfs.prune(&vol.zfs, snap, "not kept", &mut plan) {
+                    keep.insert(snap.clone());
+                }
+            }
+            for name in &names {
+                if keep.contains(name) {
+                    decision!(" keep {:?}@{:?}", vol.zfs, name);
+                }
+            }
+            let dropped = |name: &str| times.contains_key(name) && !keep.contains(name);
+
+            for (r, snaps) in rvols.iter().zip(&rsnaps) {
+                // The sure stores pushed into the repo are tagged with the
+                // snapshot they were captured from.
+                let ids: Vec<_> = snaps
+                    .iter()
+                    .filter(|s| {
+                        let tags = s.tags.as_deref().unwrap_or_default();
+                        let pushed = |(path, _): &(&str, _)| s.paths.iter().any(|p| p == path);
+                        let ours = s.paths.contains(&r.bind)
+                            || (tags.iter().any(|t| t == SURE_TAG) && stores.iter().any(pushed));
+                        ours && tags.iter().any(|t| dropped(t))
+                            && !tags.iter().any(|t| keep.contains(t))
+                    })
+                    .map(|s| s.id.clone())
+                    .collect();
+                if !ids.is_empty() {
+                    let reason =
+                        format!("forget {} snapshots of {:?}: not kept", ids.len(), vol.zfs);
+                    backups.restic_forget(reason, r, ids);
+                }
+            }
+
+            for b in &bvols {
+                let archives: Vec<_> = barchives[b.repo.as_str()]
+                    .iter()
+                    .filter(|a| a.name.strip_prefix(b.prefix.as_str()).map_or(false, dropped))
+                    .map(|a| a.name.clone())
+                    .collect();
+                if !archives.is_empty() {
+                    let reason =
+                        format!("delete {} archives of {:?}: not kept", archives.len(), vol.zfs);
+                    backups.borg_delete(reason, b, archives);
+                }
+            }
+
+            for (path, _) in &stores {
+                surestore::plan_prune(path, &|name| !dropped(name), &mut sures)?;
+            }
+        }
+
+        plan.actions.extend(backups.actions);
+        plan.actions.extend(sures.actions);
+        Ok(plan)
+    }
+}
@@ -0,0 +1,155 @@
+//! Retrying commands that go over the network.
+//!
+//! A nightly run can be lost to a network that drops out for a minute.
+//! With a `retry` section in the config, restic and borg backups to remote
+//! repositories, and clones pulled over ssh, are tried again after such a
+//! failure, waiting longer before each retry.  Only network failures are
+//! retried, unless the config asks for repositories locked by another
+//! client, or for any failure at all, to be retried too.  Failures are told
+//! apart by what the command wrote to stderr, or for ssh, by its exit
+//! status of 255.
+
+use crate::{
+    borg::BorgError,
+    config::{RetryConfig, RetryOn},
+    restic::ResticError,
+    zfs::ZfsError,
+    Error, Result,
+};
+use std::{sync::Mutex, thread, time::Duration};
+
+/// The policy, set once the config is loaded.
+static POLICY: Mutex<Option<RetryConfig>> = Mutex::new(None);
+
+/// The messages, in lowercase, of failures to reach another host.
+const NETWORK_ERRORS: &[&str] = &[
+    "connection refused",
+    "connection reset",
+    "connection timed out",
+    "connection closed by remote host",
+    "broken pipe",
+    "i/o timeout",
+    "tls handshake timeout",
+    "no such host",
+    "temporary failure in name resolution",
+    "could not resolve hostname",
+    "network is unreachable",
+    "no route to host",
+];
+
+/// Set the policy for retrying failures.
+pub fn set_policy(policy: Option<&RetryConfig>) {
+    *POLICY.lock().unwrap() = policy.cloned();
+}
+
+/// Does this stderr output say that another host couldn't be reached?
+pub(crate) fn network_error(stderr: &[u8]) -> bool {
+    let text = String::from_utf8_lossy(stderr).to_lowercase();
+    NETWORK_ERRORS.iter().any(|e| text.contains(e))
+}
+
+/// The kind of an error, if it is one that can be retried, short of `any`.
+fn failure_of(error: &Error) -> Option<RetryOn> {
+    match error {
+        Error::Zfs(ZfsError::Connection { .. }) => Some(RetryOn::Network),
+        Error::Command { command, status }
+            if command.starts_with("\"ssh\"") && status.code() == Some(255) =>
+        {
+            Some(RetryOn::Network)
+        }
+        Error::Restic(ResticError::Locked { .. }) => Some(RetryOn::Locked),
+        Error::Borg(BorgError::Locked { .. }) | Error::Borg(BorgError::LockedByOther { .. }) => {
+            Some(RetryOn::Locked)
+        }
+        Error::Context { source, .. } => failure_of(source),
+        _ => None,
+    }
+}
+
+/// The retries made of one operation.
+pub(crate) struct Retries {
+    policy: Option<RetryConfig>,
+    tried: u32,
+}
+
+impl Retries {
+    pub fn new() -> Retries {
+        Retries::with(POLICY.lock().unwrap().clone())
+    }
+
+    fn with(policy: Option<RetryConfig>) -> Retries {
+        Retries {
+            policy: policy,
+            tried: 0,
+        }
+    }
+
+    /// After `what` has failed, with a failure of kind `failure`, whether
+    /// to try it again.  When it is tried again, this first waits out the
+    /// backoff.
+    pub fn again(&mut self, what: &str, failure: Option<RetryOn>) -> bool {
+        let policy = match self.policy {
+            Some(ref policy) => policy,
+            None => return false,
+        };
+        let count = policy.count.unwrap_or(3);
+        let on = policy.on.as_deref().unwrap_or(&[RetryOn::Network]);
+        let retried = on.contains(&RetryOn::Any) || failure.map_or(false, |f| on.contains(&f));
+        if !retried || self.tried >= count {
+            return false;
+        }
+
+        let wait = policy.backoff.unwrap_or(30).saturating_mul(1 << self.tried.min(16));
+        self.tried += 1;
+        warning!("{} failed, retry {} of {} in {}s", what, self.tried, count, wait);
+        thread::sleep(Duration::from_secs(wait));
+        true
+    }
+}
+
+/// Run `op`, trying it again after the failures the policy retries.
+pub(crate) fn retrying<T, F>(what: &str, mut op: F) -> Result<T>
+where
+    F: FnMut() -> Result<T>,
+{
+    let mut retries = Retries::new();
+    loop {
+        match op() {
+            Err(e) if retries.again(what, failure_of(&e)) => (),
+            result => return result,
+        }
+    }
+}
+
+#[test]
+fn test_retries() {
+    let policy = RetryConfig {
+        count: Some(2),
+        backoff: Some(0),
+        on: None,
+    };
+    let mut retries = Retries::with(Some(policy.clone()));
+    assert!(!retries.again("borg", None));
+    assert!(!retries.again("borg", Some(RetryOn::Locked)));
+    assert!(retries.again("borg", Some(RetryOn::Network)));
+    assert!(retries.again("borg", Some(RetryOn::Network)));
+    assert!(!retries.again("borg", Some(RetryOn::Network)));
+
+    let any = RetryConfig {
+        on: Some(vec![RetryOn::Any]),
+        ..policy
+    };
+    assert!(Retries::with(Some(any)).again("restic", None));
+    assert!(!Retries::with(None).again("restic", Some(RetryOn::Network)));
+
+    let lost = Error::Context {
+        context: "pull home".into(),
+        source: Box::new(ZfsError::Connection { host: "laptop".into() }.into()),
+    };
+    assert_eq!(failure_of(&lost), Some(RetryOn::Network));
+    assert_eq!(failure_of(&ResticError::NoMatchingSure.into()), None);
+
+    assert!(network_error(b"Fatal: unable to open repo: dial tcp: i/o timeout\n"));
+    assert!(network_error(b"Remote: ssh: Could not resolve hostname nas\n"));
+    assert!(!network_error(b"Fatal: wrong password or no key found\n"));
+}
@@ -0,0 +1,77 @@
+//! A lock held while rack is doing work.
+//!
+//! The lock is a file in the state directory holding the pid of the rack
+//! process that owns it.  A lock whose process has exited is considered
+//! stale and is taken over.  Holding this lock is how rack knows that
+//! repository locks left behind belong to a crashed run, rather than to
+//! another rack that is still running.
+
+use crate::{journal::state_dir, Result};
+use std::{
+    fs::{self, OpenOptions},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    process,
+};
+
+pub struct RunLock(PathBuf);
+
+impl RunLock {
+    /// Try to take the run lock.  Returns None if another rack process
+    /// holds it.
+    pub fn try_acquire() -> Result<Option<RunLock>> {
+        let path = state_dir()?.join("run.lock");
+
+        // Two tries: the second after removing a stale lock.
+        for _ in 0..2 {
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(mut fd) => {
+                    writeln!(fd, "{}", process::id())?;
+                    return Ok(Some(RunLock(path)));
+                }
+                Err(ref e) if e.kind() == ErrorKind::AlreadyExists => {
+                    if holder_running(&path) {
+                        return Ok(None);
+                    }
+                    warning!("Removing stale run lock {:?}", path);
+                    fs::remove_file(&path)?;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Is the run lock held by this process?
+pub fn held_by_us() -> bool {
+    let path = match state_dir() {
+        Ok(dir) => dir.join("run.lock"),
+        Err(_) => return false,
+    };
+    match fs::read_to_string(&path) {
+        Ok(text) => text.trim().parse::<u32>().ok() == Some(process::id()),
+        Err(_) => false,
+    }
+}
+
+impl Drop for RunLock {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.0) {
+            warning!("Error removing run lock {:?}: {}", self.0, e);
+        }
+    }
+}
+
+/// Is the process named in the given lock file still running?  A lock that
+/// can't be read is treated as held, to be safe.
+fn holder_running(path: &Path) -> bool {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(_) => return true,
+    };
+    match text.trim().parse::<u32>() {
+        Ok(pid) => Path::new(&format!("/proc/{}", pid)).exists(),
+        Err(_) => true,
+    }
+}
@@ -0,0 +1,193 @@
+//! When each snap convention fires.
+//!
+//! A snap convention can be given a `schedule`, in the five fields of a cron
+//! entry: minute, hour, day of the month, month, and day of the week, in
+//! local time, such as "0 3 * * *" for three in the morning, or one of
+//! "@hourly", "@daily", "@weekly", "@monthly" and "@yearly".  A field is
+//! "*", a value, a range such as "1-5", a list of those such as "0,30", and
+//! any of them can be followed by a step, as in "*/15".  As with cron, when
+//! both the day of the month and the day of the week are given, a day
+//! matching either of them is picked.
+//!
+//! There is no daemon: `rack auto` is expected to be run often, such as
+//! every few minutes by cron, and takes a snapshot of each volume whose
+//! convention has been scheduled since the newest snapshot of it, so a run
+//! that is late, or missed, is made up for by the next one.  `rack snap`
+//! ignores the schedules, and snapshots every volume.
+
+use chrono::{DateTime, Datelike, Duration, Local, NaiveDateTime, TimeZone, Timelike, Utc};
+use std::fmt;
+
+/// How far back to look for a time the schedule picks, so that one that
+/// can never fire, such as on the 30th of February, isn't searched forever.
+const SEARCH_DAYS: i64 = 8 * 366;
+
+/// The times picked by a cron-like schedule.  Each field is a set of bits,
+/// one for each value.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Schedule {
+    text: String,
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Whether the day of the month, or of the week, was given as "*".
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl Schedule {
+    /// Parse a schedule such as "0 */6 * * *" or "@daily".
+    pub fn parse(text: &str) -> Option<Schedule> {
+        let expanded = match text.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            other => other,
+        };
+        let fields: Vec<_> = expanded.split_whitespace().collect();
+        let (minute, hour, day, month, weekday) = match fields[..] {
+            [minute, hour, day, month, weekday] => (minute, hour, day, month, weekday),
+            _ => return None,
+        };
+        // Sunday is either 0 or 7.
+        let mut weekdays = field(weekday, 0, 7)?;
+        if weekdays & (1 << 7) != 0 {
+            weekdays = (weekdays | 1) & !(1 << 7);
+        }
+        Some(Schedule {
+            text: text.trim().to_string(),
+            minutes: field(minute, 0, 59)?,
+            hours: field(hour, 0, 23)?,
+            days: field(day, 1, 31)?,
+            months: field(month, 1, 12)?,
+            weekdays: weekdays,
+            any_day: day.starts_with('*'),
+            any_weekday: weekday.starts_with('*'),
+        })
+    }
+
+    /// Has the schedule picked a time since `newest`, the time, in UTC, of
+    /// the newest snapshot, if there is one.
+    pub fn is_due(&self, newest: Option<NaiveDateTime>, now: DateTime<Utc>) -> bool {
+        let local = |time: &NaiveDateTime| Local.from_utc_datetime(time).naive_local();
+        match self.last_before(local(&now.naive_utc())) {
+            Some(fired) => newest.map_or(true, |newest| local(&newest) < fired),
+            None => false,
+        }
+    }
+
+    /// The latest time the schedule picks, to the minute, at or before
+    /// `now`.
+    fn last_before(&self, now: NaiveDateTime) -> Option<NaiveDateTime> {
+        let today = now.date();
+        for back in 0..SEARCH_DAYS {
+            let date = today - Duration::days(back);
+            if !self.picks_day(date.day(), date.month(), date.weekday().num_days_from_sunday()) {
+                continue;
+            }
+            let (last_hour, last_minute) = if back == 0 {
+                (now.hour(), now.minute())
+            } else {
+                (23, 59)
+            };
+            for hour in (0..=last_hour).rev().filter(|h| self.hours & (1 << h) != 0) {
+                let last_minute = if hour == last_hour { last_minute } else { 59 };
+                let minute = (0..=last_minute).rev().find(|m| self.minutes & (1 << m) != 0);
+                if let Some(minute) = minute {
+                    return date.and_hms_opt(hour, minute, 0);
+                }
+            }
+        }
+        None
+    }
+
+    fn picks_day(&self, day: u32, month: u32, weekday: u32) -> bool {
+        if self.months & (1 << month) == 0 {
+            return false;
+        }
+        let day = self.days & (1 << day) != 0;
+        let weekday = self.weekdays & (1 << weekday) != 0;
+        if self.any_day || self.any_weekday {
+            day && weekday
+        } else {
+            day || weekday
+        }
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+/// The values, from `min` to `max`, picked by one field of a schedule, as
+/// bits.
+fn field(text: &str, min: u32, max: u32) -> Option<u64> {
+    let number = |s: &str| -> Option<u32> {
+        let n = s.parse().ok()?;
+        if n < min || n > max {
+            return None;
+        }
+        Some(n)
+    };
+    let mut bits = 0;
+    for part in text.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, Some(step.parse::<u32>().ok().filter(|&s| s > 0)?)),
+            None => (part, None),
+        };
+        let (first, last) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((first, last)) => (number(first)?, number(last)?),
+            // A value with a step runs to the end of the field.
+            None if step.is_some() => (number(range)?, max),
+            None => (number(range)?, number(range)?),
+        };
+        if first > last {
+            return None;
+        }
+        for n in (first..=last).step_by(step.unwrap_or(1) as usize) {
+            bits |= 1 << n;
+        }
+    }
+    Some(bits)
+}
+
+#[test]
+fn test_schedule() {
+    use chrono::NaiveDate;
+
+    let at = |month, day, hour, minute| {
+        let date = NaiveDate::from_ymd_opt(2024, month, day).unwrap();
+        date.and_hms_opt(hour, minute, 0).unwrap()
+    };
+
+    let daily = Schedule::parse("0 3 * * *").unwrap();
+    assert_eq!(daily.last_before(at(6, 3, 3, 0)), Some(at(6, 3, 3, 0)));
+    assert_eq!(daily.last_before(at(6, 3, 2, 59)), Some(at(6, 2, 3, 0)));
+
+    // 2024-06-03 is a Monday.
+    let quarters = Schedule::parse("*/15 9-17 * * 1-5").unwrap();
+    assert_eq!(quarters.last_before(at(6, 3, 12, 44)), Some(at(6, 3, 12, 30)));
+    assert_eq!(quarters.last_before(at(6, 3, 8, 0)), Some(at(5, 31, 17, 45)));
+    assert_eq!(quarters.last_before(at(6, 2, 12, 0)), Some(at(5, 31, 17, 45)));
+
+    // With both days given, either one picks the day: the 1st, or a Sunday.
+    let either = Schedule::parse("30 1 1 * 7").unwrap();
+    assert_eq!(either.last_before(at(6, 5, 0, 0)), Some(at(6, 2, 1, 30)));
+    assert_eq!(either.last_before(at(6, 2, 1, 0)), Some(at(6, 1, 1, 30)));
+
+    let weekly = Schedule::parse("@weekly").unwrap();
+    assert_eq!(weekly.last_before(at(6, 5, 0, 0)), Some(at(6, 2, 0, 0)));
+    assert_eq!(weekly.to_string(), "@weekly");
+
+    assert!(Schedule::parse("0 0 30 2 *").unwrap().last_before(at(6, 3, 0, 0)).is_none());
+    for bad in ["", "0 3 * *", "60 * * * *", "5-1 * * * *", "*/0 * * * *", "0 3 * * mon"] {
+        assert!(Schedule::parse(bad).is_none(), "{:?}", bad);
+    }
+}
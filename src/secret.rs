@@ -0,0 +1,194 @@
+//! Secrets used to access backup repositories.
+//!
+//! Rather than placing passwords directly in the config file, a secret can
+//! name where the value should be read from.  In the config, a secret is
+//! either a mapping of one kind to its argument, such as `{file: /root/pw}`,
+//! or a single string, such as "keyring:rack/restic-home".
+
+use crate::{Context, Result};
+use serde::de::{self, Deserialize, Deserializer, MapAccess, Visitor};
+use serde_derive::Serialize;
+use std::{env, fmt, fs, path::Path, process::Command, result};
+use thiserror::Error;
+
+use crate::checked::CheckedExt;
+
+#[derive(Debug, Error)]
+pub enum SecretError {
+    #[error("Unknown secret kind {0:?}")]
+    UnknownKind(String),
+    #[error("Secret {0:?} is not of the form kind:argument")]
+    NotKindArg(String),
+    #[error("Empty secret source")]
+    Empty,
+    #[error("Secret file {0:?} does not exist")]
+    NoFile(String),
+    #[error("Secret environment variable {0:?} not set")]
+    NotSet(String),
+    #[error("No systemd credentials, for {0:?}, rack isn't running as a service")]
+    NoCredentials(String),
+}
+
+/// The source of a single secret value.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SecretSource {
+    /// The secret is given directly.
+    Value(String),
+    /// The secret is the contents of the given file, with trailing
+    /// whitespace removed.
+    File(String),
+    /// The secret is taken from an environment variable of rack itself.
+    Env(String),
+    /// The secret is the first line of output of a shell command.
+    Command(String),
+    /// The secret is stored in the desktop keyring, looked up with
+    /// `secret-tool`.  Given as "attribute/value", or just the value, which
+    /// uses the attribute "rack".
+    Keyring(String),
+    /// The secret is the first line of an entry in the `pass` password
+    /// store.
+    Pass(String),
+    /// The secret is a systemd credential, passed to the service rack is
+    /// running as, with `LoadCredential=` or `LoadCredentialEncrypted=`.
+    #[serde(rename = "systemd-creds")]
+    SystemdCreds(String),
+}
+
+impl SecretSource {
+    /// Build a secret from its kind, as written in the config, and its
+    /// argument.
+    pub fn from_parts(kind: &str, arg: String) -> Result<SecretSource> {
+        Ok(match kind {
+            "value" => SecretSource::Value(arg),
+            "file" => SecretSource::File(arg),
+            "env" => SecretSource::Env(arg),
+            "command" => SecretSource::Command(arg),
+            "keyring" => SecretSource::Keyring(arg),
+            "pass" => SecretSource::Pass(arg),
+            "systemd-creds" => SecretSource::SystemdCreds(arg),
+            _ => return Err(SecretError::UnknownKind(kind.to_string()).into()),
+        })
+    }
+
+    /// Parse a secret given as "kind:argument".
+    pub fn parse(text: &str) -> Result<SecretSource> {
+        match text.find(':') {
+            Some(pos) => SecretSource::from_parts(&text[..pos], text[pos + 1..].to_string()),
+            None => Err(SecretError::NotKindArg(text.to_string()).into()),
+        }
+    }
+
+    /// Retrieve the value of this secret.
+    pub fn get(&self) -> Result<String> {
+        match self {
+            SecretSource::Value(v) => Ok(v.clone()),
+            SecretSource::File(name) => {
+                let text = fs::read_to_string(name)
+                    .context(format!("Unable to read secret file {:?}", name))?;
+                Ok(text.trim_end().to_string())
+            }
+            SecretSource::Env(name) => {
+                env::var(name).map_err(|_| SecretError::NotSet(name.clone()).into())
+            }
+            SecretSource::Command(cmd) => {
+                let out = Command::new("sh").args(&["-c", cmd]).checked_output()?;
+                let text = String::from_utf8(out.stdout)?;
+                Ok(text.lines().next().unwrap_or("").to_string())
+            }
+            SecretSource::Keyring(name) => {
+                let (attr, value) = match name.find('/') {
+                    Some(pos) => (&name[..pos], &name[pos + 1..]),
+                    None => ("rack", name.as_str()),
+                };
+                let out = Command::new("secret-tool")
+                    .args(&["lookup", attr, value])
+                    .checked_output()?;
+                Ok(String::from_utf8(out.stdout)?.trim_end().to_string())
+            }
+            SecretSource::Pass(name) => {
+                let out = Command::new("pass").args(&["show", name]).checked_output()?;
+                let text = String::from_utf8(out.stdout)?;
+                Ok(text.lines().next().unwrap_or("").to_string())
+            }
+            SecretSource::SystemdCreds(name) => {
+                let dir = env::var("CREDENTIALS_DIRECTORY")
+                    .map_err(|_| SecretError::NoCredentials(name.clone()))?;
+                let path = Path::new(&dir).join(name);
+                let text = fs::read_to_string(&path)
+                    .context(format!("Unable to read credential {:?}", path))?;
+                Ok(text.trim_end().to_string())
+            }
+        }
+    }
+
+    /// Check that this secret looks usable, without actually retrieving it.
+    pub fn validate(&self) -> Result<()> {
+        match self {
+            SecretSource::Value(v)
+            | SecretSource::Env(v)
+            | SecretSource::Command(v)
+            | SecretSource::Keyring(v)
+            | SecretSource::Pass(v)
+            | SecretSource::SystemdCreds(v)
+                if v.is_empty() =>
+            {
+                Err(SecretError::Empty.into())
+            }
+            SecretSource::File(name) if !Path::new(name).is_file() => {
+                Err(SecretError::NoFile(name.clone()).into())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for SecretSource {
+    fn deserialize<D: Deserializer<'de>>(de: D) -> result::Result<SecretSource, D::Error> {
+        struct SecretVisitor;
+
+        impl<'de> Visitor<'de> for SecretVisitor {
+            type Value = SecretSource;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a secret, such as \"keyring:rack/restic\" or {file: path}")
+            }
+
+            fn visit_str<E: de::Error>(self, text: &str) -> result::Result<SecretSource, E> {
+                SecretSource::parse(text).map_err(E::custom)
+            }
+
+            fn visit_map<A>(self, mut map: A) -> result::Result<SecretSource, A::Error>
+            where
+                A: MapAccess<'de>,
+            {
+                let (kind, arg): (String, String) = map
+                    .next_entry()?
+                    .ok_or_else(|| de::Error::custom("secret has no source"))?;
+                if map.next_key::<String>()?.is_some() {
+                    return Err(de::Error::custom("secret has more than one source"));
+                }
+                SecretSource::from_parts(&kind, arg).map_err(de::Error::custom)
+            }
+        }
+
+        de.deserialize_any(SecretVisitor)
+    }
+}
+
+#[test]
+fn test_secret_forms() {
+    let parse = |text: &str| serde_yaml::from_str::<SecretSource>(text);
+    assert_eq!(
+        parse("keyring:rack/restic-home").unwrap(),
+        SecretSource::Keyring("rack/restic-home".into())
+    );
+    assert_eq!(parse("{file: /root/pw}").unwrap(), SecretSource::File("/root/pw".into()));
+    assert_eq!(
+        parse("systemd-creds: restic").unwrap(),
+        SecretSource::SystemdCreds("restic".into())
+    );
+    assert_eq!(parse("pass:backup/borg").unwrap(), SecretSource::Pass("backup/borg".into()));
+    assert!(parse("hunter2").is_err());
+    assert!(parse("{file: a, env: b}").is_err());
+}
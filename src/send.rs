@@ -0,0 +1,399 @@
+//! Streams of zfs snapshots kept outside of zfs.
+//!
+//! Cloud volumes and exports both keep snapshots as `zfs send` streams,
+//! run through compression and, optionally, encryption.  The first stream
+//! of a filesystem is a full one, and each after that is an increment from
+//! the last one written.  This holds what they share: choosing what to
+//! send, building the pipelines that write and read the streams, and
+//! replaying a chain of them into `zfs receive`.
+
+use crate::{
+    catalog::{self, Stream},
+    checked::{heavy_command, run_pipeline},
+    config::{AgeConfig, Compression},
+    zfs::Filesystem,
+    Result,
+};
+use chrono::Utc;
+use std::process::Command;
+use thiserror::Error;
+
+/// Errors from reading streams back.
+#[derive(Debug, Error)]
+pub enum StreamError {
+    #[error("No streams of {volume:?} have been written")]
+    NoStreams { volume: String },
+    #[error("Can't restore {snap:?} of {volume:?}: there is no complete chain of streams to it")]
+    BrokenChain { volume: String, snap: String },
+    #[error("Streams of {volume:?} are encrypted, but there is no way configured to decrypt them")]
+    NoDecrypt { volume: String },
+    #[error("Stream {file:?} is damaged: its checksum doesn't match")]
+    Checksum { file: String },
+    #[error("No stream of {volume:?} has matching sure data")]
+    NoMatchingSure { volume: String },
+    #[error("{failures} files received from streams of {volume:?} did not match sure data")]
+    VerifyFailed { volume: String, failures: usize },
+}
+
+impl Compression {
+    /// The commands that compress, and decompress, streams, unless they are
+    /// left alone.
+    pub fn commands(self) -> Option<(&'static [&'static str], &'static [&'static str])> {
+        match self {
+            Compression::None => None,
+            Compression::Gzip => Some((&["gzip"], &["gzip", "-d"])),
+            Compression::Zstd => Some((&["zstd", "-q"], &["zstd", "-dq"])),
+            Compression::Xz => Some((&["xz"], &["xz", "-d"])),
+        }
+    }
+
+    /// The extension of files compressed this way.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Compression::None => "",
+            Compression::Gzip => ".gz",
+            Compression::Zstd => ".zst",
+            Compression::Xz => ".xz",
+        }
+    }
+}
+
+/// Build a command from a program and its arguments.  Heavy commands are
+/// run at the configured priority.
+pub fn command<S: AsRef<str>>(line: &[S], heavy: bool) -> Command {
+    let program = line[0].as_ref();
+    let mut cmd = if heavy {
+        heavy_command(program)
+    } else {
+        Command::new(program)
+    };
+    cmd.args(line[1..].iter().map(|a| a.as_ref()));
+    cmd
+}
+
+/// How the streams of a volume are written.
+pub struct StreamFormat {
+    pub compression: Compression,
+    pub encryption: Option<Encryption>,
+    /// Write a new full stream after this many increments.
+    pub full_every: Option<usize>,
+}
+
+/// How streams are encrypted.
+pub struct Encryption {
+    /// A command that encrypts its input.
+    pub encrypt: Vec<String>,
+    /// The command that decrypts what `encrypt` wrote, if it can be done
+    /// here.
+    pub decrypt: Option<Vec<String>>,
+    /// The extension of encrypted streams.
+    pub extension: &'static str,
+}
+
+impl Encryption {
+    /// The encryption configured for a volume, either as commands, or with
+    /// age.
+    pub fn new(
+        encrypt: &Option<Vec<String>>,
+        decrypt: &Option<Vec<String>>,
+        age: &Option<AgeConfig>,
+    ) -> Option<Encryption> {
+        if let Some(age) = age {
+            return Some(age.encryption());
+        }
+        Some(Encryption {
+            encrypt: encrypt.clone()?,
+            decrypt: decrypt.clone(),
+            extension: ".enc",
+        })
+    }
+}
+
+impl AgeConfig {
+    /// The age commands that encrypt to the recipients and identity, and
+    /// decrypt with the identity.
+    fn encryption(&self) -> Encryption {
+        let mut encrypt = vec!["age".to_string(), "-e".to_string()];
+        for r in self.recipients.iter().flatten() {
+            encrypt.push("-r".into());
+            encrypt.push(r.clone());
+        }
+        if let Some(ref identity) = self.identity {
+            encrypt.push("-i".into());
+            encrypt.push(identity.clone());
+        }
+        let decrypt = self.identity.as_ref().map(|identity| {
+            vec!["age".to_string(), "-d".to_string(), "-i".to_string(), identity.clone()]
+        });
+        Encryption {
+            encrypt: encrypt,
+            decrypt: decrypt,
+            extension: ".age",
+        }
+    }
+}
+
+/// The next stream to write, as the snapshot to send from, or None for a
+/// full stream, and the snapshot to send.
+pub struct Next<'a> {
+    pub from: Option<&'a str>,
+    pub to: &'a str,
+    /// Whether `from` has been pruned, leaving only its bookmark, so the
+    /// stream is sent from that, without the snapshots in between.
+    pub bookmark: bool,
+}
+
+impl StreamFormat {
+    /// Decide the next stream to write of `fs`, given the streams already
+    /// written, oldest first.  Returns None, after saying so, if the latest
+    /// snapshot has already been written.
+    pub fn next<'b>(
+        &self,
+        name: &str,
+        streams: &[&'b Stream],
+        fs: &'b Filesystem,
+    ) -> Option<Next<'b>> {
+        let to = match fs.snaps.last() {
+            Some(to) => to,
+            None => {
+                decision!("{}: {} has no snapshots to write", name, fs.name);
+                return None;
+            }
+        };
+        let full = Some(Next {
+            from: None,
+            to: to,
+            bookmark: false,
+        });
+        let last = match streams.last() {
+            Some(last) => last,
+            None => return full,
+        };
+        if &last.to == to {
+            decision!("{}: {}@{} is already written", name, fs.name, to);
+            return None;
+        }
+        let increments = streams.iter().rev().take_while(|s| s.from.is_some()).count();
+        if self.full_every.map_or(false, |every| increments >= every) {
+            return full;
+        }
+        // Pruning the last snapshot written leaves a bookmark of it, which
+        // an increment can still be sent from.
+        let bookmark = !fs.snaps.contains(&last.to);
+        if bookmark && !fs.bookmarks.contains(&last.to) {
+            warning!(
+                "{}: last snapshot written, {:?}, is gone, writing a full stream",
+                name,
+                last.to
+            );
+            return full;
+        }
+        Some(Next {
+            from: Some(&last.to),
+            to: to,
+            bookmark: bookmark,
+        })
+    }
+
+    /// The name of the stream, whose extensions say how to read it.
+    pub fn object(&self, next: &Next) -> String {
+        let mut object = match next.from {
+            Some(from) => format!("{}_{}.zfs", from, next.to),
+            None => format!("{}.full.zfs", next.to),
+        };
+        object.push_str(self.compression.extension());
+        if let Some(ref encryption) = self.encryption {
+            object.push_str(encryption.extension);
+        }
+        object
+    }
+
+    /// The commands that produce the stream: zfs send, then compression and
+    /// encryption.  Whatever stores the stream goes on the end.
+    pub fn sender(&self, fs: &str, next: &Next) -> Vec<Command> {
+        let mut send = heavy_command("zfs");
+        send.arg("send");
+        match next.from {
+            // Only a single increment can be sent from a bookmark.
+            Some(from) if next.bookmark => {
+                send.arg("-i").arg(format!("{}#{}", fs, from));
+            }
+            Some(from) => {
+                send.arg("-I").arg(format!("@{}", from));
+            }
+            None => (),
+        }
+        send.arg(format!("{}@{}", fs, next.to));
+        let mut cmds = vec![send];
+        if let Some((compress, _)) = self.compression.commands() {
+            cmds.push(command(compress, true));
+        }
+        if let Some(ref encryption) = self.encryption {
+            cmds.push(command(&encryption.encrypt, false));
+        }
+        cmds
+    }
+
+    /// The record of a stream that has been written.
+    pub fn record(&self, target: &str, zfs: &str, object: String, next: &Next) -> Stream {
+        Stream {
+            target: target.to_string(),
+            zfs: zfs.to_string(),
+            object: object,
+            from: next.from.map(|f| f.to_string()),
+            to: next.to.to_string(),
+            compression: self.compression,
+            encrypted: self.encryption.is_some(),
+            sha256: None,
+            time: Utc::now().to_rfc3339(),
+        }
+    }
+
+    /// Replay the streams of the chain, found by `chain`, into `dest`.  The
+    /// stored form of each stream is read by the command from `fetch`.
+    pub fn replay(
+        &self,
+        name: &str,
+        chain: &[&Stream],
+        dest: &str,
+        fetch: &dyn Fn(&Stream) -> Command,
+        pretend: bool,
+    ) -> Result<()> {
+        let decrypt = self.encryption.as_ref().and_then(|e| e.decrypt.as_ref());
+        if chain.iter().any(|s| s.encrypted) && decrypt.is_none() {
+            return Err(StreamError::NoDecrypt { volume: name.to_string() }.into());
+        }
+
+        for (i, stream) in chain.iter().enumerate() {
+            decision!("{}: receive {} into {}", name, stream.object, dest);
+            if pretend {
+                continue;
+            }
+
+            let mut cmds = vec![fetch(stream)];
+            if let (true, Some(decrypt)) = (stream.encrypted, decrypt) {
+                cmds.push(command(decrypt, false));
+            }
+            if let Some((_, decompress)) = stream.compression.commands() {
+                cmds.push(command(decompress, true));
+            }
+            // Later streams are increments, which need anything changed since the last one,
+            // such as by mounting it, rolled back.
+            let mut receive = heavy_command("zfs");
+            receive.args(&["receive", "-u"]);
+            if i > 0 {
+                receive.arg("-F");
+            }
+            receive.arg(dest);
+            cmds.push(receive);
+            run_pipeline(&mut cmds)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The chain of streams, full first, that restores `snap`, or the latest
+/// snapshot written.
+pub fn chain<'a>(name: &str, streams: &'a [Stream], snap: Option<&str>) -> Result<Vec<&'a Stream>> {
+    let last = match streams.last() {
+        Some(last) => last,
+        None => return Err(StreamError::NoStreams { volume: name.to_string() }.into()),
+    };
+    let refs: Vec<_> = streams.iter().collect();
+    catalog::chain(&refs, snap).ok_or_else(|| {
+        StreamError::BrokenChain {
+            volume: name.to_string(),
+            snap: snap.unwrap_or(&last.to).to_string(),
+        }
+        .into()
+    })
+}
+
+#[test]
+fn test_age() {
+    let age = |recipients: Option<&[&str]>, identity: Option<&str>| {
+        Some(AgeConfig {
+            recipients: recipients.map(|r| r.iter().map(|r| r.to_string()).collect()),
+            identity: identity.map(|i| i.to_string()),
+        })
+    };
+    let format = |age| StreamFormat {
+        compression: Compression::Zstd,
+        encryption: Encryption::new(&None, &None, &age),
+        full_every: None,
+    };
+    let next = Next {
+        from: None,
+        to: "a",
+        bookmark: false,
+    };
+    let line = |cmd: &Command| crate::checked::command_line(cmd).join(" ");
+
+    let both = format(age(Some(&["age1abc", "age1def"]), Some("/root/rack.key")));
+    assert_eq!(both.object(&next), "a.full.zfs.zst.age");
+    let cmds: Vec<_> = both.sender("pool/home", &next).iter().map(line).collect();
+    assert_eq!(
+        cmds,
+        vec!["zfs send pool/home@a", "zstd -q", "age -e -r age1abc -r age1def -i /root/rack.key"]
+    );
+    let decrypt = both.encryption.and_then(|e| e.decrypt).map(|d| d.join(" "));
+    assert_eq!(decrypt.as_deref(), Some("age -d -i /root/rack.key"));
+
+    // Without the identity, streams can be written, but not read back.
+    let public = format(age(Some(&["age1abc"]), None));
+    let stream = public.record("b2:x", "pool/home", "a".into(), &next);
+    let err = public.replay(
+        "home",
+        &[&stream],
+        "pool/restored",
+        &|_| Command::new("cat"),
+        false,
+    );
+    assert!(matches!(err, Err(crate::Error::Stream(StreamError::NoDecrypt { .. }))));
+}
+
+#[test]
+fn test_next() {
+    use crate::zfs::{DatasetKind, MountState};
+    use std::collections::HashMap;
+
+    let format = StreamFormat {
+        compression: Compression::None,
+        encryption: None,
+        full_every: None,
+    };
+    let mut fs = Filesystem {
+        name: "pool/home".into(),
+        kind: DatasetKind::Filesystem,
+        snaps: vec!["b".into(), "c".into()],
+        bookmarks: vec!["a".into()],
+        mount_state: MountState::Mounted,
+        pins: HashMap::new(),
+        space: HashMap::new(),
+    };
+    let written = |to: &str| {
+        let next = Next {
+            from: None,
+            to: to,
+            bookmark: false,
+        };
+        format.record("b2:x", "pool/home", "x".into(), &next)
+    };
+    let line = |next: &Next| crate::checked::command_line(&format.sender("pool/home", next)[0]);
+
+    // The last snapshot written is still there.
+    let b = written("b");
+    let next = format.next("home", &[&b], &fs).unwrap();
+    assert_eq!(line(&next).join(" "), "zfs send -I @b pool/home@c");
+
+    // It has been pruned, but its bookmark is left.
+    let a = written("a");
+    let next = format.next("home", &[&a], &fs).unwrap();
+    assert_eq!(line(&next).join(" "), "zfs send -i pool/home#a pool/home@c");
+
+    // Without the bookmark, only a full stream will do.
+    fs.bookmarks.clear();
+    let next = format.next("home", &[&a], &fs).unwrap();
+    assert_eq!(line(&next).join(" "), "zfs send pool/home@c");
+}
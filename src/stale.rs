@@ -0,0 +1,210 @@
+//! Finding volumes whose backups have fallen behind.
+//!
+//! `rack stale` finds the newest copy of each configured volume: its newest
+//! snapshot, clone, sure capture, restic or borg backup, or cloud stream,
+//! from zfs, the repositories and stores, and the catalog.  A copy older
+//! than its snapshot convention allows is stale.  A convention allows
+//! twice its most frequent period, so a daily one allows two days, and a
+//! run that is a little late isn't reported.  Volumes without a convention
+//! taking periodic snapshots can't be stale.
+
+use crate::{
+    borg,
+    catalog::Catalog,
+    config::{Config, SnapConvention},
+    naming,
+    restic::SURE_TAG,
+    zfs::{Filesystem, Zfs},
+    Error, Result,
+};
+use chrono::{DateTime, Duration, Local, TimeZone, Utc};
+
+/// The time between snapshots of a convention, by its most frequent
+/// period, if it takes periodic snapshots at all.
+fn period(conv: &SnapConvention) -> Option<Duration> {
+    let periods = [
+        (conv.hourly, Duration::hours(1)),
+        (conv.daily, Duration::days(1)),
+        (conv.weekly, Duration::days(7)),
+        (conv.monthly, Duration::days(31)),
+        (conv.yearly, Duration::days(366)),
+    ];
+    periods
+        .iter()
+        .find(|(count, _)| count.map_or(false, |c| c > 0))
+        .map(|&(_, period)| period)
+}
+
+/// An age, roughly, such as "3d4h".
+fn show_age(age: Duration) -> String {
+    if age.num_days() > 0 {
+        format!("{}d{}h", age.num_days(), age.num_hours() % 24)
+    } else {
+        format!("{}h{}m", age.num_hours(), age.num_minutes() % 60)
+    }
+}
+
+/// The time of the newest snapshot of a filesystem, optionally only those
+/// made by a convention.
+fn newest_snapshot(fs: &Filesystem, conv: Option<&SnapConvention>) -> Option<DateTime<Utc>> {
+    fs.snaps
+        .iter()
+        .filter(|snap| conv.map_or(true, |c| naming::parse(&c.name, snap).is_some()))
+        .filter_map(|snap| fs.space.get(snap))
+        .filter(|space| space.creation > 0)
+        .filter_map(|space| Utc.timestamp_opt(space.creation, 0).single())
+        .max()
+}
+
+struct Checker {
+    now: DateTime<Utc>,
+    stale: usize,
+}
+
+impl Checker {
+    /// Report on the newest copy of a volume, found by `newest`.
+    fn check(
+        &mut self,
+        what: &str,
+        allowed: Option<Duration>,
+        newest: Result<Option<DateTime<Utc>>>,
+    ) {
+        let allowed = match allowed {
+            Some(period) => period * 2,
+            None => {
+                output!("  -      {}: no periodic convention", what);
+                return;
+            }
+        };
+        match newest {
+            Ok(Some(time)) if self.now - time <= allowed => {
+                output!("  ok     {}: {} old", what, show_age(self.now - time));
+            }
+            Ok(Some(time)) => {
+                self.stale += 1;
+                let age = show_age(self.now - time);
+                output!("  STALE  {}: {} old, allowed {}", what, age, show_age(allowed));
+            }
+            Ok(None) => {
+                self.stale += 1;
+                output!("  STALE  {}: never made", what);
+            }
+            Err(e) => {
+                self.stale += 1;
+                output!("  ERROR  {}: {}", what, e);
+            }
+        }
+    }
+}
+
+impl Config {
+    /// Show how old the newest copy of each volume is.  Fails if any are
+    /// older than their convention allows, or couldn't be checked.
+    pub fn stale(&self) -> Result<()> {
+        let mut checker = Checker {
+            now: Utc::now(),
+            stale: 0,
+        };
+        let zfs = Zfs::from_inventory("none", &self.inventory)?;
+        let active = |skip: Option<bool>| skip != Some(true);
+
+        for vol in self.snap.volumes.iter().filter(|v| active(v.skip)) {
+            let conv = self.convention(&vol.convention);
+            let newest = zfs.find(&vol.zfs).map(|fs| newest_snapshot(fs, conv));
+            checker.check(&format!("snap {}", vol.name), conv.and_then(period), newest);
+        }
+
+        for vol in self.clone.volumes.iter().filter(|v| active(v.skip)) {
+            let newest = match zfs.find(&vol.dest) {
+                Ok(fs) => Ok(newest_snapshot(fs, None)),
+                // Nothing has been received yet.
+                Err(_) => Ok(None),
+            };
+            let allowed = self.period_of(&vol.source);
+            checker.check(&format!("clone {}", vol.name), allowed, newest);
+        }
+
+        for vol in self.sure.volumes.iter().filter(|v| active(v.skip)) {
+            let newest = vol
+                .open_store()
+                .and_then(|store| Ok(store.get_versions()?))
+                .map(|versions| versions.iter().map(|v| v.time).max());
+            let allowed = self.convention(&vol.convention).and_then(period);
+            checker.check(&format!("sure {}", vol.name), allowed, newest);
+        }
+
+        for vol in self.restic.volumes.iter().filter(|v| active(v.skip)) {
+            let newest = vol.get_snapshots().map(|snaps| {
+                snaps
+                    .iter()
+                    .filter(|s| s.paths.iter().any(|p| p == &vol.bind))
+                    .filter(|s| !s.tags.iter().flatten().any(|t| t == SURE_TAG))
+                    .filter_map(|s| DateTime::parse_from_rfc3339(&s.time).ok())
+                    .map(|time| time.with_timezone(&Utc))
+                    .max()
+            });
+            checker.check(&format!("restic {}", vol.name), self.period_of(&vol.zfs), newest);
+        }
+
+        for vol in self.borg.volumes.iter().filter(|v| active(v.skip)) {
+            let newest = borg::list_archives(vol).map(|archives| {
+                let time = archives.newest(&vol.prefix)?;
+                Local.from_local_datetime(&time).earliest().map(|t| t.with_timezone(&Utc))
+            });
+            let allowed = match vol.convention {
+                Some(ref name) => self.convention(name).and_then(period),
+                None => self.period_of(&vol.zfs),
+            };
+            checker.check(&format!("borg {}", vol.name), allowed, newest);
+        }
+
+        if self.cloud.volumes.iter().any(|v| active(v.skip)) {
+            let catalog = Catalog::load(&Catalog::default_path()?)?;
+            for vol in self.cloud.volumes.iter().filter(|v| active(v.skip)) {
+                let newest = catalog
+                    .streams(&vol.remote, &vol.zfs)
+                    .iter()
+                    .filter_map(|s| DateTime::parse_from_rfc3339(&s.time).ok())
+                    .map(|time| time.with_timezone(&Utc))
+                    .max();
+                checker.check(&format!("cloud {}", vol.name), self.period_of(&vol.zfs), Ok(newest));
+            }
+        }
+
+        match checker.stale {
+            0 => Ok(()),
+            1 => Err(Error::msg("1 volume is stale")),
+            n => Err(Error::msg(format!("{} volumes are stale", n))),
+        }
+    }
+
+    fn convention(&self, name: &str) -> Option<&SnapConvention> {
+        self.snap.conventions.iter().find(|c| c.name == name)
+    }
+
+    /// The period of the convention snapshotting a zfs filesystem.
+    fn period_of(&self, zfs: &str) -> Option<Duration> {
+        let vol = self.snap.volumes.iter().find(|v| v.zfs == zfs)?;
+        period(self.convention(&vol.convention)?)
+    }
+}
+
+#[test]
+fn test_period() {
+    let text = "\
+snap:
+  conventions:
+    - {name: daily, daily: 7, weekly: 4}
+    - {name: monthly, last: 2, monthly: 12}
+    - {name: manual, last: 5}
+  volumes: [{name: home, convention: daily, zfs: a/home}]
+";
+    let conf = Config::parse(text, "lint").unwrap();
+    assert_eq!(conf.period_of("a/home"), Some(Duration::days(1)));
+    assert_eq!(conf.period_of("a/other"), None);
+    assert_eq!(period(conf.convention("monthly").unwrap()), Some(Duration::days(31)));
+    assert_eq!(period(conf.convention("manual").unwrap()), None);
+
+    assert_eq!(show_age(Duration::minutes(2 * 24 * 60 + 185)), "2d3h");
+    assert_eq!(show_age(Duration::minutes(185)), "3h5m");
+}
@@ -0,0 +1,419 @@
+//! Comparison of rsure data.
+//!
+//! Rsure trees are streams of nodes, with each directory's subdirectories
+//! coming before its files, and each in name order.  Two trees can be
+//! compared by walking them together, without having to load either one
+//! into memory.
+
+use crate::{
+    config::{configured, Config, ConfigError},
+    journal,
+    verify::ScratchDir,
+    surestore::SureError,
+    zfs::find_mount,
+    Result,
+};
+use rsure::{AttMap, SureNode, Version};
+use serde_derive::Serialize;
+use std::{cmp::Ordering, iter::Peekable};
+
+/// Attributes that are expected to differ between otherwise identical
+/// trees, and are ignored in comparisons.
+static IGNORED_ATTS: &'static [&'static str] = &["ctime", "ino"];
+
+/// A single entry of a tree, with its full path.
+struct Entry {
+    /// The sort key, one element per path component.  Directories sort
+    /// before files at each level, matching the order of the nodes.
+    key: Vec<(u8, String)>,
+    path: String,
+    atts: AttMap,
+}
+
+/// Flattens the nodes of a tree into entries.
+struct Flatten<I> {
+    nodes: I,
+    dirs: Vec<String>,
+}
+
+impl<I: Iterator<Item = rsure::Result<SureNode>>> Iterator for Flatten<I> {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Result<Entry>> {
+        loop {
+            let node = match self.nodes.next()? {
+                Ok(node) => node,
+                Err(e) => return Some(Err(e.into())),
+            };
+            let (rank, name, atts) = match node {
+                SureNode::Enter { name, atts } => {
+                    self.dirs.push(name.clone());
+                    // The root of the tree itself has no entry.
+                    if self.dirs.len() == 1 {
+                        continue;
+                    }
+                    (0, name, atts)
+                }
+                SureNode::File { name, atts } => (1, name, atts),
+                SureNode::Leave => {
+                    self.dirs.pop();
+                    continue;
+                }
+                SureNode::Sep => continue,
+            };
+
+            // For a directory, it has already been pushed.
+            let parents = if rank == 0 {
+                &self.dirs[1..self.dirs.len() - 1]
+            } else {
+                &self.dirs[1..]
+            };
+            let mut key: Vec<_> = parents.iter().map(|d| (0, d.clone())).collect();
+            key.push((rank, name.clone()));
+            let mut path = parents.join("/");
+            if !path.is_empty() {
+                path.push('/');
+            }
+            path.push_str(&name);
+
+            return Some(Ok(Entry {
+                key: key,
+                path: path,
+                atts: atts,
+            }));
+        }
+    }
+}
+
+fn flatten<I>(nodes: I) -> Peekable<Flatten<I>>
+where
+    I: Iterator<Item = rsure::Result<SureNode>>,
+{
+    Flatten {
+        nodes: nodes,
+        dirs: vec![],
+    }
+    .peekable()
+}
+
+/// A difference between two trees.
+#[derive(Debug, Serialize)]
+#[serde(tag = "change", rename_all = "lowercase")]
+pub enum Change {
+    Added { path: String },
+    Removed { path: String },
+    Changed { path: String, atts: Vec<String> },
+}
+
+impl Change {
+    /// Print this change in a short, human readable form.
+    pub fn show(&self) {
+        match self {
+            Change::Added { path } => output!("+ {}", path),
+            Change::Removed { path } => output!("- {}", path),
+            Change::Changed { path, atts } => output!("~ {} ({})", path, atts.join(",")),
+        }
+    }
+}
+
+/// Compare two trees, returning the changes needed to turn `old` into `new`.
+pub fn compare<I1, I2>(old: I1, new: I2) -> Result<Vec<Change>>
+where
+    I1: Iterator<Item = rsure::Result<SureNode>>,
+    I2: Iterator<Item = rsure::Result<SureNode>>,
+{
+    let mut old = flatten(old);
+    let mut new = flatten(new);
+    let mut changes = vec![];
+
+    loop {
+        let order = match (old.peek(), new.peek()) {
+            (None, None) => break,
+            (Some(Err(_)), _) => return Err(old.next().unwrap().err().unwrap()),
+            (_, Some(Err(_))) => return Err(new.next().unwrap().err().unwrap()),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (Some(Ok(a)), Some(Ok(b))) => a.key.cmp(&b.key),
+        };
+
+        match order {
+            Ordering::Less => {
+                let a = old.next().unwrap()?;
+                changes.push(Change::Removed { path: a.path });
+            }
+            Ordering::Greater => {
+                let b = new.next().unwrap()?;
+                changes.push(Change::Added { path: b.path });
+            }
+            Ordering::Equal => {
+                let a = old.next().unwrap()?;
+                let b = new.next().unwrap()?;
+                let atts = diff_atts(&a.atts, &b.atts);
+                if !atts.is_empty() {
+                    changes.push(Change::Changed {
+                        path: b.path,
+                        atts: atts,
+                    });
+                }
+            }
+        }
+    }
+
+    Ok(changes)
+}
+
+/// Return the names of the attributes that differ between two entries.
+fn diff_atts(old: &AttMap, new: &AttMap) -> Vec<String> {
+    let mut names: Vec<&String> = old.keys().chain(new.keys()).collect();
+    names.sort();
+    names.dedup();
+    names
+        .into_iter()
+        .filter(|n| !IGNORED_ATTS.contains(&n.as_str()))
+        .filter(|n| old.get(*n) != new.get(*n))
+        .cloned()
+        .collect()
+}
+
+/// Attributes reflecting the contents of a file.
+static CONTENT_ATTS: &'static [&'static str] = &["sha1", "size", "targ"];
+
+/// Attributes reflecting ownership and permissions.
+static PERM_ATTS: &'static [&'static str] = &["perm", "uid", "gid"];
+
+/// A report of the differences between two versions.
+#[derive(Debug, Serialize)]
+struct DiffReport<'a> {
+    volume: &'a str,
+    old: &'a str,
+    new: &'a str,
+    added: Vec<&'a str>,
+    removed: Vec<&'a str>,
+    content: Vec<&'a str>,
+    permissions: Vec<&'a str>,
+    other: Vec<&'a str>,
+}
+
+impl<'a> DiffReport<'a> {
+    fn new(volume: &'a str, old: &'a str, new: &'a str, changes: &'a [Change]) -> DiffReport<'a> {
+        let mut report = DiffReport {
+            volume: volume,
+            old: old,
+            new: new,
+            added: vec![],
+            removed: vec![],
+            content: vec![],
+            permissions: vec![],
+            other: vec![],
+        };
+
+        // A change to both contents and permissions is listed under both.
+        for ch in changes {
+            match ch {
+                Change::Added { path } => report.added.push(path),
+                Change::Removed { path } => report.removed.push(path),
+                Change::Changed { path, atts } => {
+                    let has = |set: &[&str]| atts.iter().any(|a| set.contains(&a.as_str()));
+                    let content = has(CONTENT_ATTS);
+                    let perms = has(PERM_ATTS);
+                    if content {
+                        report.content.push(path);
+                    }
+                    if perms {
+                        report.permissions.push(path);
+                    }
+                    if !content && !perms {
+                        report.other.push(path);
+                    }
+                }
+            }
+        }
+        report
+    }
+
+    fn show(&self) {
+        output!("Sure diff {:?}: {} -> {}", self.volume, self.old, self.new);
+        let sections = [
+            ("Added", &self.added),
+            ("Removed", &self.removed),
+            ("Content changed", &self.content),
+            ("Permissions changed", &self.permissions),
+            ("Other changes", &self.other),
+        ];
+        for &(title, paths) in &sections {
+            if paths.is_empty() {
+                continue;
+            }
+            output!("{} ({}):", title, paths.len());
+            for p in paths.iter() {
+                output!("  {}", p);
+            }
+        }
+    }
+}
+
+/// Summary of a verification, for the journal.
+#[derive(Debug, Serialize)]
+struct VerifyRecord<'a> {
+    version: &'a str,
+    target: &'a str,
+    added: usize,
+    removed: usize,
+    changed: usize,
+}
+
+impl Config {
+    /// Compare the most recent sure capture of each volume (or just the
+    /// named one) against the live filesystem, or against the given snapshot
+    /// of it.
+    pub fn sure_verify(&self, name: Option<&str>, snapshot: Option<&str>) -> Result<()> {
+        configured("sure", &self.sure.volumes)?;
+        for vol in &self.sure.volumes {
+            match name {
+                None => (),
+                Some(given) if given == vol.name => (),
+                _ => continue,
+            }
+
+            let store = vol.open_store()?;
+            let latest = store
+                .get_versions()?
+                .into_iter()
+                .max_by_key(|v| v.time)
+                .ok_or_else(|| SureError::NoData(vol.name.clone()))?;
+
+            let mount = find_mount(&vol.zfs)?;
+            let target = match snapshot {
+                Some(snap) => format!("{}/.zfs/snapshot/{}", mount, snap),
+                None => mount,
+            };
+            progress!("Sure verify {:?}: {:?} against {:?}", vol.name, latest.name, target);
+
+            // Scan the target into a scratch store, so that it can be
+            // compared the same way as any other version.
+            let scratch = ScratchDir::new("sure-verify")?;
+            let scan_name = scratch.0.join("scan.dat.gz");
+            let scan = rsure::parse_store(&scan_name.to_string_lossy())?;
+            rsure::update(&target, &*scan, false, &rsure::StoreTags::new())?;
+
+            let changes = compare(
+                store.load_iter(latest.version.clone())?,
+                scan.load_iter(Version::Latest)?,
+            )?;
+            for ch in &changes {
+                ch.show();
+            }
+
+            let mut record = VerifyRecord {
+                version: &latest.name,
+                target: &target,
+                added: 0,
+                removed: 0,
+                changed: 0,
+            };
+            for ch in &changes {
+                match ch {
+                    Change::Added { .. } => record.added += 1,
+                    Change::Removed { .. } => record.removed += 1,
+                    Change::Changed { .. } => record.changed += 1,
+                }
+            }
+            progress!(
+                "Sure verify {:?}: {} added, {} removed, {} changed",
+                vol.name, record.added, record.removed, record.changed
+            );
+            journal::record("sure-verify", &vol.name, &record)?;
+        }
+
+        Ok(())
+    }
+
+    /// Report the differences between two captured versions of the named
+    /// volume, either for humans or, if `json` is set, as JSON.
+    pub fn sure_diff(&self, volume: &str, old: &str, new: &str, json: bool) -> Result<()> {
+        let vol = self
+            .sure
+            .volumes
+            .iter()
+            .find(|v| v.name == volume)
+            .ok_or_else(|| ConfigError::NoVolume {
+                section: "sure",
+                name: volume.to_string(),
+            })?;
+
+        let store = vol.open_store()?;
+        let versions = store.get_versions()?;
+        let find = |name: &str| {
+            versions
+                .iter()
+                .find(|v| v.name == name)
+                .map(|v| v.version.clone())
+                .ok_or_else(|| SureError::NoVersion {
+                    version: name.to_string(),
+                    volume: volume.to_string(),
+                })
+        };
+        let old_version = find(old)?;
+        let new_version = find(new)?;
+
+        let changes = compare(store.load_iter(old_version)?, store.load_iter(new_version)?)?;
+        let report = DiffReport::new(volume, old, new, &changes);
+
+        if json {
+            output!("{}", serde_json::to_string_pretty(&report)?);
+        } else {
+            report.show();
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_compare() {
+    fn file(name: &str, sha1: &str) -> rsure::Result<SureNode> {
+        let mut atts = AttMap::new();
+        atts.insert("kind".to_string(), "file".to_string());
+        atts.insert("sha1".to_string(), sha1.to_string());
+        atts.insert("ino".to_string(), sha1.to_string());
+        Ok(SureNode::File {
+            name: name.to_string(),
+            atts: atts,
+        })
+    }
+    fn enter(name: &str) -> rsure::Result<SureNode> {
+        Ok(SureNode::Enter {
+            name: name.to_string(),
+            atts: AttMap::new(),
+        })
+    }
+
+    let old = vec![
+        enter("__root__"),
+        enter("a"),
+        Ok(SureNode::Sep),
+        file("x", "1"),
+        Ok(SureNode::Leave),
+        Ok(SureNode::Sep),
+        file("b", "2"),
+        file("c", "3"),
+        Ok(SureNode::Leave),
+    ];
+    let new = vec![
+        enter("__root__"),
+        Ok(SureNode::Sep),
+        file("a", "4"),
+        file("c", "5"),
+        Ok(SureNode::Leave),
+    ];
+
+    let changes: Vec<_> = compare(old.into_iter(), new.into_iter())
+        .unwrap()
+        .iter()
+        .map(|c| match c {
+            Change::Added { path } => format!("+{}", path),
+            Change::Removed { path } => format!("-{}", path),
+            Change::Changed { path, atts } => format!("~{}:{}", path, atts.join(",")),
+        })
+        .collect();
+    assert_eq!(changes, vec!["-a", "-a/x", "+a", "-b", "~c:sha1"]);
+}
@@ -0,0 +1,238 @@
+//! Capturing sure data with several threads.
+//!
+//! Most of the time of a sure capture of a large snapshot goes to hashing
+//! files.  With `sure.hash_threads` above 1, each directory at the top of
+//! the snapshot is first captured on its own, into a scratch store, with
+//! that many at once, each as an update of what the latest version has for
+//! it.  These are joined into a seed, and the capture of the whole snapshot
+//! is made as an update of the seed, so that only the files at the top, and
+//! anything the seed lacks, are left for rsure to hash.  A directory whose
+//! name rsure would escape, or that is another filesystem, is left to that
+//! last capture.
+//!
+//! A meter counts the files hashed, as each directory is done, against the
+//! number the latest version has.  With a single thread, rsure is left to
+//! show its own progress, as it has no way to report how far it has got.
+
+use crate::{
+    jobs::Jobs,
+    meter::{Meter, Unit},
+    verify::ScratchDir,
+    Error, Result,
+};
+use rsure::{node::NodeWriter, AttMap, Store, StoreTags, SureNode, Version};
+use std::{
+    fs,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::Instant,
+};
+
+/// Capture `dir` into `store`, as `rsure::update` does, hashing the
+/// directories at the top of it `threads` at a time.
+pub fn update(
+    dir: &str,
+    store: &dyn Store,
+    is_update: bool,
+    tags: &StoreTags,
+    threads: usize,
+) -> Result<()> {
+    let dirs = top_dirs(Path::new(dir))?;
+    if threads <= 1 || dirs.is_empty() {
+        rsure::update(dir, store, is_update, tags)?;
+        return Ok(());
+    }
+
+    let scratch = ScratchDir::new("sure-hash")?;
+    let part = |name: &str| scratch.0.join(format!("{}.dat.gz", name));
+    let open = |path: &PathBuf| rsure::parse_store(&path.to_string_lossy());
+
+    // What the latest version has of each directory is the base of its
+    // update, and the files at the top are kept for the seed.
+    let mut root = None;
+    let mut top_files = vec![];
+    let mut total = None;
+    if is_update {
+        let mut files = 0;
+        let mut nodes = store.load_iter(Version::Latest)?;
+        if let Some(SureNode::Enter { name, atts }) = nodes.next().transpose()? {
+            root = Some((name, atts));
+        }
+        let root_name = root.as_ref().map_or("", |(name, _)| name.as_str());
+        while let Some(node) = nodes.next().transpose()? {
+            match node {
+                SureNode::Enter { name, atts } if dirs.contains(&name) => {
+                    let base = open(&part(&name))?;
+                    let mut writer = NodeWriter::new(base.make_new(&StoreTags::new())?)?;
+                    writer.write_node(&SureNode::Enter {
+                        name: root_name.to_string(),
+                        atts: atts,
+                    })?;
+                    copy_dir(&mut nodes, |node| {
+                        if let SureNode::File { .. } = node {
+                            files += 1;
+                        }
+                        writer.write_node(node)
+                    })?;
+                    writer.into_inner().commit()?;
+                }
+                SureNode::Enter { .. } => copy_dir(&mut nodes, |_| Ok(()))?,
+                SureNode::Sep => {
+                    for node in nodes.by_ref() {
+                        match node? {
+                            SureNode::Leave => break,
+                            node => top_files.push(node),
+                        }
+                    }
+                }
+                _ => break,
+            }
+        }
+        total = Some(files);
+    }
+
+    let meter = Mutex::new(Meter::new(&format!("Sure {}", dir), Unit::Files, total));
+    let (done, hashed) = (AtomicUsize::new(0), AtomicU64::new(0));
+    let mut jobs = Jobs::new();
+    for name in &dirs {
+        let (path, count) = (part(name), dirs.len());
+        let (meter, done, hashed) = (&meter, &done, &hashed);
+        jobs.add(format!("hash {}", name), vec![], move || {
+            let start = Instant::now();
+            let seeded = path.exists();
+            let target = Path::new(dir).join(name);
+            let captured = open(&path)?;
+            rsure::update(target, &*captured, seeded, &StoreTags::new())?;
+            let mut files = 0;
+            for node in captured.load_iter(Version::Latest)? {
+                if let SureNode::File { .. } = node? {
+                    files += 1;
+                }
+            }
+            meter.lock().unwrap().set(hashed.fetch_add(files, Ordering::SeqCst) + files);
+            let done = done.fetch_add(1, Ordering::SeqCst) + 1;
+            let secs = start.elapsed().as_secs();
+            progress!("Hashed [{}/{}] {}/{} in {}s", done, count, dir, name, secs);
+            Ok(())
+        });
+    }
+    jobs.run(threads)?;
+    drop(meter);
+
+    // Join the directories into the seed, under the root of the latest
+    // version, or else named as rsure names the root of a capture.
+    if root.is_none() {
+        let first = open(&part(&dirs[0]))?;
+        let mut nodes = first.load_iter(Version::Latest)?;
+        if let Some(SureNode::Enter { name, .. }) = nodes.next().transpose()? {
+            root = Some((name, AttMap::new()));
+        }
+    }
+    let (name, atts) = root.ok_or_else(|| Error::msg("sure data has no root directory"))?;
+    let seed = open(&scratch.0.join("seed.dat.gz"))?;
+    let mut writer = NodeWriter::new(seed.make_new(&StoreTags::new())?)?;
+    writer.write_node(&SureNode::Enter { name, atts })?;
+    for name in &dirs {
+        let captured = open(&part(name))?;
+        let mut nodes = captured.load_iter(Version::Latest)?;
+        let atts = match nodes.next().transpose()? {
+            Some(SureNode::Enter { atts, .. }) => atts,
+            _ => continue,
+        };
+        writer.write_node(&SureNode::Enter {
+            name: name.clone(),
+            atts: atts,
+        })?;
+        copy_dir(&mut nodes, |node| writer.write_node(node))?;
+    }
+    writer.write_node(&SureNode::Sep)?;
+    for node in &top_files {
+        writer.write_node(node)?;
+    }
+    writer.write_node(&SureNode::Leave)?;
+    writer.into_inner().commit()?;
+
+    // The whole capture reuses the hashes of the seed, and is then copied
+    // into the store.
+    rsure::update(dir, &*seed, true, &StoreTags::new())?;
+    let mut writer = NodeWriter::new(store.make_new(tags)?)?;
+    for node in seed.load_iter(Version::Latest)? {
+        writer.write_node(&node?)?;
+    }
+    writer.into_inner().commit()?;
+    Ok(())
+}
+
+/// The directories at the top of `dir` that can be captured on their own,
+/// sorted by name, as rsure orders them.
+fn top_dirs(dir: &Path) -> Result<Vec<String>> {
+    let dev = fs::symlink_metadata(dir)?.dev();
+    let mut dirs = vec![];
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let meta = entry.metadata()?;
+        if !meta.is_dir() || meta.dev() != dev {
+            continue;
+        }
+        if let Some(name) = entry.file_name().to_str().filter(|n| plain(n)) {
+            dirs.push(name.to_string());
+        }
+    }
+    dirs.sort();
+    Ok(dirs)
+}
+
+/// Whether rsure writes a name as it is, without escaping any of it.
+fn plain(name: &str) -> bool {
+    name.chars().all(|c| c.is_ascii_alphanumeric() || "._-+,@".contains(c))
+}
+
+/// Give the nodes of a directory, following its `Enter`, up to and
+/// including its `Leave`, to `each`.
+fn copy_dir<I, F>(nodes: &mut I, mut each: F) -> Result<()>
+where
+    I: Iterator<Item = rsure::Result<SureNode>>,
+    F: FnMut(&SureNode) -> rsure::Result<()>,
+{
+    let mut depth = 1;
+    for node in nodes {
+        let node = node?;
+        match node {
+            SureNode::Enter { .. } => depth += 1,
+            SureNode::Leave => depth -= 1,
+            _ => (),
+        }
+        each(&node)?;
+        if depth == 0 {
+            return Ok(());
+        }
+    }
+    Err(Error::msg("sure data ends within a directory"))
+}
+
+#[test]
+fn test_copy_dir() {
+    let enter = |name: &str| SureNode::Enter {
+        name: name.to_string(),
+        atts: AttMap::new(),
+    };
+    let nodes = vec![enter("a"), SureNode::Sep, SureNode::Leave, SureNode::Sep, SureNode::Leave];
+    let mut nodes = nodes.into_iter().chain(vec![enter("b")]).map(Ok);
+    let mut count = 0;
+    copy_dir(&mut nodes, |_| {
+        count += 1;
+        Ok(())
+    })
+    .unwrap();
+    // The nodes of "a" and the rest of the directory, but not "b" after it.
+    assert_eq!(count, 5);
+    assert!(matches!(nodes.next(), Some(Ok(SureNode::Enter { .. }))));
+    assert!(copy_dir(&mut vec![Ok(SureNode::Sep)].into_iter(), |_| Ok(())).is_err());
+
+    assert!(plain("david") && plain("lost+found") && plain(".cache"));
+    assert!(!plain("two words") && !plain("caf\u{e9}") && !plain("a=b"));
+}
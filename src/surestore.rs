@@ -0,0 +1,263 @@
+//! Maintenance of rsure stores.
+//!
+//! An rsure store keeps every version that was ever captured into it.  Once
+//! the snapshot a version was captured from has been pruned, and it is no
+//! longer in any backup, the version is only taking up space.
+
+use crate::{
+    config::{configured, Config, SureConfig, SureVolume},
+    plan::Plan,
+    Error, Result,
+};
+use rsure::{node::NodeWriter, Store, StoreTags};
+use std::{collections::HashSet, fs, path::Path};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum SureError {
+    #[error("Unknown sure store kind {0:?}")]
+    UnknownKind(String),
+    #[error("Sure store {0:?} has no path")]
+    NoPath(String),
+    /// A problem with the configuration of a volume.
+    #[error("Sure volume {name:?}: {msg}")]
+    Volume { name: String, msg: String },
+    #[error("Duplicate sure volume {0:?}")]
+    Duplicate(String),
+    #[error("Invalid sure file name {0:?}")]
+    BadFileName(String),
+    #[error("No sure data for volume {0:?}")]
+    NoData(String),
+    #[error("No sure version {version:?} in {volume:?}")]
+    NoVersion { version: String, volume: String },
+}
+
+/// The kind of backing store for sure data.
+#[derive(Debug, PartialEq)]
+pub enum StoreKind {
+    /// Let rsure decide from the file name.
+    Guess,
+    /// A compressed file holding each version separately.
+    Plain,
+    /// A weave file, holding all versions as deltas.
+    Weave,
+    /// An sqlite database.  Not yet supported by rsure.
+    Sqlite,
+}
+
+/// Split a store string into its kind and path.
+pub fn parse_uri(text: &str) -> Result<(StoreKind, &str)> {
+    let (scheme, path) = match text.find(':') {
+        Some(pos) if !text[..pos].contains('/') => (&text[..pos], &text[pos + 1..]),
+        _ => return Ok((StoreKind::Guess, text)),
+    };
+    let kind = match scheme {
+        "plain" => StoreKind::Plain,
+        "weave" => StoreKind::Weave,
+        "sqlite" => StoreKind::Sqlite,
+        _ => return Err(SureError::UnknownKind(scheme.to_string()).into()),
+    };
+    if path.is_empty() {
+        return Err(SureError::NoPath(text.to_string()).into());
+    }
+    Ok((kind, path))
+}
+
+impl SureVolume {
+    /// The path of the sure store, without any kind prefix.
+    pub fn store_path(&self) -> Result<&str> {
+        Ok(parse_uri(&self.sure)?.1)
+    }
+
+    /// Open the sure store for this volume.
+    pub fn open_store(&self) -> Result<Box<dyn Store>> {
+        self.validate()?;
+        Ok(rsure::parse_store(self.store_path()?)?)
+    }
+
+    /// Check that the store for this volume is one rsure can use, and that
+    /// its name matches the kind given.  Rsure chooses the backend from the
+    /// name, so a mismatch would silently use the wrong one.
+    pub fn validate(&self) -> Result<()> {
+        let err = |msg: String| -> Error {
+            SureError::Volume {
+                name: self.name.clone(),
+                msg,
+            }
+            .into()
+        };
+        let (kind, path) = parse_uri(&self.sure).map_err(|e| err(e.to_string()))?;
+        let suffix = match kind {
+            StoreKind::Guess => None,
+            StoreKind::Plain => Some(".dat.gz"),
+            StoreKind::Weave => Some(".weave.gz"),
+            StoreKind::Sqlite => return Err(err("sqlite stores are not yet supported".into())),
+        };
+        if let Some(suffix) = suffix {
+            if !path.ends_with(suffix) {
+                return Err(err(format!("{:?} store name must end in {:?}", kind, suffix)));
+            }
+        }
+
+        let path = Path::new(path);
+        match path.parent() {
+            Some(dir) if dir.as_os_str().is_empty() || dir.is_dir() => (),
+            _ => return Err(err(format!("directory of {:?} does not exist", path))),
+        }
+        if self.create == Some(false) && !path.exists() {
+            return Err(err(format!("store {:?} does not exist", path)));
+        }
+        Ok(())
+    }
+}
+
+impl SureConfig {
+    pub fn validate(&self) -> Result<()> {
+        let mut names = HashSet::new();
+        for v in &self.volumes {
+            if !names.insert(&v.name) {
+                return Err(SureError::Duplicate(v.name.clone()).into());
+            }
+            v.validate()?;
+        }
+        Ok(())
+    }
+}
+
+/// The name of this host, recorded with each capture so that stores shared
+/// between machines can be told apart.
+pub fn hostname() -> Result<String> {
+    Ok(fs::read_to_string("/proc/sys/kernel/hostname")?.trim().to_string())
+}
+
+/// Does a version have all of the given tags?  Each filter is either
+/// "key=value", or just "key", which matches any value.
+fn tags_match(tags: &StoreTags, filters: &[String]) -> bool {
+    filters.iter().all(|f| match f.find('=') {
+        Some(pos) => tags.get(&f[..pos]).map(|v| v.as_str()) == Some(&f[pos + 1..]),
+        None => tags.contains_key(f.as_str()),
+    })
+}
+
+impl Config {
+    /// List the versions in the sure stores, of all volumes or just the
+    /// named one, showing only those whose tags match all of the filters.
+    pub fn sure_versions(&self, name: Option<&str>, filters: &[String]) -> Result<()> {
+        configured("sure", &self.sure.volumes)?;
+        for vol in &self.sure.volumes {
+            match name {
+                None => (),
+                Some(given) if given == vol.name => (),
+                _ => continue,
+            }
+
+            let store = vol.open_store()?;
+            let mut versions = store.get_versions()?;
+            versions.sort_by_key(|v| v.time);
+            for v in versions.iter().filter(|v| tags_match(&v.tags, filters)) {
+                let tags: Vec<_> = v
+                    .tags
+                    .iter()
+                    .filter(|&(k, _)| k != "name")
+                    .map(|(k, v)| format!("{}={}", k, v))
+                    .collect();
+                output!(
+                    "{:<12} {:<30} {}  {}",
+                    vol.name,
+                    v.name,
+                    v.time.format("%Y-%m-%d %H:%M:%S"),
+                    tags.join(" ")
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Plan the pruning of the given sure store file, dropping the versions
+/// whose names `keep` returns false for.
+pub fn plan_prune(surefile: &str, keep: &dyn Fn(&str) -> bool, plan: &mut Plan) -> Result<()> {
+    let store = rsure::parse_store(surefile)?;
+    let mut versions = store.get_versions()?;
+    versions.sort_by_key(|v| v.time);
+
+    let dropped: Vec<_> = versions
+        .into_iter()
+        .filter(|v| !keep(&v.name))
+        .map(|v| v.name)
+        .collect();
+    if !dropped.is_empty() {
+        let reason = format!("drop {} unused sure versions from {:?}", dropped.len(), surefile);
+        plan.sure_prune(reason, surefile, dropped);
+    }
+    Ok(())
+}
+
+/// Rewrite the given sure store file, keeping only the versions whose names
+/// `keep` returns true for.  The store is rewritten to a scratch directory
+/// beside it, and only moved into place once complete.  The original is
+/// left alongside, with ".pre-prune" appended to its name.
+pub fn prune(surefile: &str, keep: &dyn Fn(&str) -> bool) -> Result<()> {
+    let store = rsure::parse_store(surefile)?;
+    let mut versions = store.get_versions()?;
+    versions.sort_by_key(|v| v.time);
+
+    let (kept, dropped): (Vec<_>, Vec<_>) = versions.into_iter().partition(|v| keep(&v.name));
+    if dropped.is_empty() {
+        return Ok(());
+    }
+    for v in &dropped {
+        decision!("drop sure version {:?} from {:?}", v.name, surefile);
+    }
+
+    let path = Path::new(surefile);
+    let base = path
+        .file_name()
+        .ok_or_else(|| SureError::BadFileName(surefile.to_string()))?;
+    let work = path.with_file_name(".rack-prune");
+    if work.exists() {
+        fs::remove_dir_all(&work)?;
+    }
+    fs::create_dir(&work)?;
+    let new_name = work.join(base);
+
+    // Copy the kept versions, oldest first, so that they keep their order.
+    let new_store = rsure::parse_store(&new_name.to_string_lossy())?;
+    for v in &kept {
+        let mut tags = v.tags.clone();
+        tags.insert("name".into(), v.name.clone());
+        let mut writer = NodeWriter::new(new_store.make_new(&tags)?)?;
+        for node in store.load_iter(v.version.clone())? {
+            writer.write_node(&node?)?;
+        }
+        writer.into_inner().commit()?;
+    }
+
+    let mut saved = surefile.to_string();
+    saved.push_str(".pre-prune");
+    fs::rename(surefile, &saved)?;
+    fs::rename(&new_name, surefile)?;
+    fs::remove_dir(&work)?;
+    Ok(())
+}
+
+#[test]
+fn test_parse_uri() {
+    assert_eq!(parse_uri("/a/b.dat.gz").unwrap(), (StoreKind::Guess, "/a/b.dat.gz"));
+    assert_eq!(parse_uri("weave:/a/b.weave.gz").unwrap(), (StoreKind::Weave, "/a/b.weave.gz"));
+    assert_eq!(parse_uri("./x:y").unwrap(), (StoreKind::Guess, "./x:y"));
+    assert!(parse_uri("bogus:/a").is_err());
+    assert!(parse_uri("plain:").is_err());
+}
+
+#[test]
+fn test_tags_match() {
+    let mut tags = StoreTags::new();
+    tags.insert("host".into(), "lint".into());
+    tags.insert("zfs".into(), "lint/home".into());
+    let f = |fs: &[&str]| -> Vec<String> { fs.iter().map(|s| s.to_string()).collect() };
+    assert!(tags_match(&tags, &f(&[])));
+    assert!(tags_match(&tags, &f(&["host=lint", "zfs"])));
+    assert!(!tags_match(&tags, &f(&["host=other"])));
+    assert!(!tags_match(&tags, &f(&["convention"])));
+}
@@ -1,58 +1,571 @@
-//! Sync the root filesystem to a volume on ZFS.
+//! Sync filesystems that aren't on ZFS to a volume on ZFS, from an LVM or
+//! btrfs snapshot.
 
-use failure::format_err;
-use std::{fs, path::Path, process::Command};
+use chrono::Local;
+use serde_derive::Serialize;
+use std::{
+    fs::{self, File},
+    io::Write,
+    path::Path,
+    process::{Command, ExitStatus, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering as AtomicOrdering},
+        Mutex,
+    },
+    thread,
+    time::Duration,
+};
+use thiserror::Error;
 
+use crate::btrfs::BtrfsSnap;
+use crate::hooks::Hooks;
+use crate::checked::{heavy_command, watch_pipeline, CheckedExt, Watch};
+use crate::config::{configured, skipped, Config, ConfigError, SyncKind, SyncVolume};
+use crate::jobs::Jobs;
+use crate::journal::{self, state_dir};
 use crate::lvm::Lvm;
-use crate::Result;
+use crate::mount;
+use crate::plan::Plan;
+use crate::verify::{sha1sum, Rng};
+use crate::zfs::{check_space, find_mount, size_property};
+use crate::{Error, Result};
 use crate::HOME_BIND_DIR;
 use crate::ROOT_BIND_DIR;
 
+#[derive(Debug, Error)]
+pub enum SyncError {
+    /// The lvm snapshot being synced from nearly ran out of room, and would
+    /// have been dropped.
+    #[error("Snapshot {snap} is {usage:.1}% full")]
+    SnapshotFull { snap: String, usage: f64 },
+    #[error("Sync verify of {0:?} failed")]
+    VerifyFailed(String),
+    #[error("{vg}/{lv} is not mounted")]
+    NotMounted { vg: String, lv: String },
+    #[error("Error running rsync: {0:?}")]
+    Rsync(ExitStatus),
+}
+
 /// Sync the root filesystem to a volume on ZFS.
 ///
 /// The root filesystem on my system lives on ext4, mostly because of the added complexity of
 /// having ZFS on root.  This used to just bind mount, but now that root is on lvm, we can make a
 /// proper snapshot.
-pub fn sync_root(root_fs: &str) -> Result<()> {
-    let mut lvols = Lvm::scan("ubuntu-vg", "gentooroot")?;
-    let snap = lvols.new_name();
-    lvols.create_snapshot(&snap)?;
-
-    let _root = lvols.mount_snapshot(&snap, ROOT_BIND_DIR)?;
-
-    let status = Command::new("rsync")
-        // .arg("-n")
-        .arg("-aiHAX")
-        .arg("--delete")
-        .arg(&format!("{}/.", ROOT_BIND_DIR))
-        .arg(&format!("/{}/.", root_fs))
-        .status()?;
-    if !status.success() {
-        return Err(format_err!("Error running rsync: {:?}", status));
-    }
-    Ok(())
+pub fn sync_root(root_fs: &str, bwlimit: Option<&str>, pretend: bool) -> Result<()> {
+    legacy_volume("root", "gentooroot", ROOT_BIND_DIR, root_fs).sync(bwlimit, pretend)
 }
 
 /// Sync the home filesystem to a volume on ZFS.
 ///
 /// The home filesystem also lives on ext4, with a lvm thinvol snapshot.
-pub fn sync_home(home_fs: &str) -> Result<()> {
-    let mut lvols = Lvm::scan("ubuntu-vg", "home")?;
-    let snap = lvols.new_name();
-    lvols.create_snapshot(&snap)?;
+pub fn sync_home(home_fs: &str, bwlimit: Option<&str>, pretend: bool) -> Result<()> {
+    legacy_volume("home", "home", HOME_BIND_DIR, home_fs).sync(bwlimit, pretend)
+}
+
+/// The volumes that were synced before there was a sync config.
+fn legacy_volume(name: &str, lv: &str, mountpoint: &str, zfs_dest: &str) -> SyncVolume {
+    SyncVolume {
+        name: name.into(),
+        skip: None,
+        kind: SyncKind::Lvm,
+        vg: Some("ubuntu-vg".into()),
+        lv: Some(lv.into()),
+        subvolume: None,
+        mountpoint: mountpoint.into(),
+        zfs_dest: zfs_dest.into(),
+        excludes: vec![],
+        exclude_profiles: vec![],
+        exclude_from: None,
+        bwlimit: None,
+        verify: None,
+        keep_last: None,
+        keep_days: None,
+        pool_threshold: None,
+        pool_warn_only: None,
+        snapshot_size: None,
+        pre_hook: vec![],
+        post_hook: vec![],
+        hook_failure: None,
+    }
+}
+
+impl Config {
+    /// Sync the named volume from the sync section of the config.  A given
+    /// `bwlimit` overrides the one in the config.
+    pub fn sync(&self, name: &str, bwlimit: Option<&str>, pretend: bool) -> Result<()> {
+        let vol = self
+            .sync
+            .volumes
+            .iter()
+            .find(|v| v.name == name)
+            .ok_or_else(|| ConfigError::NoVolume {
+                section: "sync",
+                name: name.to_string(),
+            })?;
+        let _lock = if pretend { None } else { self.preflight()? };
+        vol.sync(bwlimit, pretend)
+    }
+
+    /// Remove old lvm snapshots made by syncs, according to the retention
+    /// of each volume.
+    pub fn sync_prune(&self, really: bool) -> Result<()> {
+        self.plan_sync_prune()?.execute(!really)
+    }
+
+    /// Plan the removal of old lvm snapshots made by syncs.
+    pub fn plan_sync_prune(&self) -> Result<Plan> {
+        configured("sync", &self.sync.volumes)?;
+        let mut plan = Plan::new("sync-prune");
+        for vol in &self.sync.volumes {
+            if vol.kind != SyncKind::Lvm {
+                continue;
+            }
+            let (vg, lv) = vol.lvm()?;
+            Lvm::scan(vg, lv)?.prune(vol.keep_last, vol.keep_days, &mut plan);
+        }
+        Ok(plan)
+    }
+
+    /// Sync every volume in the sync section of the config, running up to
+    /// `jobs` at a time.  Volumes sharing a mountpoint or destination are
+    /// always run one after another.
+    pub fn sync_all(&self, bwlimit: Option<&str>, jobs: usize, pretend: bool) -> Result<()> {
+        configured("sync", &self.sync.volumes)?;
+        let _lock = if pretend { None } else { self.preflight()? };
+
+        let mut queue = Jobs::new();
+        for vol in &self.sync.volumes {
+            if skipped("sync", &vol.name, vol.skip) {
+                continue;
+            }
+            let uses = vec![format!("dir:{}", vol.mountpoint), format!("zfs:{}", vol.zfs_dest)];
+            queue.add(format!("sync {:?}", vol.name), uses, move || vol.sync(bwlimit, pretend));
+        }
+        queue.run(jobs)
+    }
+}
+
+impl SyncVolume {
+    /// Snapshot the source, and rsync the snapshot, mounted at `mountpoint`,
+    /// to the zfs filesystem.  With `pretend`, no snapshot is made, and rsync
+    /// is run with `--dry-run` from the live filesystem, to show what would
+    /// be changed.
+    pub fn sync(&self, bwlimit: Option<&str>, pretend: bool) -> Result<()> {
+        progress!("Sync {:?}", self);
+        let dest = find_mount(&self.zfs_dest)?;
+        let mut args = self.rsync_args(bwlimit);
+
+        if pretend {
+            let src = match self.kind {
+                SyncKind::Lvm => {
+                    let (vg, lv) = self.lvm()?;
+                    lv_mount(vg, lv)?
+                }
+                SyncKind::Btrfs => self.subvolume()?.to_string(),
+            };
+            if let Err(e) = self.check_space(&src) {
+                warning!("Warning: {}", e);
+            }
+            args.push("--dry-run".into());
+            let summary = rsync(&self.name, &src, &dest, &args, false, None)?;
+            summary.show(&self.name);
+            return Ok(());
+        }
+
+        match self.kind {
+            SyncKind::Lvm => {
+                let (vg, lv) = self.lvm()?;
+                let mut lvols = Lvm::scan(vg, lv)?;
+                lvols.check_pool(
+                    self.pool_threshold.unwrap_or(90.0),
+                    self.pool_warn_only == Some(true),
+                )?;
+                let snap = lvols.new_name();
+                let size = self.snapshot_size.as_ref().map(|s| s.as_str());
+                self.hooks().around(&self.name, || lvols.create_snapshot(&snap, size))?;
+
+                {
+                    let _root = lvols.mount_snapshot(&snap, &self.mountpoint)?;
+
+                    // A classic snapshot becomes invalid if it fills up, so
+                    // stop before that happens.
+                    let monitor = || -> Result<()> {
+                        let usage = lvols.snapshot_usage(&snap)?;
+                        if usage >= SNAPSHOT_FULL {
+                            let snap = snap.clone();
+                            return Err(SyncError::SnapshotFull { snap, usage }.into());
+                        }
+                        Ok(())
+                    };
+                    let monitor: Option<Monitor> = match size {
+                        Some(_) => Some(&monitor),
+                        None => None,
+                    };
+                    self.transfer(&dest, &args, monitor)?;
+                }
+                let mut plan = Plan::new("sync-prune");
+                lvols.prune(self.keep_last, self.keep_days, &mut plan);
+                plan.apply()
+            }
+            SyncKind::Btrfs => {
+                let subvolume = self.subvolume()?;
+                let snap = self.hooks().around(&self.name, || BtrfsSnap::create(subvolume))?;
+                let _root = MountedDir::new(snap.path(), Path::new(&self.mountpoint))?;
+                self.transfer(&dest, &args, None)
+            }
+        }
+    }
+
+    fn hooks(&self) -> Hooks<'_> {
+        Hooks::new(&self.pre_hook, &self.post_hook, self.hook_failure)
+    }
+
+    /// Rsync the snapshot, once mounted, to `dest`.
+    fn transfer(&self, dest: &str, args: &[String], monitor: Option<Monitor>) -> Result<()> {
+        self.check_space(&self.mountpoint)?;
+
+        let summary = rsync(&self.name, &self.mountpoint, dest, args, true, monitor)?;
+        summary.show(&self.name);
+        journal::record("sync", &self.name, &summary)?;
+
+        if let Some(count) = self.verify {
+            self.verify(dest, count)?;
+        }
+        Ok(())
+    }
+
+    /// Compare the mounted snapshot against `dest`: the number of entries
+    /// and files, the total size of the files, and the checksums of `count`
+    /// randomly chosen files.  Excludes are honored only approximately (see
+    /// `Tree::excluded`), so a volume with complex excludes may not verify.
+    fn verify(&self, dest: &str, count: usize) -> Result<()> {
+        progress!("Sync verify {:?}", self.name);
+        let mut src = Tree::new(&self.excludes, count);
+        src.walk(Path::new(&self.mountpoint), "")?;
+        let mut dst = Tree::new(&self.excludes, 0);
+        dst.walk(Path::new(dest), "")?;
+
+        let mut record = SyncCheck {
+            entries: (src.entries, dst.entries),
+            files: (src.files, dst.files),
+            bytes: (src.bytes, dst.bytes),
+            sampled: src.sample.len(),
+            mismatched: vec![],
+        };
+        for path in &src.sample {
+            let a = sha1sum(&Path::new(&self.mountpoint).join(path))?;
+            let b = sha1sum(&Path::new(dest).join(path)).unwrap_or_default();
+            if a != b {
+                record.mismatched.push(path.clone());
+            }
+        }
+        journal::record("sync-verify", &self.name, &record)?;
+
+        let ok = record.entries.0 == record.entries.1
+            && record.files.0 == record.files.1
+            && record.bytes.0 == record.bytes.1
+            && record.mismatched.is_empty();
+        progress!("Sync verify {:?}: {:?}", self.name, record);
+        if !ok {
+            return Err(SyncError::VerifyFailed(self.name.clone()).into());
+        }
+        Ok(())
+    }
+
+    /// The volume group and logical volume of an lvm source.
+    fn lvm(&self) -> Result<(&str, &str)> {
+        match (&self.vg, &self.lv) {
+            (Some(vg), Some(lv)) => Ok((vg, lv)),
+            _ => Err(Error::msg(format!("Sync volume {:?} needs vg and lv", self.name))),
+        }
+    }
+
+    /// The source subvolume of a btrfs source.
+    fn subvolume(&self) -> Result<&str> {
+        self.subvolume
+            .as_ref()
+            .map(|s| s.as_str())
+            .ok_or_else(|| Error::msg(format!("Sync volume {:?} needs subvolume", self.name)))
+    }
+
+    /// Check that the destination has room for the contents of `src`.  The
+    /// destination will grow by at most the difference in used space, less
+    /// with compression.
+    fn check_space(&self, src: &str) -> Result<()> {
+        let referenced = size_property(&self.zfs_dest, "referenced")?;
+        check_space(&self.zfs_dest, used_space(src)?.saturating_sub(referenced))
+    }
+
+    /// Additional arguments to give rsync for this volume.
+    fn rsync_args(&self, bwlimit: Option<&str>) -> Vec<String> {
+        let bwlimit = bwlimit.or(self.bwlimit.as_ref().map(|b| b.as_str()));
+        let mut args: Vec<_> = bwlimit.map(|b| format!("--bwlimit={}", b)).into_iter().collect();
+        for ex in &self.excludes {
+            args.push(format!("--exclude={}", ex));
+        }
+        if let Some(ref from) = self.exclude_from {
+            args.push(format!("--exclude-from={}", from));
+        }
+        args
+    }
+}
+
+/// The result of verifying a sync.  Each pair is (source, destination).
+#[derive(Debug, Serialize)]
+struct SyncCheck {
+    entries: (usize, usize),
+    files: (usize, usize),
+    bytes: (u64, u64),
+    sampled: usize,
+    mismatched: Vec<String>,
+}
 
-    let _home = lvols.mount_snapshot(&snap, HOME_BIND_DIR)?;
+/// Totals gathered by walking a tree.
+struct Tree<'a> {
+    excludes: &'a [String],
+    entries: usize,
+    files: usize,
+    bytes: u64,
+    /// A random sample of regular files, up to `want` of them.
+    sample: Vec<String>,
+    want: usize,
+    rng: Rng,
+}
+
+impl<'a> Tree<'a> {
+    fn new(excludes: &'a [String], want: usize) -> Tree<'a> {
+        Tree {
+            excludes: excludes,
+            entries: 0,
+            files: 0,
+            bytes: 0,
+            sample: vec![],
+            want: want,
+            rng: Rng::new(),
+        }
+    }
+
+    /// Walk the directory `base/rel`, without following symlinks.
+    fn walk(&mut self, base: &Path, rel: &str) -> Result<()> {
+        for ent in fs::read_dir(base.join(rel))? {
+            let ent = ent?;
+            let name = ent.file_name().to_string_lossy().into_owned();
+            let path = if rel.is_empty() {
+                name.clone()
+            } else {
+                format!("{}/{}", rel, name)
+            };
+            if (rel.is_empty() && name == ".zfs") || self.excluded(&path, &name) {
+                continue;
+            }
+
+            let meta = ent.metadata()?;
+            self.entries += 1;
+            if meta.is_dir() {
+                self.walk(base, &path)?;
+            } else if meta.is_file() {
+                self.files += 1;
+                self.bytes += meta.len();
+
+                // Reservoir sampling.
+                if self.sample.len() < self.want {
+                    self.sample.push(path);
+                } else if self.want > 0 {
+                    let pos = self.rng.below(self.files);
+                    if pos < self.want {
+                        self.sample[pos] = path;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Is this path excluded?  Only the simple forms of rsync patterns are
+    /// understood: a pattern starting with "/" matches a path from the root,
+    /// and any other matches a name anywhere.  Wildcards are not expanded.
+    fn excluded(&self, path: &str, name: &str) -> bool {
+        self.excludes.iter().any(|ex| {
+            let ex = ex.trim_end_matches('/');
+            if ex.starts_with('/') {
+                &ex[1..] == path
+            } else {
+                ex == name
+            }
+        })
+    }
+}
+
+/// Find where the given logical volume itself is mounted.
+fn lv_mount(vg: &str, lv: &str) -> Result<String> {
+    let dev = fs::canonicalize(format!("/dev/{}/{}", vg, lv))?;
+    for m in mount::mounts()? {
+        // A bind mount of part of the volume isn't where it is mounted.
+        if !m.source.starts_with('/') || m.root != "/" {
+            continue;
+        }
+        if fs::canonicalize(&m.source).ok().as_ref() == Some(&dev) {
+            return Ok(m.mountpoint);
+        }
+    }
+    Err(SyncError::NotMounted {
+        vg: vg.to_string(),
+        lv: lv.to_string(),
+    }
+    .into())
+}
+
+/// The space used on the filesystem mounted at `path`, in bytes.
+fn used_space(path: &str) -> Result<usize> {
+    let out = Command::new("df")
+        .args(&["-B1", "--output=used", path])
+        .checked_output()?;
+    let text = String::from_utf8(out.stdout)?;
+    text.lines()
+        .nth(1)
+        .and_then(|l| l.trim().parse().ok())
+        .ok_or_else(|| Error::msg(format!("Unable to parse df output: {:?}", text)))
+}
+
+/// Counts of the changes made by an rsync run.
+#[derive(Debug, Default, Serialize)]
+struct RsyncSummary {
+    added: usize,
+    changed: usize,
+    deleted: usize,
+    /// The compressed itemized log of this run, if it was kept.
+    log: Option<String>,
+}
+
+impl RsyncSummary {
+    /// Count a single line of rsync's itemized output.
+    fn count(&mut self, line: &str) {
+        // Itemized lines are an 11 character code, a space, and the name.
+        let code = line.as_bytes();
+        if line.starts_with("*deleting") {
+            self.deleted += 1;
+        } else if code.len() < 12 || code[11] != b' ' {
+            return;
+        } else if code[2..11].iter().all(|&b| b == b'+') {
+            self.added += 1;
+        } else if b".<>ch".contains(&code[0]) {
+            self.changed += 1;
+        }
+    }
+
+    fn show(&self, name: &str) {
+        progress!(
+            "Sync {:?}: {} added, {} changed, {} deleted",
+            name, self.added, self.changed, self.deleted
+        );
+        if let Some(ref log) = self.log {
+            progress!("Sync {:?}: log in {}", name, log);
+        }
+    }
+}
+
+/// A check run periodically during a transfer.  Returning an error aborts the
+/// transfer.
+type Monitor<'a> = &'a (dyn Fn() -> Result<()> + Sync);
 
-    let status = Command::new("rsync")
+/// How often the monitor is run, in seconds.
+const MONITOR_INTERVAL: u64 = 10;
+
+/// How full, in percent, a classic snapshot can get before the sync is
+/// aborted.
+const SNAPSHOT_FULL: f64 = 95.0;
+
+/// Rsync `src` to `dest`.  The itemized output is shown as it is produced,
+/// and, if `keep_log` is set, also saved, compressed, in the state directory.
+/// If a `monitor` is given, rsync is killed if it ever fails.
+fn rsync(
+    name: &str,
+    src: &str,
+    dest: &str,
+    args: &[String],
+    keep_log: bool,
+    monitor: Option<Monitor>,
+) -> Result<RsyncSummary> {
+    let mut summary = RsyncSummary::default();
+    let mut gzip = if keep_log {
+        let logdir = state_dir()?.join("sync");
+        fs::create_dir_all(&logdir)?;
+        let logname =
+            logdir.join(format!("{}-{}.log.gz", name, Local::now().format("%Y%m%d%H%M%S")));
+        summary.log = Some(logname.to_string_lossy().into_owned());
+        Some(
+            Command::new("gzip")
+                .stdin(Stdio::piped())
+                .stdout(File::create(&logname)?)
+                .spawn()?,
+        )
+    } else {
+        None
+    };
+
+    let mut rsync = heavy_command("rsync");
+    rsync
         .arg("-aiHAX")
         .arg("--delete")
-        .arg(&format!("{}/.", HOME_BIND_DIR))
-        .arg(&format!("/{}/.", home_fs))
-        .status()?;
+        .args(args)
+        .arg(&format!("{}/.", src))
+        .arg(&format!("{}/.", dest));
+
+    let mut monitor = monitor;
+    let done = AtomicBool::new(false);
+    let aborted = Mutex::new(None);
+    let statuses = thread::scope(|s| {
+        let mut started = |pids: &[u32]| {
+            if let (Some(monitor), Some(&pid)) = (monitor.take(), pids.first()) {
+                let (done, aborted) = (&done, &aborted);
+                s.spawn(move || {
+                    while !done.load(AtomicOrdering::SeqCst) {
+                        if let Err(e) = monitor() {
+                            warning!("Aborting rsync of {:?}: {}", name, e);
+                            let _ = Command::new("kill").arg(pid.to_string()).status();
+                            *aborted.lock().unwrap() = Some(e);
+                            break;
+                        }
+                        // Sleep in short steps, so the end isn't held up.
+                        for _ in 0..MONITOR_INTERVAL {
+                            if done.load(AtomicOrdering::SeqCst) {
+                                break;
+                            }
+                            thread::sleep(Duration::from_secs(1));
+                        }
+                    }
+                });
+            }
+        };
+
+        let mut log = gzip.as_mut().map(|g| g.stdin.as_mut().expect("gzip stdin"));
+        let mut copy = |line: &str| -> Result<()> {
+            progress!("{}", line);
+            if let Some(ref mut log) = log {
+                writeln!(log, "{}", line)?;
+            }
+            summary.count(line);
+            Ok(())
+        };
+        let res = watch_pipeline(&mut [rsync], (0, Watch::Stdout), &mut started, &mut copy);
+        done.store(true, AtomicOrdering::SeqCst);
+        res
+    })?;
+
+    let status = statuses[0];
+    if let Some(e) = aborted.into_inner().unwrap() {
+        return Err(e);
+    }
+    if let Some(mut gzip) = gzip {
+        drop(gzip.stdin.take());
+        if !gzip.wait()?.success() {
+            return Err(Error::msg(format!("Error compressing rsync log {:?}", summary.log)));
+        }
+    }
     if !status.success() {
-        return Err(format_err!("Error running rsync: {:?}", status));
+        return Err(SyncError::Rsync(status).into());
     }
-    Ok(())
+    Ok(summary)
 }
 
 // Ensure the named directory is empty, but exists.
@@ -60,47 +573,110 @@ fn ensure_empty<P: AsRef<Path>>(name: P) -> Result<()> {
     let name = name.as_ref();
 
     if !name.is_dir() {
-        return Err(format_err!("Root {:?} is not a directory", name));
+        return Err(Error::msg(format!("Root {:?} is not a directory", name)));
     }
 
     if let Some(entry) = fs::read_dir(name)?.next() {
-        return Err(format_err!(
+        return Err(Error::msg(format!(
             "Root {:?} is not empty (has {:?})",
             name,
             entry?
-        ));
+        )));
     }
 
     Ok(())
 }
 
 // Bind mount a directory, making sure to unmount it when this value goes out of scope.
-pub struct MountedDir<'a>(&'a Path);
+pub struct MountedDir<'a> {
+    dir: &'a Path,
+    mounted: bool,
+}
 
 impl<'a> MountedDir<'a> {
     pub fn new<P1: AsRef<Path>>(from: P1, to: &'a Path) -> Result<MountedDir<'a>> {
         ensure_empty(to)?;
-        let from = from.as_ref();
-        let status = Command::new("mount")
-            .arg("--bind")
-            .arg(from)
-            .arg(to)
-            .status()?;
-        if !status.success() {
-            return Err(format_err!("Error running mount command: {:?}", status));
+        mount::bind(from.as_ref(), to)?;
+        Ok(MountedDir::mounted(to))
+    }
+
+    /// Mount a zfs snapshot, read-only, rather than bind mounting.
+    pub fn zfs(snapshot: &str, to: &'a Path) -> Result<MountedDir<'a>> {
+        ensure_empty(to)?;
+        mount::read_only(snapshot, Some("zfs"), to)?;
+        Ok(MountedDir::mounted(to))
+    }
+
+    fn mounted(to: &'a Path) -> MountedDir<'a> {
+        MountedDir {
+            dir: to,
+            mounted: true,
         }
-        Ok(MountedDir(to))
+    }
+
+    /// Leave the directory mounted, after this is dropped.
+    pub fn keep(mut self) {
+        self.mounted = false;
+    }
+
+    /// Unmount the directory, returning any error, rather than just logging
+    /// it as happens when this is dropped.
+    pub fn unmount(mut self) -> Result<()> {
+        self.mounted = false;
+        unmount(self.dir)
     }
 }
 
 impl<'a> Drop for MountedDir<'a> {
     fn drop(&mut self) {
-        let status = Command::new("umount")
-            .arg(self.0)
-            .status()
-            .expect("Umount command");
-        if !status.success() {
-            panic!("Error running unmount command");
+        if self.mounted {
+            if let Err(e) = unmount(self.dir) {
+                warning!("Error unmounting {:?}: {}", self.dir, e);
+            }
         }
     }
 }
+
+/// Unmount a directory.  The unmount is retried a few times, since the
+/// filesystem can be briefly busy just after whatever was using it exits.
+/// If it still fails, fall back to a lazy unmount, so that the directory is
+/// at least detached.
+fn unmount(dir: &Path) -> Result<()> {
+    for attempt in 0..3 {
+        if attempt > 0 {
+            thread::sleep(Duration::from_secs(1));
+        }
+        if mount::unmount(dir, false).is_ok() {
+            return Ok(());
+        }
+    }
+    warning!("Unable to unmount {:?}, doing a lazy unmount", dir);
+    mount::unmount(dir, true)
+}
+
+#[test]
+fn test_rsync_count() {
+    let mut summary = RsyncSummary::default();
+    for line in &[
+        "*deleting   old/file",
+        ">f+++++++++ new/file",
+        "cd+++++++++ new/",
+        ">f.st...... changed/file",
+        ".d..t...... dir/",
+        "cannot delete non-empty directory: x",
+    ] {
+        summary.count(line);
+    }
+    assert_eq!((summary.added, summary.changed, summary.deleted), (2, 2, 1));
+}
+
+#[test]
+fn test_excluded() {
+    let excludes = vec!["/var/cache/".to_string(), "lost+found".to_string()];
+    let tree = Tree::new(&excludes, 0);
+    assert!(tree.excluded("var/cache", "cache"));
+    assert!(!tree.excluded("home/var/cache", "cache"));
+    assert!(tree.excluded("lost+found", "lost+found"));
+    assert!(tree.excluded("home/lost+found", "lost+found"));
+    assert!(!tree.excluded("var", "var"));
+}
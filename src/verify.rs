@@ -0,0 +1,547 @@
+//! Verify backups against rsure data.
+//!
+//! A backup that can't be restored isn't much of a backup.  This restores a
+//! random sample of files from a restic snapshot into a scratch directory,
+//! and compares them against the rsure data captured from the same zfs
+//! snapshot.  Streams written by cloud and export volumes are checked the
+//! same way, by receiving them into a scratch filesystem.
+//!
+//! Run without a volume, `rack verify` checks one randomly chosen restic
+//! volume, and one volume of streams, recording each result in the journal.
+//!
+//! Clones can be checked after they are made: the GUID of each snapshot
+//! received is compared with the one it was sent from, and a sample of the
+//! files of the latest snapshot with rsure data is compared, where it is
+//! mounted, against that data.
+
+use crate::{
+    catalog::{Catalog, Stream},
+    checked::{heavy_command, CheckedExt},
+    config::{configured, skipped, CloneVolume, Config, ConfigError},
+    journal,
+    restic::{ResticError, RESTIC_BIN},
+    send::StreamError,
+    zfs::{Inventory, Zfs},
+    Context, Error, Result,
+};
+use serde_derive::Serialize;
+use rsure::{AttMap, SureNode};
+use std::{
+    env, fs,
+    os::unix::fs::{MetadataExt, PermissionsExt},
+    path::{Path, PathBuf},
+    process::{self, Command, Stdio},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// The number of files compared, unless configured otherwise.
+const COUNT: usize = 20;
+
+/// The outcome of a verification, as recorded in the journal.
+#[derive(Serialize)]
+struct Verified<'a> {
+    kind: &'a str,
+    passed: bool,
+    error: Option<String>,
+}
+
+impl Config {
+    /// Verify a sample of the backups: a random snapshot of a random restic
+    /// volume, and, if `verify.scratch` is configured, the latest snapshot of
+    /// a random cloud or export volume.  Export volumes are only chosen when
+    /// their drive is attached.  Each result is recorded in the journal, and
+    /// failures are sent to the `notify` destinations.
+    pub fn run_verify(&self, count: Option<usize>) -> Result<()> {
+        let count = count.or(self.verify.count).unwrap_or(COUNT);
+        // Volumes can only be checked against sure data, and skipped ones
+        // are left alone.
+        let eligible = |zfs: &str, skip: Option<bool>| {
+            skip != Some(true) && self.sure.volumes.iter().any(|s| s.zfs == zfs)
+        };
+        let mut rng = Rng::new();
+        let mut results = vec![];
+
+        let restic: Vec<_> =
+            self.restic.volumes.iter().filter(|v| eligible(&v.zfs, v.skip)).collect();
+        if !restic.is_empty() {
+            let vol = restic[rng.below(restic.len())];
+            let result = self.check_restic(&vol.name, None, count, Some(&mut rng));
+            results.push(("restic", vol.name.as_str(), result));
+        }
+
+        if let Some(ref scratch) = self.verify.scratch {
+            let mut choices: Vec<(&str, &str)> = vec![];
+            choices.extend(
+                self.cloud
+                    .volumes
+                    .iter()
+                    .filter(|v| eligible(&v.zfs, v.skip))
+                    .map(|v| ("cloud", v.name.as_str())),
+            );
+            let drive = match self.find_target(None) {
+                Ok((_, path)) => Some(path),
+                Err(_) => None,
+            };
+            if drive.is_some() {
+                choices.extend(
+                    self.export
+                        .volumes
+                        .iter()
+                        .filter(|v| eligible(&v.zfs, v.skip))
+                        .map(|v| ("export", v.name.as_str())),
+                );
+            }
+            if !choices.is_empty() {
+                let (kind, name) = choices[rng.below(choices.len())];
+                let result = if kind == "cloud" {
+                    let vol = self.cloud.volumes.iter().find(|v| v.name == name).unwrap();
+                    let catalog = Catalog::load(&Catalog::default_path()?)?;
+                    vol.find_streams(&catalog).and_then(|streams| {
+                        let receive = |dest: &str, snap: &str| {
+                            vol.restore(&catalog, dest, Some(snap), false)
+                        };
+                        self.check_streams(name, &vol.zfs, &streams, scratch, count, &receive)
+                    })
+                } else {
+                    let vol = self.export.volumes.iter().find(|v| v.name == name).unwrap();
+                    let drive = drive.as_ref().unwrap();
+                    vol.manifest(drive).and_then(|manifest| {
+                        let receive =
+                            |dest: &str, snap: &str| vol.import(drive, dest, Some(snap), false);
+                        let streams = &manifest.streams;
+                        self.check_streams(name, &vol.zfs, streams, scratch, count, &receive)
+                    })
+                };
+                results.push((kind, name, result));
+            }
+        }
+
+        if results.is_empty() {
+            return Err(Error::msg("Nothing is configured that can be verified"));
+        }
+        self.report_verified(&results)
+    }
+
+    /// Check each of the clone volumes, as `verify_clone`, recording the
+    /// results as any other verification.
+    pub fn verify_clones(&self) -> Result<()> {
+        configured("clone", &self.clone.volumes)?;
+        let count = self.verify.count.unwrap_or(COUNT);
+        let mut results = vec![];
+        for vol in &self.clone.volumes {
+            if skipped("clone", &vol.name, vol.skip) {
+                continue;
+            }
+            results.push(("clone", vol.name.as_str(), self.verify_clone(vol, count)));
+        }
+        self.report_verified(&results)
+    }
+
+    /// Check that the snapshots of a clone volume are those of its source,
+    /// by their GUIDs, and compare `count` files of the latest snapshot of
+    /// each filesystem with sure data against that data.  Sure data is of
+    /// local filesystems, so the files of a pulled volume aren't compared.
+    fn verify_clone(&self, vol: &CloneVolume, count: usize) -> Result<()> {
+        progress!("Verify {:?}: snapshots of {} in {}", vol.name, vol.source, vol.dest);
+        let from = match vol.host {
+            Some(ref host) => {
+                Zfs::from_inventory("caz", &Inventory::remote(host).within(&vol.source))?
+            }
+            None => Zfs::from_inventory("caz", &self.inventory)?,
+        };
+        let mut zfs = Zfs::from_inventory("caz", &self.inventory)?;
+        zfs.options.renames = vol.renames.clone().into_iter().collect();
+        let problems = zfs.check_clone(&from, &vol.source, &vol.dest)?;
+        for problem in &problems {
+            progress!("  FAIL {}", problem);
+        }
+        if !problems.is_empty() {
+            let msg = format!("{} problems with the snapshots cloned", problems.len());
+            return Err(Error::msg(msg));
+        }
+        if vol.host.is_some() {
+            return Ok(());
+        }
+
+        let mut failures = 0;
+        let under = format!("{}/", vol.source);
+        for svol in &self.sure.volumes {
+            if svol.zfs != vol.source && !svol.zfs.starts_with(&under) {
+                continue;
+            }
+            let fs = zfs.find_filesystem(&zfs.cloned_name(&vol.source, &vol.dest, &svol.zfs))?;
+            let store = svol.open_store()?;
+            let latest = store
+                .get_versions()?
+                .into_iter()
+                .filter(|v| fs.snaps.contains(&v.name))
+                .max_by_key(|v| v.time);
+            let latest = match latest {
+                Some(latest) => latest,
+                None => continue,
+            };
+            let sample = sample_files(store.load_iter(latest.version)?, count)?;
+            progress!("Verify {:?}: files of {}@{}", vol.name, fs.name, latest.name);
+            let scratch = ScratchDir::new("verify")?;
+            let _mounted = fs.mount_snapshot(&latest.name, &scratch.0)?;
+            failures += compare_sample(&vol.name, &scratch.0, &sample);
+        }
+        if failures > 0 {
+            let msg = format!("{} files of the clone didn't match sure data", failures);
+            return Err(Error::msg(msg));
+        }
+        Ok(())
+    }
+
+    /// Record the results of verifications in the journal, and send any
+    /// failures to the `notify` destinations.
+    fn report_verified(&self, results: &[(&str, &str, Result<()>)]) -> Result<()> {
+        let mut failures = 0;
+        for (kind, name, result) in results {
+            let error = result.as_ref().err().map(|e| e.to_string());
+            journal::record(
+                "verify",
+                name,
+                &Verified {
+                    kind: kind,
+                    passed: error.is_none(),
+                    error: error.clone(),
+                },
+            )?;
+            if let Some(error) = error {
+                failures += 1;
+                let msg = format!("rack verify of {} volume {:?} failed: {}", kind, name, error);
+                warning!("{}", msg);
+                // A failure to send has already been warned about.
+                let _ = self.notify.send(&format!("rack verify of {} failed", name), &msg);
+            }
+        }
+        if failures > 0 {
+            return Err(Error::msg(format!(
+                "{} of {} verifications failed",
+                failures,
+                results.len()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Restore `count` randomly chosen files from a restic snapshot of the
+    /// named volume and compare them against rsure.  If `tag` is not given,
+    /// the most recent restic snapshot that also has rsure data is used.
+    pub fn verify_restic(&self, volume: &str, tag: Option<&str>, count: usize) -> Result<()> {
+        self.check_restic(volume, tag, count, None)
+    }
+
+    /// Verify a restic snapshot, as `verify_restic`, but when `rng` is given,
+    /// choose a random snapshot with rsure data, instead of the latest.
+    fn check_restic(
+        &self,
+        volume: &str,
+        tag: Option<&str>,
+        count: usize,
+        rng: Option<&mut Rng>,
+    ) -> Result<()> {
+        configured("restic", &self.restic.volumes)?;
+        let rvol = self
+            .restic
+            .volumes
+            .iter()
+            .find(|v| v.name == volume)
+            .ok_or_else(|| ConfigError::NoVolume {
+                section: "restic",
+                name: volume.to_string(),
+            })?;
+        let svol = self
+            .sure
+            .volumes
+            .iter()
+            .find(|v| v.zfs == rvol.zfs)
+            .ok_or_else(|| ConfigError::NoVolumeFor {
+                section: "sure",
+                zfs: rvol.zfs.clone(),
+            })?;
+
+        let store = svol.open_store()?;
+        let versions = store.get_versions()?;
+
+        // Restic snapshots of this bind point, paired with the matching sure
+        // version.
+        let mut candidates = vec![];
+        for snap in rvol.get_snapshots()? {
+            if !snap.paths.iter().any(|p| p == &rvol.bind) {
+                continue;
+            }
+            let tags = match snap.tags {
+                Some(ref tags) => tags.clone(),
+                None => continue,
+            };
+            for t in &tags {
+                if let Some(tag) = tag {
+                    if t != tag {
+                        continue;
+                    }
+                }
+                if let Some(v) = versions.iter().find(|v| &v.name == t) {
+                    candidates.push((
+                        snap.time.clone(),
+                        snap.id.clone(),
+                        t.clone(),
+                        v.version.clone(),
+                    ));
+                }
+            }
+        }
+        // Restic times are RFC3339, which sort lexically.
+        candidates.sort_by(|a, b| a.0.cmp(&b.0));
+        if let (Some(rng), false) = (rng, candidates.is_empty()) {
+            let pick = rng.below(candidates.len());
+            let last = candidates.len() - 1;
+            candidates.swap(pick, last);
+        }
+        let (_, id, name, version) = candidates
+            .pop()
+            .ok_or(ResticError::NoMatchingSure)?;
+        progress!("Verify {:?}: restic {} ({})", volume, id, name);
+
+        let sample = sample_files(store.load_iter(version)?, count)?;
+        if sample.is_empty() {
+            return Err(Error::msg("No files found in sure data"));
+        }
+
+        let scratch = ScratchDir::new("verify")?;
+        let mut cmd = heavy_command(RESTIC_BIN);
+        rvol.add_auth(&mut cmd)?;
+        cmd.args(&["restore", &id, "--target"]);
+        cmd.arg(&scratch.0);
+        for (path, _) in &sample {
+            cmd.arg("--include");
+            cmd.arg(Path::new(&rvol.bind).join(path));
+        }
+        cmd.stderr(Stdio::inherit());
+        cmd.checked_run()?;
+
+        let base = scratch.0.join(rvol.bind.trim_start_matches('/'));
+        let failures = compare_sample(volume, &base, &sample);
+        if failures > 0 {
+            return Err(ResticError::VerifyFailed(failures).into());
+        }
+        Ok(())
+    }
+
+    /// Receive the latest snapshot of `name` with rsure data, from its
+    /// `streams`, into a new filesystem under `scratch`, with `receive`, and
+    /// compare `count` randomly chosen files against rsure.  The received
+    /// filesystem is destroyed afterwards.
+    fn check_streams(
+        &self,
+        name: &str,
+        zfs: &str,
+        streams: &[Stream],
+        scratch: &str,
+        count: usize,
+        receive: &dyn Fn(&str, &str) -> Result<()>,
+    ) -> Result<()> {
+        let svol = self
+            .sure
+            .volumes
+            .iter()
+            .find(|v| v.zfs == zfs)
+            .ok_or_else(|| ConfigError::NoVolumeFor {
+                section: "sure",
+                zfs: zfs.to_string(),
+            })?;
+        let store = svol.open_store()?;
+        let versions = store.get_versions()?;
+        let (snap, version) = streams
+            .iter()
+            .rev()
+            .find_map(|s| {
+                let v = versions.iter().find(|v| v.name == s.to)?;
+                Some((s.to.clone(), v.version.clone()))
+            })
+            .ok_or_else(|| StreamError::NoMatchingSure { volume: name.to_string() })?;
+
+        let sample = sample_files(store.load_iter(version)?, count)?;
+        if sample.is_empty() {
+            return Err(Error::msg("No files found in sure data"));
+        }
+
+        let dest = format!("{}/{}", scratch.trim_end_matches('/'), name);
+        progress!("Verify {:?}: receive @{} into {}", name, snap, dest);
+        let mount = ScratchDir::new("verify")?;
+        let result = receive(&dest, &snap).and_then(|()| {
+            let mut set = Command::new("zfs");
+            set.arg("set").arg(format!("mountpoint={}", mount.0.display())).arg(&dest);
+            set.checked_run()?;
+            Command::new("zfs").args(&["mount", &dest]).checked_run()?;
+            Ok(compare_sample(name, &mount.0, &sample))
+        });
+
+        // A failed receive may still have left something behind.
+        let exists = Command::new("zfs")
+            .args(&["list", "-H", "-o", "name", &dest])
+            .stderr(Stdio::null())
+            .run_status()?
+            .success();
+        if exists {
+            Command::new("zfs").args(&["destroy", "-r", &dest]).checked_run()?;
+        }
+
+        let failures = result?;
+        if failures > 0 {
+            return Err(StreamError::VerifyFailed {
+                volume: name.to_string(),
+                failures: failures,
+            }
+            .into());
+        }
+        Ok(())
+    }
+}
+
+/// Compare the files of the sample, restored under `base`, reporting each,
+/// and return how many didn't match.
+fn compare_sample(volume: &str, base: &Path, sample: &[(String, AttMap)]) -> usize {
+    let mut failures = 0;
+    for (path, atts) in sample {
+        match compare_file(&base.join(path), atts) {
+            Ok(()) => progress!("  ok   {}", path),
+            Err(e) => {
+                progress!("  FAIL {}: {}", path, e);
+                failures += 1;
+            }
+        }
+    }
+
+    progress!(
+        "Verify {:?}: {} of {} files matched",
+        volume,
+        sample.len() - failures,
+        sample.len()
+    );
+    failures
+}
+
+/// Walk the sure nodes, choosing up to `count` regular files uniformly at
+/// random.  Returns the path (relative to the root of the snapshot) and the
+/// attributes of each file.
+fn sample_files<I>(nodes: I, count: usize) -> Result<Vec<(String, AttMap)>>
+where
+    I: Iterator<Item = rsure::Result<SureNode>>,
+{
+    let mut rng = Rng::new();
+    let mut dirs: Vec<String> = vec![];
+    let mut seen = 0;
+    let mut result = vec![];
+
+    for node in nodes {
+        match node? {
+            SureNode::Enter { name, .. } => dirs.push(name),
+            SureNode::Leave => {
+                dirs.pop();
+            }
+            SureNode::File { name, atts } => {
+                if atts.get("kind").map(|k| k.as_str()) != Some("file") {
+                    continue;
+                }
+                // The first directory is the root itself.
+                let mut path = dirs[1..].join("/");
+                if !path.is_empty() {
+                    path.push('/');
+                }
+                path.push_str(&name);
+
+                // Reservoir sampling.
+                seen += 1;
+                if result.len() < count {
+                    result.push((path, atts));
+                } else {
+                    let pos = rng.below(seen);
+                    if pos < count {
+                        result[pos] = (path, atts);
+                    }
+                }
+            }
+            SureNode::Sep => (),
+        }
+    }
+
+    Ok(result)
+}
+
+/// Compare a restored file against the attributes recorded by rsure.
+fn compare_file(path: &Path, atts: &AttMap) -> Result<()> {
+    let meta = fs::symlink_metadata(path).context("not restored")?;
+
+    if let Some(size) = atts.get("size") {
+        if meta.len().to_string() != *size {
+            return Err(Error::msg(format!("size {} expected {}", meta.len(), size)));
+        }
+    }
+    if let Some(perm) = atts.get("perm") {
+        let mode = meta.permissions().mode() & 0o7777;
+        if mode.to_string() != *perm {
+            return Err(Error::msg(format!("perm {:o} differs", mode)));
+        }
+    }
+    if let Some(mtime) = atts.get("mtime") {
+        if meta.mtime().to_string() != *mtime {
+            return Err(Error::msg(format!("mtime {} expected {}", meta.mtime(), mtime)));
+        }
+    }
+    if let Some(sha1) = atts.get("sha1") {
+        let hash = sha1sum(path)?;
+        if hash != *sha1 {
+            return Err(Error::msg(format!("sha1 {} expected {}", hash, sha1)));
+        }
+    }
+    Ok(())
+}
+
+/// The sha1 hash of a file's contents, in hex.
+pub fn sha1sum(path: &Path) -> Result<String> {
+    let out = Command::new("sha1sum").arg(path).checked_output()?;
+    let text = String::from_utf8(out.stdout)?;
+    Ok(text.split_whitespace().next().unwrap_or("").to_string())
+}
+
+/// A temporary directory that is removed, with its contents, when dropped.
+pub struct ScratchDir(pub PathBuf);
+
+impl ScratchDir {
+    /// Create a new scratch directory, whose name includes `purpose`.
+    pub fn new(purpose: &str) -> Result<ScratchDir> {
+        let path = env::temp_dir().join(format!("rack-{}-{}", purpose, process::id()));
+        fs::create_dir(&path)?;
+        Ok(ScratchDir(path))
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_dir_all(&self.0) {
+            warning!("Error removing {:?}: {}", self.0, e);
+        }
+    }
+}
+
+/// A small xorshift generator.  This only needs to pick different files on
+/// different runs, not be cryptographically strong.
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new() -> Rng {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time before epoch");
+        Rng(((now.as_nanos() as u64) ^ ((process::id() as u64) << 32)) | 1)
+    }
+
+    pub fn below(&mut self, limit: usize) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 % limit as u64) as usize
+    }
+}
@@ -0,0 +1,146 @@
+//! The hours heavy operations are allowed to run in.
+//!
+//! The clone, restic and borg sections of the config can each be given a
+//! window, such as "01:00-06:00", in local time, which may wrap past
+//! midnight.  `rack auto` defers those steps when it reaches them outside
+//! their window, leaving them to a later run.  A clone can also be paused
+//! when its window closes part way through, by stopping the `pv` in the
+//! middle of its pipeline, which stalls the send and receive on either side
+//! of it, and is continued when the window opens again.  Restic and borg
+//! aren't paused, since a repository lock that isn't refreshed is taken to
+//! be stale by other clients.
+
+use crate::checked::Progress;
+use chrono::{Local, NaiveTime, Timelike};
+use nix::{
+    sys::signal::{kill, Signal},
+    unistd::Pid,
+};
+use std::{
+    fmt,
+    sync::mpsc::{Receiver, RecvTimeoutError},
+    time::Duration,
+};
+
+/// How often a running clone checks whether its window has closed.
+const CHECK_EVERY: Duration = Duration::from_secs(30);
+
+/// A range of times of day, in minutes since midnight.  The start is in the
+/// window, the end isn't.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Window {
+    start: u32,
+    end: u32,
+}
+
+impl Window {
+    /// Parse a window such as "22:30-05:00".  The end can be "24:00".
+    pub fn parse(text: &str) -> Option<Window> {
+        let (start, end) = text.split_once('-')?;
+        let window = Window {
+            start: minutes(start)?,
+            end: minutes(end)?,
+        };
+        if window.start == window.end || window.start >= 24 * 60 {
+            return None;
+        }
+        Some(window)
+    }
+
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        let time = time.hour() * 60 + time.minute();
+        if self.start < self.end {
+            self.start <= time && time < self.end
+        } else {
+            self.start <= time || time < self.end
+        }
+    }
+
+    /// Is the window open now.
+    pub fn is_open(&self) -> bool {
+        self.contains(Local::now().time())
+    }
+}
+
+impl fmt::Display for Window {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (start, end) = (self.start, self.end);
+        write!(f, "{:02}:{:02}-{:02}:{:02}", start / 60, start % 60, end / 60, end % 60)
+    }
+}
+
+fn minutes(text: &str) -> Option<u32> {
+    let (hours, mins) = text.trim().split_once(':')?;
+    let digits = |s: &str| s.len() == 2 && s.bytes().all(|b| b.is_ascii_digit());
+    if !digits(hours) || !digits(mins) {
+        return None;
+    }
+    let (hours, mins): (u32, u32) = (hours.parse().ok()?, mins.parse().ok()?);
+    if mins >= 60 || hours * 60 + mins > 24 * 60 {
+        return None;
+    }
+    Some(hours * 60 + mins)
+}
+
+/// Stop the process `pid` while the window is closed, and continue it when
+/// it opens again, until `done` is dropped.  A window that was closed when
+/// this started, as when a clone is run by hand, has to open before it
+/// pauses anything.  Being paused counts as `progress`, so that a watchdog
+/// doesn't take the clone to have hung.
+pub(crate) fn pause_outside(
+    window: Window,
+    pid: u32,
+    done: Receiver<()>,
+    progress: Option<Progress>,
+) {
+    let pid = Pid::from_raw(pid as i32);
+    let mut seen_open = window.is_open();
+    let mut paused = false;
+    let poke = || progress.iter().for_each(|p| p.poke());
+    while let Err(RecvTimeoutError::Timeout) = done.recv_timeout(CHECK_EVERY) {
+        if paused {
+            poke();
+        }
+        let open = window.is_open();
+        seen_open |= open;
+        let signal = if open && paused {
+            progress!("Window {} has opened, continuing", window);
+            Signal::SIGCONT
+        } else if !open && !paused && seen_open {
+            progress!("Window {} has closed, pausing until it opens", window);
+            Signal::SIGSTOP
+        } else {
+            continue;
+        };
+        match kill(pid, signal) {
+            Ok(()) => {
+                paused = !open;
+                poke();
+            }
+            Err(e) => warning!("Unable to send {} to pv: {}", signal, e),
+        }
+    }
+}
+
+#[test]
+fn test_window() {
+    let at = |h, m| NaiveTime::from_hms_opt(h, m, 0).unwrap();
+
+    let night = Window::parse("22:30-05:00").unwrap();
+    assert_eq!(night.to_string(), "22:30-05:00");
+    assert!(night.contains(at(23, 0)));
+    assert!(night.contains(at(4, 59)));
+    assert!(!night.contains(at(5, 0)));
+    assert!(!night.contains(at(12, 0)));
+
+    let morning = Window::parse("01:00-06:00").unwrap();
+    assert!(morning.contains(at(1, 0)));
+    assert!(!morning.contains(at(0, 59)));
+    assert!(!morning.contains(at(6, 0)));
+    assert!(Window::parse("18:00-24:00").unwrap().contains(at(23, 59)));
+
+    let bad = ["1:00-06:00", "01:00", "01:00-01:00", "24:00-01:00", "01:60-02:00", "aa:00-01:00"];
+    for bad in &bad {
+        assert_eq!(Window::parse(bad), None, "{}", bad);
+    }
+}
@@ -1,19 +1,63 @@
 //! ZFS operations
 
-use chrono::{Datelike, Local, Timelike};
-use failure::{err_msg, format_err};
+use chrono::Local;
 use regex::{self, Regex};
 use serde_derive::Serialize;
 use std::{
-    collections::{BTreeSet, HashMap},
-    fs::File,
-    io::{self, BufRead, BufReader},
-    os::unix::io::{AsRawFd, FromRawFd},
+    cell::OnceCell,
+    collections::{HashMap, HashSet},
+    fmt,
+    io::{BufRead, BufReader},
+    path::Path,
     process::{Command, Stdio},
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::Duration,
 };
 
-use crate::checked::CheckedExt;
-use crate::{RackError, Result};
+use crate::checked::{heavy_command, watch_pipeline, CheckedExt, Watch, Watchdog};
+use crate::config::{CloneVolume, Unmounted};
+use crate::digest;
+use crate::meter::{Meter, Unit};
+use crate::mount;
+use crate::naming::{naming, SnapNaming};
+use crate::plan::Plan;
+use crate::prune::hanoi;
+use crate::sync::MountedDir;
+use crate::window::{pause_outside, Window};
+use crate::{Error, Result};
+use thiserror::Error;
+
+/// Errors from zfs operations.
+#[derive(Debug, Error)]
+pub enum ZfsError {
+    #[error("not mounted: {fs:?}")]
+    NotMounted { fs: String },
+    #[error("no zfs filesystem {fs:?}")]
+    NotFound { fs: String },
+    #[error("{fs:?} is a zfs volume, which can't be mounted")]
+    NotFilesystem { fs: String },
+    #[error("{fs:?} has no snapshots")]
+    NoSnapshots { fs: String },
+    #[error("last snapshot of {dest:?} is not present in {src:?}")]
+    Diverged { src: String, dest: String },
+    #[error(
+        "Not enough space on {dest:?}: need {}, only {} available",
+        humanize_size(*.needed).trim(),
+        humanize_size(*.available).trim()
+    )]
+    NoSpace {
+        dest: String,
+        needed: usize,
+        available: usize,
+    },
+    #[error("{0} failed")]
+    Stream(&'static str),
+    #[error("lost the connection to {host}")]
+    Connection { host: String },
+    #[error("unexpected output from zfs: {0}")]
+    BadOutput(String),
+}
 
 #[derive(Debug)]
 pub struct Zfs {
@@ -21,60 +65,353 @@ pub struct Zfs {
     /// in independent snapshots.
     pub prefix: String,
     /// The filesystems found on the system.
-    pub filesystems: Vec<Filesystem>,
-    /// A re to match snapshot names.
-    snap_re: Regex,
+    pub filesystems: Arc<Vec<Filesystem>>,
+    /// Where the filesystems came from, to be invalidated after changing them.
+    inventory: Inventory,
+    /// How snapshots with the prefix are named.
+    naming: SnapNaming,
+    /// The host the filesystems are on, over ssh, if they aren't local.
+    host: Option<String>,
+    /// How clones are run.
+    pub options: CloneOptions,
+}
+
+/// How a clone is held back, so that it doesn't get in the way of other work, how long it can
+/// stall, what becomes of a receive that fails part way through, and where each filesystem is
+/// received.
+#[derive(Clone, Debug, Default)]
+pub struct CloneOptions {
+    /// The most bytes per second to receive, as understood by `pv -L`.
+    pub rate_limit: Option<String>,
+    /// Pause while this window is closed.
+    pub pause: Option<Window>,
+    /// Kill the clone once it has sent nothing for this long.
+    pub idle_timeout: Option<Duration>,
+    /// Keep what a failed receive got, for a later clone to resume, rather than aborting it.
+    pub keep_partial: bool,
+    /// Resume the receives kept by earlier clones, before cloning.
+    pub resume: bool,
+    /// Filesystems to receive under another name, as pairs of names relative to the source and
+    /// the destination, such as ("home", "homes").  Those under each are renamed along with it.
+    pub renames: Vec<(String, String)>,
+    /// More arguments to `zfs receive`, overriding properties of the source.
+    pub recv_args: Vec<String>,
+    /// Properties to give the filesystems created for fresh clones, in place of the source's.
+    pub create_set: Vec<(String, String)>,
+    /// Properties of the source not to give the filesystems created for fresh clones.
+    pub create_skip: Vec<String>,
+}
+
+impl CloneOptions {
+    /// Whether the receives kept in the destination are resumed before cloning: when asked to,
+    /// and whenever failed receives are kept, since nothing else can be received into a volume
+    /// until its partial receive is finished.
+    pub fn resumes(&self) -> bool {
+        self.resume || self.keep_partial
+    }
+}
+
+/// A shared list of the zfs filesystems and their snapshots.  Listing every snapshot can take a
+/// while on a system with thousands of them, so the list is read the first time it is needed, and
+/// kept until it is invalidated, which must be done after anything that adds or removes
+/// snapshots.  Clones of an inventory share the same list.
+#[derive(Clone, Debug, Default)]
+pub struct Inventory {
+    list: Arc<Mutex<Option<Arc<Vec<Filesystem>>>>>,
+    host: Option<String>,
+    /// The dataset whose subtree is listed, rather than every dataset.
+    root: Option<String>,
+}
+
+impl Inventory {
+    pub fn new() -> Inventory {
+        Inventory::default()
+    }
+
+    /// The inventory of the filesystems on another host, which zfs is run on
+    /// over ssh.
+    pub fn remote(host: &str) -> Inventory {
+        Inventory {
+            list: Arc::default(),
+            host: Some(host.to_string()),
+            root: None,
+        }
+    }
+
+    /// Only list `root`, and the datasets under it, for a caller that needs
+    /// nothing else.
+    pub fn within(self, root: &str) -> Inventory {
+        Inventory {
+            list: Arc::default(),
+            host: self.host,
+            root: Some(root.to_string()),
+        }
+    }
+
+    /// The filesystems, reading them from zfs if they aren't known.
+    pub fn filesystems(&self) -> Result<Arc<Vec<Filesystem>>> {
+        let mut current = self.list.lock().unwrap();
+        if let Some(ref fss) = *current {
+            return Ok(fss.clone());
+        }
+        let fss = Arc::new(list_filesystems(self.host.as_deref(), self.root.as_deref())?);
+        *current = Some(fss.clone());
+        Ok(fss)
+    }
+
+    /// Forget the filesystems, so that they are read again when next needed.
+    pub fn invalidate(&self) {
+        *self.list.lock().unwrap() = None;
+    }
+}
+
+/// A zfs command, run on `host` over ssh if one is given.  Heavy commands are run at the
+/// configured priority, which, for a remote one, is that of the ssh carrying its output.
+pub fn zfs_command(host: Option<&str>, heavy: bool) -> Command {
+    let program = if host.is_some() { "ssh" } else { "zfs" };
+    let mut cmd = if heavy {
+        heavy_command(program)
+    } else {
+        Command::new(program)
+    };
+    if let Some(host) = host {
+        cmd.arg(host).arg("zfs");
+    }
+    cmd
+}
+
+/// The properties listed for each dataset.  The mountpoint is last, as it is the only one that
+/// could contain a tab.
+const LIST_FIELDS: &str = "name,type,mounted,userrefs,clones,used,referenced,creation,mountpoint";
+
+/// Ask ZFS what all of the Filesystems are that it knows about, or just those under `root`.  Just
+/// get the names, types, mount information, and what keeps snapshots from being destroyed (which
+/// will include all snapshots and bookmarks).  Order of the volumes seems to mostly be
+/// lexicographically, at least in some kind of tree order.  The snapshots come out in the order
+/// they were created.  The output is parsed as it is read, since it can be large.
+fn list_filesystems(host: Option<&str>, root: Option<&str>) -> Result<Vec<Filesystem>> {
+    let mut cmd = zfs_command(host, false);
+    cmd.args(&["list", "-Hp", "-t", "all", "-o", LIST_FIELDS]);
+    if let Some(root) = root {
+        cmd.args(&["-r", root]);
+    }
+    cmd.stderr(Stdio::inherit());
+
+    let mut builder = SnapBuilder::new();
+    cmd.checked_stream(&mut |line| parse_list_line(&mut builder, line))?;
+    Ok(builder.into_sets())
+}
+
+/// Add a line of `zfs list` output to what has been found.
+fn parse_list_line(builder: &mut SnapBuilder, line: &str) -> Result<()> {
+    let fields: Vec<_> = line.splitn(9, '\t').collect();
+    if fields.len() != 9 {
+        let msg = format!("zfs line doesn't have nine fields: {:?}", line);
+        return Err(ZfsError::BadOutput(msg).into());
+    }
+    // fields[0] is the name, fields[1] the type, fields[2] whether it is mounted, fields[3] the
+    // number of holds, and fields[4] the clones of a snapshot.  fields[5] to [7] are the space
+    // used, and when it was created, and fields[8] is the mountpoint.
+    let mount_state = match (fields[1], fields[2], fields[8]) {
+        ("filesystem", "yes", _) => MountState::Mounted,
+        ("filesystem", _, "legacy") => MountState::Legacy,
+        ("filesystem", _, "none") | (_, _, "-") => MountState::NoMountpoint,
+        _ => MountState::Unmounted,
+    };
+    let split = |sep| match fields[0].find(sep) {
+        Some(pos) => Ok((&fields[0][..pos], &fields[0][pos + 1..])),
+        None => {
+            let msg = format!("zfs {} without {:?}: {:?}", fields[1], sep, fields[0]);
+            Err(ZfsError::BadOutput(msg))
+        }
+    };
+    match fields[1] {
+        "filesystem" => builder.push_volume(fields[0], DatasetKind::Filesystem, mount_state),
+        "volume" => builder.push_volume(fields[0], DatasetKind::Volume, mount_state),
+        "snapshot" => {
+            let (name, snap) = split('@')?;
+            let space = SnapSpace {
+                used: fields[5].parse().unwrap_or(0),
+                referenced: fields[6].parse().unwrap_or(0),
+                creation: fields[7].parse().unwrap_or(0),
+            };
+            builder.push_snap(name, snap, pins(fields[3], fields[4]), space);
+        }
+        "bookmark" => {
+            let (name, mark) = split('#')?;
+            builder.push_bookmark(name, mark);
+        }
+        kind => {
+            let msg = format!("unknown zfs type {:?} of {:?}", kind, fields[0]);
+            return Err(ZfsError::BadOutput(msg).into());
+        }
+    }
+    Ok(())
+}
+
+/// Decode the holds and clones of a snapshot.
+fn pins(userrefs: &str, clones: &str) -> Vec<Pin> {
+    let mut pins = vec![];
+    match userrefs.parse::<usize>() {
+        Ok(0) | Err(_) => (),
+        Ok(count) => pins.push(Pin::Holds(count)),
+    }
+    if clones != "-" && !clones.is_empty() {
+        pins.push(Pin::Clones(clones.split(',').map(|c| c.to_string()).collect()));
+    }
+    pins
 }
 
 #[derive(Debug, Serialize)]
 pub struct Filesystem {
     pub name: String,
+    pub kind: DatasetKind,
     pub snaps: Vec<String>,
-    pub mount: String,
+    /// The bookmarks of this dataset, which are left behind by pruning.
+    pub bookmarks: Vec<String>,
+    pub mount_state: MountState,
+    /// The snapshots that can't be destroyed, and why.
+    pub pins: HashMap<String, Vec<Pin>>,
+    /// The space used by each snapshot.
+    pub space: HashMap<String, SnapSpace>,
 }
 
-impl Zfs {
-    /// Construct a new Zfs retrieving all of the filesystems that are found on this system.
-    pub fn new(prefix: &str) -> Result<Zfs> {
-        let quoted = regex::escape(prefix);
-        let pat = format!("^{}(\\d{{4}})-([-\\d]+)$", quoted);
-        let re = Regex::new(&pat)?;
-
-        // Ask ZFS what all of the Filesystems are that it knows about.  Just get the names and
-        // mountpoints (which will include all snapshots).  Order of the volumes seems to mostly be
-        // lexicographically, at least in some kind of tree order.  The snapshots come out in the
-        // order they were created.
-        let out = Command::new("zfs")
-            .args(&["list", "-H", "-t", "all", "-o", "name,mountpoint"])
-            .stderr(Stdio::inherit())
-            .checked_output()?;
-        let buf = out.stdout;
+/// The space taken by a snapshot, in bytes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct SnapSpace {
+    /// The space only this snapshot holds, which destroying it would free.
+    pub used: u64,
+    /// All of the data the snapshot refers to, shared or not.
+    pub referenced: u64,
+    /// When the snapshot was created, in seconds since the epoch.
+    pub creation: i64,
+}
+
+/// Something that keeps a snapshot from being destroyed.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Pin {
+    /// It is held, by `zfs hold`, this many times.
+    Holds(usize),
+    /// It is the origin of these clones.
+    Clones(Vec<String>),
+}
+
+impl fmt::Display for Pin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Pin::Holds(1) => write!(f, "has a hold"),
+            Pin::Holds(count) => write!(f, "has {} holds", count),
+            Pin::Clones(clones) => write!(f, "has clones {}", clones.join(", ")),
+        }
+    }
+}
 
-        let mut builder = SnapBuilder::new();
+/// Whether a dataset is mounted, and if not, why.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MountState {
+    /// Mounted, either by zfs, or, for a legacy mountpoint, from fstab.
+    Mounted,
+    /// Not mounted, though zfs could mount it at its mountpoint.
+    Unmounted,
+    /// Has a legacy mountpoint, and isn't mounted.
+    Legacy,
+    /// Has no mountpoint, or is a zvol.
+    NoMountpoint,
+}
+
+impl Filesystem {
+    /// Why a snapshot can't be destroyed, if it can't.
+    pub fn pinned(&self, snap: &str) -> Option<String> {
+        let pins = self.pins.get(snap)?;
+        let why: Vec<_> = pins.iter().map(|p| p.to_string()).collect();
+        Some(why.join(", "))
+    }
 
-        for line in BufReader::new(&buf[..]).lines() {
-            let line = line?;
-            let fields: Vec<_> = line.splitn(2, '\t').collect();
-            if fields.len() != 2 {
-                return Err(format_err!("zfs line doesn't have two fields: {:?}", line));
+    /// Can the snapshots of this filesystem be read?  If it isn't mounted, they can be mounted
+    /// directly, or the filesystem skipped, as `unmounted` says.
+    pub fn readable(&self, unmounted: Unmounted) -> Result<bool> {
+        match (self.mount_state, unmounted) {
+            (MountState::Mounted, _) | (_, Unmounted::Mount) => Ok(true),
+            (state, Unmounted::Skip) => {
+                decision!("Skipping {:?}, which isn't mounted ({:?})", self.name, state);
+                Ok(false)
             }
-            // fields[0] is now the volume/snap name, and fields[1] is the mountpoint.
-            let vols: Vec<_> = fields[0].splitn(2, '@').collect();
-            match vols.len() {
-                1 => builder.push_volume(vols[0], fields[1]),
-                2 => builder.push_snap(vols[0], vols[1]),
-                _ => panic!("Unexpected zfs output"),
+            (_, Unmounted::Fail) => Err(ZfsError::NotMounted { fs: self.name.clone() }.into()),
+        }
+    }
+
+    /// Mount a snapshot of this filesystem on `to`, until the result is dropped.  The snapshots
+    /// of a mounted filesystem are found under its `.zfs` directory, and others are mounted
+    /// directly.
+    pub fn mount_snapshot<'a>(&self, snap: &str, to: &'a Path) -> Result<MountedDir<'a>> {
+        // Neither where ZFS thinks things are mounted, nor whether it thinks they are, is always
+        // right, instead find out where Linux has it mounted.
+        let mount = match mount::zfs_mountpoint(&self.name)? {
+            Some(mount) => mount,
+            None => {
+                progress!("Mount {}@{} on {:?}", self.name, snap, to);
+                return MountedDir::zfs(&format!("{}@{}", self.name, snap), to);
             }
+        };
+
+        // Zfs snapshots seem to not mount until something inside is read.  It seems sufficient
+        // to stat "." in the root (but not the root directory itself).
+        let dest = Path::new(&mount).join(".zfs").join("snapshot").join(snap);
+        if !std::fs::metadata(dest.join("."))?.is_dir() {
+            return Err(Error::msg(format!("Snapshot is not a directory: {:?}", dest)));
         }
-        let result = builder.into_sets();
+        progress!("Bind mount: {:?} from {:?}", dest, to);
+        MountedDir::new(&dest, to)
+    }
+}
 
+/// The kinds of dataset that snapshots are taken of.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DatasetKind {
+    Filesystem,
+    /// A zvol, which is a block device, rather than files.
+    Volume,
+}
+
+impl Zfs {
+    /// Construct a new Zfs retrieving all of the filesystems that are found on this system.
+    pub fn new(prefix: &str) -> Result<Zfs> {
+        Zfs::from_inventory(prefix, &Inventory::new())
+    }
+
+    /// Construct a new Zfs with the filesystems from a shared inventory.
+    pub fn from_inventory(prefix: &str, inventory: &Inventory) -> Result<Zfs> {
         Ok(Zfs {
             prefix: prefix.to_string(),
-            filesystems: result,
-            snap_re: re,
+            filesystems: inventory.filesystems()?,
+            inventory: inventory.clone(),
+            naming: naming(prefix),
+            host: inventory.host.clone(),
+            options: CloneOptions::default(),
         })
     }
 
+    /// Find the named filesystem.
+    pub fn find(&self, name: &str) -> Result<&Filesystem> {
+        self.filesystems
+            .iter()
+            .find(|fs| fs.name == name)
+            .ok_or_else(|| ZfsError::NotFound { fs: name.to_string() }.into())
+    }
+
+    /// Find the named filesystem, which must be one that can be mounted, for backups that read
+    /// files from its snapshots.
+    pub fn find_filesystem(&self, name: &str) -> Result<&Filesystem> {
+        let fs = self.find(name)?;
+        if fs.kind != DatasetKind::Filesystem {
+            return Err(ZfsError::NotFilesystem { fs: name.to_string() }.into());
+        }
+        Ok(fs)
+    }
+
     /// Determine the next snapshot number to use, under a given prefix.  The prefix should be a
     /// filesystem name (possibly top level) without a trailing slash.  All filesystems at this
     /// point and under will be considered when looking for volumes.
@@ -83,8 +420,7 @@ impl Zfs {
 
         for fs in self.filtered(under)? {
             for snap in &fs.snaps {
-                if let Some(caps) = self.snap_re.captures(snap) {
-                    let num = caps.get(1).unwrap().as_str().parse::<usize>().unwrap();
+                if let Some(num) = self.snap_number(snap) {
                     if num + 1 > next {
                         next = num + 1;
                     }
@@ -98,9 +434,7 @@ impl Zfs {
     /// Given a snapshot name, return the number of that snapshot, if it matches the pattern,
     /// otherwise None.
     fn snap_number(&self, text: &str) -> Option<usize> {
-        self.snap_re
-            .captures(text)
-            .map(|caps| caps.get(1).unwrap().as_str().parse::<usize>().unwrap())
+        self.naming.parse(&self.prefix, text).and_then(|name| name.index)
     }
 
     /// Return the filtered subset of the filesystems under a given prefix.  Collected into a
@@ -117,54 +451,126 @@ impl Zfs {
 
     /// Generate a snapshot name of the given index, and the current time.
     pub fn snap_name(&self, index: usize) -> String {
-        let now = Local::now();
-        let name = format!(
-            "{}{:04}-{:04}{:02}{:02}{:02}{:02}",
-            self.prefix,
-            index,
-            now.year(),
-            now.month(),
-            now.day(),
-            now.hour(),
-            now.minute()
-        );
-        name
+        self.naming.name(&self.prefix, Some(index), Local::now().naive_local())
     }
 
     /// Make a new snapshot of the given index on the given filesystem name.  The snapshot itself
     /// will be made recursively.
     pub fn take_snapshot(&self, fs: &str, index: usize) -> Result<()> {
         let name = format!("{}@{}", fs, self.snap_name(index));
-        println!("Make snapshot: {}", name);
+        progress!("Make snapshot: {}", name);
         Command::new("zfs")
             .args(&["snapshot", "-r", &name])
             .stderr(Stdio::inherit())
             .checked_run()?;
+        self.inventory.invalidate();
         Ok(())
     }
 
-    /// Make a new snapshot, of a given name.
-    pub fn take_named_snapshot(&self, fs: &str, name: &str) -> Result<()> {
+    /// Plan a new snapshot, of a given name.
+    pub fn plan_named_snapshot(&self, fs: &str, name: &str, reason: String, plan: &mut Plan) {
         let name = format!("{}@{}", fs, name);
-        Command::new("zfs")
-            .args(&["snapshot", &name])
+        plan.run(reason, Command::new("zfs").args(&["snapshot", &name]));
+    }
+
+    /// The newest snapshot of `fs` made by `convention`, if nothing has been written to the
+    /// filesystem since it was taken.  Snapshots made otherwise, such as by hand, don't count.
+    pub fn unchanged(&self, fs: &str, convention: &str) -> Result<Option<&str>> {
+        let mut snaps = self.find(fs)?.snaps.iter().rev();
+        let last = match snaps.find(|s| crate::naming::parse(convention, s).is_some()) {
+            Some(last) => last,
+            None => return Ok(None),
+        };
+        let out = zfs_command(self.host.as_deref(), false)
+            .args(&["get", "-H", "-p", "-o", "value", &format!("written@{}", last), fs])
             .stderr(Stdio::inherit())
-            .checked_run()?;
+            .checked_output()?;
+        let text = String::from_utf8(out.stdout)?;
+        let written: u64 = text.trim().parse().map_err(|_| {
+            ZfsError::BadOutput(format!("Invalid written@{} of {}: {:?}", last, fs, text))
+        })?;
+        Ok(if written == 0 { Some(last) } else { None })
+    }
+
+    /// Plan the renaming of numbered snapshots under a given filesystem whose numbers aren't
+    /// written with the current padding, such as after the padding has been widened to make room
+    /// for more snapshots.
+    pub fn plan_renumber(&self, under: &str, plan: &mut Plan) -> Result<()> {
+        for fs in self.filtered(under)? {
+            self.plan_renumber_fs(fs, plan);
+        }
+        Ok(())
+    }
+
+    /// Plan the same renames as `plan_renumber` of `under` on the filesystems `vol` clones them
+    /// to, so that the clones keep the names of the snapshots they are sent from.  Those already
+    /// under `under` are renamed with it.
+    pub fn plan_renumber_clone(
+        &self,
+        under: &str,
+        vol: &CloneVolume,
+        plan: &mut Plan,
+    ) -> Result<()> {
+        let renumbered: Vec<&str> = self.filtered(under)?.iter().map(|f| f.name.as_str()).collect();
+        let renames: Vec<_> = vol.renames.clone().into_iter().collect();
+        for src in self.filtered(&vol.source)? {
+            if !renumbered.contains(&src.name.as_str()) {
+                continue;
+            }
+            let name = format!("{}{}", vol.dest, renamed(&renames, &src.name[vol.source.len()..]));
+            if renumbered.contains(&name.as_str()) {
+                continue;
+            }
+            if let Some(dest) = self.filesystems.iter().find(|f| f.name == name) {
+                self.plan_renumber_fs(dest, plan);
+            }
+        }
         Ok(())
     }
 
+    fn plan_renumber_fs(&self, fs: &Filesystem, plan: &mut Plan) {
+        for snap in &fs.snaps {
+            let new = match self.naming.renumber(&self.prefix, snap) {
+                Some(new) => new,
+                None => continue,
+            };
+            if fs.snaps.contains(&new) {
+                warning!("Not renaming {}@{}: {} already exists", fs.name, snap, new);
+                continue;
+            }
+            let old = format!("{}@{}", fs.name, snap);
+            let new = format!("{}@{}", fs.name, new);
+            let reason = format!("Renumber {} to {}", old, new);
+            plan.run(reason, Command::new("zfs").args(&["rename", &old, &new]));
+        }
+    }
+
     /// Clone one volume tree to another.  Perform should be set to true to
     /// actually do the clones, otherwise it just prints what it would do.
     pub fn clone(&self, source: &str, dest: &str, perform: bool, excludes: &[&str]) -> Result<()> {
+        self.clone_from(self, source, dest, perform, excludes)
+    }
+
+    /// Clone a volume tree of `from`, which may be on another host, to one of these filesystems.
+    /// The sends are run where the source is, and the receives here.
+    pub fn clone_from(
+        &self,
+        from: &Zfs,
+        source: &str,
+        dest: &str,
+        perform: bool,
+        excludes: &[&str],
+    ) -> Result<()> {
         let excludes = Exclusions::new(excludes)?;
 
         // Get filtered views of the source and destination filesystems under the given trees.
-        let source_fs = self.filtered(source)?;
+        let source_fs = from.filtered(source)?;
         let dest_fs = self.filtered(dest)?;
 
         // Make a mapping between the suffixes of the names (including the empty string for one
         // that exactly matches `dest`.  This should be safe as long as `.filtered()` above
-        // always returns ones with this string as a prefix.
+        // always returns ones with this string as a prefix.  The suffixes of the source are
+        // renamed to find their destination.
         let dest_map: HashMap<&str, &Filesystem> = dest_fs
             .iter()
             .map(|&d| (&d.name[dest.len()..], d))
@@ -181,45 +587,44 @@ impl Zfs {
                 continue;
             }
 
-            match dest_map.get(&src.name[source.len()..]) {
+            let suffix = renamed(&self.options.renames, &src.name[source.len()..]);
+            match dest_map.get(suffix.as_str()) {
                 Some(d) => {
-                    println!("Clone existing: {:?} to {:?}", src.name, d.name);
-                    self.clone_one(src, d, perform)?;
+                    progress!("Clone existing: {:?} to {:?}", src.name, d.name);
+                    self.clone_one(from, src, d, perform)?;
                     if !perform {
-                        println!("Clone from:");
-                        serde_yaml::to_writer(io::stdout().lock(), src)?;
-                        println!("");
-                        println!("Clone to:");
-                        serde_yaml::to_writer(io::stdout().lock(), d)?;
-                        println!("");
+                        decision!(
+                            "Clone from:\n{}\nClone to:\n{}",
+                            serde_yaml::to_string(src)?,
+                            serde_yaml::to_string(d)?
+                        );
                     }
                 }
                 None => {
-                    println!(
-                        "Clone fresh: {:?} {:?}+{:?}",
-                        src.name,
-                        dest,
-                        &src.name[source.len()..]
-                    );
-
-                    // Construct the new volume.
+                    progress!("Clone fresh: {:?} {:?}+{:?}", src.name, dest, suffix);
+
+                    // Construct the new volume.  A zvol is left for the receive to create, as its
+                    // size comes with it.
                     let destfs = Filesystem {
-                        name: format!("{}{}", dest, &src.name[source.len()..]),
+                        name: format!("{}{}", dest, suffix),
+                        kind: src.kind,
                         snaps: vec![],
-                        mount: "*INVALID*".into(),
+                        bookmarks: vec![],
+                        mount_state: MountState::NoMountpoint,
+                        pins: HashMap::new(),
+                        space: HashMap::new(),
                     };
 
-                    if perform {
-                        self.make_volume(src, &destfs)?;
+                    if perform && src.kind == DatasetKind::Filesystem {
+                        self.make_volume(from, src, &destfs)?;
                     }
-                    self.clone_one(src, &destfs, perform)?;
+                    self.clone_one(from, src, &destfs, perform)?;
                     if !perform {
-                        println!("Clone from:");
-                        serde_yaml::to_writer(io::stdout().lock(), src)?;
-                        println!("");
-                        println!("Clone to:");
-                        serde_yaml::to_writer(io::stdout().lock(), &destfs)?;
-                        println!("");
+                        decision!(
+                            "Clone from:\n{}\nClone to:\n{}",
+                            serde_yaml::to_string(src)?,
+                            serde_yaml::to_string(&destfs)?
+                        );
                     }
                 }
             }
@@ -228,34 +633,147 @@ impl Zfs {
         Ok(())
     }
 
+    /// Plan destroying the filesystems under the destination of `vol` that nothing under its
+    /// source, of `from`, is cloned to, such as after the source was destroyed.  Since such a
+    /// filesystem holds the only copy of its snapshots, it is only destroyed if it, or one above
+    /// it, is listed in the volume's `delete_orphans`, and even then, is kept if any of its
+    /// snapshots are held, or it has a snapshot made after `before`, in seconds since the epoch.
+    /// Those with anything kept under them are kept too, as is everything under `nested`, the
+    /// destinations of other clone volumes.
+    pub fn plan_orphans(
+        &self,
+        from: &Zfs,
+        vol: &CloneVolume,
+        nested: &[&str],
+        before: i64,
+        plan: &mut Plan,
+    ) -> Result<()> {
+        let (source, dest) = (vol.source.as_str(), vol.dest.as_str());
+        let source_fs = from.filtered(source)?;
+        if !source_fs.iter().any(|s| s.name == source) {
+            // Without the source, everything would look orphaned.
+            return Err(ZfsError::NotFound { fs: source.to_owned() }.into());
+        }
+        let wanted: HashSet<String> = source_fs
+            .iter()
+            .map(|s| renamed(&self.options.renames, &s.name[source.len()..]))
+            .collect();
+        let within = |name: &str, top: &str| {
+            name == top || name.starts_with(&format!("{}/", top))
+        };
+        let opted = |name: &str| {
+            vol.delete_orphans.iter().any(|d| within(name, &format!("{}/{}", dest, d)))
+        };
+
+        // Children come after their parents, so are looked at first.
+        let mut kept: Vec<&str> = vec![];
+        let mut doomed: Vec<&str> = vec![];
+        for d in self.filtered(dest)?.into_iter().rev() {
+            let suffix = &d.name[dest.len()..];
+            if suffix.is_empty() || wanted.contains(suffix) {
+                kept.push(&d.name);
+                continue;
+            }
+
+            let child = kept.iter().find(|k| k.starts_with(&format!("{}/", d.name)));
+            let pinned = d.snaps.iter().find_map(|s| d.pinned(s).map(|why| (s, why)));
+            let newest = d.snaps.iter().filter_map(|s| d.space.get(s)).map(|s| s.creation).max();
+            if let Some(other) = nested.iter().find(|n| within(&d.name, n)) {
+                decision!("Keeping {}, which another clone volume receives into {}", d.name, other);
+            } else if let Some(child) = child {
+                decision!("Keeping {}, whose source is gone, for {} under it", d.name, child);
+            } else if !opted(&d.name) {
+                decision!("Keeping {}, whose source is gone, not in delete_orphans", d.name);
+            } else if let Some((snap, why)) = pinned {
+                decision!("Keeping {}, whose source is gone: {}@{} {}", d.name, d.name, snap, why);
+            } else if newest.map_or(false, |t| t > before) {
+                decision!("Keeping {}, whose source is gone, but has recent snapshots", d.name);
+            } else {
+                doomed.push(&d.name);
+                continue;
+            }
+            kept.push(&d.name);
+        }
+
+        // Destroying a filesystem takes everything under it along.
+        for name in &doomed {
+            if doomed.iter().any(|other| name.starts_with(&format!("{}/", other))) {
+                continue;
+            }
+            let reason = format!("Destroy {}, as nothing in {} maps to it", name, source);
+            plan.run(reason, Command::new("zfs").args(&["destroy", "-r", name]));
+        }
+        Ok(())
+    }
+
+    /// The filesystem under `dest` that `name`, under `source`, is cloned to.
+    pub fn cloned_name(&self, source: &str, dest: &str, name: &str) -> String {
+        format!("{}{}", dest, renamed(&self.options.renames, &name[source.len()..]))
+    }
+
+    /// Check that the snapshots cloned from `source`, of `from`, into `dest` are the ones they
+    /// were sent from, by their GUIDs, and that each filesystem has the latest snapshot of its
+    /// source.  Returns what is wrong.
+    pub fn check_clone(&self, from: &Zfs, source: &str, dest: &str) -> Result<Vec<String>> {
+        let mut problems = vec![];
+        for src in from.filtered(source)? {
+            let latest = match src.snaps.last() {
+                Some(latest) => latest,
+                None => continue,
+            };
+            let name = self.cloned_name(source, dest, &src.name);
+            if !self.filesystems.iter().any(|fs| fs.name == name) {
+                problems.push(format!("{} hasn't been cloned to {}", src.name, name));
+                continue;
+            }
+            let theirs = snapshot_guids(from.host.as_deref(), &src.name)?;
+            let ours = snapshot_guids(self.host.as_deref(), &name)?;
+            problems.extend(compare_guids(&src.name, &name, latest, &theirs, &ours));
+        }
+        Ok(problems)
+    }
+
     /// Clone a single filesystem to an existing volume.  We assume there are no snapshots on the
     /// destination that aren't on the source (otherwise it isn't possible to do the clone).
-    fn clone_one(&self, source: &Filesystem, dest: &Filesystem, perform: bool) -> Result<()> {
+    fn clone_one(
+        &self,
+        from: &Zfs,
+        source: &Filesystem,
+        dest: &Filesystem,
+        perform: bool,
+    ) -> Result<()> {
         if let Some(ssnap) = dest.snaps.last() {
             if !source.snaps.contains(ssnap) {
-                return Err(err_msg("Last dest snapshot not present in source"));
+                return Err(ZfsError::Diverged {
+                    src: source.name.clone(),
+                    dest: dest.name.clone(),
+                }
+                .into());
             }
             let dsnap = if let Some(dsnap) = source.snaps.last() {
                 dsnap
             } else {
-                return Err(err_msg("Source volume has no snapshots"));
+                return Err(ZfsError::NoSnapshots {
+                    fs: source.name.clone(),
+                }
+                .into());
             };
 
             if dsnap == ssnap {
-                println!("Destination is up to date");
+                progress!("Destination is up to date");
                 return Ok(());
             }
 
-            println!(
+            progress!(
                 "Clone from {}@{} to {}@{}",
                 source.name, ssnap, dest.name, dsnap
             );
 
-            let size = self.estimate_size(&source.name, Some(ssnap), dsnap)?;
-            println!("Estimate: {}", humanize_size(size));
+            let size = from.estimate_size(&source.name, Some(ssnap), dsnap)?;
+            progress!("Estimate: {}", humanize_size(size));
 
             if perform {
-                self.do_clone(&source.name, &dest.name, Some(ssnap), dsnap, size)?;
+                self.do_clone(from, &source.name, &dest.name, Some(ssnap), dsnap, size)?;
             }
 
             Ok(())
@@ -265,14 +783,19 @@ impl Zfs {
             let dsnap = if let Some(dsnap) = source.snaps.first() {
                 dsnap
             } else {
-                return Err(err_msg("Source volume has no snapshots"));
+                return Err(ZfsError::NoSnapshots {
+                    fs: source.name.clone(),
+                }
+                .into());
             };
 
-            println!("Full clone from {}@{} to {}", source.name, dsnap, dest.name);
+            progress!("Full clone from {}@{} to {}", source.name, dsnap, dest.name);
 
-            let size = self.estimate_size(&source.name, None, dsnap)?;
-            println!("Estimate: {}", humanize_size(size));
-            self.do_clone(&source.name, &dest.name, None, dsnap, size)?;
+            let size = from.estimate_size(&source.name, None, dsnap)?;
+            progress!("Estimate: {}", humanize_size(size));
+            if perform {
+                self.do_clone(from, &source.name, &dest.name, None, dsnap, size)?;
+            }
 
             // Run the clone on the rest of the image.
             let ssnap = dsnap;
@@ -280,9 +803,9 @@ impl Zfs {
 
             // If there are more snapshots to make, clone the rest.
             if ssnap != dsnap {
-                let size = self.estimate_size(&source.name, Some(ssnap), dsnap)?;
+                let size = from.estimate_size(&source.name, Some(ssnap), dsnap)?;
                 if perform {
-                    self.do_clone(&source.name, &dest.name, Some(ssnap), dsnap, size)?;
+                    self.do_clone(from, &source.name, &dest.name, Some(ssnap), dsnap, size)?;
                 }
             }
 
@@ -293,90 +816,180 @@ impl Zfs {
     /// Use zfs send to estimate the size of this incremental backup.  If the source snap is none,
     /// operate as a full clone.
     fn estimate_size(&self, source: &str, ssnap: Option<&str>, dsnap: &str) -> Result<usize> {
-        let mut cmd = Command::new("zfs");
-        cmd.arg("send");
-        cmd.arg("-nP");
-        if let Some(ssnap) = ssnap {
-            cmd.arg("-I");
-            cmd.arg(&format!("@{}", ssnap));
-        }
-        cmd.arg(&format!("{}@{}", source, dsnap));
-        cmd.stderr(Stdio::inherit());
-        let out = cmd.checked_output()?;
-
-        let buf = out.stdout;
-        for line in BufReader::new(&buf[..]).lines() {
-            let line = line?;
-            let fields: Vec<_> = line.split('\t').collect();
-            if fields.len() < 2 {
-                return Err(format_err!(
-                    "Invalid line from zfs send size estimate: {:?}",
-                    line
-                ));
-            }
-            if fields[0] != "size" {
-                continue;
-            }
-
-            return Ok(fields[1].parse().unwrap());
-        }
-
-        Ok(0)
+        estimate_size_on(self.host.as_deref(), &send_args(source, ssnap, dsnap))
     }
 
-    /// Perform the actual clone.
+    /// Perform the actual clone, sending from `from`.
     fn do_clone(
         &self,
+        from: &Zfs,
         source: &str,
         dest: &str,
         ssnap: Option<&str>,
         dsnap: &str,
         size: usize,
     ) -> Result<()> {
-        // Construct a pipeline from zfs -> pv -> zfs.  PV is used to monitor the progress.
-        let mut cmd = Command::new("zfs");
-        cmd.arg("send");
-        if let Some(ssnap) = ssnap {
-            cmd.arg("-I");
-            cmd.arg(&format!("@{}", ssnap));
+        // A fresh zvol is only created by the receive, so until then, the room for it is that of
+        // the dataset above it.
+        let known = self.filesystems.iter().any(|fs| fs.name == dest);
+        let space = match dest.rsplit_once('/') {
+            Some((parent, _)) if !known => parent,
+            _ => dest,
+        };
+        check_space(space, size)?;
+        self.receive(from, &send_args(source, ssnap, dsnap), dest, size)
+    }
+
+    /// Resume the receives into `dest`, and the volumes under it, that earlier clones kept when
+    /// they failed part way through.  The sends are run on `from`, which must be where they came
+    /// from.
+    pub fn resume(&self, from: &Zfs, dest: &str, perform: bool) -> Result<()> {
+        for (name, token) in resume_tokens(dest)? {
+            if !perform {
+                decision!("Resume the partial receive into {}", name);
+                continue;
+            }
+            progress!("Resuming the partial receive into {}", name);
+            let send = vec!["-t".to_string(), token];
+            let size = estimate_size_on(from.host.as_deref(), &send)?;
+            progress!("Estimate: {}", humanize_size(size));
+            self.receive(from, &send, &name, size)?;
         }
-        cmd.arg(&format!("{}@{}", source, dsnap));
-        cmd.stderr(Stdio::inherit());
-        cmd.stdout(Stdio::piped());
-        let mut sender = cmd.spawn()?;
-
-        let send_out = sender.stdout.as_ref().expect("Child output").as_raw_fd();
-
-        // The unsafe is because using raw descriptors could make them available after they are
-        // closed.  These are being given to a spawn, which will be inherited by a fork, and is
-        // safe.
-        let mut pv = Command::new("pv")
-            .args(&["-s", &size.to_string()])
-            .stdin(unsafe { Stdio::from_raw_fd(send_out) })
-            .stdout(Stdio::piped())
-            .stderr(Stdio::inherit())
-            .spawn()?;
+        Ok(())
+    }
 
-        let pv_out = pv.stdout.as_ref().expect("PV output").as_raw_fd();
+    /// Receive into `dest` what `zfs send` sends with the arguments `send`, cleaning up after a
+    /// receive that fails.
+    fn receive(&self, from: &Zfs, send: &[String], dest: &str, size: usize) -> Result<()> {
+        self.inventory.invalidate();
+        let result = self.stream(from, send, dest, size);
+        if result.is_err() {
+            self.failed_receive(dest);
+        }
+        result
+    }
 
-        let mut receiver = Command::new("zfs")
-            .args(&["receive", "-vF", "-x", "mountpoint", dest])
-            .stdin(unsafe { Stdio::from_raw_fd(pv_out) })
-            .stderr(Stdio::inherit())
-            .spawn()?;
+    /// A resumable receive that fails leaves what it got behind, which has to be aborted, or
+    /// resumed, before anything else can be received.  Keep it, when asked to, or abort it, so
+    /// that the next clone starts afresh.
+    fn failed_receive(&self, dest: &str) {
+        let partial = match resume_tokens(dest) {
+            Ok(tokens) => tokens.iter().any(|(name, _)| name == dest),
+            Err(e) => {
+                warning!("Unable to check {} for a partial receive: {}", dest, e);
+                return;
+            }
+        };
+        if !partial {
+            return;
+        }
+        if self.options.keep_partial {
+            warning!(
+                "Kept the partial receive into {}, for the next clone to continue",
+                dest
+            );
+            return;
+        }
+        progress!("Aborting the partial receive into {}", dest);
+        let abort = Command::new("zfs").args(&["receive", "-A", dest]).checked_run();
+        if let Err(e) = abort {
+            warning!("Unable to abort the partial receive into {}: {}", dest, e);
+        }
+    }
 
-        // pv -s <size>
-        // zfs receive -vFu <dest>
+    /// Send from `from` to `dest`, through a pipeline.
+    fn stream(&self, from: &Zfs, send: &[String], dest: &str, size: usize) -> Result<()> {
+        // Construct a pipeline from zfs -> pv -> zfs.  PV is used to monitor the progress, and
+        // to limit the rate.  It writes the bytes copied so far, once a second, which are shown
+        // against the estimate.
+        let mut cmd = zfs_command(from.host.as_deref(), true);
+        cmd.arg("send");
+        cmd.args(send);
+        cmd.stderr(Stdio::inherit());
+
+        let mut pv_cmd = Command::new("pv");
+        pv_cmd.args(&["-f", "-n", "-b", "-i", "1"]);
+        if let Some(ref rate) = self.options.rate_limit {
+            pv_cmd.args(&["-L", rate]);
+        }
+
+        let mut receive = heavy_command("zfs");
+        // The receive is resumable, so that one that fails part way can be continued.
+        receive.args(&["receive", "-s", "-vF", "-x", "mountpoint"]);
+        receive.args(&self.options.recv_args).arg(dest);
+        receive.stderr(Stdio::inherit());
+
+        // A stalled pipeline, such as a hung receive or a dead ssh connection, is killed.
+        let mut watchdog = None;
+        let progress = OnceCell::new();
+
+        // Pausing stops pv, which leaves the send and receive waiting on it.
+        let (done, watch) = mpsc::channel();
+        let mut watch = Some(watch);
+        let mut pauser = None;
+
+        let mut meter = Meter::new(&format!("Clone {}", dest), Unit::Bytes, Some(size as u64));
+        let mut last = 0;
+        let statuses = watch_pipeline(
+            &mut [cmd, pv_cmd, receive],
+            (1, Watch::Stderr),
+            &mut |pids| {
+                if let Some(idle) = self.options.idle_timeout {
+                    let dog = Watchdog::start(idle, pids.to_vec());
+                    let _ = progress.set(dog.progress());
+                    watchdog = Some(dog);
+                }
+                if let (Some(window), Some(&pid)) = (self.options.pause, pids.get(1)) {
+                    let (watch, progress) = (watch.take().expect("Pause channel"), progress.get());
+                    let progress = progress.cloned();
+                    pauser = Some(thread::spawn(move || {
+                        pause_outside(window, pid, watch, progress)
+                    }));
+                }
+            },
+            &mut |line| {
+                if let Ok(count) = line.trim().parse() {
+                    if let (Some(progress), true) = (progress.get(), count != last) {
+                        progress.poke();
+                    }
+                    last = count;
+                    meter.set(count);
+                }
+                Ok(())
+            },
+        );
+        drop(meter);
+        drop(done);
+        if let Some(pauser) = pauser {
+            pauser.join().expect("Pause thread");
+        }
 
-        if !sender.wait()?.success() {
-            return Err(format_err!("zfs send error"));
+        let fired = watchdog.map_or(false, |w: Watchdog| w.finish());
+        let (sent, piped, received) = match statuses?[..] {
+            [sent, piped, received] => (sent, piped, received),
+            _ => unreachable!("Three commands in the pipeline"),
+        };
+        if fired {
+            return Err(Error::Timeout {
+                command: format!("clone to {}", dest),
+                secs: self.options.idle_timeout.map_or(0, |idle| idle.as_secs()),
+            });
+        }
+        if let (Some(ref host), Some(255)) = (&from.host, sent.code()) {
+            // ssh itself failed, rather than the zfs send it was running.
+            return Err(ZfsError::Connection { host: host.clone() }.into());
+        }
+        if !sent.success() {
+            return Err(ZfsError::Stream("zfs send").into());
         }
-        if !pv.wait()?.success() {
-            return Err(format_err!("pv error"));
+        if !piped.success() {
+            return Err(ZfsError::Stream("pv").into());
         }
-        if !receiver.wait()?.success() {
-            return Err(format_err!("zfs receive error"));
+        if !received.success() {
+            return Err(ZfsError::Stream("zfs receive").into());
         }
+        digest::volume("clone", dest);
+        digest::bytes(size as u64);
 
         Ok(())
     }
@@ -384,129 +997,71 @@ impl Zfs {
     /// Prune old snapshots.  This is a Hanoi-type pruning model, where we keep the most recent
     /// snapshot that has the same number of bits set in it.  In addition, we keep a certain number
     /// `PRUNE_KEEP` of the most recent snapshots.
-    pub fn prune_hanoi(&self, fs_name: &str, really: bool) -> Result<()> {
-        let fs = if let Some(fs) = self.filesystems.iter().find(|fs| fs.name == fs_name) {
-            fs
-        } else {
-            return Err(format_err!("Volume not found in zfs {:?}", fs_name));
-        };
+    pub fn prune_hanoi(&self, fs_name: &str, plan: &mut Plan) -> Result<()> {
+        let fs = self.find(fs_name)?;
 
         // Get all of the snapshots, oldest first, that match this tag, and pair them up with
         // the decoded number.
-        let mut snaps: Vec<_> = fs
+        let snaps: Vec<_> = fs
             .snaps
             .iter()
-            .filter_map(|sn| self.snap_number(sn).map(|num| (sn, num)))
+            .filter_map(|sn| self.snap_number(sn).map(|num| (sn.as_str(), num)))
             .collect();
-        snaps.reverse();
-
-        let mut pops = BTreeSet::<u32>::new();
-        let mut to_prune = vec![];
+        let keep = hanoi(&snaps);
 
-        for item in snaps.iter().enumerate() {
-            // Don't prune the most recent ones.
-            let index = item.0;
-            if index < PRUNE_KEEP {
+        // Now do the actual pruning, starting with the oldest ones.
+        for (name, _) in snaps.iter().filter(|(name, _)| !keep.contains(name)) {
+            if let Some(why) = fs.pinned(name) {
+                decision!(" keep {:?}@{:?}: {}", fs_name, name, why);
                 continue;
             }
-
-            let name = (item.1).0;
-            let num = (item.1).1;
-
-            let bit_count = num.count_ones();
-            if pops.contains(&bit_count) {
-                let prune_name = format!("{}@{}", fs_name, name);
-
-                to_prune.push(prune_name);
-            }
-            pops.insert(bit_count);
-        }
-
-        // Now do the actual pruning, starting with the oldest ones.
-        to_prune.reverse();
-
-        for prune_name in &to_prune {
-            println!(
-                "{}prune: {}",
-                if really { "" } else { "would " },
-                prune_name
+            let prune_name = format!("{}@{}", fs_name, name);
+            plan.run(
+                format!("prune: {}", prune_name),
+                Command::new("zfs").arg("destroy").arg(&prune_name),
             );
-            if really {
-                Command::new("zfs")
-                    .arg("destroy")
-                    .arg(&prune_name)
-                    .stderr(Stdio::inherit())
-                    .checked_run()?;
-            }
         }
 
         Ok(())
     }
 
-    /// Prune a single snapshot (possibly, based on `really`).  This will
-    /// attempt to make a bookmark first.
-    pub fn prune(&self, vol: &str, snap: &str, really: bool) -> Result<()> {
-        if really {
-            // Try creating a bookmark.
-            println!("pruning: {:?}@{:?}", vol, snap);
-            let status = Command::new("zfs")
-                .arg("bookmark")
-                .arg(&format!("{}@{}", vol, snap))
-                .arg(&format!("{}#{}", vol, snap))
-                .stderr(Stdio::inherit())
-                .status()?;
-            if !status.success() {
-                println!("  error creating bookmark");
-            }
-
-            // destroy the snapshot
-            Command::new("zfs")
-                .arg("destroy")
-                .arg(&format!("{}@{}", vol, snap))
-                .stderr(Stdio::inherit())
-                .checked_run()?;
-        } else {
-            println!("would prune {:?}@{:?}", vol, snap);
+    /// Plan the pruning of a single snapshot.  A bookmark is made first,
+    /// but failing to make one doesn't stop the prune.  Snapshots that are
+    /// held, or that have clones, can't be destroyed, so are kept, and
+    /// false returned.
+    pub fn prune(&self, vol: &str, snap: &str, reason: &str, plan: &mut Plan) -> bool {
+        let fs = self.find(vol).ok();
+        if let Some(why) = fs.and_then(|fs| fs.pinned(snap)) {
+            decision!(" keep {:?}@{:?}: {}", vol, snap, why);
+            return false;
         }
-        Ok(())
+        let marked = fs.map_or(false, |fs| fs.bookmarks.iter().any(|b| b == snap));
+        if !marked {
+            plan.try_run(
+                format!("bookmark {}@{} before pruning", vol, snap),
+                Command::new("zfs")
+                    .arg("bookmark")
+                    .arg(&format!("{}@{}", vol, snap))
+                    .arg(&format!("{}#{}", vol, snap)),
+            );
+        }
+        plan.run(
+            format!("prune {}@{}: {}", vol, snap, reason),
+            Command::new("zfs").arg("destroy").arg(&format!("{}@{}", vol, snap)),
+        );
+        true
     }
 
     /// Construct a new volume at "dest".  Copies over certain attributes (acltype, xattr, atime,
     /// relatime) that are relevant to the snapshot being correct.
-    fn make_volume(&self, src: &Filesystem, dest: &Filesystem) -> Result<()> {
+    fn make_volume(&self, from: &Zfs, src: &Filesystem, dest: &Filesystem) -> Result<()> {
         // Read the attributes from the source volume.
-        let out = Command::new("zfs")
+        let out = zfs_command(from.host.as_deref(), false)
             .args(&["get", "-Hp", "all", &src.name])
             .stderr(Stdio::inherit())
             .checked_output()?;
-        let buf = out.stdout;
-        let mut props = vec![];
-        for line in BufReader::new(&buf[..]).lines() {
-            let line = line?;
-            let fields: Vec<_> = line.split('\t').collect();
-            if fields.len() != 4 {
-                return Err(format_err!(
-                    "zfs get line doesn't have 4 fields: {:?}",
-                    line
-                ));
-            }
-            // 0 - name
-            // 1 - property
-            // 2 - value
-            // 3 - source
-
-            // We care about "local" or "received" properties, which are ones that will be set to a
-            // value not present.  But, don't include the 'mountpoint' property, so that the backup
-            // won't have things randomly mounted.
-            if fields[1] == "mountpoint" {
-                continue;
-            }
-            if fields[3] == "local" || fields[3] == "received" {
-                props.push("-o".into());
-                props.push(format!("{}={}", fields[1], fields[2]));
-            }
-        }
-        println!("   props: {:?}", props);
+        let props = create_props(&out.stdout, &self.options)?;
+        progress!("   props: {:?}", props);
 
         Command::new("zfs")
             .arg("create")
@@ -514,6 +1069,7 @@ impl Zfs {
             .arg(&dest.name)
             .stderr(Stdio::inherit())
             .checked_run()?;
+        self.inventory.invalidate();
 
         Ok(())
     }
@@ -528,23 +1084,219 @@ impl Zfs {
 /// mount table, instead of ZFS.  This also will correctly return an
 /// error if the volume is not mounted.
 pub fn find_mount(name: &str) -> Result<String> {
-    for line in BufReader::new(File::open("/proc/mounts")?).lines() {
+    mount::zfs_mountpoint(name)?.ok_or_else(|| {
+        ZfsError::NotMounted {
+            fs: name.to_owned(),
+        }
+        .into()
+    })
+}
+
+/// The arguments to `zfs create` for the properties of a source filesystem, from the output of
+/// `zfs get -Hp all`, with those of `options` overriding them.
+fn create_props(buf: &[u8], options: &CloneOptions) -> Result<Vec<String>> {
+    let mut props = vec![];
+    for line in BufReader::new(buf).lines() {
+        let line = line?;
+        let fields: Vec<_> = line.split('\t').collect();
+        if fields.len() != 4 {
+            let msg = format!("zfs get line doesn't have 4 fields: {:?}", line);
+            return Err(ZfsError::BadOutput(msg).into());
+        }
+        // 0 - name
+        // 1 - property
+        // 2 - value
+        // 3 - source
+
+        // We care about "local" or "received" properties, which are ones that will be set to a
+        // value not present.  But, don't include the 'mountpoint' property, so that the backup
+        // won't have things randomly mounted, nor those that are skipped or set.
+        let prop = fields[1];
+        if prop == "mountpoint"
+            || options.create_skip.iter().any(|p| p == prop)
+            || options.create_set.iter().any(|(p, _)| p == prop)
+        {
+            continue;
+        }
+        if fields[3] == "local" || fields[3] == "received" {
+            props.push("-o".into());
+            props.push(format!("{}={}", prop, fields[2]));
+        }
+    }
+    for (prop, value) in &options.create_set {
+        props.push("-o".into());
+        props.push(format!("{}={}", prop, value));
+    }
+    Ok(props)
+}
+
+/// The GUIDs of the snapshots of `fs`, on `host`, by the names of the snapshots.
+fn snapshot_guids(host: Option<&str>, fs: &str) -> Result<HashMap<String, String>> {
+    let out = zfs_command(host, false)
+        .args(&["get", "-H", "-p", "-o", "name,value", "-d", "1", "-t", "snapshot", "guid", fs])
+        .stderr(Stdio::inherit())
+        .checked_output()?;
+    let mut guids = HashMap::new();
+    for line in String::from_utf8(out.stdout)?.lines() {
+        match line.split_once('\t') {
+            Some((name, guid)) => match name.split_once('@') {
+                Some((_, snap)) => guids.insert(snap.to_string(), guid.to_string()),
+                None => continue,
+            },
+            None => {
+                let msg = format!("zfs get line doesn't have 2 fields: {:?}", line);
+                return Err(ZfsError::BadOutput(msg).into());
+            }
+        };
+    }
+    Ok(guids)
+}
+
+/// What is wrong with the snapshots of `dest`, received from `source`, whose latest snapshot is
+/// `latest`, by the GUIDs of each.  Snapshots since pruned from the source aren't checked.
+fn compare_guids(
+    source: &str,
+    dest: &str,
+    latest: &str,
+    theirs: &HashMap<String, String>,
+    ours: &HashMap<String, String>,
+) -> Vec<String> {
+    let mut problems = vec![];
+    if !ours.contains_key(latest) {
+        problems.push(format!("{} is missing the latest snapshot, @{}", dest, latest));
+    }
+    let mut snaps: Vec<_> = ours.iter().collect();
+    snaps.sort();
+    for (snap, guid) in snaps {
+        match theirs.get(snap) {
+            Some(source_guid) if source_guid != guid => problems.push(format!(
+                "{}@{} has guid {}, but {}@{} has {}",
+                dest, snap, guid, source, snap, source_guid
+            )),
+            _ => (),
+        }
+    }
+    problems
+}
+
+/// The name, relative to the destination of a clone, that the source filesystem with the relative
+/// name `suffix`, such as "/home/david", is received as, after the longest of the renames that
+/// covers it.
+fn renamed(renames: &[(String, String)], suffix: &str) -> String {
+    let covers = |from: &str| {
+        suffix.strip_prefix('/').map_or(false, |name| {
+            name == from || name.strip_prefix(from).map_or(false, |rest| rest.starts_with('/'))
+        })
+    };
+    match renames.iter().filter(|(from, _)| covers(from)).max_by_key(|(from, _)| from.len()) {
+        Some((from, to)) => format!("/{}{}", to, &suffix[from.len() + 1..]),
+        None => suffix.to_string(),
+    }
+}
+
+/// Use zfs send to estimate the size of the stream between two snapshots of a volume.  If the
+/// source snap is none, the estimate is of a full send of `dsnap`.
+pub fn estimate_size(source: &str, ssnap: Option<&str>, dsnap: &str) -> Result<usize> {
+    estimate_size_on(None, &send_args(source, ssnap, dsnap))
+}
+
+/// The arguments to `zfs send` for a stream of `source` up to `dsnap`, from `ssnap`, or a full
+/// stream if it is none.
+fn send_args(source: &str, ssnap: Option<&str>, dsnap: &str) -> Vec<String> {
+    let mut args = vec![];
+    if let Some(ssnap) = ssnap {
+        args.push("-I".to_string());
+        args.push(format!("@{}", ssnap));
+    }
+    args.push(format!("{}@{}", source, dsnap));
+    args
+}
+
+/// Estimate the size of the stream `zfs send` would send, with the arguments `send`, on `host`.
+fn estimate_size_on(host: Option<&str>, send: &[String]) -> Result<usize> {
+    let mut cmd = zfs_command(host, false);
+    cmd.arg("send");
+    cmd.arg("-nP");
+    cmd.args(send);
+    cmd.stderr(Stdio::inherit());
+    let out = cmd.checked_output()?;
+
+    let buf = out.stdout;
+    for line in BufReader::new(&buf[..]).lines() {
         let line = line?;
-        let fields: Vec<_> = line.split(' ').collect();
-        if fields.len() < 3 || fields[2] != "zfs" {
+        let fields: Vec<_> = line.split('\t').collect();
+        if fields.len() < 2 {
+            let msg = format!("Invalid line from zfs send size estimate: {:?}", line);
+            return Err(ZfsError::BadOutput(msg).into());
+        }
+        if fields[0] != "size" {
             continue;
         }
-        if fields[0] == name {
-            return Ok(fields[1].to_owned());
+
+        return fields[1].parse().map_err(|_| {
+            let msg = format!("Invalid size from zfs send size estimate: {:?}", line);
+            ZfsError::BadOutput(msg).into()
+        });
+    }
+
+    Ok(0)
+}
+
+/// The tokens to resume the receives kept in `dest`, and the volumes under it, by the volume they
+/// are in.  A `dest` that doesn't exist has nothing kept in it.
+fn resume_tokens(dest: &str) -> Result<Vec<(String, String)>> {
+    let out = Command::new("zfs")
+        .args(&["get", "-H", "-r", "-o", "name,value", "receive_resume_token", dest])
+        .stderr(Stdio::null())
+        .checked_output();
+    let out = match out {
+        Ok(out) => out,
+        Err(Error::Command { .. }) => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+    let mut tokens = vec![];
+    for line in String::from_utf8(out.stdout)?.lines() {
+        match line.split_once('\t') {
+            Some((_, "-")) => (),
+            Some((name, token)) => tokens.push((name.to_string(), token.to_string())),
+            None => {
+                let msg = format!("Invalid line from zfs get: {:?}", line);
+                return Err(ZfsError::BadOutput(msg).into());
+            }
         }
     }
-    return Err(RackError::NotMounted {
-        fs: name.to_owned(),
-    }.into());
+    Ok(tokens)
+}
+
+/// Get a numeric (size) property of a zfs dataset, in bytes.
+pub fn size_property(name: &str, prop: &str) -> Result<usize> {
+    let out = Command::new("zfs")
+        .args(&["get", "-Hp", "-o", "value", prop, name])
+        .stderr(Stdio::inherit())
+        .checked_output()?;
+    let text = String::from_utf8(out.stdout)?;
+    text.trim()
+        .parse()
+        .map_err(|_| {
+            let msg = format!("Invalid {} for {:?}: {:?}", prop, name, text.trim());
+            ZfsError::BadOutput(msg).into()
+        })
 }
 
-/// The number of recent ones to keep.
-const PRUNE_KEEP: usize = 10;
+/// Make sure the dataset `dest` has room for `needed` more bytes, so that a
+/// transfer fails up front, rather than after filling the pool.
+pub fn check_space(dest: &str, needed: usize) -> Result<()> {
+    let avail = size_property(dest, "available")?;
+    if needed > avail {
+        return Err(ZfsError::NoSpace {
+            dest: dest.to_string(),
+            needed: needed,
+            available: avail,
+        }
+        .into());
+    }
+    Ok(())
+}
 
 /// A `SnapBuilder` is used to build up the snapshot view of filesystems.
 struct SnapBuilder {
@@ -560,15 +1312,19 @@ impl SnapBuilder {
         self.work
     }
 
-    fn push_volume(&mut self, name: &str, mount: &str) {
+    fn push_volume(&mut self, name: &str, kind: DatasetKind, mount_state: MountState) {
         self.work.push(Filesystem {
             name: name.to_owned(),
+            kind: kind,
             snaps: vec![],
-            mount: mount.to_owned(),
+            bookmarks: vec![],
+            mount_state: mount_state,
+            pins: HashMap::new(),
+            space: HashMap::new(),
         });
     }
 
-    fn push_snap(&mut self, name: &str, snap: &str) {
+    fn push_snap(&mut self, name: &str, snap: &str, pins: Vec<Pin>, space: SnapSpace) {
         let pos = self.work.len();
         if pos == 0 {
             panic!("Got snapshot from zfs before volume");
@@ -578,6 +1334,18 @@ impl SnapBuilder {
             panic!("Got snapshot from zfs without same volume name");
         }
         set.snaps.push(snap.to_owned());
+        if !pins.is_empty() {
+            set.pins.insert(snap.to_owned(), pins);
+        }
+        set.space.insert(snap.to_owned(), space);
+    }
+
+    /// Bookmarks aren't always listed right after their dataset, so look for it.
+    fn push_bookmark(&mut self, name: &str, mark: &str) {
+        match self.work.iter_mut().rev().find(|fs| fs.name == name) {
+            Some(set) => set.bookmarks.push(mark.to_owned()),
+            None => panic!("Got bookmark from zfs before volume"),
+        }
     }
 }
 
@@ -609,7 +1377,7 @@ impl Exclusions {
 }
 
 /// Humanize sizes with base-2 SI-like prefixes.
-fn humanize_size(size: usize) -> String {
+pub fn humanize_size(size: usize) -> String {
     // This unit table covers at least 80 bits, so the later ones will never be used.
     static UNITS: &'static [&'static str] = &[
         "B  ", "KiB", "MiB", "GiB", "TiB", "PiB", "EiB", "ZiB", "YiB",
@@ -633,3 +1401,375 @@ fn humanize_size(size: usize) -> String {
 
     format!("{:6.*}{}", precision, value, UNITS[unit])
 }
+
+#[test]
+fn test_prune_hanoi() {
+    use crate::checked::{set_executor, RecordingExecutor};
+    use std::sync::Arc;
+
+    // Twenty snapshots, 0 through 19, of one volume.
+    // Snapshot 3 is held, and "manual" has been cloned.
+    let mut list = "pool/home\tfilesystem\tyes\t-\t-\t0\t0\t0\t/home\n".to_string();
+    for num in 0..20 {
+        let holds = if num == 3 { 1 } else { 0 };
+        let snap =
+            format!("pool/home@caz{:04}-201903041530\tsnapshot\t-\t{}\t\t0\t0\t0\t-\n", num, holds);
+        list.push_str(&snap);
+    }
+    list.push_str("pool/home@manual\tsnapshot\t-\t0\tpool/work\t0\t0\t0\t-\n");
+    list.push_str("pool/home@other\tsnapshot\t-\t0\t\t0\t0\t0\t-\n");
+    list.push_str("pool/home#other\tbookmark\t-\t-\t-\t0\t0\t0\t-\n");
+    let exec = Arc::new(RecordingExecutor::new());
+    exec.respond(&["zfs", "list"], &list);
+    let running = set_executor(exec.clone());
+
+    let zfs = Zfs::new("caz").unwrap();
+    assert_eq!(zfs.filesystems.len(), 1);
+    assert_eq!(zfs.filesystems[0].snaps.len(), 22);
+    assert_eq!(zfs.next_under("pool").unwrap(), 20);
+
+    let mut plan = Plan::new("prune");
+    zfs.prune_hanoi("pool/home", &mut plan).unwrap();
+    assert!(!zfs.prune("pool/home", "manual", "testing", &mut plan));
+    assert!(zfs.prune("pool/home", "other", "testing", &mut plan));
+    plan.apply().unwrap();
+    drop(running);
+
+    // The newest ten are kept.  Of the rest, the newest with each bit count
+    // are kept: 9, 8, 7, and 0.  3 is held, so is kept as well.
+    let destroyed: Vec<_> = [1, 2, 4, 5, 6]
+        .iter()
+        .map(|n| format!("zfs destroy pool/home@caz{:04}-201903041530", n))
+        .collect();
+    let list = format!("zfs list -Hp -t all -o {}", LIST_FIELDS);
+    let mut expect = vec![list];
+    expect.extend(destroyed);
+    // The bookmark already exists, so only the snapshot is destroyed.
+    expect.push("zfs destroy pool/home@other".into());
+    assert_eq!(exec.commands(), expect);
+}
+
+#[test]
+fn test_inventory() {
+    use crate::checked::{set_executor, RecordingExecutor};
+    use std::sync::Arc;
+
+    let exec = Arc::new(RecordingExecutor::new());
+    exec.respond(
+        &["zfs", "list"],
+        "pool/home\tfilesystem\tyes\t-\t-\t0\t0\t0\t/home\n\
+         pool/home@daily-201903041530\tsnapshot\t-\t0\t\t1024\t4096\t1551713400\t-\n\
+         pool/old\tfilesystem\tno\t-\t-\t0\t0\t0\tlegacy\n\
+         pool/swap\tvolume\t-\t-\t-\t0\t0\t0\t-\n\
+         pool/swap@daily-201903041530\tsnapshot\t-\t0\t\t0\t0\t0\t-\n\
+         pool/home#daily-201903031530\tbookmark\t-\t-\t-\t0\t0\t0\t-\n",
+    );
+    let running = set_executor(exec.clone());
+
+    // The list is only read once, until something changes it.
+    let inv = Inventory::new();
+    let zfs = Zfs::from_inventory("none", &inv).unwrap();
+    Zfs::from_inventory("caz", &inv.clone()).unwrap();
+    zfs.take_snapshot("pool/home", 1).unwrap();
+    let zfs = Zfs::from_inventory("none", &inv).unwrap();
+    drop(running);
+
+    let home = zfs.find("pool/home").unwrap();
+    assert_eq!(home.snaps, vec!["daily-201903041530"]);
+    assert_eq!(home.bookmarks, vec!["daily-201903031530"]);
+    let space = SnapSpace {
+        used: 1024,
+        referenced: 4096,
+        creation: 1551713400,
+    };
+    assert_eq!(home.space["daily-201903041530"], space);
+    assert_eq!(zfs.find("pool/swap").unwrap().kind, DatasetKind::Volume);
+    assert!(zfs.find_filesystem("pool/swap").is_err());
+    let old = zfs.find("pool/old").unwrap();
+    assert_eq!(old.mount_state, MountState::Legacy);
+    assert!(old.readable(Unmounted::Fail).is_err());
+    assert!(!old.readable(Unmounted::Skip).unwrap());
+    let lists = exec.commands().iter().filter(|c| c.starts_with("zfs list")).count();
+    assert_eq!(lists, 2);
+}
+
+#[test]
+fn test_unchanged() {
+    use crate::checked::{set_executor, RecordingExecutor};
+    use std::sync::Arc;
+
+    let exec = Arc::new(RecordingExecutor::new());
+    exec.respond(
+        &["zfs", "list"],
+        "pool/home\tfilesystem\tyes\t-\t-\t0\t0\t0\t/home\n\
+         pool/home@daily-201903041530\tsnapshot\t-\t0\t\t0\t0\t0\t-\n\
+         pool/home@before-upgrade\tsnapshot\t-\t0\t\t0\t0\t0\t-\n\
+         pool/new\tfilesystem\tyes\t-\t-\t0\t0\t0\t/new\n\
+         pool/new@manual\tsnapshot\t-\t0\t\t0\t0\t0\t-\n",
+    );
+    exec.respond(&["zfs", "get"], "0\n");
+    let running = set_executor(exec.clone());
+    let zfs = Zfs::new("none").unwrap();
+    // The newer snapshot taken by hand is passed over.
+    assert_eq!(zfs.unchanged("pool/home", "daily").unwrap(), Some("daily-201903041530"));
+    assert_eq!(zfs.unchanged("pool/new", "daily").unwrap(), None);
+    drop(running);
+
+    let get = "zfs get -H -p -o value written@daily-201903041530 pool/home";
+    assert_eq!(exec.commands()[1..], [get]);
+}
+
+#[test]
+fn test_pull() {
+    use crate::checked::{set_executor, RecordingExecutor};
+    use std::sync::Arc;
+
+    let exec = Arc::new(RecordingExecutor::new());
+    exec.respond(
+        &["ssh", "root@laptop", "zfs", "list"],
+        "tank/home\tfilesystem\tyes\t-\t-\t0\t0\t0\t/home\n\
+         tank/home@a\tsnapshot\t-\t0\t\t0\t0\t0\t-\n\
+         tank/home@b\tsnapshot\t-\t0\t\t0\t0\t0\t-\n",
+    );
+    exec.respond(
+        &["zfs", "list"],
+        "backup/laptop\tfilesystem\tno\t-\t-\t0\t0\t0\tnone\n\
+         backup/laptop@a\tsnapshot\t-\t0\t\t0\t0\t0\t-\n",
+    );
+    exec.respond(&["ssh", "root@laptop", "zfs", "send"], "size\t1024\n");
+    let running = set_executor(exec.clone());
+
+    let remote = Inventory::remote("root@laptop").within("tank/home");
+    let from = Zfs::from_inventory("caz", &remote).unwrap();
+    let zfs = Zfs::from_inventory("caz", &Inventory::new()).unwrap();
+    zfs.clone_from(&from, "tank/home", "backup/laptop", false, &[]).unwrap();
+    drop(running);
+
+    let list = format!("zfs list -Hp -t all -o {}", LIST_FIELDS);
+    assert_eq!(
+        exec.commands(),
+        vec![
+            format!("ssh root@laptop {} -r tank/home", list),
+            list.clone(),
+            "ssh root@laptop zfs send -nP -I @a tank/home@b".to_string(),
+        ]
+    );
+}
+
+#[test]
+fn test_clone_fresh_zvol() {
+    use crate::checked::{set_executor, RecordingExecutor};
+    use std::sync::Arc;
+
+    let exec = Arc::new(RecordingExecutor::new());
+    exec.respond(
+        &["zfs", "list"],
+        "tank/vm\tvolume\t-\t-\t-\t0\t0\t0\t-\n\
+         tank/vm@a\tsnapshot\t-\t0\t\t0\t0\t0\t-\n\
+         backup\tfilesystem\tno\t-\t-\t0\t0\t0\tnone\n",
+    );
+    exec.respond(&["zfs", "send", "-nP"], "size\t1024\n");
+    exec.respond(&["zfs", "get", "-Hp", "-o", "value", "available"], "4096\n");
+    let running = set_executor(exec.clone());
+
+    let zfs = Zfs::from_inventory("caz", &Inventory::new()).unwrap();
+    zfs.clone_from(&zfs, "tank/vm", "backup/vm", false, &[]).unwrap();
+    zfs.clone_from(&zfs, "tank/vm", "backup/vm", true, &[]).unwrap();
+    drop(running);
+
+    // Nothing is sent when pretending, and the room is checked in backup, as backup/vm doesn't
+    // exist until it is received.
+    let commands = exec.commands();
+    let sends: Vec<_> = commands.iter().filter(|c| !c.starts_with("zfs list")).collect();
+    assert_eq!(sends[0], "zfs send -nP tank/vm@a");
+    assert_eq!(sends[1], "zfs send -nP tank/vm@a");
+    assert_eq!(sends[2], "zfs get -Hp -o value available backup");
+    assert!(sends[3].starts_with("zfs send tank/vm@a | pv"), "{}", sends[3]);
+    assert!(sends[3].ends_with("backup/vm"), "{}", sends[3]);
+}
+
+#[test]
+fn test_plan_orphans() {
+    use crate::checked::{set_executor, RecordingExecutor};
+    use std::sync::Arc;
+
+    let exec = Arc::new(RecordingExecutor::new());
+    exec.respond(
+        &["zfs", "list"],
+        "tank\tfilesystem\tno\t-\t-\t0\t0\t0\tnone\n\
+         tank/home\tfilesystem\tyes\t-\t-\t0\t0\t0\t/home\n\
+         tank/home/alice\tfilesystem\tyes\t-\t-\t0\t0\t0\t/home/alice\n\
+         backup\tfilesystem\tno\t-\t-\t0\t0\t0\tnone\n\
+         backup/homes\tfilesystem\tno\t-\t-\t0\t0\t0\tnone\n\
+         backup/homes/alice\tfilesystem\tno\t-\t-\t0\t0\t0\tnone\n\
+         backup/homes/bob\tfilesystem\tno\t-\t-\t0\t0\t0\tnone\n\
+         backup/homes/bob@a\tsnapshot\t-\t0\t\t0\t0\t1000\t-\n\
+         backup/homes/carol\tfilesystem\tno\t-\t-\t0\t0\t0\tnone\n\
+         backup/homes/carol@a\tsnapshot\t-\t1\t\t0\t0\t1000\t-\n\
+         backup/homes/dave\tfilesystem\tno\t-\t-\t0\t0\t0\tnone\n\
+         backup/homes/dave@a\tsnapshot\t-\t0\t\t0\t0\t9000\t-\n\
+         backup/old\tfilesystem\tno\t-\t-\t0\t0\t0\tnone\n\
+         backup/old/keep\tfilesystem\tno\t-\t-\t0\t0\t0\tnone\n\
+         backup/old/keep@a\tsnapshot\t-\t2\t\t0\t0\t1000\t-\n\
+         backup/older\tfilesystem\tno\t-\t-\t0\t0\t0\tnone\n\
+         backup/older/a\tfilesystem\tno\t-\t-\t0\t0\t0\tnone\n\
+         backup/misc\tfilesystem\tno\t-\t-\t0\t0\t0\tnone\n\
+         backup/laptop\tfilesystem\tno\t-\t-\t0\t0\t0\tnone\n\
+         backup/laptop/home\tfilesystem\tno\t-\t-\t0\t0\t0\tnone\n",
+    );
+    let running = set_executor(exec.clone());
+    let mut zfs = Zfs::new("caz").unwrap();
+    zfs.options.renames = vec![("home".into(), "homes".into())];
+    let vol = |source: &str| -> CloneVolume {
+        let text = format!(
+            "{{name: x, source: {}, dest: backup, delete_orphans: [homes, old, older, laptop]}}",
+            source
+        );
+        serde_yaml::from_str(&text).unwrap()
+    };
+    let mut plan = Plan::new("clone-delete");
+    zfs.plan_orphans(&zfs, &vol("tank/gone"), &[], 5000, &mut plan).unwrap_err();
+    zfs.plan_orphans(&zfs, &vol("tank"), &["backup/laptop"], 5000, &mut plan).unwrap();
+    drop(running);
+
+    // Bob's is old and not held.  Carol's is held, and Dave's recent.  Old is kept for what is
+    // held under it.  Older goes with everything under it, misc isn't listed to be deleted, and
+    // laptop is another volume's.
+    let destroyed: Vec<_> = plan
+        .actions
+        .iter()
+        .map(|a| match a {
+            crate::plan::Action::Run { command, .. } => command.join(" "),
+            a => panic!("Unexpected action: {:?}", a),
+        })
+        .collect();
+    assert_eq!(destroyed, vec!["zfs destroy -r backup/older", "zfs destroy -r backup/homes/bob"]);
+}
+
+#[test]
+fn test_plan_renumber_clone() {
+    use crate::checked::{set_executor, RecordingExecutor};
+    use std::sync::Arc;
+
+    let exec = Arc::new(RecordingExecutor::new());
+    exec.respond(
+        &["zfs", "list"],
+        "tank\tfilesystem\tno\t-\t-\t0\t0\t0\tnone\n\
+         tank/home\tfilesystem\tyes\t-\t-\t0\t0\t0\t/home\n\
+         tank/home@caz42-201903041530\tsnapshot\t-\t0\t\t0\t0\t1000\t-\n\
+         tank/home/alice\tfilesystem\tyes\t-\t-\t0\t0\t0\t/home/alice\n\
+         tank/home/alice@caz42-201903041530\tsnapshot\t-\t0\t\t0\t0\t1000\t-\n\
+         tank/misc\tfilesystem\tyes\t-\t-\t0\t0\t0\t/misc\n\
+         tank/misc@caz42-201903041530\tsnapshot\t-\t0\t\t0\t0\t1000\t-\n\
+         backup\tfilesystem\tno\t-\t-\t0\t0\t0\tnone\n\
+         backup/homes\tfilesystem\tno\t-\t-\t0\t0\t0\tnone\n\
+         backup/homes@caz42-201903041530\tsnapshot\t-\t0\t\t0\t0\t1000\t-\n\
+         backup/homes/alice\tfilesystem\tno\t-\t-\t0\t0\t0\tnone\n\
+         backup/misc\tfilesystem\tno\t-\t-\t0\t0\t0\tnone\n\
+         backup/misc@caz42-201903041530\tsnapshot\t-\t0\t\t0\t0\t1000\t-\n",
+    );
+    let running = set_executor(exec.clone());
+    let zfs = Zfs::new("caz").unwrap();
+    let vol: CloneVolume =
+        serde_yaml::from_str("{name: x, source: tank, dest: backup, renames: {home: homes}}")
+            .unwrap();
+    let mut plan = Plan::new("renumber");
+    zfs.plan_renumber("tank/home", &mut plan).unwrap();
+    zfs.plan_renumber_clone("tank/home", &vol, &mut plan).unwrap();
+    drop(running);
+
+    // Alice's clone hasn't received that snapshot, and misc isn't being renumbered.
+    let renamed: Vec<_> = plan
+        .actions
+        .iter()
+        .map(|a| match a {
+            crate::plan::Action::Run { command, .. } => command[2..].join(" "),
+            a => panic!("Unexpected action: {:?}", a),
+        })
+        .collect();
+    assert_eq!(
+        renamed,
+        vec![
+            "tank/home@caz42-201903041530 tank/home@caz0042-201903041530",
+            "tank/home/alice@caz42-201903041530 tank/home/alice@caz0042-201903041530",
+            "backup/homes@caz42-201903041530 backup/homes@caz0042-201903041530",
+        ]
+    );
+}
+
+#[test]
+fn test_create_props() {
+    let get = "tank/home\tcompression\tlz4\tlocal\n\
+               tank/home\tmountpoint\t/home\tlocal\n\
+               tank/home\tquota\t1000000\tlocal\n\
+               tank/home\tcom.sun:auto-snapshot\ttrue\treceived\n\
+               tank/home\tatime\toff\tinherited from tank\n\
+               tank/home\tcanmount\ton\tlocal\n";
+    let options = CloneOptions {
+        create_set: vec![("canmount".into(), "off".into())],
+        create_skip: vec!["quota".into(), "com.sun:auto-snapshot".into()],
+        ..CloneOptions::default()
+    };
+    assert_eq!(
+        create_props(get.as_bytes(), &options).unwrap(),
+        vec!["-o", "compression=lz4", "-o", "canmount=off"]
+    );
+}
+
+#[test]
+fn test_compare_guids() {
+    let guids = |pairs: &[(&str, &str)]| -> HashMap<String, String> {
+        pairs.iter().map(|(s, g)| (s.to_string(), g.to_string())).collect()
+    };
+    let theirs = guids(&[("b", "22"), ("c", "33")]);
+    let good = guids(&[("a", "11"), ("b", "22"), ("c", "33")]);
+    assert!(compare_guids("tank/home", "backup/home", "c", &theirs, &good).is_empty());
+
+    let bad = guids(&[("a", "11"), ("b", "99")]);
+    assert_eq!(
+        compare_guids("tank/home", "backup/home", "c", &theirs, &bad),
+        vec![
+            "backup/home is missing the latest snapshot, @c",
+            "backup/home@b has guid 99, but tank/home@b has 22",
+        ]
+    );
+}
+
+#[test]
+fn test_renamed() {
+    let renames = vec![
+        ("home".to_string(), "homes".to_string()),
+        ("home/david".to_string(), "people/david".to_string()),
+    ];
+    assert_eq!(renamed(&renames, ""), "");
+    assert_eq!(renamed(&renames, "/home"), "/homes");
+    assert_eq!(renamed(&renames, "/home/alice"), "/homes/alice");
+    assert_eq!(renamed(&renames, "/home/david"), "/people/david");
+    assert_eq!(renamed(&renames, "/home/david/src"), "/people/david/src");
+    assert_eq!(renamed(&renames, "/homework"), "/homework");
+}
+
+#[test]
+fn test_resume_tokens() {
+    use crate::checked::{set_executor, RecordingExecutor};
+    use std::sync::Arc;
+
+    let exec = Arc::new(RecordingExecutor::new());
+    exec.respond(
+        &["zfs", "get"],
+        "backup/laptop\t-\n\
+         backup/laptop/home\t1-e3f2a8c1d-c8-789c636064\n",
+    );
+    let running = set_executor(exec.clone());
+    let tokens = resume_tokens("backup/laptop").unwrap();
+    drop(running);
+
+    assert_eq!(
+        tokens,
+        vec![("backup/laptop/home".to_string(), "1-e3f2a8c1d-c8-789c636064".to_string())]
+    );
+    assert_eq!(
+        exec.commands(),
+        vec!["zfs get -H -r -o name,value receive_resume_token backup/laptop"]
+    );
+}